@@ -1,18 +1,85 @@
 use anyhow::{Context, Result};
+use lsp_types::Url;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::{debug, info};
+
+use crate::utils::{override_json, secs_to_duration};
 
 pub(crate) type Kwargs = HashMap<String, Value>;
 
+// Completion parameters that build the completion prompt rather than configure the model
+const COMPLETION_PROMPT_PARAMETERS: &[&str] = &[
+    "fim",
+    "fim_template",
+    "fim_mode",
+    "infill",
+    "max_context",
+    "messages",
+    "system",
+    "contents",
+    "systemInstruction",
+    "prefill",
+    "include_prefill",
+];
+
+// Config files looked for in the workspace root when no `--config` is passed
+const WORKSPACE_CONFIG_FILES: [&str; 2] = [".lsp-ai.json", ".lsp-ai.toml"];
+
 const fn max_requests_per_second_default() -> f32 {
     1.
 }
 
+const fn max_concurrent_requests_default() -> usize {
+    4
+}
+
 const fn true_default() -> bool {
     true
 }
 
+// End of turn tokens. Anything a model generates after one of these is dropped
+pub(crate) const END_OF_TURN_TOKENS: [&str; 8] = [
+    "<|im_end|>",
+    "</s>",
+    "<|eot_id|>",
+    "<|endoftext|>",
+    "<|end_of_text|>",
+    "<|end|>",
+    "<end_of_turn>",
+    "<EOT>",
+];
+
+fn special_tokens_to_strip_default() -> Vec<String> {
+    END_OF_TURN_TOKENS
+        .into_iter()
+        .chain([
+            "<|im_start|>",
+            "<|begin_of_text|>",
+            "<fim_prefix>",
+            "<fim_suffix>",
+            "<fim_middle>",
+            "<|fim_prefix|>",
+            "<|fim_suffix|>",
+            "<|fim_middle|>",
+            "<｜fim▁begin｜>",
+            "<｜fim▁hole｜>",
+            "<｜fim▁end｜>",
+        ])
+        .map(|x| x.to_string())
+        .collect()
+}
+
+pub(crate) const fn max_generated_chars_default() -> Option<usize> {
+    Some(20_000)
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct PostProcess {
     pub(crate) extractor: Option<String>,
@@ -20,6 +87,16 @@ pub(crate) struct PostProcess {
     pub(crate) remove_duplicate_start: bool,
     #[serde(default = "true_default")]
     pub(crate) remove_duplicate_end: bool,
+    // Special tokens removed from the response. If one of the `END_OF_TURN_TOKENS` is in this list
+    // the response is also cut off where it first appears. Set to an empty list to disable
+    #[serde(default = "special_tokens_to_strip_default")]
+    pub(crate) special_tokens_to_strip: Vec<String>,
+    // Responses longer than this many characters are truncated. Set to null to disable
+    #[serde(default = "max_generated_chars_default")]
+    pub(crate) max_generated_chars: Option<usize>,
+    // Cuts off responses that loop over the same lines
+    #[serde(default)]
+    pub(crate) repetition: RepetitionDetection,
 }
 
 impl Default for PostProcess {
@@ -28,6 +105,36 @@ impl Default for PostProcess {
             extractor: None,
             remove_duplicate_start: true,
             remove_duplicate_end: true,
+            special_tokens_to_strip: special_tokens_to_strip_default(),
+            max_generated_chars: max_generated_chars_default(),
+            repetition: RepetitionDetection::default(),
+        }
+    }
+}
+
+const fn max_block_lines_default() -> usize {
+    8
+}
+
+const fn repetitions_default() -> usize {
+    4
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub(crate) struct RepetitionDetection {
+    // The longest block of lines checked for repeats
+    #[serde(default = "max_block_lines_default")]
+    pub(crate) max_block_lines: usize,
+    // How many times in a row a block has to appear to be cut off. Set to 0 to disable
+    #[serde(default = "repetitions_default")]
+    pub(crate) repetitions: usize,
+}
+
+impl Default for RepetitionDetection {
+    fn default() -> Self {
+        Self {
+            max_block_lines: max_block_lines_default(),
+            repetitions: repetitions_default(),
         }
     }
 }
@@ -57,10 +164,14 @@ const fn chunk_overlap_default() -> usize {
 
 #[derive(Debug, Clone, Deserialize)]
 pub(crate) struct TreeSitter {
+    // In bytes
     #[serde(default = "chunk_size_default")]
     pub(crate) chunk_size: usize,
+    // Bytes shared by consecutive chunks. Must be less than `chunk_size`
     #[serde(default = "chunk_overlap_default")]
     pub(crate) chunk_overlap: usize,
+    #[serde(default)]
+    pub(crate) granularity: TreeSitterGranularity,
 }
 
 impl Default for TreeSitter {
@@ -68,17 +179,37 @@ impl Default for TreeSitter {
         Self {
             chunk_size: 1500,
             chunk_overlap: 0,
+            granularity: TreeSitterGranularity::default(),
         }
     }
 }
 
+// Where the tree_sitter splitter may end a chunk
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub(crate) enum TreeSitterGranularity {
+    // At any node, packing neighbouring nodes together
+    #[default]
+    #[serde(rename = "node")]
+    Node,
+    // Only between whole declarations like functions, classes and impl blocks, unless a single
+    // declaration is larger than `chunk_size`
+    #[serde(rename = "declaration")]
+    Declaration,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub(crate) struct TextSplitter {
+    // In characters
     #[serde(default = "chunk_size_default")]
     pub(crate) chunk_size: usize,
+    // Characters shared by consecutive chunks. Must be less than `chunk_size`
+    #[serde(default = "chunk_overlap_default")]
+    pub(crate) chunk_overlap: usize,
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
+// Prepended to texts before they are embedded. Many models expect a different prefix for the
+// texts that are stored and the queries used to retrieve them
+#[derive(Debug, Clone, Deserialize, Default, PartialEq)]
 pub(crate) struct EmbeddingPrefix {
     #[serde(default)]
     pub(crate) storage: String,
@@ -95,6 +226,8 @@ pub(crate) struct OllamaEmbeddingModel {
     // The prefix to apply to the embeddings
     #[serde(default)]
     pub(crate) prefix: EmbeddingPrefix,
+    // The number of dimensions the model's embeddings have. Embeddings of any other size are rejected
+    pub(crate) dimensions: Option<usize>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -104,6 +237,35 @@ pub(crate) enum ValidEmbeddingModel {
     Ollama(OllamaEmbeddingModel),
 }
 
+impl ValidEmbeddingModel {
+    pub(crate) fn prefix(&self) -> &EmbeddingPrefix {
+        match self {
+            ValidEmbeddingModel::Ollama(config) => &config.prefix,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CohereReranker {
+    // The rerank endpoint, default: 'https://api.cohere.com/v1/rerank'
+    pub(crate) endpoint: Option<String>,
+    // The model name, e.g. 'rerank-english-v3.0'
+    pub(crate) model: String,
+    // The auth token env var name
+    pub(crate) auth_token_env_var_name: Option<String>,
+    // The auth token
+    pub(crate) auth_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+pub(crate) enum ValidReranker {
+    // Also works with other APIs matching Cohere's, like Jina AI and Voyage AI
+    #[serde(rename = "cohere")]
+    Cohere(CohereReranker),
+}
+
 #[derive(Debug, Clone, Copy, Deserialize)]
 pub(crate) enum VectorDataType {
     #[serde(rename = "f32")]
@@ -112,6 +274,16 @@ pub(crate) enum VectorDataType {
     Binary,
 }
 
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+pub(crate) enum Similarity {
+    // The fast path. Only equivalent to cosine similarity if the embedding model returns normalized vectors
+    #[serde(rename = "dot")]
+    Dot,
+    // Normalizes vectors when they are stored and searched so unnormalized embeddings rank correctly
+    #[serde(rename = "cosine")]
+    Cosine,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub(crate) struct VectorStore {
     pub(crate) crawl: Option<Crawl>,
@@ -119,6 +291,49 @@ pub(crate) struct VectorStore {
     pub(crate) splitter: ValidSplitter,
     pub(crate) embedding_model: ValidEmbeddingModel,
     pub(crate) data_type: VectorDataType,
+    // Exclude every chunk of the current file from search instead of only the chunk containing the cursor
+    #[serde(default)]
+    pub(crate) exclude_current_file: bool,
+    // Defaults to `dot` for embedding models that return normalized vectors and `cosine` otherwise
+    pub(crate) similarity: Option<Similarity>,
+    // Only used with the `binary` data_type. Retrieves this many candidates by hamming distance and
+    // reranks them against the full precision query embedding. Somewhere around 4 to 10 times the
+    // number of chunks that fit in the context window works well. Values below that number are ignored
+    pub(crate) rerank_top_k: Option<usize>,
+    // Chunks scoring below this are left out of the context. The score is the cosine similarity
+    // with `cosine` and the raw dot product with `dot`. With the `binary` data_type it is translated
+    // to a maximum hamming distance
+    pub(crate) min_score: Option<f32>,
+    // The max number of chunks put in the context. Fewer are used when they don't fit in `max_context`
+    pub(crate) retrieval_limit: Option<usize>,
+    // Files whose chunks are kept out of the context
+    #[serde(default)]
+    pub(crate) retrieval_exclude: Vec<RetrievalExclusion>,
+    // Where the most relevant retrieved chunk goes in the context. `relevance_desc` puts it first
+    // and `relevance_asc` puts it last, right before the code. The least relevant end is cut when
+    // the context does not fit
+    #[serde(default)]
+    pub(crate) context_order: ContextOrder,
+    // Also rank chunks with an in memory BM25 index and fuse both rankings. Helps when exact
+    // identifier matches matter more than meaning
+    #[serde(default)]
+    pub(crate) hybrid: bool,
+    // Added to the score of chunks containing identifiers from around the cursor, scaled by the
+    // fraction of those identifiers they contain. On the scale of `min_score`, e.g. 0.1
+    pub(crate) keyword_boost: Option<f32>,
+    // Reorders the retrieved chunks by relevance to the code around the cursor before the best
+    // ones are put in the context. Without it chunks are ordered by their vector similarity
+    pub(crate) reranker: Option<ValidReranker>,
+    // The number of chunks retrieved for the reranker to choose from, default: 4 times the number of
+    // chunks put in the context
+    pub(crate) rerank_candidates: Option<usize>,
+    // The header put above every chunk. Supports the `{path}`, `{language}` and `{language_id}`
+    // placeholders. Use `{language_id}` (e.g. `cpp`) for markdown code fences, `{language}` is a
+    // display name (e.g. `C++`)
+    // Defaults to `--{path}--`
+    pub(crate) chunk_header_template: Option<String>,
+    // Files put in the context before the retrieved chunks
+    pub(crate) pinned_context_files: Option<PinnedContextFiles>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -137,8 +352,12 @@ pub(crate) enum ValidModel {
     #[cfg(feature = "llama_cpp")]
     #[serde(rename = "llama_cpp")]
     LLaMACPP(LLaMACPP),
+    #[serde(rename = "llama_cpp_server")]
+    LLaMACPPServer(LLaMACPPServer),
     #[serde(rename = "open_ai")]
     OpenAI(OpenAI),
+    #[serde(rename = "open_ai_compatible")]
+    OpenAICompatible(OpenAICompatible),
     #[serde(rename = "anthropic")]
     Anthropic(Anthropic),
     #[serde(rename = "mistral_fim")]
@@ -147,6 +366,82 @@ pub(crate) enum ValidModel {
     Ollama(Ollama),
     #[serde(rename = "gemini")]
     Gemini(Gemini),
+    #[cfg(feature = "mock")]
+    #[serde(rename = "mock")]
+    Mock(Mock),
+}
+
+impl ValidModel {
+    fn request_timeout(&self) -> Option<f32> {
+        match self {
+            #[cfg(feature = "llama_cpp")]
+            ValidModel::LLaMACPP(llama_cpp) => llama_cpp.request_timeout,
+            ValidModel::LLaMACPPServer(llama_cpp_server) => llama_cpp_server.request_timeout,
+            ValidModel::OpenAI(open_ai) => open_ai.request_timeout,
+            ValidModel::OpenAICompatible(open_ai_compatible) => open_ai_compatible.request_timeout,
+            ValidModel::Anthropic(anthropic) => anthropic.request_timeout,
+            ValidModel::MistralFIM(mistral_fim) => mistral_fim.request_timeout,
+            ValidModel::Ollama(ollama) => ollama.request_timeout,
+            ValidModel::Gemini(gemini) => gemini.request_timeout,
+            #[cfg(feature = "mock")]
+            ValidModel::Mock(mock) => mock.request_timeout,
+        }
+    }
+
+    // Whether the model runs on this machine so requests to it cost nothing
+    pub(crate) fn is_local(&self) -> bool {
+        // For backends whose default endpoints are local
+        let all_local = |endpoints: &[Option<&String>]| {
+            endpoints
+                .iter()
+                .flatten()
+                .all(|endpoint| is_local_url(endpoint))
+        };
+        // For backends without default endpoints or with hosted ones
+        let all_local_and_set = |endpoints: &[Option<&String>]| {
+            endpoints.iter().any(Option::is_some) && all_local(endpoints)
+        };
+        match self {
+            #[cfg(feature = "llama_cpp")]
+            ValidModel::LLaMACPP(_) => true,
+            ValidModel::LLaMACPPServer(llama_cpp_server) => {
+                all_local(&[llama_cpp_server.endpoint.as_ref()])
+            }
+            ValidModel::OpenAI(open_ai) => all_local_and_set(&[
+                open_ai.completions_endpoint.as_ref(),
+                open_ai.chat_endpoint.as_ref(),
+            ]),
+            ValidModel::OpenAICompatible(open_ai_compatible) => {
+                is_local_url(&open_ai_compatible.base_url)
+            }
+            ValidModel::Anthropic(anthropic) => all_local_and_set(&[
+                anthropic.completions_endpoint.as_ref(),
+                anthropic.chat_endpoint.as_ref(),
+            ]),
+            ValidModel::MistralFIM(mistral_fim) => {
+                all_local_and_set(&[mistral_fim.fim_endpoint.as_ref()])
+            }
+            ValidModel::Ollama(ollama) => all_local(&[
+                ollama.generate_endpoint.as_ref(),
+                ollama.chat_endpoint.as_ref(),
+            ]),
+            ValidModel::Gemini(gemini) => all_local_and_set(&[
+                gemini.completions_endpoint.as_ref(),
+                gemini.chat_endpoint.as_ref(),
+            ]),
+            #[cfg(feature = "mock")]
+            ValidModel::Mock(_) => true,
+        }
+    }
+}
+
+fn is_local_url(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| {
+        matches!(
+            url.host_str(),
+            Some("localhost" | "127.0.0.1" | "[::1]" | "0.0.0.0")
+        )
+    })
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -175,18 +470,93 @@ pub(crate) struct FIM {
     pub(crate) end: String,
 }
 
+impl FIM {
+    fn new(start: &str, middle: &str, end: &str) -> Self {
+        Self {
+            start: start.to_string(),
+            middle: middle.to_string(),
+            end: end.to_string(),
+        }
+    }
+
+    // A named `fim_template` takes precedence over explicitly provided `fim` tokens
+    pub(crate) fn resolve(fim: Option<&FIM>, fim_template: Option<FIMTemplate>) -> Option<FIM> {
+        fim_template.map(FIMTemplate::fim).or_else(|| fim.cloned())
+    }
+}
+
+// Built-in FIM tokens for common models selectable with `fim_template`
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub(crate) enum FIMTemplate {
+    #[serde(rename = "starcoder")]
+    StarCoder,
+    #[serde(rename = "deepseek")]
+    DeepSeek,
+    #[serde(rename = "codellama")]
+    CodeLlama,
+    #[serde(rename = "qwen")]
+    Qwen,
+    #[serde(rename = "codegemma")]
+    CodeGemma,
+}
+
+impl FIMTemplate {
+    pub(crate) fn fim(self) -> FIM {
+        match self {
+            FIMTemplate::StarCoder => FIM::new("<fim_prefix>", "<fim_suffix>", "<fim_middle>"),
+            FIMTemplate::DeepSeek => FIM::new("<｜fim▁begin｜>", "<｜fim▁hole｜>", "<｜fim▁end｜>"),
+            FIMTemplate::CodeLlama => FIM::new("<PRE> ", " <SUF>", " <MID>"),
+            FIMTemplate::Qwen | FIMTemplate::CodeGemma => {
+                FIM::new("<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>")
+            }
+        }
+    }
+}
+
+// How a FIM prompt is sent to the model
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub(crate) enum FIMMode {
+    // Wrapped in the FIM tokens from `fim` or `fim_template`
+    #[serde(rename = "tokens")]
+    #[default]
+    Tokens,
+    // Described in a chat instruction so instruct models that were not trained on FIM can fill the hole
+    #[serde(rename = "instruct")]
+    Instruct,
+}
+
 const fn max_crawl_memory_default() -> u64 {
     100_000_000
 }
 
-const fn max_crawl_file_size_default() -> u64 {
+const fn max_file_size_default() -> u64 {
     10_000_000
 }
 
+// Files are read fully into memory before being split so caps above this are rejected
+const MAX_FILE_SIZE_LIMIT: u64 = 1_000_000_000;
+
+fn deserialize_file_size<'de, D>(deserializer: D) -> std::result::Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let size = u64::deserialize(deserializer)?;
+    if size == 0 || size > MAX_FILE_SIZE_LIMIT {
+        return Err(serde::de::Error::custom(format!(
+            "file size caps must be between 1 and {MAX_FILE_SIZE_LIMIT} bytes, got {size}"
+        )));
+    }
+    Ok(size)
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct Crawl {
-    #[serde(default = "max_crawl_file_size_default")]
+    // Larger files are skipped. Each file is read fully into memory while it is indexed
+    #[serde(
+        default = "max_file_size_default",
+        deserialize_with = "deserialize_file_size"
+    )]
     pub(crate) max_file_size: u64,
     #[serde(default = "max_crawl_memory_default")]
     pub(crate) max_crawl_memory: u64,
@@ -194,11 +564,70 @@ pub(crate) struct Crawl {
     pub(crate) all_files: bool,
 }
 
+impl Default for Crawl {
+    fn default() -> Self {
+        Self {
+            max_file_size: max_file_size_default(),
+            max_crawl_memory: max_crawl_memory_default(),
+            all_files: false,
+        }
+    }
+}
+
+const fn max_tokens_per_file_default() -> usize {
+    512
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PinnedContextFiles {
+    // Paths or globs relative to the workspace root. They are resolved when the memory backend starts
+    pub(crate) files: Vec<String>,
+    // Each file is cut to this many tokens. Pinned files are not counted in `max_context`
+    #[serde(default = "max_tokens_per_file_default")]
+    pub(crate) max_tokens_per_file: usize,
+}
+
+// Where the most relevant retrieved chunk is put in the context
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub(crate) enum ContextOrder {
+    // First, furthest from the code
+    #[default]
+    #[serde(rename = "relevance_desc")]
+    RelevanceDesc,
+    // Last, right before the code
+    #[serde(rename = "relevance_asc")]
+    RelevanceAsc,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RetrievalExclusion {
+    // Globs relative to the workspace root, e.g. `**/tests/**`. Matching files are still indexed
+    // but their chunks are left out of the context
+    pub(crate) globs: Vec<String>,
+    // Only exclude the chunks when the prompt is built for a file matching one of these globs.
+    // Empty excludes them everywhere
+    #[serde(default)]
+    pub(crate) for_files: Vec<String>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub(crate) struct PostgresMLEmbeddingModel {
     pub(crate) model: String,
     pub(crate) embed_parameters: Option<Value>,
     pub(crate) query_parameters: Option<Value>,
+    // Sent as the `prompt` parameter unless `embed_parameters` or `query_parameters` set one
+    #[serde(default)]
+    pub(crate) prefix: EmbeddingPrefix,
+}
+
+const fn upsert_batch_bytes_default() -> usize {
+    10_000_000
+}
+
+const fn upsert_concurrency_default() -> usize {
+    4
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -209,20 +638,101 @@ pub(crate) struct PostgresML {
     #[serde(default)]
     pub(crate) splitter: ValidSplitter,
     pub(crate) embedding_model: Option<PostgresMLEmbeddingModel>,
+    // Chunks whose cosine similarity with the query is below this are left out of the context
+    pub(crate) min_score: Option<f32>,
+    // The max number of chunks put in the context. Fewer are used when they don't fit in `max_context`
+    pub(crate) retrieval_limit: Option<usize>,
+    // Files whose chunks are kept out of the context
+    #[serde(default)]
+    pub(crate) retrieval_exclude: Vec<RetrievalExclusion>,
+    // Where the most relevant retrieved chunk goes in the context. `relevance_desc` puts it first
+    // and `relevance_asc` puts it last, right before the code. The least relevant end is cut when
+    // the context does not fit
+    #[serde(default)]
+    pub(crate) context_order: ContextOrder,
+    // The header put above every chunk. Supports the `{path}`, `{language}` and `{language_id}`
+    // placeholders. Use `{language_id}` (e.g. `cpp`) for markdown code fences, `{language}` is a
+    // display name (e.g. `C++`)
+    // Defaults to `--{path}--`
+    pub(crate) chunk_header_template: Option<String>,
+    // Crawled and resynced chunks are upserted in batches of roughly this many bytes
+    #[serde(default = "upsert_batch_bytes_default")]
+    pub(crate) upsert_batch_bytes: usize,
+    // The max number of batches upserted at once. Crawling and resyncing wait for a free slot
+    #[serde(default = "upsert_concurrency_default")]
+    pub(crate) upsert_concurrency: usize,
+    // Use this collection as is. By default the name is derived from the workspace and the pipeline
+    pub(crate) collection_name: Option<String>,
+    // Fail to start when the database can't be reached instead of falling back to the file store
+    #[serde(default)]
+    pub(crate) required: bool,
+    // Indexed files larger than this are removed from the collection when resyncing at startup
+    #[serde(
+        default = "max_file_size_default",
+        deserialize_with = "deserialize_file_size"
+    )]
+    pub(crate) resync_max_file_size: u64,
+}
+
+// How the file store fills `max_context` when building prompts
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq)]
+pub(crate) enum ContextStrategy {
+    // Only the current file
+    #[serde(rename = "current_file")]
+    CurrentFile,
+    // The current file with the start of other accessed files prepended until `max_context` is filled
+    #[default]
+    #[serde(rename = "recent_files")]
+    RecentFiles,
+    // A window centered on the cursor. If the current file can't fill the half of the window before the
+    // cursor, the end of other accessed files is prepended. If it can't fill the half after the cursor,
+    // the start of other accessed files is appended
+    #[serde(rename = "cursor_window")]
+    CursorWindow,
 }
 
 #[derive(Clone, Debug, Deserialize, Default)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct FileStore {
     pub(crate) crawl: Option<Crawl>,
+    #[serde(default)]
+    pub(crate) context_strategy: ContextStrategy,
+    // Prefer files referenced by the current file's `use` / `mod` (Rust) and `import` / `from` (Python)
+    // statements over other accessed files when filling `max_context`
+    #[serde(default)]
+    pub(crate) include_imports: bool,
+    // Files put in the context before the other files
+    pub(crate) pinned_context_files: Option<PinnedContextFiles>,
+    // The max number of files kept in memory. The least recently opened or edited files are dropped
+    // first and crawling stops once it is reached. Default: no limit
+    pub(crate) max_tracked_files: Option<usize>,
 }
 
 impl FileStore {
     pub(crate) fn new_without_crawl() -> Self {
-        Self { crawl: None }
+        Self {
+            crawl: None,
+            context_strategy: ContextStrategy::default(),
+            include_imports: false,
+            pinned_context_files: None,
+            max_tracked_files: None,
+        }
     }
 }
 
+#[cfg(feature = "mock")]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Mock {
+    // The response returned for every request, default: the prompt is echoed back
+    pub(crate) response: Option<String>,
+    // The maximum requests per second
+    #[serde(default = "max_requests_per_second_default")]
+    pub(crate) max_requests_per_second: f32,
+    // The request timeout in seconds for this model, default: the global `request_timeout`
+    pub(crate) request_timeout: Option<f32>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct Ollama {
@@ -235,6 +745,20 @@ pub(crate) struct Ollama {
     // The maximum requests per second
     #[serde(default = "max_requests_per_second_default")]
     pub(crate) max_requests_per_second: f32,
+    // The request timeout in seconds for this model, default: the global `request_timeout`
+    pub(crate) request_timeout: Option<f32>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LLaMACPPServer {
+    // The base url of a running llama-server, default: 'http://localhost:8080'
+    pub(crate) endpoint: Option<String>,
+    // The maximum requests per second
+    #[serde(default = "max_requests_per_second_default")]
+    pub(crate) max_requests_per_second: f32,
+    // The request timeout in seconds for this model, default: the global `request_timeout`
+    pub(crate) request_timeout: Option<f32>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -250,6 +774,8 @@ pub(crate) struct MistralFIM {
     // The maximum requests per second
     #[serde(default = "max_requests_per_second_default")]
     pub(crate) max_requests_per_second: f32,
+    // The request timeout in seconds for this model, default: the global `request_timeout`
+    pub(crate) request_timeout: Option<f32>,
 }
 
 #[cfg(feature = "llama_cpp")]
@@ -276,9 +802,22 @@ pub(crate) struct LLaMACPP {
     // The context size
     #[serde(default = "n_ctx_default")]
     pub(crate) n_ctx: u32,
+    // The number of threads used for generation, default: llama.cpp's default
+    pub(crate) n_threads: Option<i32>,
+    // The maximum number of tokens decoded in a single batch. The prompt is decoded in one batch so this
+    // must be at least the number of prompt tokens, default: llama.cpp's default
+    pub(crate) n_batch: Option<u32>,
+    // The RoPE base frequency, default: the value stored in the model
+    pub(crate) rope_freq_base: Option<f32>,
+    // The RoPE frequency scaling factor, default: the value stored in the model
+    pub(crate) rope_freq_scale: Option<f32>,
+    // Use flash attention, default: llama.cpp's default
+    pub(crate) flash_attn: Option<bool>,
     // The maximum requests per second
     #[serde(default = "max_requests_per_second_default")]
     pub(crate) max_requests_per_second: f32,
+    // The request timeout in seconds for this model, default: the global `request_timeout`
+    pub(crate) request_timeout: Option<f32>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -295,8 +834,60 @@ pub(crate) struct OpenAI {
     // The maximum requests per second
     #[serde(default = "max_requests_per_second_default")]
     pub(crate) max_requests_per_second: f32,
+    // The request timeout in seconds for this model, default: the global `request_timeout`
+    pub(crate) request_timeout: Option<f32>,
     // The model name
     pub(crate) model: String,
+    // Sent as the `OpenAI-Organization` header. Supports `${ENV_VAR}` interpolation
+    pub(crate) organization: Option<String>,
+    // Sent as the `OpenAI-Project` header. Supports `${ENV_VAR}` interpolation
+    pub(crate) project: Option<String>,
+}
+
+// A preset for OpenAI compatible servers like vLLM and LM Studio that derives the endpoints from the base url
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct OpenAICompatible {
+    // The server's base url, e.g. 'http://localhost:8000'. A trailing '/v1' is allowed
+    pub(crate) base_url: String,
+    // The auth token env var name
+    pub(crate) auth_token_env_var_name: Option<String>,
+    // The auth token
+    pub(crate) auth_token: Option<String>,
+    // The maximum requests per second
+    #[serde(default = "max_requests_per_second_default")]
+    pub(crate) max_requests_per_second: f32,
+    // The request timeout in seconds for this model, default: the global `request_timeout`
+    pub(crate) request_timeout: Option<f32>,
+    // The model name, default: the first model listed by the server's '/v1/models' endpoint
+    pub(crate) model: Option<String>,
+}
+
+impl OpenAICompatible {
+    pub(crate) fn api_url(&self) -> String {
+        let base_url = self.base_url.trim_end_matches('/');
+        format!("{}/v1", base_url.strip_suffix("/v1").unwrap_or(base_url))
+    }
+
+    pub(crate) fn into_open_ai(self) -> OpenAI {
+        let api_url = self.api_url();
+        // Local servers usually do not check the token but we always send one
+        let auth_token = match (&self.auth_token_env_var_name, self.auth_token) {
+            (None, None) => Some("EMPTY".to_string()),
+            (_, auth_token) => auth_token,
+        };
+        OpenAI {
+            auth_token_env_var_name: self.auth_token_env_var_name,
+            auth_token,
+            completions_endpoint: Some(format!("{api_url}/completions")),
+            chat_endpoint: Some(format!("{api_url}/chat/completions")),
+            max_requests_per_second: self.max_requests_per_second,
+            request_timeout: self.request_timeout,
+            model: self.model.unwrap_or_default(),
+            organization: None,
+            project: None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -314,6 +905,8 @@ pub(crate) struct Gemini {
     // The maximum requests per second
     #[serde(default = "max_requests_per_second_default")]
     pub(crate) max_requests_per_second: f32,
+    // The request timeout in seconds for this model, default: the global `request_timeout`
+    pub(crate) request_timeout: Option<f32>,
     // The model name
     pub(crate) model: String,
 }
@@ -332,8 +925,21 @@ pub(crate) struct Anthropic {
     // The maximum requests per second
     #[serde(default = "max_requests_per_second_default")]
     pub(crate) max_requests_per_second: f32,
+    // The request timeout in seconds for this model, default: the global `request_timeout`
+    pub(crate) request_timeout: Option<f32>,
     // The model name
     pub(crate) model: String,
+    // Mark the system prompt and the last user message as cacheable
+    #[serde(default)]
+    pub(crate) enable_prompt_caching: bool,
+}
+
+fn label_template_default() -> String {
+    "ai - {first_line}".to_string()
+}
+
+const fn label_max_length_default() -> usize {
+    50
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -346,6 +952,71 @@ pub(crate) struct Completion {
     // Parameters for post processing
     #[serde(default)]
     pub(crate) post_process: PostProcess,
+    // The label shown in the completion menu. Supports `{first_line}` and `{insert_text}`
+    #[serde(default = "label_template_default")]
+    pub(crate) label_template: String,
+    // The max number of characters each placeholder in the label is truncated to
+    #[serde(default = "label_max_length_default")]
+    pub(crate) label_max_length: usize,
+    // Only complete when the line before the cursor (ignoring indentation) is at least this long
+    #[serde(default)]
+    pub(crate) min_prefix_chars: usize,
+    // Always complete when the line before the cursor ends with one of these
+    #[serde(default)]
+    pub(crate) trigger_characters: Vec<String>,
+    // The text editors filter completions by as you type
+    #[serde(default)]
+    pub(crate) filter_text_strategy: FilterTextStrategy,
+    // The whitespace trimmed from the completion before it is inserted
+    #[serde(default)]
+    pub(crate) trim: TrimPolicy,
+    // Precompute a completion for the cursor once editing pauses
+    pub(crate) prefetch: Option<Prefetch>,
+}
+
+const fn prefetch_idle_ms_default() -> u64 {
+    300
+}
+
+// Prefetched completions are only used by completion requests without a profile
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Prefetch {
+    // How long after the last edit to prefetch
+    #[serde(default = "prefetch_idle_ms_default")]
+    pub(crate) idle_ms: u64,
+    // Every prefetch calls the model whether or not it is used, so by default models that don't
+    // run on this machine are never prefetched for
+    #[serde(default)]
+    pub(crate) allow_remote_models: bool,
+}
+
+// Which ends of a completion have their whitespace and newlines trimmed
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq)]
+pub(crate) enum TrimPolicy {
+    #[serde(rename = "none")]
+    None,
+    // Leading indentation is kept
+    #[default]
+    #[serde(rename = "trailing")]
+    Trailing,
+    #[serde(rename = "both")]
+    Both,
+}
+
+// What completion items send as their `filterText`
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq)]
+pub(crate) enum FilterTextStrategy {
+    // The line before the cursor
+    #[default]
+    #[serde(rename = "line_prefix")]
+    LinePrefix,
+    // The identifier before the cursor
+    #[serde(rename = "word_prefix")]
+    WordPrefix,
+    // No filter text so editors fall back to the label
+    #[serde(rename = "none")]
+    None,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -359,6 +1030,16 @@ pub(crate) struct Chat {
     // Args are deserialized by the backend using them
     #[serde(default)]
     pub(crate) parameters: Kwargs,
+    // Files substituted for the `{ATTACHED_FILES}` placeholder, like for `actions`
+    #[serde(default)]
+    pub(crate) attached_files: Vec<String>,
+    // The total budget for the attached files. Files are cut once it is used up
+    #[serde(default = "max_attached_tokens_default")]
+    pub(crate) max_attached_tokens: usize,
+}
+
+const fn max_attached_tokens_default() -> usize {
+    8192
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -373,6 +1054,37 @@ pub(crate) struct Action {
     // Parameters for post processing
     #[serde(default)]
     pub(crate) post_process: PostProcess,
+    // Where the generated text goes
+    #[serde(default)]
+    pub(crate) mode: ActionMode,
+    // Files substituted for the `{ATTACHED_FILES}` placeholder. Relative paths are resolved
+    // against the workspace root
+    #[serde(default)]
+    pub(crate) attached_files: Vec<String>,
+    // The total budget for the attached files. Files are cut once it is used up
+    #[serde(default = "max_attached_tokens_default")]
+    pub(crate) max_attached_tokens: usize,
+    // Only offer the action when the editor sends diagnostics with the request, e.g. for
+    // "fix this error" actions using the `{DIAGNOSTICS}` placeholder
+    #[serde(default)]
+    pub(crate) only_with_diagnostics: bool,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Default, PartialEq)]
+pub(crate) enum ActionMode {
+    // Replace the selected text
+    #[default]
+    #[serde(rename = "replace")]
+    Replace,
+    // Insert after the selected text
+    #[serde(rename = "append")]
+    Append,
+    // Create a new document containing only the generated text
+    #[serde(rename = "new_document")]
+    NewDocument,
+    // Show the generated text without editing, e.g. to explain the selected code
+    #[serde(rename = "explain")]
+    Explain,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -386,18 +1098,151 @@ pub(crate) struct ValidConfig {
     #[serde(default)]
     #[serde(alias = "chat")] // Legacy from when it was called chat, remove soon
     pub(crate) chats: Vec<Chat>,
+    // Named completion configs selected with the `profile` field of a completion request
+    #[serde(default)]
+    pub(crate) completion_profiles: HashMap<String, Completion>,
+    // The maximum number of requests handled at once. Requests over the limit wait in a queue
+    #[serde(default = "max_concurrent_requests_default")]
+    pub(crate) max_concurrent_requests: usize,
+    // The timeout in seconds for requests to models. Timed out completions return no items
+    pub(crate) request_timeout: Option<f32>,
+    // TLS settings for every HTTP backend. Only read when the server starts
+    pub(crate) tls: Option<Tls>,
+    // Connection reuse settings for every HTTP backend. Only read when the server starts
+    pub(crate) connection_pool: Option<ConnectionPool>,
+    // Log filter directives like `debug` or `lsp_ai::transformer_worker=debug,warn`. Ignored when
+    // the `LSP_AI_LOG` environment variable or `--log-level` is set
+    pub(crate) log_level: Option<String>,
+    // Type the response of `actions` into the document as it is generated instead of applying it
+    // once it is done. The models of the actions must support streaming. Actions in the
+    // `new_document` and `explain` modes are not streamed
+    #[serde(default)]
+    pub(crate) stream_actions: bool,
+    // The language `.h` files are parsed as. Only read when the server starts
+    #[serde(default)]
+    pub(crate) header_language: HeaderLanguage,
+    // Never parse files with tree-sitter. Saves parsing on every change in large files, but the
+    // tree_sitter splitter falls back to text splitting so chunks no longer follow the code's
+    // structure, and `include_imports` finds no imports
+    #[serde(default)]
+    pub(crate) disable_tree_sitter: bool,
+    // Parse the whole file after every edit instead of reusing the previous tree. Slower, but
+    // the tree can't drift from the text if an edit is mapped to the wrong bytes
+    #[serde(default)]
+    pub(crate) always_full_reparse: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub(crate) enum HeaderLanguage {
+    #[default]
+    #[serde(rename = "c")]
+    C,
+    #[serde(rename = "cpp")]
+    Cpp,
+}
+
+// Unset values keep reqwest's defaults
+#[derive(Clone, Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ConnectionPool {
+    // The max idle connections kept open per host, default: unlimited. Lowering it frees sockets
+    // but completions after a burst may pay for a new TLS handshake
+    pub(crate) pool_max_idle_per_host: Option<usize>,
+    // Seconds before an idle connection is closed, default: 90. Raise it if you pause between
+    // completions for longer, though some servers and proxies drop idle connections earlier anyway
+    pub(crate) pool_idle_timeout: Option<f32>,
+    // Seconds between TCP keepalive probes on idle connections, default: disabled. Keeps
+    // connections through NATs and proxies alive at the cost of a little background traffic
+    pub(crate) tcp_keepalive: Option<f32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Tls {
+    // A PEM encoded certificate authority trusted on top of the system ones
+    pub(crate) ca_cert_path: Option<String>,
+    // A PEM encoded certificate and PKCS#8 key for servers requiring mutual TLS
+    pub(crate) client_cert_path: Option<String>,
+    pub(crate) client_key_path: Option<String>,
+    // Skips certificate verification entirely. Anyone between the server and the endpoint can then
+    // read and change requests, including API keys. Prefer `ca_cert_path` for private CAs
+    #[serde(default)]
+    pub(crate) danger_accept_invalid_certs: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
+pub(crate) struct ValidWindowClientCapabilities {
+    #[serde(default)]
+    #[serde(alias = "workDoneProgress")]
+    pub(crate) work_done_progress: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
+pub(crate) struct ValidCompletionItemClientCapabilities {
+    #[serde(default)]
+    #[serde(alias = "insertReplaceSupport")]
+    pub(crate) insert_replace_support: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
+pub(crate) struct ValidCompletionClientCapabilities {
+    #[serde(default)]
+    #[serde(alias = "completionItem")]
+    pub(crate) completion_item: ValidCompletionItemClientCapabilities,
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
+pub(crate) struct ValidTextDocumentClientCapabilities {
+    #[serde(default)]
+    pub(crate) completion: ValidCompletionClientCapabilities,
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
+pub(crate) struct ValidWorkspaceClientCapabilities {
+    #[serde(default)]
+    #[serde(alias = "applyEdit")]
+    pub(crate) apply_edit: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Default)]
+pub(crate) struct ValidClientCapabilities {
+    #[serde(default)]
+    pub(crate) window: ValidWindowClientCapabilities,
+    #[serde(default)]
+    pub(crate) workspace: ValidWorkspaceClientCapabilities,
+    #[serde(default)]
+    #[serde(alias = "textDocument")]
+    pub(crate) text_document: ValidTextDocumentClientCapabilities,
 }
 
 #[derive(Clone, Debug, Deserialize, Default)]
 pub(crate) struct ValidClientParams {
     #[serde(alias = "rootUri")]
     pub(crate) root_uri: Option<String>,
+    #[serde(default)]
+    pub(crate) capabilities: ValidClientCapabilities,
 }
 
 #[derive(Clone, Debug)]
 pub(crate) struct Config {
     pub(crate) config: ValidConfig,
     pub(crate) client_params: ValidClientParams,
+    // The JSON `config` was built from. Configuration changes are merged over it
+    initialization_options: Value,
+}
+
+// Timeouts are turned into a `Duration` for every request, so bad values are caught up front
+fn validate_request_timeouts(config: &ValidConfig) -> Result<()> {
+    if let Some(request_timeout) = config.request_timeout {
+        secs_to_duration(request_timeout, "request_timeout")?;
+    }
+    for (name, model) in &config.models {
+        if let Some(request_timeout) = model.request_timeout() {
+            secs_to_duration(request_timeout, "request_timeout")
+                .with_context(|| format!("invalid model `{name}`"))?;
+        }
+    }
+    Ok(())
 }
 
 impl Config {
@@ -407,17 +1252,129 @@ impl Config {
             .as_object_mut()
             .context("Server configuration must be a JSON object")?
             .remove("initializationOptions");
-        let valid_args = match configuration_args {
-            Some(configuration_args) => serde_json::from_value(configuration_args)?,
+        let (valid_args, initialization_options) = match configuration_args {
+            Some(configuration_args) => (
+                serde_json::from_value(configuration_args.clone())?,
+                configuration_args,
+            ),
             None => anyhow::bail!("lsp-ai does not currently provide a default configuration. Please pass a configuration. See https://github.com/SilasMarvin/lsp-ai for configuration options and examples"),
         };
+        validate_request_timeouts(&valid_args)?;
         let client_params: ValidClientParams = serde_json::from_value(args)?;
         Ok(Self {
             config: valid_args,
             client_params,
+            initialization_options,
         })
     }
 
+    // Merges the `--config` file, or the workspace config file when `workspace_config` is set,
+    // into the client's `initialize` params. Workspace files come with the repository being
+    // opened so they are only read when the user opts in
+    pub(crate) fn load_config_file(
+        args: Value,
+        config_path: Option<&Path>,
+        workspace_config: bool,
+    ) -> Result<Value> {
+        let config_path = match config_path {
+            Some(config_path) => Some(config_path.to_path_buf()),
+            None if workspace_config => Self::find_workspace_config_file(&args),
+            None => None,
+        };
+        match config_path {
+            Some(config_path) => {
+                info!("loading config file: {}", config_path.display());
+                Self::merge_config_file(args, &config_path)
+            }
+            None => Ok(args),
+        }
+    }
+
+    // Finds a config file in the workspace root given the client's `initialize` params
+    fn find_workspace_config_file(args: &Value) -> Option<PathBuf> {
+        let root = args
+            .get("rootUri")
+            .and_then(Value::as_str)
+            .and_then(|uri| Url::parse(uri).ok()?.to_file_path().ok())
+            .or_else(|| {
+                args.get("rootPath")
+                    .and_then(Value::as_str)
+                    .map(PathBuf::from)
+            })?;
+        WORKSPACE_CONFIG_FILES
+            .iter()
+            .map(|file_name| root.join(file_name))
+            .find(|path| path.is_file())
+    }
+
+    // Merges the config file under the client's `initializationOptions` which take precedence
+    // Files with a `.toml` extension are read as TOML, all others as JSON
+    fn merge_config_file(mut args: Value, path: &Path) -> Result<Value> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config file: {}", path.display()))?;
+        let mut config: Value = if path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            toml::from_str(&data)?
+        } else {
+            serde_json::from_str(&data)?
+        };
+        // Config files used to hold the full `initialize` params
+        if let Some(initialization_options) = config.get_mut("initializationOptions") {
+            config = initialization_options.take();
+        }
+        let args_object = args
+            .as_object_mut()
+            .context("Server configuration must be a JSON object")?;
+        if let Some(initialization_options) = args_object.get("initializationOptions") {
+            override_json(&mut config, initialization_options);
+        }
+        args_object.insert("initializationOptions".to_string(), config);
+        Ok(args)
+    }
+
+    // Merges `workspace/didChangeConfiguration` settings, optionally nested under `lsp-ai`, over
+    // the current config. Returns `None` for settings that are empty or meant for other servers
+    // The memory backend is not reloaded as that would drop its index so the current one is kept
+    pub(crate) fn with_settings(&self, mut settings: Value) -> Result<Option<Self>> {
+        let nested = settings.get("lsp-ai").is_some();
+        if let Some(lsp_ai_settings) = settings.get_mut("lsp-ai") {
+            settings = lsp_ai_settings.take();
+        }
+        match settings.as_object() {
+            Some(settings_object) if settings_object.is_empty() => return Ok(None),
+            Some(_) => (),
+            None if nested && !settings.is_null() => {
+                anyhow::bail!("Configuration settings must be a JSON object")
+            }
+            None => return Ok(None),
+        }
+        let mut initialization_options = self.initialization_options.clone();
+        override_json(&mut initialization_options, &settings);
+        if let Some(initialization_options) = initialization_options.as_object_mut() {
+            initialization_options
+                .entry("memory")
+                .or_insert_with(|| serde_json::json!({ "file_store": {} }));
+        }
+        let mut config: ValidConfig = match serde_json::from_value(initialization_options.clone()) {
+            Ok(config) => config,
+            // Settings for every server may be sent without nesting them
+            Err(e) if !nested => {
+                debug!("ignoring configuration settings not meant for lsp-ai: {e}");
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        validate_request_timeouts(&config)?;
+        config.memory = self.config.memory.clone();
+        Ok(Some(Self {
+            config,
+            client_params: self.client_params.clone(),
+            initialization_options,
+        }))
+    }
+
     ///////////////////////////////////////
     // Helpers for the backends ///////////
     ///////////////////////////////////////
@@ -430,8 +1387,103 @@ impl Config {
         &self.config.actions
     }
 
-    pub(crate) fn get_completions_post_process(&self) -> Option<&PostProcess> {
-        self.config.completion.as_ref().map(|x| &x.post_process)
+    pub(crate) fn get_completion_trigger_characters(&self) -> Option<Vec<String>> {
+        self.config
+            .completion
+            .as_ref()
+            .map(|x| x.trigger_characters.clone())
+            .filter(|x| !x.is_empty())
+    }
+
+    // The run parameters configured for a model, like its sampling options. Currently only
+    // completions configure these so the parameters that build completion prompts are left out
+    pub(crate) fn get_model_parameters(&self, model: &str) -> Option<Kwargs> {
+        self.config
+            .completion
+            .as_ref()
+            .filter(|completion| completion.model == model)
+            .map(|completion| {
+                completion
+                    .parameters
+                    .iter()
+                    .filter(|(key, _)| !COMPLETION_PROMPT_PARAMETERS.contains(&key.as_str()))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect()
+            })
+    }
+
+    // The model's own timeout takes precedence over the global one
+    pub(crate) fn get_request_timeout(&self, model: Option<&str>) -> Option<Duration> {
+        model
+            .and_then(|model| self.config.models.get(model))
+            .and_then(|model| model.request_timeout())
+            .or(self.config.request_timeout)
+            // Checked by `validate_request_timeouts` when the config is built
+            .and_then(|request_timeout| Duration::try_from_secs_f32(request_timeout).ok())
+    }
+
+    // The named completion profile or the default `completion` when no profile is given
+    pub(crate) fn get_completion(&self, profile: Option<&str>) -> Result<&Completion> {
+        match profile {
+            Some(profile) => self
+                .config
+                .completion_profiles
+                .get(profile)
+                .with_context(|| format!("`{profile}` not found in `completion_profiles` config")),
+            None => self
+                .config
+                .completion
+                .as_ref()
+                .context("Completions is none"),
+        }
+    }
+
+    // `get_completion` with its model replaced by a per request `model` override
+    pub(crate) fn get_completion_with_model(
+        &self,
+        profile: Option<&str>,
+        model: Option<&str>,
+    ) -> Result<Cow<'_, Completion>> {
+        let completion = self.get_completion(profile)?;
+        match model {
+            Some(model) if model != completion.model => {
+                anyhow::ensure!(
+                    self.config.models.contains_key(model),
+                    "`{model}` not found in `models` config"
+                );
+                Ok(Cow::Owned(Completion {
+                    model: model.to_string(),
+                    ..completion.clone()
+                }))
+            }
+            _ => Ok(Cow::Borrowed(completion)),
+        }
+    }
+
+    // The model of `chat` or a per request `model` override
+    pub(crate) fn get_chat_model<'a>(
+        &'a self,
+        chat: &'a Chat,
+        model: Option<&'a str>,
+    ) -> Result<&'a str> {
+        match model {
+            Some(model) => {
+                anyhow::ensure!(
+                    self.config.models.contains_key(model),
+                    "`{model}` not found in `models` config"
+                );
+                Ok(model)
+            }
+            None => Ok(&chat.model),
+        }
+    }
+
+    // The completion config to prefetch with when prefetching is enabled for its model
+    pub(crate) fn get_prefetch_completion(&self) -> Option<&Completion> {
+        let completion = self.config.completion.as_ref()?;
+        let prefetch = completion.prefetch.as_ref()?;
+        let model = self.config.models.get(&completion.model)?;
+        (prefetch.allow_remote_models || model.is_local()).then_some(completion)
     }
 
     pub(crate) fn get_completion_transformer_max_requests_per_second(&self) -> anyhow::Result<f32> {
@@ -454,11 +1506,19 @@ impl Config {
             })? {
             #[cfg(feature = "llama_cpp")]
             ValidModel::LLaMACPP(llama_cpp) => Ok(llama_cpp.max_requests_per_second),
+            ValidModel::LLaMACPPServer(llama_cpp_server) => {
+                Ok(llama_cpp_server.max_requests_per_second)
+            }
             ValidModel::OpenAI(open_ai) => Ok(open_ai.max_requests_per_second),
+            ValidModel::OpenAICompatible(open_ai_compatible) => {
+                Ok(open_ai_compatible.max_requests_per_second)
+            }
             ValidModel::Gemini(gemini) => Ok(gemini.max_requests_per_second),
             ValidModel::Anthropic(anthropic) => Ok(anthropic.max_requests_per_second),
             ValidModel::MistralFIM(mistral_fim) => Ok(mistral_fim.max_requests_per_second),
             ValidModel::Ollama(ollama) => Ok(ollama.max_requests_per_second),
+            #[cfg(feature = "mock")]
+            ValidModel::Mock(mock) => Ok(mock.max_requests_per_second),
         }
     }
 }
@@ -469,13 +1529,24 @@ impl Config {
     pub(crate) fn default_with_file_store_without_models() -> Self {
         Self {
             config: ValidConfig {
-                memory: ValidMemoryBackend::FileStore(FileStore { crawl: None }),
+                memory: ValidMemoryBackend::FileStore(FileStore::new_without_crawl()),
                 models: HashMap::new(),
                 completion: None,
                 actions: vec![],
                 chats: vec![],
+                completion_profiles: HashMap::new(),
+                max_concurrent_requests: max_concurrent_requests_default(),
+                request_timeout: None,
+                tls: None,
+                connection_pool: None,
+                log_level: None,
+                stream_actions: false,
+                header_language: HeaderLanguage::default(),
+                disable_tree_sitter: false,
+                always_full_reparse: false,
             },
-            client_params: ValidClientParams { root_uri: None },
+            client_params: ValidClientParams::default(),
+            initialization_options: Value::Null,
         }
     }
 
@@ -487,8 +1558,19 @@ impl Config {
                 completion: None,
                 actions: vec![],
                 chats: vec![],
+                completion_profiles: HashMap::new(),
+                max_concurrent_requests: max_concurrent_requests_default(),
+                request_timeout: None,
+                tls: None,
+                connection_pool: None,
+                log_level: None,
+                stream_actions: false,
+                header_language: HeaderLanguage::default(),
+                disable_tree_sitter: false,
+                always_full_reparse: false,
             },
-            client_params: ValidClientParams { root_uri: None },
+            client_params: ValidClientParams::default(),
+            initialization_options: Value::Null,
         }
     }
 }
@@ -563,6 +1645,31 @@ mod test {
         Config::new(args).unwrap();
     }
 
+    #[test]
+    fn max_concurrent_requests_config() {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {}
+            }
+        });
+        let config = Config::new(args).unwrap();
+        assert_eq!(config.config.max_concurrent_requests, 4);
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {},
+                "max_concurrent_requests": 1
+            }
+        });
+        let config = Config::new(args).unwrap();
+        assert_eq!(config.config.max_concurrent_requests, 1);
+    }
+
     #[test]
     fn open_ai_config() {
         let args = json!({
@@ -599,6 +1706,46 @@ mod test {
         Config::new(args).unwrap();
     }
 
+    #[test]
+    fn open_ai_compatible_config() {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {
+                    "model1": {
+                        "type": "open_ai_compatible",
+                        "base_url": "http://localhost:8000/v1/",
+                    },
+                },
+                "completion": {
+                    "model": "model1",
+                    "parameters": {
+                        "max_tokens": 32,
+                    }
+                }
+            }
+        });
+        let config = Config::new(args).unwrap();
+        let ValidModel::OpenAICompatible(open_ai_compatible) =
+            config.config.models["model1"].clone()
+        else {
+            panic!("expected an open_ai_compatible model");
+        };
+        let open_ai = open_ai_compatible.into_open_ai();
+        assert_eq!(
+            open_ai.completions_endpoint.as_deref(),
+            Some("http://localhost:8000/v1/completions")
+        );
+        assert_eq!(
+            open_ai.chat_endpoint.as_deref(),
+            Some("http://localhost:8000/v1/chat/completions")
+        );
+        assert_eq!(open_ai.auth_token.as_deref(), Some("EMPTY"));
+        assert!(open_ai.model.is_empty());
+    }
+
     #[test]
     fn gemini_config() {
         let args = json!({
@@ -672,4 +1819,221 @@ mod test {
         });
         Config::new(args).unwrap();
     }
+
+    #[test]
+    fn fim_template_resolution() {
+        let explicit: FIM = serde_json::from_value(json!({
+            "start": "<s>",
+            "middle": "<m>",
+            "end": "<e>"
+        }))
+        .unwrap();
+        let fim_template: FIMTemplate = serde_json::from_value(json!("deepseek")).unwrap();
+        let fim = FIM::resolve(Some(&explicit), Some(fim_template)).unwrap();
+        assert_eq!(fim.start, "<｜fim▁begin｜>");
+        assert_eq!(fim.middle, "<｜fim▁hole｜>");
+        assert_eq!(fim.end, "<｜fim▁end｜>");
+
+        let fim = FIM::resolve(Some(&explicit), None).unwrap();
+        assert_eq!(fim.start, "<s>");
+
+        assert!(FIM::resolve(None, None).is_none());
+        assert!(serde_json::from_value::<FIMTemplate>(json!("unknown")).is_err());
+    }
+
+    #[test]
+    fn merge_config_file() -> Result<()> {
+        let root = std::env::temp_dir().join(format!("lsp-ai-config-{}", std::process::id()));
+        std::fs::create_dir_all(&root)?;
+        let path = root.join(".lsp-ai.toml");
+        std::fs::write(
+            &path,
+            r#"
+[memory.file_store]
+
+[models.model1]
+type = "ollama"
+model = "llama3"
+
+[completion]
+model = "model1"
+parameters = { max_context = 1024 }
+"#,
+        )?;
+        let args = json!({
+            "rootUri": Url::from_file_path(&root).unwrap(),
+            "initializationOptions": {
+                "completion": {
+                    "model": "model1",
+                    "parameters": {
+                        "max_context": 2048
+                    }
+                }
+            }
+        });
+        assert_eq!(
+            Config::find_workspace_config_file(&args),
+            Some(path.clone())
+        );
+
+        // The workspace file is only read when opted into
+        assert_eq!(Config::load_config_file(args.clone(), None, false)?, args);
+
+        let config = Config::new(Config::load_config_file(args.clone(), None, true)?)?;
+        assert!(config.config.models.contains_key("model1"));
+        assert_eq!(
+            config.config.completion.as_ref().unwrap().parameters["max_context"],
+            2048
+        );
+
+        // An explicit config file is always read
+        let config = Config::new(Config::load_config_file(args, Some(&path), false)?)?;
+        assert!(config.config.models.contains_key("model1"));
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn completion_profiles() -> Result<()> {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {
+                    "model1": {
+                        "type": "ollama",
+                        "model": "deepseek-coder:1.3b-base"
+                    },
+                    "model2": {
+                        "type": "ollama",
+                        "model": "llama3"
+                    }
+                },
+                "completion": {
+                    "model": "model1",
+                    "parameters": {
+                        "fim": {
+                            "start": "<fim_prefix>",
+                            "middle": "<fim_suffix>",
+                            "end": "<fim_middle>"
+                        }
+                    }
+                },
+                "completion_profiles": {
+                    "smart": {
+                        "model": "model2",
+                        "parameters": {}
+                    }
+                }
+            }
+        });
+        let config = Config::new(args)?;
+        assert_eq!(config.get_completion(None)?.model, "model1");
+        assert_eq!(config.get_completion(Some("smart"))?.model, "model2");
+        assert!(config.get_completion(Some("fast")).is_err());
+
+        // A per request model override keeps the rest of the completion config
+        let completion = config.get_completion_with_model(None, Some("model2"))?;
+        assert_eq!(completion.model, "model2");
+        assert!(completion.parameters.contains_key("fim"));
+        assert_eq!(
+            config.get_completion_with_model(Some("smart"), None)?.model,
+            "model2"
+        );
+        assert!(config
+            .get_completion_with_model(None, Some("model3"))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn request_timeouts() -> Result<()> {
+        let args = json!({
+            "initializationOptions": {
+                "memory": {
+                    "file_store": {}
+                },
+                "models": {
+                    "model1": {
+                        "type": "ollama",
+                        "model": "llama3",
+                        "request_timeout": 2.5
+                    },
+                    "model2": {
+                        "type": "ollama",
+                        "model": "llama3"
+                    }
+                },
+                "request_timeout": 10
+            }
+        });
+        let config = Config::new(args.clone())?;
+        assert_eq!(
+            config.get_request_timeout(Some("model1")),
+            Some(Duration::from_secs_f32(2.5))
+        );
+        assert_eq!(
+            config.get_request_timeout(Some("model2")),
+            Some(Duration::from_secs(10))
+        );
+
+        // Negative timeouts are config errors
+        let mut invalid_args = args.clone();
+        invalid_args["initializationOptions"]["request_timeout"] = json!(-1);
+        assert!(Config::new(invalid_args).is_err());
+        let mut invalid_args = args;
+        invalid_args["initializationOptions"]["models"]["model2"]["request_timeout"] = json!(-1);
+        assert!(Config::new(invalid_args).is_err());
+        assert!(config
+            .with_settings(json!({ "request_timeout": -1 }))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn with_settings() -> Result<()> {
+        let config = Config::default_with_file_store_without_models();
+        let settings = json!({
+            "lsp-ai": {
+                "models": {
+                    "model1": {
+                        "type": "ollama",
+                        "model": "llama3"
+                    }
+                },
+                "completion": {
+                    "model": "model1",
+                    "parameters": {}
+                }
+            }
+        });
+        let config = config.with_settings(settings)?.unwrap();
+        assert!(config.config.models.contains_key("model1"));
+        assert!(matches!(
+            config.config.memory,
+            ValidMemoryBackend::FileStore(_)
+        ));
+
+        // Settings are merged over the current config
+        let config = config
+            .with_settings(json!({ "max_concurrent_requests": 2 }))?
+            .unwrap();
+        assert_eq!(config.config.max_concurrent_requests, 2);
+        assert!(config.config.models.contains_key("model1"));
+        assert_eq!(config.config.completion.as_ref().unwrap().model, "model1");
+
+        // Empty settings and settings for other servers are ignored
+        assert!(config.with_settings(Value::Null)?.is_none());
+        assert!(config.with_settings(json!({}))?.is_none());
+        assert!(config
+            .with_settings(json!({ "rust-analyzer": { "checkOnSave": true } }))?
+            .is_none());
+        assert!(config.with_settings(json!({ "lsp-ai": 1 })).is_err());
+        assert!(config
+            .with_settings(json!({ "lsp-ai": { "max_concurrent_requests": "2" } }))
+            .is_err());
+        Ok(())
+    }
 }
@@ -21,6 +21,10 @@ impl Crawl {
         }
     }
 
+    pub(crate) fn crawl_config(&self) -> &config::Crawl {
+        &self.crawl_config
+    }
+
     #[instrument(skip(self, f))]
     pub(crate) fn maybe_do_crawl(
         &mut self,
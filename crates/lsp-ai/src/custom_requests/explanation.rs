@@ -0,0 +1,13 @@
+use lsp_types::{MarkupContent, Range, TextDocumentIdentifier};
+use serde::{Deserialize, Serialize};
+
+// The `data` of a resolved `explain` action. Editor extensions can render the contents in a
+// popup. Every other editor gets the text in a `window/showMessage` notification
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ExplanationData {
+    pub(crate) text_document: TextDocumentIdentifier,
+    // The range the action was requested for
+    pub(crate) range: Range,
+    pub(crate) contents: MarkupContent,
+}
@@ -2,7 +2,7 @@ use lsp_types::TextDocumentPositionParams;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::config;
+use crate::{config, memory_backends::ContextChunk};
 
 pub(crate) enum Generation {}
 
@@ -16,16 +16,23 @@ pub(crate) struct GenerationParams {
     pub(crate) model: String,
     #[serde(default)]
     // Args are deserialized by the backend using them
+    // Merged over the parameters configured for the model with the values here taking precedence
     pub(crate) parameters: Value,
     // Parameters for post processing
     #[serde(default)]
     pub(crate) post_process: config::PostProcess,
+    // Return the chunks retrieved to build the prompt's context
+    #[serde(default)]
+    pub(crate) include_context: bool,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct GenerateResult {
     pub(crate) generated_text: String,
+    // Only set when `include_context` is requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) context_chunks: Option<Vec<ContextChunk>>,
 }
 
 impl lsp_types::request::Request for Generation {
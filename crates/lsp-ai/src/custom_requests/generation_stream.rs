@@ -1,5 +1,8 @@
 use lsp_types::{ProgressToken, TextDocumentPositionParams};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config;
 
 pub(crate) enum GenerationStream {}
 
@@ -11,6 +14,18 @@ pub(crate) struct GenerationStreamParams {
     // This field was "mixed-in" from TextDocumentPositionParams
     #[serde(flatten)]
     pub(crate) text_document_position: TextDocumentPositionParams,
+    // The model key to use
+    pub(crate) model: String,
+    #[serde(default)]
+    // Args are deserialized by the backend using them
+    // Merged over the parameters configured for the model with the values here taking precedence
+    pub(crate) parameters: Value,
+    // Stops the generation once it starts looping over the same lines
+    #[serde(default)]
+    pub(crate) repetition: config::RepetitionDetection,
+    // Stops the generation once it is longer than this many characters. Set to null to disable
+    #[serde(default = "config::max_generated_chars_default")]
+    pub(crate) max_generated_chars: Option<usize>,
 }
 
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
@@ -20,6 +35,17 @@ pub(crate) struct GenerationStreamResult {
     pub(crate) partial_result_token: ProgressToken,
 }
 
+// The params of the `$/progress` notifications sent while the generation streams
+// `value.generatedText` holds only the text generated since the previous notification. Joining
+// every chunk in order gives the `generatedText` of the final result
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GenerationStreamProgressParams {
+    // The `partialResultToken` of the request
+    pub(crate) token: ProgressToken,
+    pub(crate) value: GenerationStreamResult,
+}
+
 impl lsp_types::request::Request for GenerationStream {
     type Params = GenerationStreamParams;
     type Result = GenerationStreamResult;
@@ -1,2 +1,6 @@
+pub(crate) mod explanation;
 pub(crate) mod generation;
 pub(crate) mod generation_stream;
+pub(crate) mod preview_prompt;
+pub(crate) mod stats;
+pub(crate) mod undo_generation;
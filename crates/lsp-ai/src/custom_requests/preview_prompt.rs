@@ -0,0 +1,79 @@
+use lsp_types::TextDocumentPositionParams;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::memory_backends::{ContextChunk, Prompt, PromptType};
+
+pub(crate) enum PreviewPrompt {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PreviewPromptParams {
+    // This field was "mixed-in" from TextDocumentPositionParams
+    #[serde(flatten)]
+    pub(crate) text_document_position: TextDocumentPositionParams,
+    // The model key to build the prompt for
+    pub(crate) model: String,
+    #[serde(default)]
+    // Merged over the parameters configured for the model like in a generation request
+    pub(crate) parameters: Value,
+    // Overrides the prompt type the model's parameters would select
+    pub(crate) prompt_type: Option<PromptType>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[allow(clippy::upper_case_acronyms)]
+#[serde(tag = "type")]
+pub(crate) enum PreviewedPrompt {
+    #[serde(rename = "context_and_code")]
+    ContextAndCode { context: String, code: String },
+    #[serde(rename = "fim")]
+    FIM { prompt: String, suffix: String },
+}
+
+impl PreviewedPrompt {
+    pub(crate) fn characters(&self) -> usize {
+        match self {
+            PreviewedPrompt::ContextAndCode { context, code } => {
+                context.chars().count() + code.chars().count()
+            }
+            PreviewedPrompt::FIM { prompt, suffix } => {
+                prompt.chars().count() + suffix.chars().count()
+            }
+        }
+    }
+}
+
+impl From<Prompt> for PreviewedPrompt {
+    fn from(prompt: Prompt) -> Self {
+        match prompt {
+            Prompt::ContextAndCode(context_and_code) => PreviewedPrompt::ContextAndCode {
+                context: context_and_code.context,
+                code: context_and_code.code,
+            },
+            Prompt::FIM(fim) => PreviewedPrompt::FIM {
+                prompt: fim.prompt,
+                suffix: fim.suffix,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PreviewPromptResult {
+    pub(crate) prompt: PreviewedPrompt,
+    // The `max_context` budget in tokens and the estimated number of characters it allows
+    pub(crate) max_context: usize,
+    pub(crate) max_context_characters: usize,
+    // The number of characters in the built prompt
+    pub(crate) used_characters: usize,
+    // The chunks retrieved to build the context. Only retrieval based memory backends return these
+    pub(crate) retrieved_chunks: Vec<ContextChunk>,
+}
+
+impl lsp_types::request::Request for PreviewPrompt {
+    type Params = PreviewPromptParams;
+    type Result = PreviewPromptResult;
+    const METHOD: &'static str = "textDocument/previewPrompt";
+}
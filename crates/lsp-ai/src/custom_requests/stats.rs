@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+pub(crate) enum Stats {}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StatsParams {
+    // Zero the counters after reading them
+    #[serde(default)]
+    pub(crate) reset: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LatencyBucket {
+    // The upper bound of the bucket. Null for the final bucket holding every slower request
+    pub(crate) le_ms: Option<u64>,
+    pub(crate) count: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ModelStats {
+    pub(crate) requests: u64,
+    pub(crate) errors: u64,
+    // Only counted for providers that report usage
+    pub(crate) prompt_tokens: u64,
+    pub(crate) completion_tokens: u64,
+    pub(crate) average_latency_ms: f64,
+    pub(crate) latency_histogram: Vec<LatencyBucket>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StatsResult {
+    // Keyed by the model names in the configuration
+    pub(crate) models: HashMap<String, ModelStats>,
+}
+
+impl lsp_types::request::Request for Stats {
+    type Params = StatsParams;
+    type Result = StatsResult;
+    const METHOD: &'static str = "lsp-ai/stats";
+}
@@ -0,0 +1,16 @@
+use lsp_types::{TextDocumentIdentifier, WorkspaceEdit};
+use serde::{Deserialize, Serialize};
+
+pub(crate) enum UndoGeneration {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UndoGenerationParams {
+    pub(crate) text_document: TextDocumentIdentifier,
+}
+
+impl lsp_types::request::Request for UndoGeneration {
+    type Params = UndoGenerationParams;
+    type Result = WorkspaceEdit;
+    const METHOD: &'static str = "textDocument/undoGeneration";
+}
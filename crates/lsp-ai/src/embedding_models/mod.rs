@@ -1,8 +1,8 @@
-use crate::config::ValidEmbeddingModel;
+use crate::config::{EmbeddingPrefix, ValidEmbeddingModel};
 
 mod ollama;
 
-fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+pub(crate) fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
     let magnitude = (vector.iter().map(|&x| x * x).sum::<f32>()).sqrt();
 
     if magnitude != 0.0 {
@@ -14,12 +14,34 @@ fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
     vector
 }
 
+pub(crate) fn validate_dimensions(
+    embedding: Vec<f32>,
+    dimensions: Option<usize>,
+) -> anyhow::Result<Vec<f32>> {
+    match dimensions {
+        Some(dimensions) if dimensions != embedding.len() => anyhow::bail!(
+            "embedding model returned an embedding with {} dimensions but {dimensions} dimensions are configured",
+            embedding.len()
+        ),
+        _ => Ok(embedding),
+    }
+}
+
 #[derive(Clone, Copy)]
 pub(crate) enum EmbeddingPurpose {
     Storage,
     Retrieval,
 }
 
+impl EmbeddingPurpose {
+    pub(crate) fn prefix(self, prefix: &EmbeddingPrefix) -> &str {
+        match self {
+            EmbeddingPurpose::Storage => &prefix.storage,
+            EmbeddingPurpose::Retrieval => &prefix.retrieval,
+        }
+    }
+}
+
 #[async_trait::async_trait]
 pub(crate) trait EmbeddingModel {
     async fn embed(
@@ -27,14 +49,109 @@ pub(crate) trait EmbeddingModel {
         batch: Vec<&str>,
         purpose: EmbeddingPurpose,
     ) -> anyhow::Result<Vec<Vec<f32>>>;
+
+    // Whether the embeddings returned have a magnitude of 1
+    fn returns_normalized(&self) -> bool {
+        false
+    }
+}
+
+// Applies the configured prefix for the purpose so the backends only embed what they are given
+struct Prefixed {
+    model: Box<dyn EmbeddingModel + Send + Sync>,
+    prefix: EmbeddingPrefix,
+}
+
+#[async_trait::async_trait]
+impl EmbeddingModel for Prefixed {
+    async fn embed(
+        &self,
+        batch: Vec<&str>,
+        purpose: EmbeddingPurpose,
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        let prefix = purpose.prefix(&self.prefix);
+        if prefix.is_empty() {
+            return self.model.embed(batch, purpose).await;
+        }
+        let batch: Vec<String> = batch.iter().map(|text| format!("{prefix}{text}")).collect();
+        self.model
+            .embed(batch.iter().map(String::as_str).collect(), purpose)
+            .await
+    }
+
+    fn returns_normalized(&self) -> bool {
+        self.model.returns_normalized()
+    }
 }
 
 impl TryFrom<ValidEmbeddingModel> for Box<dyn EmbeddingModel + Send + Sync> {
     type Error = anyhow::Error;
 
     fn try_from(value: ValidEmbeddingModel) -> Result<Self, Self::Error> {
-        match value {
-            ValidEmbeddingModel::Ollama(config) => Ok(Box::new(ollama::Ollama::new(config))),
-        }
+        let prefix = value.prefix().clone();
+        let model: Box<dyn EmbeddingModel + Send + Sync> = match value {
+            ValidEmbeddingModel::Ollama(config) => Box::new(ollama::Ollama::new(config)),
+        };
+        Ok(with_prefix(model, prefix))
+    }
+}
+
+pub(crate) fn with_prefix(
+    model: Box<dyn EmbeddingModel + Send + Sync>,
+    prefix: EmbeddingPrefix,
+) -> Box<dyn EmbeddingModel + Send + Sync> {
+    Box::new(Prefixed { model, prefix })
+}
+
+// Records the texts it is asked to embed and returns the same embedding for each
+#[cfg(test)]
+pub(crate) struct RecordingEmbeddingModel {
+    pub(crate) texts: std::sync::Arc<parking_lot::Mutex<Vec<String>>>,
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl EmbeddingModel for RecordingEmbeddingModel {
+    async fn embed(
+        &self,
+        batch: Vec<&str>,
+        _purpose: EmbeddingPurpose,
+    ) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.texts
+            .lock()
+            .extend(batch.iter().map(|text| text.to_string()));
+        Ok(batch.iter().map(|_| vec![1., 0.]).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn prefix_by_purpose() -> anyhow::Result<()> {
+        let texts = Arc::new(Mutex::new(vec![]));
+        let prefixed = with_prefix(
+            Box::new(RecordingEmbeddingModel {
+                texts: texts.clone(),
+            }),
+            serde_json::from_value(serde_json::json!({
+                "storage": "search_document: ",
+                "retrieval": "search_query: "
+            }))?,
+        );
+        prefixed
+            .embed(vec!["fn a()"], EmbeddingPurpose::Storage)
+            .await?;
+        prefixed
+            .embed(vec!["a"], EmbeddingPurpose::Retrieval)
+            .await?;
+        assert_eq!(
+            *texts.lock(),
+            vec!["search_document: fn a()", "search_query: a"]
+        );
+        Ok(())
     }
 }
@@ -3,9 +3,9 @@ use std::collections::HashMap;
 use serde::Deserialize;
 use serde_json::{json, Value};
 
-use crate::config;
+use crate::{config, utils::http_client};
 
-use super::{normalize, EmbeddingModel, EmbeddingPurpose};
+use super::{normalize, validate_dimensions, EmbeddingModel, EmbeddingPurpose};
 
 #[derive(Deserialize)]
 pub(crate) struct Embed {
@@ -40,16 +40,11 @@ impl EmbeddingModel for Ollama {
     async fn embed(
         &self,
         batch: Vec<&str>,
-        purpose: EmbeddingPurpose,
+        _purpose: EmbeddingPurpose,
     ) -> anyhow::Result<Vec<Vec<f32>>> {
         let mut results = vec![];
-        let prefix = match purpose {
-            EmbeddingPurpose::Storage => &self.config.prefix.storage,
-            EmbeddingPurpose::Retrieval => &self.config.prefix.retrieval,
-        };
-        let client = reqwest::Client::new();
-        for item in batch {
-            let prompt = format!("{prefix}{item}");
+        let client = http_client();
+        for prompt in batch {
             let res: EmbedResponse = client
                 .post(
                     self.config
@@ -68,7 +63,10 @@ impl EmbeddingModel for Ollama {
                 .json()
                 .await?;
             match res {
-                EmbedResponse::Success(embedding) => results.push(normalize(embedding.embedding)),
+                EmbedResponse::Success(embedding) => results.push(normalize(validate_dimensions(
+                    embedding.embedding,
+                    self.config.dimensions,
+                )?)),
                 EmbedResponse::Error(error) => anyhow::bail!("{:?}", error.error.to_string()),
                 EmbedResponse::Other(other) => {
                     anyhow::bail!("Unknown error while making request to Ollama: {:?}", other)
@@ -77,6 +75,10 @@ impl EmbeddingModel for Ollama {
         }
         Ok(results)
     }
+
+    fn returns_normalized(&self) -> bool {
+        true
+    }
 }
 
 #[cfg(test)]
@@ -1,22 +1,29 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
 use directories::BaseDirs;
-use lsp_server::{Connection, ExtractError, Message, Notification, Request, RequestId};
+use lsp_server::{Connection, ExtractError, Message, Notification, Request, RequestId, Response};
 use lsp_types::{
-    request::{CodeActionRequest, CodeActionResolveRequest, Completion, Shutdown},
-    CodeActionOptions, CompletionOptions, DidChangeTextDocumentParams, DidOpenTextDocumentParams,
-    RenameFilesParams, ServerCapabilities, TextDocumentSyncKind,
+    notification::ShowMessage,
+    request::{
+        CodeActionRequest, CodeActionResolveRequest, Completion, InlineCompletionRequest, Shutdown,
+    },
+    CodeActionOptions, CompletionOptions, DeleteFilesParams, DidChangeConfigurationParams,
+    DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+    DidSaveTextDocumentParams, FileOperationFilter, FileOperationPattern, FileOperationPatternKind,
+    FileOperationRegistrationOptions, MessageType, OneOf, RenameFilesParams, SaveOptions,
+    ServerCapabilities, ShowMessageParams, TextDocumentSyncKind, TextDocumentSyncOptions,
+    TextDocumentSyncSaveOptions, Url, WorkspaceFileOperationsServerCapabilities,
+    WorkspaceServerCapabilities,
 };
 use std::sync::Mutex;
 use std::{
-    collections::HashMap,
     fs,
     path::{Path, PathBuf},
     sync::{mpsc, Arc},
     thread,
 };
 use tracing::{error, info};
-use tracing_subscriber::{EnvFilter, FmtSubscriber};
+use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, reload, EnvFilter, Registry};
 
 mod config;
 mod crawl;
@@ -24,7 +31,10 @@ mod custom_requests;
 mod embedding_models;
 mod memory_backends;
 mod memory_worker;
+mod progress;
+mod rerankers;
 mod splitters;
+mod stats;
 #[cfg(feature = "llama_cpp")]
 mod template;
 mod transformer_backends;
@@ -34,12 +44,16 @@ mod utils;
 use config::Config;
 use custom_requests::generation::Generation;
 use memory_backends::MemoryBackend;
-use transformer_backends::TransformerBackend;
+use progress::ProgressReporter;
 use transformer_worker::{CompletionRequest, GenerationRequest, WorkerRequest};
 
 use crate::{
-    custom_requests::generation_stream::GenerationStream,
-    transformer_worker::GenerationStreamRequest,
+    custom_requests::{
+        generation_stream::GenerationStream, preview_prompt::PreviewPrompt, stats::Stats,
+        undo_generation::UndoGeneration,
+    },
+    stats::USAGE_STATS,
+    transformer_worker::{GenerationStreamRequest, PreviewPromptRequest, UndoGenerationRequest},
 };
 
 fn notification_is<N: lsp_types::notification::Notification>(notification: &Notification) -> bool {
@@ -58,6 +72,15 @@ where
     req.extract(R::METHOD)
 }
 
+// Completion requests may select a completion profile with an extra `profile` field. Completion
+// and code action requests may override the model with an extra `model` field
+fn get_request_option(req: &Request, name: &str) -> Option<String> {
+    req.params
+        .get(name)
+        .and_then(|value| value.as_str())
+        .map(str::to_string)
+}
+
 // LSP-AI parameters
 #[derive(Parser)]
 #[command(version)]
@@ -68,9 +91,31 @@ struct Args {
     // A dummy argument for now
     #[arg(long, default_value_t = true)]
     stdio: bool,
-    // JSON configuration file location
-    #[arg(long, value_parser = utils::validate_file_exists, required = false)]
+    // JSON or TOML configuration file location
+    #[arg(long, value_parser = utils::validate_file_exists, required = false, global = true)]
     config: Option<PathBuf>,
+    // Read `.lsp-ai.json` or `.lsp-ai.toml` from the workspace root when `--config` is not given.
+    // Only enable it for workspaces you trust, the file picks the models and endpoints used
+    #[arg(long, default_value_t = false, global = true)]
+    workspace_config: bool,
+    // Log filter directives like `debug` or `lsp_ai::transformer_worker=debug,warn`, default: the
+    // `log_level` config option. Ignored when the `LSP_AI_LOG` environment variable is set
+    #[arg(long, value_parser = validate_log_level, global = true)]
+    log_level: Option<String>,
+    // Runs a command instead of the server
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    // Crawls and embeds a workspace into the configured memory backend then exits, so the index
+    // can be built ahead of time
+    Index {
+        // The workspace root
+        #[arg(long, value_parser = utils::validate_dir_exists)]
+        path: PathBuf,
+    },
 }
 
 fn create_log_file(base_path: &Path) -> anyhow::Result<fs::File> {
@@ -80,10 +125,28 @@ fn create_log_file(base_path: &Path) -> anyhow::Result<fs::File> {
     Ok(fs::File::create(file_path)?)
 }
 
-// Builds a tracing subscriber from the `LSP_AI_LOG` environment variable
-// If the variables value is malformed or missing, sets the default log level to ERROR
-fn init_logger(args: &Args) {
-    let builder = FmtSubscriber::builder().with_env_filter(EnvFilter::from_env("LSP_AI_LOG"));
+type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+fn validate_log_level(log_level: &str) -> Result<String> {
+    EnvFilter::try_new(log_level)?;
+    Ok(log_level.to_string())
+}
+
+// Builds the log filter from the `LSP_AI_LOG` environment variable, falling back to `log_level`
+// If the value is malformed or missing, sets the default log level to ERROR
+fn build_log_filter(log_level: Option<&str>) -> EnvFilter {
+    let builder = EnvFilter::builder().with_default_directive(LevelFilter::ERROR.into());
+    match std::env::var("LSP_AI_LOG") {
+        Ok(directives) => builder.parse_lossy(directives),
+        Err(_) => builder.parse_lossy(log_level.unwrap_or_default()),
+    }
+}
+
+// Builds a tracing subscriber filtered by `build_log_filter`
+// The returned handle swaps the filter once the `log_level` config option is known
+fn init_logger(args: &Args) -> LogFilterHandle {
+    let (filter, filter_handle) = reload::Layer::new(build_log_filter(args.log_level.as_deref()));
+    let registry = tracing_subscriber::registry().with(filter);
     let base_dirs = BaseDirs::new();
 
     if args.use_seperate_log_file && base_dirs.is_some() {
@@ -93,46 +156,105 @@ fn init_logger(args: &Args) {
         // Windows: C:\Users\Alice\AppData\Local
         // macOS:   /Users/Alice/Library/Caches
         match create_log_file(&cache_dir) {
-            Ok(log_file) => builder.with_writer(Mutex::new(log_file)).init(),
+            Ok(log_file) => registry
+                .with(fmt::layer().with_writer(Mutex::new(log_file)))
+                .init(),
             Err(e) => {
                 eprintln!("creating log file: {e:?} - falling back to stderr");
-                builder
-                    .with_writer(std::io::stderr)
-                    .without_time()
-                    .with_ansi(false)
+                registry
+                    .with(
+                        fmt::layer()
+                            .with_writer(std::io::stderr)
+                            .without_time()
+                            .with_ansi(false),
+                    )
                     .init()
             }
         }
     } else {
-        builder
-            .with_writer(std::io::stderr)
-            .without_time()
-            .with_ansi(false)
+        registry
+            .with(
+                fmt::layer()
+                    .with_writer(std::io::stderr)
+                    .without_time()
+                    .with_ansi(false),
+            )
             .init()
     }
+    filter_handle
 }
 
-fn load_config(args: &Args, init_args: serde_json::Value) -> anyhow::Result<serde_json::Value> {
-    if let Some(config_path) = &args.config {
-        let config_data = fs::read_to_string(config_path)?;
-        let mut config = serde_json::from_str(&config_data)?;
-        utils::merge_json(&mut config, &init_args);
-        Ok(config)
-    } else {
-        Ok(init_args)
+// The `log_level` config option only applies when neither `LSP_AI_LOG` nor `--log-level` is set
+fn apply_config_log_level(args: &Args, config: &Config, filter_handle: &LogFilterHandle) {
+    if std::env::var_os("LSP_AI_LOG").is_some() || args.log_level.is_some() {
+        return;
     }
+    if let Some(log_level) = &config.config.log_level {
+        if let Err(e) = filter_handle.reload(build_log_filter(Some(log_level))) {
+            error!("setting the log level: {e:?}");
+        }
+    }
+}
+
+fn load_config(args: &Args, init_args: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    Config::load_config_file(init_args, args.config.as_deref(), args.workspace_config)
+}
+
+// Indexes the workspace at `path` the same way the server would when opened there
+fn index_workspace(args: &Args, path: &Path, filter_handle: &LogFilterHandle) -> Result<()> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("resolving workspace path: {}", path.display()))?;
+    let root_uri = Url::from_file_path(&path)
+        .map_err(|_| anyhow::anyhow!("invalid workspace path: {}", path.display()))?;
+    let config =
+        load_config(args, serde_json::json!({ "rootUri": root_uri })).and_then(Config::new)?;
+    apply_config_log_level(args, &config, filter_handle);
+    utils::init_http_client(
+        config.config.tls.as_ref(),
+        config.config.connection_pool.as_ref(),
+    )?;
+    utils::init_header_language(config.config.header_language);
+    let summary = memory_backends::index_workspace(config)?;
+    println!(
+        "Indexed {} files into {} chunks",
+        summary.files, summary.chunks
+    );
+    Ok(())
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    init_logger(&args);
+    let filter_handle = init_logger(&args);
+
+    if let Some(Command::Index { path }) = &args.command {
+        info!("lsp-ai logger initialized indexing {}", path.display());
+        return index_workspace(&args, path, &filter_handle);
+    }
     info!("lsp-ai logger initialized starting server");
 
     let (connection, io_threads) = Connection::stdio();
+    let (initialize_id, initialization_args) = connection.initialize_start()?;
+    let config = load_config(&args, initialization_args).and_then(Config::new);
     let server_capabilities = serde_json::to_value(ServerCapabilities {
-        completion_provider: Some(CompletionOptions::default()),
-        text_document_sync: Some(lsp_types::TextDocumentSyncCapability::Kind(
-            TextDocumentSyncKind::INCREMENTAL,
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: config
+                .as_ref()
+                .ok()
+                .and_then(|config| config.get_completion_trigger_characters()),
+            ..Default::default()
+        }),
+        inline_completion_provider: Some(OneOf::Left(true)),
+        text_document_sync: Some(lsp_types::TextDocumentSyncCapability::Options(
+            TextDocumentSyncOptions {
+                open_close: Some(true),
+                change: Some(TextDocumentSyncKind::INCREMENTAL),
+                // The saved text keeps the index current for clients that don't send every change
+                save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions {
+                    include_text: Some(true),
+                })),
+                ..Default::default()
+            },
         )),
         code_action_provider: Some(lsp_types::CodeActionProviderCapability::Options(
             CodeActionOptions {
@@ -140,21 +262,86 @@ fn main() -> Result<()> {
                 ..Default::default()
             },
         )),
+        // Clients only send `workspace/didDeleteFiles` for files matching a registered filter
+        workspace: Some(WorkspaceServerCapabilities {
+            file_operations: Some(WorkspaceFileOperationsServerCapabilities {
+                did_delete: Some(FileOperationRegistrationOptions {
+                    filters: vec![FileOperationFilter {
+                        scheme: Some("file".to_string()),
+                        pattern: FileOperationPattern {
+                            glob: "**/*".to_string(),
+                            matches: Some(FileOperationPatternKind::File),
+                            options: None,
+                        },
+                    }],
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
         ..Default::default()
     })?;
-    let initialization_args = connection.initialize(server_capabilities)?;
+    connection.initialize_finish(
+        initialize_id,
+        serde_json::json!({ "capabilities": server_capabilities }),
+    )?;
 
-    if let Err(e) = main_loop(connection, load_config(&args, initialization_args)?) {
-        error!("{e:?}");
+    match config {
+        Ok(config) => {
+            apply_config_log_level(&args, &config, &filter_handle);
+            if let Err(e) = main_loop(connection, config) {
+                error!("{e:?}");
+            }
+        }
+        Err(e) => {
+            error!("{e:?}");
+            if let Err(e) = run_without_config(&connection, &e) {
+                error!("{e:?}");
+            }
+            // The writer thread exits once every sender is gone
+            drop(connection);
+        }
     }
 
     io_threads.join()?;
     Ok(())
 }
 
-fn main_loop(connection: Connection, args: serde_json::Value) -> Result<()> {
-    // Build our configuration
-    let config = Config::new(args)?;
+// Without a valid configuration the error is shown to the user and every request fails until the
+// client shuts the server down
+fn run_without_config(connection: &Connection, error: &anyhow::Error) -> Result<()> {
+    let message = format!("lsp-ai could not load its configuration: {error:#}");
+    connection
+        .sender
+        .send(Message::Notification(Notification::new(
+            <ShowMessage as lsp_types::notification::Notification>::METHOD.to_string(),
+            ShowMessageParams {
+                typ: MessageType::ERROR,
+                message: message.clone(),
+            },
+        )))?;
+    for msg in &connection.receiver {
+        if let Message::Request(req) = msg {
+            if connection.handle_shutdown(&req)? {
+                break;
+            }
+            connection.sender.send(Message::Response(Response::new_err(
+                req.id,
+                -32603,
+                message.clone(),
+            )))?;
+        }
+    }
+    Ok(())
+}
+
+fn main_loop(connection: Connection, mut config: Config) -> Result<()> {
+    // The HTTP backends share a client configured before any of them are built
+    utils::init_http_client(
+        config.config.tls.as_ref(),
+        config.config.connection_pool.as_ref(),
+    )?;
+    utils::init_header_language(config.config.header_language);
 
     // Wrap the connection for sharing between threads
     let connection = Arc::new(connection);
@@ -165,18 +352,22 @@ fn main_loop(connection: Connection, args: serde_json::Value) -> Result<()> {
     // The channel we use to communicate with our memory worker
     let (memory_tx, memory_rx) = mpsc::channel();
 
-    // Setup the transformer worker
-    let memory_backend: Box<dyn MemoryBackend + Send + Sync> = config.clone().try_into()?;
+    // Only report indexing progress if the client supports it
+    let progress = config
+        .client_params
+        .capabilities
+        .window
+        .work_done_progress
+        .then(|| ProgressReporter::new(connection.clone()));
+
+    // Setup the memory worker
+    let memory_backend: Box<dyn MemoryBackend + Send + Sync> =
+        (config.clone(), progress).try_into()?;
     let memory_worker_thread = thread::spawn(move || memory_worker::run(memory_backend, memory_rx));
 
     // Setup our transformer worker
-    let transformer_backends: HashMap<String, Box<dyn TransformerBackend + Send + Sync>> = config
-        .config
-        .models
-        .clone()
-        .into_iter()
-        .map(|(key, value)| Ok((key, value.try_into()?)))
-        .collect::<anyhow::Result<HashMap<String, Box<dyn TransformerBackend + Send + Sync>>>>()?;
+    let transformer_backends =
+        transformer_backends::build_transformer_backends(&config.config.models)?;
     let thread_connection = connection.clone();
     let thread_memory_tx = memory_tx.clone();
     let thread_config = config.clone();
@@ -205,13 +396,31 @@ fn main_loop(connection: Connection, args: serde_json::Value) -> Result<()> {
                     connection.handle_shutdown(&req)?;
                     return Ok(());
                 } else if request_is::<Completion>(&req) {
+                    let profile = get_request_option(&req, "profile");
+                    let model = get_request_option(&req, "model");
                     match cast::<Completion>(req) {
                         Ok((id, params)) => {
-                            let completion_request = CompletionRequest::new(id, params);
+                            let completion_request = CompletionRequest::new(id, params)
+                                .with_profile(profile)
+                                .with_model(model);
                             transformer_tx.send(WorkerRequest::Completion(completion_request))?;
                         }
                         Err(err) => error!("{err:?}"),
                     }
+                } else if request_is::<InlineCompletionRequest>(&req) {
+                    let profile = get_request_option(&req, "profile");
+                    let model = get_request_option(&req, "model");
+                    match cast::<InlineCompletionRequest>(req) {
+                        Ok((id, params)) => {
+                            let inline_completion_request =
+                                transformer_worker::InlineCompletionRequest::new(id, params)
+                                    .with_profile(profile)
+                                    .with_model(model);
+                            transformer_tx
+                                .send(WorkerRequest::InlineCompletion(inline_completion_request))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
                 } else if request_is::<Generation>(&req) {
                     match cast::<Generation>(req) {
                         Ok((id, params)) => {
@@ -230,11 +439,41 @@ fn main_loop(connection: Connection, args: serde_json::Value) -> Result<()> {
                         }
                         Err(err) => error!("{err:?}"),
                     }
+                } else if request_is::<PreviewPrompt>(&req) {
+                    match cast::<PreviewPrompt>(req) {
+                        Ok((id, params)) => {
+                            let preview_prompt_request = PreviewPromptRequest::new(id, params);
+                            transformer_tx
+                                .send(WorkerRequest::PreviewPrompt(preview_prompt_request))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
+                } else if request_is::<UndoGeneration>(&req) {
+                    match cast::<UndoGeneration>(req) {
+                        Ok((id, params)) => {
+                            let undo_generation_request = UndoGenerationRequest::new(id, params);
+                            transformer_tx
+                                .send(WorkerRequest::UndoGeneration(undo_generation_request))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
+                } else if request_is::<Stats>(&req) {
+                    match cast::<Stats>(req) {
+                        Ok((id, params)) => {
+                            connection.sender.send(Message::Response(Response::new_ok(
+                                id,
+                                USAGE_STATS.get_stats(params.reset),
+                            )))?;
+                        }
+                        Err(err) => error!("{err:?}"),
+                    }
                 } else if request_is::<CodeActionRequest>(&req) {
+                    let model = get_request_option(&req, "model");
                     match cast::<CodeActionRequest>(req) {
                         Ok((id, params)) => {
                             let code_action_request =
-                                transformer_worker::CodeActionRequest::new(id, params);
+                                transformer_worker::CodeActionRequest::new(id, params)
+                                    .with_model(model);
                             transformer_tx
                                 .send(WorkerRequest::CodeActionRequest(code_action_request))?;
                         }
@@ -259,15 +498,43 @@ fn main_loop(connection: Connection, args: serde_json::Value) -> Result<()> {
                 if notification_is::<lsp_types::notification::DidOpenTextDocument>(&not) {
                     let params: DidOpenTextDocumentParams = serde_json::from_value(not.params)?;
                     memory_tx.send(memory_worker::WorkerRequest::DidOpenTextDocument(params))?;
+                } else if notification_is::<lsp_types::notification::DidCloseTextDocument>(&not) {
+                    let params: DidCloseTextDocumentParams = serde_json::from_value(not.params)?;
+                    memory_tx.send(memory_worker::WorkerRequest::DidCloseTextDocument(params))?;
                 } else if notification_is::<lsp_types::notification::DidChangeTextDocument>(&not) {
                     let params: DidChangeTextDocumentParams = serde_json::from_value(not.params)?;
-                    memory_tx.send(memory_worker::WorkerRequest::DidChangeTextDocument(params))?;
+                    memory_tx.send(memory_worker::WorkerRequest::DidChangeTextDocument(
+                        params.clone(),
+                    ))?;
+                    transformer_tx.send(WorkerRequest::DidChangeTextDocument(params))?;
+                } else if notification_is::<lsp_types::notification::DidSaveTextDocument>(&not) {
+                    let params: DidSaveTextDocumentParams = serde_json::from_value(not.params)?;
+                    memory_tx.send(memory_worker::WorkerRequest::DidSaveTextDocument(params))?;
                 } else if notification_is::<lsp_types::notification::DidRenameFiles>(&not) {
                     let params: RenameFilesParams = serde_json::from_value(not.params)?;
                     memory_tx.send(memory_worker::WorkerRequest::DidRenameFiles(params))?;
+                } else if notification_is::<lsp_types::notification::DidDeleteFiles>(&not) {
+                    let params: DeleteFilesParams = serde_json::from_value(not.params)?;
+                    memory_tx.send(memory_worker::WorkerRequest::DidDeleteFiles(params))?;
+                } else if notification_is::<lsp_types::notification::DidChangeConfiguration>(&not) {
+                    let params: DidChangeConfigurationParams = serde_json::from_value(not.params)?;
+                    match config.with_settings(params.settings) {
+                        Ok(Some(new_config)) => {
+                            // Later changes are merged over this one
+                            config = new_config.clone();
+                            transformer_tx
+                                .send(WorkerRequest::DidChangeConfiguration(Box::new(new_config)))?
+                        }
+                        Ok(None) => (),
+                        Err(e) => error!("updating configuration: {e:?}"),
+                    }
+                }
+            }
+            Message::Response(response) => {
+                if let Some(response) = progress::handle_client_response(response) {
+                    transformer_worker::handle_client_response(response)
                 }
             }
-            _ => (),
         }
     }
     Ok(())
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+// Standard BM25 parameters: term frequency saturation and document length normalization
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+// Identifiers are kept whole so exact matches on names score highest
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|token| !token.is_empty())
+        .map(str::to_lowercase)
+}
+
+struct Document {
+    term_counts: HashMap<String, u32>,
+    len: usize,
+}
+
+impl Document {
+    fn new(text: &str) -> Self {
+        let mut term_counts = HashMap::new();
+        let mut len = 0;
+        for token in tokenize(text) {
+            *term_counts.entry(token).or_default() += 1;
+            len += 1;
+        }
+        Self { term_counts, len }
+    }
+}
+
+// An in memory BM25 index over the chunks of each file
+#[derive(Default)]
+pub(crate) struct Bm25Index {
+    // The documents of each file in the order of its chunks
+    files: HashMap<String, Vec<Document>>,
+    document_frequencies: HashMap<String, usize>,
+    documents: usize,
+    total_len: usize,
+}
+
+impl Bm25Index {
+    // Replaces the documents of the file
+    pub(crate) fn index_file<'a>(&mut self, uri: &str, chunks: impl Iterator<Item = &'a str>) {
+        self.remove_file(uri);
+        let documents: Vec<Document> = chunks.map(Document::new).collect();
+        for document in &documents {
+            for term in document.term_counts.keys() {
+                *self.document_frequencies.entry(term.clone()).or_default() += 1;
+            }
+            self.total_len += document.len;
+        }
+        self.documents += documents.len();
+        self.files.insert(uri.to_string(), documents);
+    }
+
+    pub(crate) fn remove_file(&mut self, uri: &str) {
+        let Some(documents) = self.files.remove(uri) else {
+            return;
+        };
+        for document in &documents {
+            for term in document.term_counts.keys() {
+                if let Some(frequency) = self.document_frequencies.get_mut(term) {
+                    *frequency -= 1;
+                    if *frequency == 0 {
+                        self.document_frequencies.remove(term);
+                    }
+                }
+            }
+            self.total_len -= document.len;
+        }
+        self.documents -= documents.len();
+    }
+
+    pub(crate) fn rename_file(&mut self, old_uri: &str, new_uri: &str) {
+        if let Some(documents) = self.files.remove(old_uri) {
+            self.files.insert(new_uri.to_string(), documents);
+        }
+    }
+
+    // The uri, chunk index and score of every chunk sharing a term with the query
+    pub(crate) fn search(&self, query: &str) -> Vec<(&str, usize, f32)> {
+        if self.documents == 0 {
+            return vec![];
+        }
+        let mut terms: Vec<String> = tokenize(query).collect();
+        terms.sort_unstable();
+        terms.dedup();
+        let terms: Vec<(String, f32)> = terms
+            .into_iter()
+            .filter_map(|term| {
+                let frequency = *self.document_frequencies.get(&term)? as f32;
+                let idf = (1. + (self.documents as f32 - frequency + 0.5) / (frequency + 0.5)).ln();
+                Some((term, idf))
+            })
+            .collect();
+        if terms.is_empty() {
+            return vec![];
+        }
+        let average_len = (self.total_len as f32 / self.documents as f32).max(1.);
+        let mut results = vec![];
+        for (uri, documents) in &self.files {
+            for (index, document) in documents.iter().enumerate() {
+                let len_norm = 1. - B + B * document.len as f32 / average_len;
+                let score: f32 = terms
+                    .iter()
+                    .filter_map(|(term, idf)| {
+                        let count = *document.term_counts.get(term)? as f32;
+                        Some(idf * count * (K1 + 1.) / (count + K1 * len_norm))
+                    })
+                    .sum();
+                if score > 0. {
+                    results.push((uri.as_str(), index, score));
+                }
+            }
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranks_exact_identifier_matches_first() {
+        let mut index = Bm25Index::default();
+        index.index_file(
+            "file:///a.rs",
+            [
+                "fn parse_config(path: &str) -> Config",
+                "fn read(path: &str)",
+            ]
+            .into_iter(),
+        );
+        index.index_file("file:///b.rs", ["let config = load(path);"].into_iter());
+
+        let mut results = index.search("parse_config(path)");
+        results.sort_by(|a, b| b.2.total_cmp(&a.2));
+        let ranked: Vec<(&str, usize)> = results.iter().map(|(uri, i, _)| (*uri, *i)).collect();
+        assert_eq!(ranked[0], ("file:///a.rs", 0));
+        assert_eq!(ranked.len(), 3);
+
+        // Removed files no longer count towards the statistics
+        index.remove_file("file:///a.rs");
+        assert_eq!(index.documents, 1);
+        assert!(index.search("parse_config").is_empty());
+        index.rename_file("file:///b.rs", "file:///c.rs");
+        assert_eq!(index.search("config")[0].0, "file:///c.rs");
+    }
+}
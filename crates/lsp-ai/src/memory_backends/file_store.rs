@@ -1,17 +1,24 @@
 use anyhow::Context;
 use indexmap::IndexSet;
-use lsp_types::{Range, TextDocumentIdentifier, TextDocumentPositionParams};
+use lsp_types::{Position, Range, TextDocumentIdentifier, TextDocumentPositionParams, Url};
 use parking_lot::{Mutex, RwLock};
 use ropey::Rope;
 use serde_json::Value;
-use std::{collections::HashMap, io::Read};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
 use tracing::{error, instrument, warn};
-use tree_sitter::{InputEdit, Point, Tree};
+use tree_sitter::{InputEdit, Node, Point, Tree};
 
 use crate::{
     config::{self, Config},
     crawl::Crawl,
-    utils::{parse_tree, tokens_to_estimated_characters},
+    utils::{
+        add_line_numbers, find_files_matching_globs, format_file_chunk, is_binary, parse_tree,
+        read_file_with_size_cap, tokens_to_estimated_characters,
+    },
 };
 
 use super::{ContextAndCodePrompt, FIMPrompt, MemoryBackend, MemoryRunParams, Prompt, PromptType};
@@ -31,11 +38,25 @@ impl AdditionalFileStoreParams {
 pub(crate) struct File {
     rope: Rope,
     tree: Option<Tree>,
+    // When the file was last opened or edited, see `FileStore::next_access`
+    last_accessed: u64,
+    // The language id the editor opened the file with. Crawled files don't have one
+    language_id: Option<String>,
 }
 
 impl File {
-    fn new(rope: Rope, tree: Option<Tree>) -> Self {
-        Self { rope, tree }
+    fn new(
+        rope: Rope,
+        tree: Option<Tree>,
+        last_accessed: u64,
+        language_id: Option<String>,
+    ) -> Self {
+        Self {
+            rope,
+            tree,
+            last_accessed,
+            language_id,
+        }
     }
 
     pub(crate) fn rope(&self) -> &Rope {
@@ -52,6 +73,41 @@ pub(crate) struct FileStore {
     file_map: RwLock<HashMap<String, File>>,
     accessed_files: Mutex<IndexSet<String>>,
     crawl: Option<Mutex<Crawl>>,
+    context_strategy: config::ContextStrategy,
+    include_imports: bool,
+    pinned_files: Vec<String>,
+    max_pinned_characters: usize,
+    root_uri: Option<String>,
+    max_tracked_files: Option<usize>,
+    access_clock: AtomicU64,
+    // Files open in the editor are never evicted
+    open_files: Mutex<HashSet<String>>,
+    always_full_reparse: bool,
+}
+
+// Larger pinned files that are not open are left out of the context
+const PINNED_FILE_MAX_SIZE: u64 = 10_000_000;
+
+// The uris of the pinned files and the number of characters to take from each
+fn resolve_pinned_files(
+    pinned_context_files: Option<config::PinnedContextFiles>,
+    root_uri: Option<&str>,
+) -> (Vec<String>, usize) {
+    let Some(pinned_context_files) = pinned_context_files else {
+        return (vec![], 0);
+    };
+    let max_characters = tokens_to_estimated_characters(pinned_context_files.max_tokens_per_file);
+    let Some(root_uri) = root_uri else {
+        warn!("Skipping `pinned_context_files` as there is no root_uri");
+        return (vec![], max_characters);
+    };
+    match find_files_matching_globs(root_uri, &pinned_context_files.files) {
+        Ok(uris) => (uris, max_characters),
+        Err(e) => {
+            error!("resolving `pinned_context_files`: {e:?}");
+            (vec![], max_characters)
+        }
+    }
 }
 
 impl FileStore {
@@ -63,11 +119,26 @@ impl FileStore {
             .crawl
             .take()
             .map(|x| Mutex::new(Crawl::new(x, config.clone())));
+        let root_uri = config.client_params.root_uri.clone();
+        let (pinned_files, max_pinned_characters) =
+            resolve_pinned_files(file_store_config.pinned_context_files, root_uri.as_deref());
         let s = Self {
-            params: AdditionalFileStoreParams::default(),
+            // Imports are found using the tree so we need to build it
+            params: AdditionalFileStoreParams::new(
+                file_store_config.include_imports && !config.config.disable_tree_sitter,
+            ),
             file_map: RwLock::new(HashMap::new()),
             accessed_files: Mutex::new(IndexSet::new()),
             crawl,
+            context_strategy: file_store_config.context_strategy,
+            include_imports: file_store_config.include_imports,
+            pinned_files,
+            max_pinned_characters,
+            root_uri,
+            max_tracked_files: file_store_config.max_tracked_files,
+            access_clock: AtomicU64::new(0),
+            open_files: Mutex::new(HashSet::new()),
+            always_full_reparse: config.config.always_full_reparse,
         };
         if let Err(e) = s.maybe_do_crawl(None) {
             error!("{e:?}")
@@ -84,11 +155,26 @@ impl FileStore {
             .crawl
             .take()
             .map(|x| Mutex::new(Crawl::new(x, config.clone())));
+        let root_uri = config.client_params.root_uri.clone();
+        let (pinned_files, max_pinned_characters) =
+            resolve_pinned_files(file_store_config.pinned_context_files, root_uri.as_deref());
         let s = Self {
-            params,
+            params: AdditionalFileStoreParams::new(
+                (params.build_tree || file_store_config.include_imports)
+                    && !config.config.disable_tree_sitter,
+            ),
             file_map: RwLock::new(HashMap::new()),
             accessed_files: Mutex::new(IndexSet::new()),
             crawl,
+            context_strategy: file_store_config.context_strategy,
+            include_imports: file_store_config.include_imports,
+            pinned_files,
+            max_pinned_characters,
+            root_uri,
+            max_tracked_files: file_store_config.max_tracked_files,
+            access_clock: AtomicU64::new(0),
+            open_files: Mutex::new(HashSet::new()),
+            always_full_reparse: config.config.always_full_reparse,
         };
         if let Err(e) = s.maybe_do_crawl(None) {
             error!("{e:?}")
@@ -96,9 +182,9 @@ impl FileStore {
         Ok(s)
     }
 
-    fn add_new_file(&self, uri: &str, contents: String) {
+    fn add_new_file(&self, uri: &str, contents: String, language_id: Option<String>) {
         let tree = if self.params.build_tree {
-            match parse_tree(uri, &contents, None) {
+            match parse_tree(uri, &contents, None, language_id.as_deref()) {
                 Ok(tree) => Some(tree),
                 Err(e) => {
                     warn!("Failed to parse tree for {uri} with error {e}, falling back to no tree");
@@ -108,10 +194,39 @@ impl FileStore {
         } else {
             None
         };
-        self.file_map
-            .write()
-            .insert(uri.to_string(), File::new(Rope::from_str(&contents), tree));
-        self.accessed_files.lock().insert(uri.to_string());
+        let mut file_map = self.file_map.write();
+        file_map.insert(
+            uri.to_string(),
+            File::new(
+                Rope::from_str(&contents),
+                tree,
+                self.next_access(),
+                language_id,
+            ),
+        );
+        let mut accessed_files = self.accessed_files.lock();
+        accessed_files.insert(uri.to_string());
+        // Drop the least recently accessed files other than the one just added and the open ones
+        if let Some(max_tracked_files) = self.max_tracked_files {
+            let open_files = self.open_files.lock();
+            while file_map.len() > max_tracked_files {
+                let Some(evicted_uri) = file_map
+                    .iter()
+                    .filter(|(file_uri, _)| *file_uri != uri && !open_files.contains(*file_uri))
+                    .min_by_key(|(_, file)| file.last_accessed)
+                    .map(|(file_uri, _)| file_uri.clone())
+                else {
+                    break;
+                };
+                file_map.remove(&evicted_uri);
+                accessed_files.shift_remove(&evicted_uri);
+            }
+        }
+    }
+
+    // Increases with every open and edit so files can be evicted in access order
+    fn next_access(&self) -> u64 {
+        self.access_clock.fetch_add(1, Ordering::Relaxed)
     }
 
     fn maybe_do_crawl(&self, triggered_file: Option<String>) -> anyhow::Result<()> {
@@ -126,31 +241,113 @@ impl FileStore {
                         warn!("Ending crawl early due to `max_crawl_memory` resetraint");
                         return Ok(false);
                     }
+                    // Crawled files would only evict the files that are being worked on
+                    if self.max_tracked_files.is_some_and(|max_tracked_files| {
+                        self.file_map.read().len() >= max_tracked_files
+                    }) {
+                        warn!("Ending crawl early due to `max_tracked_files` restraint");
+                        return Ok(false);
+                    }
                     // This means it has been opened before
                     let insert_uri = format!("file:///{path}");
                     if self.file_map.read().contains_key(&insert_uri) {
                         return Ok(true);
                     }
-                    // Open the file and see if it is small enough to read
-                    let mut f = std::fs::File::open(path)?;
-                    let metadata = f.metadata()?;
-                    if metadata.len() > config.max_file_size {
-                        warn!("Skipping file: {path} because it is too large");
+                    // Read the file if it is small enough
+                    let Some(contents) =
+                        read_file_with_size_cap(Path::new(path), config.max_file_size)?
+                    else {
+                        warn!("Skipping file: {path} because it is too large or binary");
                         return Ok(true);
-                    }
-                    // Read the file contents
-                    let mut contents = vec![];
-                    f.read_to_end(&mut contents)?;
-                    let contents = String::from_utf8(contents)?;
+                    };
                     current_bytes += contents.len();
                     total_bytes += contents.len();
-                    self.add_new_file(&insert_uri, contents);
+                    self.add_new_file(&insert_uri, contents, None);
                     Ok(true)
                 })?;
         }
         Ok(())
     }
 
+    // The start of every pinned file other than the current document. Open files are read from
+    // memory so unsaved changes are included
+    pub(crate) fn get_pinned_context(&self, current_document_uri: &str) -> String {
+        self.pinned_files
+            .iter()
+            .filter(|uri| *uri != current_document_uri)
+            .filter_map(|uri| {
+                let open_excerpt = self.file_map.read().get(uri).map(|file| {
+                    file.rope
+                        .slice(..self.max_pinned_characters.min(file.rope.len_chars()))
+                        .to_string()
+                });
+                let excerpt = match open_excerpt {
+                    Some(excerpt) => excerpt,
+                    None => {
+                        let Some(path) =
+                            Url::parse(uri).ok().and_then(|url| url.to_file_path().ok())
+                        else {
+                            error!("pinned file is not a file uri: {uri}");
+                            return None;
+                        };
+                        match read_file_with_size_cap(&path, PINNED_FILE_MAX_SIZE) {
+                            Ok(Some(contents)) => {
+                                contents.chars().take(self.max_pinned_characters).collect()
+                            }
+                            Ok(None) => {
+                                warn!(
+                                    "Skipping pinned file: {uri} because it is too large or binary"
+                                );
+                                return None;
+                            }
+                            Err(e) => {
+                                error!("reading pinned file {uri}: {e:?}");
+                                return None;
+                            }
+                        }
+                    }
+                };
+                Some(format_file_chunk(
+                    uri,
+                    &excerpt,
+                    self.root_uri.as_deref(),
+                    None,
+                ))
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+
+    // The other files we pull context from in order of preference
+    fn get_context_files(&self, current_document_uri: &str) -> Vec<String> {
+        let accessed_files: Vec<String> = self
+            .accessed_files
+            .lock()
+            .iter()
+            .filter(|f| *f != current_document_uri)
+            .cloned()
+            .collect();
+        if !self.include_imports {
+            return accessed_files;
+        }
+        let module_names = match self.file_map.read().get(current_document_uri) {
+            Some(File {
+                rope,
+                tree: Some(tree),
+                ..
+            }) => get_imported_module_names(tree, &rope.to_string()),
+            _ => return accessed_files,
+        };
+        // Imported files in the same directory are most likely to be relevant
+        let current_directory = get_parent_directory(current_document_uri);
+        let (mut imported, rest): (Vec<String>, Vec<String>) = accessed_files
+            .into_iter()
+            .partition(|f| module_names.contains(get_module_name(f)));
+        imported.sort_by_key(|f| get_parent_directory(f) != current_directory);
+        imported.extend(rest);
+        imported
+    }
+
     fn get_rope_for_position(
         &self,
         position: &TextDocumentPositionParams,
@@ -168,27 +365,67 @@ impl FileStore {
             .clone();
         let mut cursor_index = rope.line_to_char(position.position.line as usize)
             + position.position.character as usize;
-        // Add to our rope if we need to
-        for file in self
-            .accessed_files
-            .lock()
-            .iter()
-            .filter(|f| **f != current_document_uri)
-        {
-            let needed = characters.saturating_sub(rope.len_chars() + 1);
-            if needed == 0 || !pull_from_multiple_files {
-                break;
+        let context_strategy = if pull_from_multiple_files {
+            self.context_strategy
+        } else {
+            config::ContextStrategy::CurrentFile
+        };
+        match context_strategy {
+            config::ContextStrategy::CurrentFile => (),
+            config::ContextStrategy::RecentFiles => {
+                // Add to our rope if we need to
+                for file in self.get_context_files(&current_document_uri) {
+                    let needed = characters.saturating_sub(rope.len_chars() + 1);
+                    if needed == 0 {
+                        break;
+                    }
+                    let file_map = self.file_map.read();
+                    let r = &file_map.get(&file).context("Error file not found")?.rope;
+                    let slice_max = needed.min(r.len_chars() + 1);
+                    let rope_str_slice = r
+                        .get_slice(0..slice_max - 1)
+                        .context("Error getting slice")?
+                        .to_string();
+                    rope.insert(0, "\n");
+                    rope.insert(0, &rope_str_slice);
+                    cursor_index += slice_max;
+                }
+            }
+            config::ContextStrategy::CursorWindow => {
+                let mut needed_before = (characters / 2).saturating_sub(cursor_index + 1);
+                let mut needed_after = (characters - characters / 2)
+                    .saturating_sub(rope.len_chars() - cursor_index + 1);
+                for file in self.get_context_files(&current_document_uri) {
+                    if needed_before == 0 && needed_after == 0 {
+                        break;
+                    }
+                    let file_map = self.file_map.read();
+                    let r = &file_map.get(&file).context("Error file not found")?.rope;
+                    if needed_before > 0 {
+                        // The end of the file is closest to the cursor
+                        let take = needed_before.min(r.len_chars());
+                        let rope_str_slice = r
+                            .get_slice(r.len_chars() - take..)
+                            .context("Error getting slice")?
+                            .to_string();
+                        rope.insert(0, "\n");
+                        rope.insert(0, &rope_str_slice);
+                        cursor_index += take + 1;
+                        needed_before = needed_before.saturating_sub(take + 1);
+                    } else {
+                        // The start of the file is closest to the cursor
+                        let take = needed_after.min(r.len_chars());
+                        let rope_str_slice = r
+                            .get_slice(0..take)
+                            .context("Error getting slice")?
+                            .to_string();
+                        let end = rope.len_chars();
+                        rope.insert(end, "\n");
+                        rope.insert(end + 1, &rope_str_slice);
+                        needed_after = needed_after.saturating_sub(take + 1);
+                    }
+                }
             }
-            let file_map = self.file_map.read();
-            let r = &file_map.get(file).context("Error file not found")?.rope;
-            let slice_max = needed.min(r.len_chars() + 1);
-            let rope_str_slice = r
-                .get_slice(0..slice_max - 1)
-                .context("Error getting slice")?
-                .to_string();
-            rope.insert(0, "\n");
-            rope.insert(0, &rope_str_slice);
-            cursor_index += slice_max;
         }
         Ok((rope, cursor_index))
     }
@@ -205,18 +442,43 @@ impl FileStore {
             .context("Error file not found")?
             .rope
             .clone();
-        let cursor_index = rope.line_to_char(position.position.line as usize)
-            + position.position.character as usize;
-        let start = cursor_index.saturating_sub(characters / 2);
-        let end = rope
-            .len_chars()
-            .min(cursor_index + (characters - (cursor_index - start)));
+        let (start, end) = get_window_around_position(&rope, position, characters);
         let rope_slice = rope
             .get_slice(start..end)
             .context("Error getting rope slice")?;
         Ok(rope_slice.to_string())
     }
 
+    // The identifiers in the text `get_characters_around_position` returns, sorted. Without a tree
+    // only snake_case and camelCase words are kept as they are unlikely to be keywords
+    pub(crate) fn get_identifiers_around_position(
+        &self,
+        position: &TextDocumentPositionParams,
+        characters: usize,
+    ) -> anyhow::Result<Vec<String>> {
+        let file_map = self.file_map.read();
+        let file = file_map
+            .get(position.text_document.uri.as_str())
+            .context("Error file not found")?;
+        let (start, end) = get_window_around_position(&file.rope, position, characters);
+        let identifiers = match &file.tree {
+            Some(tree) => get_identifiers_in_range(
+                tree,
+                &file.rope.to_string(),
+                file.rope.char_to_byte(start),
+                file.rope.char_to_byte(end),
+            ),
+            None => get_compound_words(
+                &file
+                    .rope
+                    .get_slice(start..end)
+                    .context("Error getting rope slice")?
+                    .to_string(),
+            ),
+        };
+        Ok(identifiers.into_iter().collect())
+    }
+
     pub(crate) fn build_code(
         &self,
         position: &TextDocumentPositionParams,
@@ -236,14 +498,23 @@ impl FileStore {
                         .len_chars()
                         .min(cursor_index + (max_length - (cursor_index - start)));
 
+                    let first_line_number =
+                        Self::first_line_number(&rope, position, start, cursor_index);
                     rope.insert(cursor_index, "<CURSOR>");
                     let rope_slice = rope
                         .get_slice(start..end + "<CURSOR>".chars().count())
                         .context("Error getting rope slice")?;
+                    let mut code = rope_slice.to_string();
+                    if params.include_line_numbers {
+                        code = add_line_numbers(&code, first_line_number);
+                    }
                     Prompt::ContextAndCode(ContextAndCodePrompt {
                         context: "".to_string(),
-                        code: rope_slice.to_string(),
+                        code,
                         selected_text: None,
+                        attached_files: None,
+                        diagnostics: None,
+                        position: Some(position.clone()),
                     })
                 } else {
                     let start = cursor_index
@@ -251,10 +522,20 @@ impl FileStore {
                     let rope_slice = rope
                         .get_slice(start..cursor_index)
                         .context("Error getting rope slice")?;
+                    let mut code = rope_slice.to_string();
+                    if params.include_line_numbers {
+                        code = add_line_numbers(
+                            &code,
+                            Self::first_line_number(&rope, position, start, cursor_index),
+                        );
+                    }
                     Prompt::ContextAndCode(ContextAndCodePrompt {
                         context: "".to_string(),
-                        code: rope_slice.to_string(),
+                        code,
                         selected_text: None,
+                        attached_files: None,
+                        diagnostics: None,
+                        position: Some(position.clone()),
                     })
                 }
             }
@@ -278,6 +559,18 @@ impl FileStore {
         })
     }
 
+    // The document line number of the rope line holding `start`. The rope may have other files
+    // prepended so we count back from the line the cursor is on
+    fn first_line_number(
+        rope: &Rope,
+        position: &TextDocumentPositionParams,
+        start: usize,
+        cursor_index: usize,
+    ) -> i64 {
+        let lines_before_cursor = rope.char_to_line(cursor_index) - rope.char_to_line(start);
+        position.position.line as i64 + 1 - lines_before_cursor as i64
+    }
+
     pub(crate) fn file_map(&self) -> &RwLock<HashMap<String, File>> {
         &self.file_map
     }
@@ -286,6 +579,11 @@ impl FileStore {
         self.file_map.read().contains_key(uri)
     }
 
+    // The language id the editor opened `uri` with
+    pub(crate) fn language_id(&self, uri: &str) -> Option<String> {
+        self.file_map.read().get(uri)?.language_id.clone()
+    }
+
     pub(crate) fn position_to_byte(&self, position: &TextDocumentPositionParams) -> anyhow::Result<usize> {
         let file_map = self.file_map.read();
         let uri = position.text_document.uri.to_string();
@@ -321,6 +619,30 @@ impl MemoryBackend for FileStore {
         Ok(line)
     }
 
+    // The range from the cursor to the end of the identifier the cursor is in or before
+    #[instrument(skip(self))]
+    fn get_replace_range(&self, position: &TextDocumentPositionParams) -> anyhow::Result<Range> {
+        let file_map = self.file_map.read();
+        let rope = &file_map
+            .get(position.text_document.uri.as_str())
+            .context("Error file not found")?
+            .rope;
+        let token_length = rope
+            .get_line(position.position.line as usize)
+            .context("Error getting replace range")?
+            .chars()
+            .skip(position.position.character as usize)
+            .take_while(|c| c.is_alphanumeric() || *c == '_')
+            .count();
+        Ok(Range::new(
+            position.position,
+            Position::new(
+                position.position.line,
+                position.position.character + token_length as u32,
+            ),
+        ))
+    }
+
     #[instrument(skip(self))]
     fn code_action_request(
         &self,
@@ -360,8 +682,24 @@ impl MemoryBackend for FileStore {
         prompt_type: PromptType,
         params: &Value,
     ) -> anyhow::Result<Prompt> {
-        let params: MemoryRunParams = params.into();
-        self.build_code(position, prompt_type, params, true)
+        let params = MemoryRunParams::new(params, &prompt_type);
+        let prompt = self.build_code(position, prompt_type, params, true)?;
+        let pinned_context = self.get_pinned_context(position.text_document.uri.as_str());
+        if pinned_context.is_empty() {
+            return Ok(prompt);
+        }
+        Ok(match prompt {
+            Prompt::ContextAndCode(context_and_code) => {
+                Prompt::ContextAndCode(ContextAndCodePrompt {
+                    context: pinned_context,
+                    ..context_and_code
+                })
+            }
+            Prompt::FIM(fim) => Prompt::FIM(FIMPrompt {
+                prompt: format!("{pinned_context}\n\n{}", fim.prompt),
+                suffix: fim.suffix,
+            }),
+        })
     }
 
     #[instrument(skip(self))]
@@ -370,13 +708,30 @@ impl MemoryBackend for FileStore {
         params: lsp_types::DidOpenTextDocumentParams,
     ) -> anyhow::Result<()> {
         let uri = params.text_document.uri.to_string();
-        self.add_new_file(&uri, params.text_document.text);
+        if is_binary(params.text_document.text.as_bytes()) {
+            warn!("Not tracking {uri} because it is binary");
+            return Ok(());
+        }
+        let language_id = Some(params.text_document.language_id).filter(|id| !id.is_empty());
+        self.open_files.lock().insert(uri.clone());
+        self.add_new_file(&uri, params.text_document.text, language_id);
         if let Err(e) = self.maybe_do_crawl(Some(uri)) {
             error!("{e:?}")
         }
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    fn closed_text_document(
+        &self,
+        params: lsp_types::DidCloseTextDocumentParams,
+    ) -> anyhow::Result<()> {
+        self.open_files
+            .lock()
+            .remove(params.text_document.uri.as_str());
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     fn changed_text_document(
         &self,
@@ -387,91 +742,85 @@ impl MemoryBackend for FileStore {
         let file = file_map
             .get_mut(&uri)
             .with_context(|| format!("Trying to get file that does not exist {uri}"))?;
+        file.last_accessed = self.next_access();
         for change in params.content_changes {
             // If range is ommitted, text is the new text of the document
             if let Some(range) = change.range {
-                // Record old positions
-                let (old_end_position, old_end_byte) = {
-                    let last_line_index = file.rope.len_lines() - 1;
-                    (
-                        file.rope
-                            .get_line(last_line_index)
-                            .context("getting last line for edit")
-                            .map(|last_line| Point::new(last_line_index, last_line.len_chars())),
-                        file.rope.bytes().count(),
-                    )
+                // Tree-sitter points are rows and byte columns
+                let point = |rope: &Rope, byte: usize| {
+                    let row = rope.byte_to_line(byte);
+                    Point::new(row, byte - rope.line_to_byte(row))
                 };
+                let start_index = file
+                    .rope
+                    .try_line_to_char(range.start.line as usize)
+                    .map(|line_char| line_char + range.start.character as usize)
+                    .context("getting edit start")?;
+                let end_index = file
+                    .rope
+                    .try_line_to_char(range.end.line as usize)
+                    .map(|line_char| line_char + range.end.character as usize)
+                    .context("getting edit end")?;
+                let start_byte = file
+                    .rope
+                    .try_char_to_byte(start_index)
+                    .context("getting edit start byte")?;
+                let old_end_byte = file
+                    .rope
+                    .try_char_to_byte(end_index)
+                    .context("getting edit end byte")?;
+                let start_position = point(&file.rope, start_byte);
+                let old_end_position = point(&file.rope, old_end_byte);
                 // Update the document
-                let start_index = file.rope.line_to_char(range.start.line as usize)
-                    + range.start.character as usize;
-                let end_index =
-                    file.rope.line_to_char(range.end.line as usize) + range.end.character as usize;
                 file.rope.remove(start_index..end_index);
                 file.rope.insert(start_index, &change.text);
-                // Set new end positions
-                let (new_end_position, new_end_byte) = {
-                    let last_line_index = file.rope.len_lines() - 1;
-                    (
-                        file.rope
-                            .get_line(last_line_index)
-                            .context("getting last line for edit")
-                            .map(|last_line| Point::new(last_line_index, last_line.len_chars())),
-                        file.rope.bytes().count(),
-                    )
-                };
+                let new_end_byte = start_byte + change.text.len();
+                let new_end_position = point(&file.rope, new_end_byte);
                 // Update the tree
                 if self.params.build_tree {
-                    let mut old_tree = file.tree.take();
-                    let start_byte = file
-                        .rope
-                        .try_line_to_char(range.start.line as usize)
-                        .and_then(|start_char| {
-                            file.rope
-                                .try_char_to_byte(start_char + range.start.character as usize)
-                        })
-                        .map_err(anyhow::Error::msg);
-                    if let Some(old_tree) = &mut old_tree {
-                        match (start_byte, old_end_position, new_end_position) {
-                            (Ok(start_byte), Ok(old_end_position), Ok(new_end_position)) => {
-                                old_tree.edit(&InputEdit {
-                                    start_byte,
-                                    old_end_byte,
-                                    new_end_byte,
-                                    start_position: Point::new(
-                                        range.start.line as usize,
-                                        range.start.character as usize,
-                                    ),
-                                    old_end_position,
-                                    new_end_position,
-                                });
-                                file.tree = match parse_tree(
-                                    &uri,
-                                    &file.rope.to_string(),
-                                    Some(old_tree),
-                                ) {
-                                    Ok(tree) => Some(tree),
-                                    Err(e) => {
-                                        error!("failed to edit tree: {e:?}");
-                                        None
-                                    }
-                                };
+                    if let Some(mut old_tree) = file.tree.take() {
+                        let contents = file.rope.to_string();
+                        let language_id = file.language_id.as_deref();
+                        let tree = if self.always_full_reparse {
+                            parse_tree(&uri, &contents, None, language_id)
+                        } else {
+                            old_tree.edit(&InputEdit {
+                                start_byte,
+                                old_end_byte,
+                                new_end_byte,
+                                start_position,
+                                old_end_position,
+                                new_end_position,
+                            });
+                            parse_tree(&uri, &contents, Some(&old_tree), language_id).map(|tree| {
+                                // Too slow to check on every edit in release builds
+                                if cfg!(debug_assertions) {
+                                    verify_incremental_tree(&uri, &contents, tree, language_id)
+                                } else {
+                                    tree
+                                }
+                            })
+                        };
+                        file.tree = match tree {
+                            Ok(tree) => Some(tree),
+                            Err(e) => {
+                                error!("failed to edit tree: {e:?}");
+                                None
                             }
-                            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
-                                error!("failed to build tree edit: {e:?}");
-                            }
-                        }
+                        };
                     }
                 }
             } else {
                 file.rope = Rope::from_str(&change.text);
                 if self.params.build_tree {
-                    file.tree = match parse_tree(&uri, &change.text, None) {
-                        Ok(tree) => Some(tree),
-                        Err(e) => {
-                            error!("failed to parse new tree: {e:?}");
-                            None
-                        }
-                    };
+                    file.tree =
+                        match parse_tree(&uri, &change.text, None, file.language_id.as_deref()) {
+                            Ok(tree) => Some(tree),
+                            Err(e) => {
+                                error!("failed to parse new tree: {e:?}");
+                                None
+                            }
+                        };
                 }
             }
         }
@@ -479,18 +828,197 @@ impl MemoryBackend for FileStore {
         Ok(())
     }
 
+    // Clients that don't send every change send the saved text instead
+    #[instrument(skip(self))]
+    fn saved_text_document(
+        &self,
+        params: lsp_types::DidSaveTextDocumentParams,
+    ) -> anyhow::Result<()> {
+        if let Some(text) = params.text {
+            let uri = params.text_document.uri.to_string();
+            // Keep the language id the file was opened with
+            let language_id = self
+                .file_map
+                .read()
+                .get(&uri)
+                .and_then(|file| file.language_id.clone());
+            self.add_new_file(&uri, text, language_id);
+            self.accessed_files.lock().shift_insert(0, uri);
+        }
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     fn renamed_files(&self, params: lsp_types::RenameFilesParams) -> anyhow::Result<()> {
         for file_rename in params.files {
             let mut file_map = self.file_map.write();
             if let Some(rope) = file_map.remove(&file_rename.old_uri) {
-                file_map.insert(file_rename.new_uri, rope);
+                file_map.insert(file_rename.new_uri.clone(), rope);
+            }
+            // Keep the renamed file where it was in the access order
+            let mut accessed_files = self.accessed_files.lock();
+            if let Some((index, _)) = accessed_files.shift_remove_full(&file_rename.old_uri) {
+                accessed_files.shift_insert(index, file_rename.new_uri.clone());
             }
+            let mut open_files = self.open_files.lock();
+            if open_files.remove(&file_rename.old_uri) {
+                open_files.insert(file_rename.new_uri);
+            }
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    fn deleted_files(&self, params: lsp_types::DeleteFilesParams) -> anyhow::Result<()> {
+        for file_delete in params.files {
+            self.file_map.write().remove(&file_delete.uri);
+            self.accessed_files.lock().shift_remove(&file_delete.uri);
+            self.open_files.lock().remove(&file_delete.uri);
         }
         Ok(())
     }
 }
 
+// A wrong `InputEdit` leaves the incrementally parsed tree silently out of sync with the text.
+// Returns a full parse instead when the two differ
+fn verify_incremental_tree(
+    uri: &str,
+    contents: &str,
+    tree: Tree,
+    language_id: Option<&str>,
+) -> Tree {
+    match parse_tree(uri, contents, None, language_id) {
+        Ok(full_tree) if !same_nodes(tree.root_node(), full_tree.root_node()) => {
+            error!("incrementally parsed tree for {uri} diverged from a full parse, using the full parse");
+            full_tree
+        }
+        Ok(_) => tree,
+        Err(e) => {
+            error!("failed to parse tree to verify the incremental parse of {uri}: {e:?}");
+            tree
+        }
+    }
+}
+
+// Compares the kind and byte range of every node
+fn same_nodes(a: Node, b: Node) -> bool {
+    a.kind_id() == b.kind_id()
+        && a.byte_range() == b.byte_range()
+        && a.child_count() == b.child_count()
+        && a.children(&mut a.walk())
+            .zip(b.children(&mut b.walk()))
+            .all(|(a, b)| same_nodes(a, b))
+}
+
+// Collects the names referenced by `use` / `mod` (Rust) and `import` / `from` (Python) statements
+fn get_imported_module_names(tree: &Tree, source: &str) -> HashSet<String> {
+    fn collect_identifiers(node: Node, source: &str, names: &mut HashSet<String>) {
+        if node.kind() == "identifier" {
+            if let Ok(name) = node.utf8_text(source.as_bytes()) {
+                names.insert(name.to_string());
+            }
+        }
+        let mut cursor = node.walk();
+        for child in node.children(&mut cursor) {
+            collect_identifiers(child, source, names);
+        }
+    }
+
+    let mut names = HashSet::new();
+    let mut stack = vec![tree.root_node()];
+    while let Some(node) = stack.pop() {
+        match node.kind() {
+            "use_declaration" | "import_statement" | "import_from_statement" => {
+                collect_identifiers(node, source, &mut names)
+            }
+            // Only the name matters for modules. Inline modules are already in the current file
+            "mod_item" if node.child_by_field_name("body").is_none() => {
+                if let Some(name) = node.child_by_field_name("name") {
+                    collect_identifiers(name, source, &mut names)
+                }
+            }
+            _ => {
+                let mut cursor = node.walk();
+                stack.extend(node.children(&mut cursor));
+            }
+        }
+    }
+    names
+}
+
+// The char range of `characters` characters centered on the cursor where the file allows it
+fn get_window_around_position(
+    rope: &Rope,
+    position: &TextDocumentPositionParams,
+    characters: usize,
+) -> (usize, usize) {
+    let cursor_index =
+        rope.line_to_char(position.position.line as usize) + position.position.character as usize;
+    let start = cursor_index.saturating_sub(characters / 2);
+    let end = rope
+        .len_chars()
+        .min(cursor_index + (characters - (cursor_index - start)));
+    (start, end)
+}
+
+// Grammars name their identifier nodes `identifier`, `type_identifier`, `field_identifier` and so on
+fn get_identifiers_in_range(
+    tree: &Tree,
+    source: &str,
+    start_byte: usize,
+    end_byte: usize,
+) -> BTreeSet<String> {
+    let mut identifiers = BTreeSet::new();
+    let mut stack = vec![tree.root_node()];
+    while let Some(node) = stack.pop() {
+        if node.end_byte() <= start_byte || node.start_byte() >= end_byte {
+            continue;
+        }
+        if node.child_count() == 0 && node.kind().ends_with("identifier") {
+            if let Ok(identifier) = node.utf8_text(source.as_bytes()) {
+                identifiers.insert(identifier.to_string());
+            }
+        } else {
+            let mut cursor = node.walk();
+            stack.extend(node.children(&mut cursor));
+        }
+    }
+    identifiers
+}
+
+fn get_compound_words(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.starts_with(|c: char| c.is_ascii_digit()))
+        .filter(|word| {
+            let word = word.trim_matches('_');
+            word.contains('_')
+                || word
+                    .chars()
+                    .zip(word.chars().skip(1))
+                    .any(|(a, b)| a.is_lowercase() && b.is_uppercase())
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+fn get_parent_directory(uri: &str) -> &str {
+    uri.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("")
+}
+
+// The name a file is imported by. `mod.rs` and `__init__.py` are imported by their directory name
+fn get_module_name(uri: &str) -> &str {
+    let path = std::path::Path::new(uri);
+    match path.file_stem().and_then(|x| x.to_str()) {
+        Some("mod") | Some("__init__") => path
+            .parent()
+            .and_then(|x| x.file_name())
+            .and_then(|x| x.to_str())
+            .unwrap_or(""),
+        Some(stem) => stem,
+        None => "",
+    }
+}
+
 // For testing use only
 #[cfg(test)]
 impl FileStore {
@@ -549,6 +1077,20 @@ mod tests {
         FileStore::new(file_store_config, config)
     }
 
+    fn generate_full_change(uri: &str, text: &str) -> lsp_types::DidChangeTextDocumentParams {
+        lsp_types::DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier {
+                uri: reqwest::Url::parse(uri).unwrap(),
+                version: 1,
+            },
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: text.to_string(),
+            }],
+        }
+    }
+
     fn generate_filler_text_document(uri: Option<&str>, text: Option<&str>) -> TextDocumentItem {
         let uri = uri.unwrap_or("file:///filler/");
         let text = text.unwrap_or("Here is the document body");
@@ -577,6 +1119,264 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn can_evict_least_recently_accessed_files() -> anyhow::Result<()> {
+        let file_store = FileStore::new(
+            config::FileStore {
+                max_tracked_files: Some(2),
+                ..config::FileStore::new_without_crawl()
+            },
+            Config::default_with_file_store_without_models(),
+        )?;
+        let open = |uri: &str| {
+            file_store.opened_text_document(DidOpenTextDocumentParams {
+                text_document: generate_filler_text_document(Some(uri), None),
+            })
+        };
+        let close = |uri: &str| {
+            file_store.closed_text_document(lsp_types::DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier {
+                    uri: reqwest::Url::parse(uri).unwrap(),
+                },
+            })
+        };
+        let tracked_files = || {
+            let mut uris: Vec<String> = file_store.file_map.read().keys().cloned().collect();
+            uris.sort();
+            uris
+        };
+        open("file:///a/")?;
+        open("file:///b/")?;
+        // Editing a makes b the least recently accessed file
+        file_store.changed_text_document(generate_full_change("file:///a/", "edited"))?;
+        close("file:///a/")?;
+        close("file:///b/")?;
+        open("file:///c/")?;
+        assert_eq!(tracked_files(), vec!["file:///a/", "file:///c/"]);
+        assert!(!file_store.accessed_files.lock().contains("file:///b/"));
+
+        // c is still open so a is dropped even though c was accessed first
+        open("file:///d/")?;
+        assert_eq!(tracked_files(), vec!["file:///c/", "file:///d/"]);
+        assert_eq!(file_store.accessed_files.lock().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn can_edit_open_files_past_max_tracked_files() -> anyhow::Result<()> {
+        let file_store = FileStore::new(
+            config::FileStore {
+                max_tracked_files: Some(1),
+                ..config::FileStore::new_without_crawl()
+            },
+            Config::default_with_file_store_without_models(),
+        )?;
+        for uri in ["file:///a/", "file:///b/"] {
+            file_store.opened_text_document(DidOpenTextDocumentParams {
+                text_document: generate_filler_text_document(Some(uri), None),
+            })?;
+        }
+        // a would have been evicted when b was opened if it was not open
+        file_store.changed_text_document(generate_full_change("file:///a/", "edited"))?;
+        assert_eq!(
+            file_store.file_map.read()["file:///a/"].rope.to_string(),
+            "edited"
+        );
+
+        // Once closed it is evicted like any other file
+        file_store.closed_text_document(lsp_types::DidCloseTextDocumentParams {
+            text_document: TextDocumentIdentifier {
+                uri: reqwest::Url::parse("file:///a/").unwrap(),
+            },
+        })?;
+        file_store.opened_text_document(DidOpenTextDocumentParams {
+            text_document: generate_filler_text_document(Some("file:///c/"), None),
+        })?;
+        let file_map = file_store.file_map.read();
+        assert!(!file_map.contains_key("file:///a/"));
+        assert!(file_map.contains_key("file:///b/"));
+        assert!(file_map.contains_key("file:///c/"));
+        Ok(())
+    }
+
+    #[test]
+    fn can_get_replace_range() -> anyhow::Result<()> {
+        let params = lsp_types::DidOpenTextDocumentParams {
+            text_document: generate_filler_text_document(None, Some("let my_var = 1;\nfoo")),
+        };
+        let file_store = generate_base_file_store()?;
+        file_store.opened_text_document(params)?;
+
+        let position = |line, character| TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: reqwest::Url::parse("file:///filler/").unwrap(),
+            },
+            position: Position::new(line, character),
+        };
+
+        // In the middle of an identifier
+        let range = file_store.get_replace_range(&position(0, 6))?;
+        assert_eq!(range, Range::new(Position::new(0, 6), Position::new(0, 10)));
+
+        // Right before an identifier
+        let range = file_store.get_replace_range(&position(0, 4))?;
+        assert_eq!(range, Range::new(Position::new(0, 4), Position::new(0, 10)));
+
+        // After an identifier
+        let range = file_store.get_replace_range(&position(0, 10))?;
+        assert_eq!(
+            range,
+            Range::new(Position::new(0, 10), Position::new(0, 10))
+        );
+
+        // At the end of the file
+        let range = file_store.get_replace_range(&position(1, 3))?;
+        assert_eq!(range, Range::new(Position::new(1, 3), Position::new(1, 3)));
+        Ok(())
+    }
+
+    fn generate_file_store_with_context_strategy(
+        context_strategy: config::ContextStrategy,
+    ) -> anyhow::Result<FileStore> {
+        let file_store = FileStore::new(
+            config::FileStore {
+                crawl: None,
+                context_strategy,
+                include_imports: false,
+                pinned_context_files: None,
+                max_tracked_files: None,
+            },
+            Config::default_with_file_store_without_models(),
+        )?;
+        for (uri, text) in [
+            ("file:///a/", "aaaa"),
+            ("file:///b/", "bbbb"),
+            ("file:///filler/", "0123456789"),
+        ] {
+            file_store.opened_text_document(DidOpenTextDocumentParams {
+                text_document: generate_filler_text_document(Some(uri), Some(text)),
+            })?;
+        }
+        Ok(file_store)
+    }
+
+    #[test]
+    fn can_assemble_context_with_each_strategy() -> anyhow::Result<()> {
+        let position = TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: reqwest::Url::parse("file:///filler/").unwrap(),
+            },
+            position: Position::new(0, 5),
+        };
+
+        let file_store =
+            generate_file_store_with_context_strategy(config::ContextStrategy::CurrentFile)?;
+        let (rope, cursor_index) = file_store.get_rope_for_position(&position, 20, true)?;
+        assert_eq!(rope.to_string(), "0123456789");
+        assert_eq!(cursor_index, 5);
+
+        let file_store =
+            generate_file_store_with_context_strategy(config::ContextStrategy::RecentFiles)?;
+        let (rope, cursor_index) = file_store.get_rope_for_position(&position, 20, true)?;
+        assert_eq!(rope.to_string(), "bbb\naaaa\n0123456789");
+        assert_eq!(cursor_index, 14);
+
+        // Not pulling from multiple files always behaves like `current_file`
+        let (rope, cursor_index) = file_store.get_rope_for_position(&position, 20, false)?;
+        assert_eq!(rope.to_string(), "0123456789");
+        assert_eq!(cursor_index, 5);
+
+        let file_store =
+            generate_file_store_with_context_strategy(config::ContextStrategy::CursorWindow)?;
+        let (rope, cursor_index) = file_store.get_rope_for_position(&position, 20, true)?;
+        assert_eq!(rope.to_string(), "aaaa\n0123456789\nbbbb");
+        assert_eq!(cursor_index, 10);
+        Ok(())
+    }
+
+    #[test]
+    fn can_prefer_imported_files_in_context() -> anyhow::Result<()> {
+        let file_store = FileStore::new(
+            config::FileStore {
+                crawl: None,
+                context_strategy: config::ContextStrategy::RecentFiles,
+                include_imports: true,
+                pinned_context_files: None,
+                max_tracked_files: None,
+            },
+            Config::default_with_file_store_without_models(),
+        )?;
+        for (uri, text) in [
+            ("file:///lib/utils.rs", "fn lib_util() {}"),
+            ("file:///src/other.rs", "fn other() {}"),
+            ("file:///src/utils.rs", "fn util() {}"),
+            ("file:///src/main.rs", "mod utils;\n\nfn main() {}"),
+        ] {
+            file_store.opened_text_document(DidOpenTextDocumentParams {
+                text_document: generate_filler_text_document(Some(uri), Some(text)),
+            })?;
+        }
+        assert_eq!(
+            file_store.get_context_files("file:///src/main.rs"),
+            vec![
+                "file:///src/utils.rs".to_string(),
+                "file:///lib/utils.rs".to_string(),
+                "file:///src/other.rs".to_string()
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn can_get_imported_module_names() -> anyhow::Result<()> {
+        let source = "import os.path\nfrom .helpers import thing\n\nx = 1\n";
+        let tree = parse_tree("file:///test.py", source, None, None)?;
+        let names = get_imported_module_names(&tree, source);
+        for name in ["os", "path", "helpers", "thing"] {
+            assert!(names.contains(name));
+        }
+        assert!(!names.contains("x"));
+
+        let source = "mod file_store;\nuse crate::utils::parse_tree;\nmod tests { fn z() {} }\n";
+        let tree = parse_tree("file:///test.rs", source, None, None)?;
+        let names = get_imported_module_names(&tree, source);
+        for name in ["file_store", "utils", "parse_tree"] {
+            assert!(names.contains(name));
+        }
+        assert!(!names.contains("tests"));
+        assert!(!names.contains("z"));
+
+        assert_eq!(get_module_name("file:///src/memory/mod.rs"), "memory");
+        assert_eq!(
+            get_module_name("file:///pkg/helpers/__init__.py"),
+            "helpers"
+        );
+        assert_eq!(get_module_name("file:///pkg/helpers.py"), "helpers");
+        Ok(())
+    }
+
+    #[test]
+    fn can_get_identifiers_around_position() -> anyhow::Result<()> {
+        let source = "fn area(rect: &Rectangle) -> u32 {\n    rect.width * rect.height\n}\n";
+        let tree = parse_tree("file:///test.rs", source, None, None)?;
+        let identifiers = get_identifiers_in_range(&tree, source, 0, source.len());
+        for identifier in ["area", "rect", "Rectangle", "width", "height"] {
+            assert!(identifiers.contains(identifier));
+        }
+        assert!(!identifiers.contains("fn"));
+        assert!(!identifiers.contains("u32"));
+        // Only identifiers overlapping the range are kept
+        let identifiers = get_identifiers_in_range(&tree, source, 0, 7);
+        assert_eq!(identifiers.into_iter().collect::<Vec<_>>(), vec!["area"]);
+
+        let words = get_compound_words("let total = parseUserConfig(raw_config) + _x + 2_000");
+        assert_eq!(
+            words.into_iter().collect::<Vec<_>>(),
+            vec!["parseUserConfig", "raw_config"]
+        );
+        Ok(())
+    }
+
     #[test]
     fn can_rename_document() -> anyhow::Result<()> {
         let params = lsp_types::DidOpenTextDocumentParams {
@@ -600,6 +1400,94 @@ mod tests {
             .unwrap()
             .clone();
         assert_eq!(file.rope.to_string(), "Here is the document body");
+        assert!(!file_store.file_map.read().contains_key("file:///filler/"));
+        let accessed_files = file_store.accessed_files.lock();
+        assert!(!accessed_files.contains("file:///filler/"));
+        assert!(accessed_files.contains("file:///filler2/"));
+        Ok(())
+    }
+
+    #[test]
+    fn can_save_document() -> anyhow::Result<()> {
+        let text_document = generate_filler_text_document(None, None);
+        let params = DidOpenTextDocumentParams {
+            text_document: text_document.clone(),
+        };
+        let file_store = generate_base_file_store()?;
+        file_store.opened_text_document(params)?;
+
+        // Without text the document is unchanged
+        file_store.saved_text_document(lsp_types::DidSaveTextDocumentParams {
+            text_document: TextDocumentIdentifier {
+                uri: text_document.uri.clone(),
+            },
+            text: None,
+        })?;
+        assert_eq!(
+            file_store.file_request(&TextDocumentIdentifier {
+                uri: text_document.uri.clone()
+            })?,
+            "Here is the document body"
+        );
+
+        file_store.saved_text_document(lsp_types::DidSaveTextDocumentParams {
+            text_document: TextDocumentIdentifier {
+                uri: text_document.uri.clone(),
+            },
+            text: Some("Here is the saved body".to_string()),
+        })?;
+        assert_eq!(
+            file_store.file_request(&TextDocumentIdentifier {
+                uri: text_document.uri
+            })?,
+            "Here is the saved body"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_build_prompt_after_rename_and_delete() -> anyhow::Result<()> {
+        let file_store = generate_base_file_store()?;
+        for uri in ["file:///filler/", "file:///filler2/", "file:///filler3/"] {
+            file_store.opened_text_document(DidOpenTextDocumentParams {
+                text_document: generate_filler_text_document(Some(uri), None),
+            })?;
+        }
+
+        file_store.renamed_files(RenameFilesParams {
+            files: vec![FileRename {
+                old_uri: "file:///filler/".to_string(),
+                new_uri: "file:///filler4/".to_string(),
+            }],
+        })?;
+        file_store.deleted_files(lsp_types::DeleteFilesParams {
+            files: vec![lsp_types::FileDelete {
+                uri: "file:///filler3/".to_string(),
+            }],
+        })?;
+        assert!(!file_store.file_map.read().contains_key("file:///filler3/"));
+        assert!(!file_store
+            .accessed_files
+            .lock()
+            .contains("file:///filler3/"));
+
+        let prompt = file_store
+            .build_prompt(
+                &TextDocumentPositionParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: reqwest::Url::parse("file:///filler2/").unwrap(),
+                    },
+                    position: Position {
+                        line: 0,
+                        character: 10,
+                    },
+                },
+                PromptType::ContextAndCode,
+                &json!({}),
+            )
+            .await?;
+        let prompt: ContextAndCodePrompt = prompt.try_into()?;
+        assert!(prompt.code.contains("Here is the document body"));
         Ok(())
     }
 
@@ -665,6 +1553,236 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn build_prompt_reserves_max_tokens() -> anyhow::Result<()> {
+        let text_document = generate_filler_text_document(None, Some("a".repeat(100).as_str()));
+        let file_store = generate_base_file_store()?;
+        file_store.opened_text_document(lsp_types::DidOpenTextDocumentParams {
+            text_document: text_document.clone(),
+        })?;
+
+        let position = TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: text_document.uri.clone(),
+            },
+            position: Position {
+                line: 0,
+                character: 100,
+            },
+        };
+        let prompt: ContextAndCodePrompt = file_store
+            .build_prompt(
+                &position,
+                PromptType::ContextAndCode,
+                &json!({"max_context": 20}),
+            )
+            .await?
+            .try_into()?;
+        assert_eq!(prompt.code.len(), 80);
+
+        let prompt: ContextAndCodePrompt = file_store
+            .build_prompt(
+                &position,
+                PromptType::ContextAndCode,
+                &json!({"max_context": 20, "max_tokens": 5}),
+            )
+            .await?
+            .try_into()?;
+        assert_eq!(prompt.code.len(), 60);
+
+        // A quarter of the window is kept when `max_tokens` would leave almost no context
+        let prompt: ContextAndCodePrompt = file_store
+            .build_prompt(
+                &position,
+                PromptType::ContextAndCode,
+                &json!({"max_context": 20, "max_tokens": 20}),
+            )
+            .await?
+            .try_into()?;
+        assert_eq!(prompt.code.len(), 20);
+
+        // Backend specific output limits are reserved too
+        let prompt: ContextAndCodePrompt = file_store
+            .build_prompt(
+                &position,
+                PromptType::ContextAndCode,
+                &json!({"max_context": 20, "max_tokens": 1, "options": {"num_predict": 5}}),
+            )
+            .await?
+            .try_into()?;
+        assert_eq!(prompt.code.len(), 60);
+        let prompt: ContextAndCodePrompt = file_store
+            .build_prompt(
+                &position,
+                PromptType::ContextAndCode,
+                &json!({"max_context": 20, "generationConfig": {"maxOutputTokens": 10}}),
+            )
+            .await?
+            .try_into()?;
+        assert_eq!(prompt.code.len(), 40);
+        Ok(())
+    }
+
+    #[test]
+    fn crawl_skips_binary_files() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("lsp-ai-crawl-binary-{}", std::process::id()));
+        std::fs::create_dir_all(&root)?;
+        std::fs::write(
+            root.join("image.png"),
+            b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR",
+        )?;
+        // Valid UTF-8 but binary
+        std::fs::write(root.join("data.bin"), b"\x00\x01\x02\x03")?;
+        std::fs::write(root.join("latin1.txt"), b"caf\xe9")?;
+        std::fs::write(root.join("main.py"), "x = 1")?;
+
+        let mut config = Config::default_with_file_store_without_models();
+        config.client_params.root_uri = Some(format!("file://{}", root.display()));
+        let file_store = FileStore::new(
+            config::FileStore {
+                crawl: Some(config::Crawl {
+                    all_files: true,
+                    ..Default::default()
+                }),
+                ..config::FileStore::new_without_crawl()
+            },
+            config,
+        )?;
+        let crawled_files: Vec<String> = file_store.file_map.read().keys().cloned().collect();
+        assert_eq!(crawled_files.len(), 1);
+        assert!(crawled_files[0].ends_with("main.py"));
+
+        // Binary files are not tracked when opened either
+        file_store.opened_text_document(DidOpenTextDocumentParams {
+            text_document: generate_filler_text_document(
+                Some("file:///data.bin"),
+                Some("\0\u{1}\u{2}"),
+            ),
+        })?;
+        assert!(!file_store.contains_file("file:///data.bin"));
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn build_prompt_with_pinned_files() -> anyhow::Result<()> {
+        // The space in the root is percent encoded in the uris
+        let root = std::env::temp_dir().join(format!("lsp-ai pinned-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("types"))?;
+        std::fs::write(root.join("types/user.py"), "class User:\n    name: str\n")?;
+        std::fs::write(root.join("types/notes.txt"), "not pinned")?;
+        std::fs::write(root.join("API.md"), "# API")?;
+        let root_uri = Url::from_file_path(&root).unwrap().to_string();
+        assert!(root_uri.contains("%20"));
+
+        let mut config = Config::default_with_file_store_without_models();
+        config.client_params.root_uri = Some(root_uri.clone());
+        let file_store = FileStore::new(
+            config::FileStore {
+                pinned_context_files: Some(serde_json::from_value(json!({
+                    "files": ["types/*.py", "API.md"],
+                    "max_tokens_per_file": 3
+                }))?),
+                ..Default::default()
+            },
+            config,
+        )?;
+        let text_document =
+            generate_filler_text_document(Some(&format!("{root_uri}/main.py")), Some("x = 1"));
+        file_store.opened_text_document(DidOpenTextDocumentParams {
+            text_document: text_document.clone(),
+        })?;
+        let position = TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: text_document.uri,
+            },
+            position: Position::new(0, 5),
+        };
+        let prompt: ContextAndCodePrompt = file_store
+            .build_prompt(&position, PromptType::ContextAndCode, &json!({}))
+            .await?
+            .try_into()?;
+        assert_eq!(
+            prompt.context,
+            "--API.md--\n# API\n\n--types/user.py--\nclass User:\n"
+        );
+        assert_eq!(prompt.code, "x = 1");
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn build_prompt_max_context_per_prompt_type() -> anyhow::Result<()> {
+        let text_document = generate_filler_text_document(None, Some("a".repeat(100).as_str()));
+        let file_store = generate_base_file_store()?;
+        file_store.opened_text_document(lsp_types::DidOpenTextDocumentParams {
+            text_document: text_document.clone(),
+        })?;
+
+        let position = TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: text_document.uri.clone(),
+            },
+            position: Position {
+                line: 0,
+                character: 100,
+            },
+        };
+        let params = json!({"max_context": {"fim": 10, "completion": 5}});
+        let prompt: FIMPrompt = file_store
+            .build_prompt(&position, PromptType::FIM, &params)
+            .await?
+            .try_into()?;
+        assert_eq!(prompt.prompt.len(), 20);
+
+        let prompt: ContextAndCodePrompt = file_store
+            .build_prompt(&position, PromptType::ContextAndCode, &params)
+            .await?
+            .try_into()?;
+        assert_eq!(prompt.code.len(), 20);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn build_prompt_with_line_numbers() -> anyhow::Result<()> {
+        let text_document = generate_filler_text_document(None, Some("fn a() {\n    1\n}"));
+        let file_store = generate_base_file_store()?;
+        file_store.opened_text_document(lsp_types::DidOpenTextDocumentParams {
+            text_document: text_document.clone(),
+        })?;
+
+        let position = TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier {
+                uri: text_document.uri.clone(),
+            },
+            position: Position {
+                line: 1,
+                character: 5,
+            },
+        };
+        let params = json!({"include_line_numbers": true});
+        let prompt: ContextAndCodePrompt = file_store
+            .build_prompt(&position, PromptType::ContextAndCode, &params)
+            .await?
+            .try_into()?;
+        assert_eq!(prompt.code, "1: fn a() {\n2:     1");
+
+        let params = json!({"include_line_numbers": true, "messages": []});
+        let prompt: ContextAndCodePrompt = file_store
+            .build_prompt(&position, PromptType::ContextAndCode, &params)
+            .await?
+            .try_into()?;
+        assert_eq!(prompt.code, "1: fn a() {\n2:     1<CURSOR>\n3: }");
+
+        // FIM prompts are left untouched
+        let prompt: FIMPrompt = file_store
+            .build_prompt(&position, PromptType::FIM, &params)
+            .await?
+            .try_into()?;
+        assert_eq!(prompt.prompt, "fn a() {\n    1");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn can_build_prompt() -> anyhow::Result<()> {
         let text_document = generate_filler_text_document(
@@ -840,6 +1958,164 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn can_replace_diverged_incremental_tree() -> anyhow::Result<()> {
+        let uri = "file:///test.rs";
+        let contents = "fn b() {}\nfn a() {}";
+        // A tree that was never told about the inserted function
+        let stale_tree = parse_tree(uri, "fn a() {}", None, None)?;
+        let tree = verify_incremental_tree(uri, contents, stale_tree, None);
+        assert!(same_nodes(
+            tree.root_node(),
+            parse_tree(uri, contents, None, None)?.root_node()
+        ));
+
+        let mut config = Config::default_with_file_store_without_models();
+        config.config.always_full_reparse = true;
+        let params = AdditionalFileStoreParams { build_tree: true };
+        let file_store =
+            FileStore::new_with_params(config::FileStore::new_without_crawl(), config, params)?;
+        let text_document = generate_filler_text_document(Some(uri), Some("fn a() {}"));
+        file_store.opened_text_document(DidOpenTextDocumentParams {
+            text_document: text_document.clone(),
+        })?;
+        file_store.changed_text_document(lsp_types::DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier {
+                uri: text_document.uri,
+                version: 1,
+            },
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: Some(Range {
+                    start: Position::new(0, 0),
+                    end: Position::new(0, 0),
+                }),
+                range_length: None,
+                text: "fn b() {}\n".to_string(),
+            }],
+        })?;
+        let file = file_store.file_map.read().get(uri).unwrap().clone();
+        assert!(same_nodes(
+            file.tree.unwrap().root_node(),
+            parse_tree(uri, contents, None, None)?.root_node()
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn can_disable_tree_sitter() -> anyhow::Result<()> {
+        let mut config = Config::default_with_file_store_without_models();
+        config.config.disable_tree_sitter = true;
+        let file_store_config = config::FileStore {
+            include_imports: true,
+            ..config::FileStore::new_without_crawl()
+        };
+        let params = AdditionalFileStoreParams { build_tree: true };
+        let file_store = FileStore::new_with_params(file_store_config, config, params)?;
+
+        let uri = "file:///filler/test.rs";
+        file_store.opened_text_document(DidOpenTextDocumentParams {
+            text_document: generate_filler_text_document(Some(uri), Some("fn a() {}")),
+        })?;
+        assert!(file_store.file_map.read().get(uri).unwrap().tree.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn can_parse_with_language_id() -> anyhow::Result<()> {
+        let config = Config::default_with_file_store_without_models();
+        let file_store_config = if let config::ValidMemoryBackend::FileStore(file_store_config) =
+            config.config.memory.clone()
+        {
+            file_store_config
+        } else {
+            anyhow::bail!("requires a file_store_config")
+        };
+        let params = AdditionalFileStoreParams { build_tree: true };
+        let file_store = FileStore::new_with_params(file_store_config, config, params)?;
+
+        // Extensionless files are parsed with the grammar of their language id
+        let uri = "file:///filler/script";
+        let mut text_document = TextDocumentItem {
+            uri: reqwest::Url::parse(uri).unwrap(),
+            language_id: "python".to_string(),
+            version: 0,
+            text: "def a():\n    return 1\n".to_string(),
+        };
+        file_store.opened_text_document(DidOpenTextDocumentParams {
+            text_document: text_document.clone(),
+        })?;
+        let file = file_store.file_map.read().get(uri).unwrap().clone();
+        assert_eq!(
+            file.tree.unwrap().root_node().to_sexp(),
+            "(module (function_definition name: (identifier) parameters: (parameters) body: (block (return_statement (integer)))))"
+        );
+
+        // Without a known language id the extension is used
+        text_document.language_id = "".to_string();
+        file_store.opened_text_document(DidOpenTextDocumentParams { text_document })?;
+        assert!(file_store.file_map.read().get(uri).unwrap().tree.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_store_tree_sitter_append_at_end() -> anyhow::Result<()> {
+        let config = Config::default_with_file_store_without_models();
+        let file_store_config = if let config::ValidMemoryBackend::FileStore(file_store_config) =
+            config.config.memory.clone()
+        {
+            file_store_config
+        } else {
+            anyhow::bail!("requires a file_store_config")
+        };
+        let params = AdditionalFileStoreParams { build_tree: true };
+        let file_store = FileStore::new_with_params(file_store_config, config, params)?;
+
+        let uri = "file:///filler/test.rs";
+        let text_document = TextDocumentItem {
+            uri: reqwest::Url::parse(uri).unwrap(),
+            language_id: "".to_string(),
+            version: 0,
+            text: "// café\nfn a() {}\n".to_string(),
+        };
+        file_store.opened_text_document(DidOpenTextDocumentParams {
+            text_document: text_document.clone(),
+        })?;
+
+        // Append on the empty last line and then at the end of the new last line
+        let changes = [(2, 0, "fn b() { let s = \"é\"; }"), (2, 23, "\nfn c() {}")];
+        for (line, character, text) in changes {
+            let params = lsp_types::DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier {
+                    uri: text_document.uri.clone(),
+                    version: 1,
+                },
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: Some(Range {
+                        start: Position { line, character },
+                        end: Position { line, character },
+                    }),
+                    range_length: None,
+                    text: text.to_string(),
+                }],
+            };
+            file_store.changed_text_document(params)?;
+        }
+
+        let file = file_store.file_map.read().get(uri).unwrap().clone();
+        let contents = file.rope.to_string();
+        assert_eq!(
+            contents,
+            "// café\nfn a() {}\nfn b() { let s = \"é\"; }\nfn c() {}"
+        );
+        assert_eq!(
+            file.tree.unwrap().root_node().to_sexp(),
+            parse_tree(uri, &contents, None, None)?
+                .root_node()
+                .to_sexp()
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_file_store_tree_sitter() -> anyhow::Result<()> {
         let config = Config::default_with_file_store_without_models();
@@ -1,33 +1,106 @@
+use anyhow::Context;
+use ignore::overrides::{Override, OverrideBuilder};
 use lsp_types::{
-    DidChangeTextDocumentParams, DidOpenTextDocumentParams, Range, RenameFilesParams,
-    TextDocumentIdentifier, TextDocumentPositionParams,
+    DeleteFilesParams, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DidSaveTextDocumentParams, Range, RenameFilesParams,
+    TextDocumentIdentifier, TextDocumentPositionParams, Url,
 };
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::path::PathBuf;
+use tracing::{error, warn};
 
-use crate::config::{Config, ValidMemoryBackend};
+use crate::{
+    config::{self, Config, ValidMemoryBackend},
+    progress::ProgressReporter,
+    utils::{add_line_numbers, TOKIO_RUNTIME},
+};
 
+mod bm25;
 pub(crate) mod file_store;
 mod postgresml;
 mod vector_store;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) enum PromptType {
+    #[serde(rename = "context_and_code")]
     ContextAndCode,
+    #[serde(rename = "fim")]
     FIM,
 }
 
+// A chunk retrieved by the memory backend to build the context of a prompt
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub(crate) struct ContextChunk {
+    pub(crate) uri: String,
+    pub(crate) text: String,
+    // The similarity score the chunk was retrieved with
+    pub(crate) score: f32,
+    // The 0-based line of the chunk's file its excerpt starts on. Unknown for chunks stored
+    // before lines were tracked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) start_line: Option<usize>,
+}
+
+// What `index_workspace` stored
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct IndexSummary {
+    pub(crate) files: usize,
+    pub(crate) chunks: usize,
+}
+
+// The most a backend generates. Backend specific keys take precedence over `max_tokens` like they
+// do when the request is sent
+fn output_token_limit(value: &Value) -> usize {
+    [
+        &value["max_completion_tokens"],
+        &value["options"]["num_predict"],
+        &value["options"]["n_predict"],
+        &value["generationConfig"]["maxOutputTokens"],
+        &value["max_new_tokens"],
+        &value["max_tokens"],
+    ]
+    .into_iter()
+    .find_map(Value::as_u64)
+    .unwrap_or(0) as usize
+}
+
 #[derive(Clone)]
 pub(crate) struct MemoryRunParams {
     pub(crate) is_for_chat: bool,
     pub(crate) max_context: usize,
+    // Prefix each line of the code with its line number. Ignored for FIM prompts
+    pub(crate) include_line_numbers: bool,
 }
 
-impl From<&Value> for MemoryRunParams {
-    fn from(value: &Value) -> Self {
+impl MemoryRunParams {
+    pub(crate) fn new(value: &Value, prompt_type: &PromptType) -> Self {
+        // messages are for most backends, contents are for Gemini
+        let is_for_chat = value["messages"].is_array() || value["contents"].is_array();
+        // `max_context` is either a number for every prompt type or set per prompt type like:
+        // `{ "fim": 1024, "chat": 4096, "completion": 2048 }`
+        let max_context = &value["max_context"];
+        let key = match (prompt_type, is_for_chat) {
+            (PromptType::FIM, _) => "fim",
+            (PromptType::ContextAndCode, true) => "chat",
+            (PromptType::ContextAndCode, false) => "completion",
+        };
+        let max_context = max_context
+            .as_u64()
+            .or_else(|| max_context[key].as_u64())
+            .unwrap_or(1024) as usize;
+        // Reserve room in the window for the tokens the model generates. A quarter of the window
+        // is always kept for the prompt
+        let max_tokens = output_token_limit(value);
+        let max_reserved = max_context - max_context / 4;
+        if max_tokens > max_reserved {
+            warn!("the output limit ({max_tokens}) leaves too little of `max_context` ({max_context}) for the prompt, only reserving {max_reserved} tokens for the output");
+        }
+        let max_context = max_context - max_tokens.min(max_reserved);
         Self {
-            max_context: value["max_context"].as_u64().unwrap_or(1024) as usize,
-            // messages are for most backends, contents are for Gemini
-            is_for_chat: value["messages"].is_array() || value["contents"].is_array(),
+            max_context,
+            is_for_chat,
+            include_line_numbers: value["include_line_numbers"].as_bool().unwrap_or(false),
         }
     }
 }
@@ -37,6 +110,12 @@ pub(crate) struct ContextAndCodePrompt {
     pub(crate) context: String,
     pub(crate) code: String,
     pub(crate) selected_text: Option<String>,
+    // The files attached to an action
+    pub(crate) attached_files: Option<String>,
+    // The diagnostics in the range of an action
+    pub(crate) diagnostics: Option<String>,
+    // The document and cursor position the prompt was built for
+    pub(crate) position: Option<TextDocumentPositionParams>,
 }
 
 #[derive(Debug)]
@@ -98,6 +177,9 @@ impl<'a> TryFrom<&'a Prompt> for &'a FIMPrompt {
 #[async_trait::async_trait]
 pub(crate) trait MemoryBackend {
     fn opened_text_document(&self, params: DidOpenTextDocumentParams) -> anyhow::Result<()>;
+    fn closed_text_document(&self, _params: DidCloseTextDocumentParams) -> anyhow::Result<()> {
+        Ok(())
+    }
     fn code_action_request(
         &self,
         text_document_identifier: &TextDocumentIdentifier,
@@ -110,33 +192,188 @@ pub(crate) trait MemoryBackend {
     ) -> anyhow::Result<String>;
     fn changed_text_document(&self, params: DidChangeTextDocumentParams) -> anyhow::Result<()>;
     fn renamed_files(&self, params: RenameFilesParams) -> anyhow::Result<()>;
+    fn deleted_files(&self, params: DeleteFilesParams) -> anyhow::Result<()>;
+    fn saved_text_document(&self, _params: DidSaveTextDocumentParams) -> anyhow::Result<()> {
+        Ok(())
+    }
     fn get_filter_text(&self, position: &TextDocumentPositionParams) -> anyhow::Result<String>;
+    fn get_replace_range(&self, position: &TextDocumentPositionParams) -> anyhow::Result<Range>;
     async fn build_prompt(
         &self,
         position: &TextDocumentPositionParams,
         prompt_type: PromptType,
         params: &Value,
     ) -> anyhow::Result<Prompt>;
+    // Builds the prompt and returns the chunks retrieved for its context
+    async fn build_prompt_with_context(
+        &self,
+        position: &TextDocumentPositionParams,
+        prompt_type: PromptType,
+        params: &Value,
+    ) -> anyhow::Result<(Prompt, Vec<ContextChunk>)> {
+        Ok((
+            self.build_prompt(position, prompt_type, params).await?,
+            vec![],
+        ))
+    }
+}
+
+// The number of chunks to retrieve for the context. One chunk of the budget is left for the code
+// around the cursor and `retrieval_limit` caps the rest
+pub(crate) fn get_retrieval_limit(
+    total_allowed_characters: usize,
+    chunk_size: usize,
+    retrieval_limit: Option<usize>,
+) -> usize {
+    let limit = (total_allowed_characters / chunk_size).saturating_sub(1);
+    retrieval_limit.map_or(limit, |retrieval_limit| retrieval_limit.min(limit))
 }
 
-impl TryFrom<Config> for Box<dyn MemoryBackend + Send + Sync> {
+// Joins chunks retrieved most relevant first in the configured order
+pub(crate) fn join_context_chunks(chunks: &[ContextChunk], order: config::ContextOrder) -> String {
+    let texts = chunks.iter().map(|chunk| chunk.text.as_str());
+    match order {
+        config::ContextOrder::RelevanceDesc => texts.collect::<Vec<_>>().join("\n\n"),
+        config::ContextOrder::RelevanceAsc => texts.rev().collect::<Vec<_>>().join("\n\n"),
+    }
+}
+
+// Prefixes the lines of each chunk's excerpt with their line numbers in its file. The header
+// `format_file_chunk` put above the excerpt is left as is
+pub(crate) fn add_context_line_numbers(chunks: &mut [ContextChunk], header_template: Option<&str>) {
+    let header_lines = header_template.map_or(1, |template| template.matches('\n').count() + 1);
+    for chunk in chunks {
+        let Some(start_line) = chunk.start_line else {
+            continue;
+        };
+        let Some((header_end, _)) = chunk.text.match_indices('\n').nth(header_lines - 1) else {
+            continue;
+        };
+        let (header, excerpt) = chunk.text.split_at(header_end + 1);
+        chunk.text = format!(
+            "{header}{}",
+            add_line_numbers(excerpt, start_line as i64 + 1)
+        );
+    }
+}
+
+// The compiled `retrieval_exclude` rules of a memory backend
+#[derive(Default)]
+pub(crate) struct RetrievalExclusions {
+    rules: Vec<(Override, Option<Override>)>,
+}
+
+impl RetrievalExclusions {
+    pub(crate) fn new(
+        root_uri: Option<&str>,
+        rules: &[config::RetrievalExclusion],
+    ) -> anyhow::Result<Self> {
+        // Without a workspace globs are matched against the path relative to `/`
+        let root = root_uri
+            .map(uri_to_path)
+            .unwrap_or_else(|| PathBuf::from("/"));
+        let build = |globs: &[String]| -> anyhow::Result<Override> {
+            let mut overrides = OverrideBuilder::new(&root);
+            for glob in globs {
+                overrides
+                    .add(glob)
+                    .with_context(|| format!("invalid retrieval_exclude glob: {glob}"))?;
+            }
+            Ok(overrides.build()?)
+        };
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let for_files = match rule.for_files.is_empty() {
+                    true => None,
+                    false => Some(build(&rule.for_files)?),
+                };
+                Ok((build(&rule.globs)?, for_files))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    // Whether any rule excludes chunks from the prompt of `current_uri`
+    pub(crate) fn apply_to(&self, current_uri: &str) -> bool {
+        self.rules
+            .iter()
+            .any(|(_, for_files)| uri_matches(for_files.as_ref(), current_uri))
+    }
+
+    // Whether the chunks of `uri` are kept out of the prompt of `current_uri`
+    pub(crate) fn excludes(&self, current_uri: &str, uri: &str) -> bool {
+        self.rules.iter().any(|(globs, for_files)| {
+            uri_matches(Some(globs), uri) && uri_matches(for_files.as_ref(), current_uri)
+        })
+    }
+}
+
+// No globs match every uri
+fn uri_matches(globs: Option<&Override>, uri: &str) -> bool {
+    let Some(globs) = globs else {
+        return true;
+    };
+    globs.matched(uri_to_path(uri), false).is_whitelist()
+}
+
+// Decodes `file` uris so escaped characters like spaces match globs. Other uris are kept as is
+pub(crate) fn uri_to_path(uri: &str) -> PathBuf {
+    Url::parse(uri)
+        .ok()
+        .and_then(|url| url.to_file_path().ok())
+        .unwrap_or_else(|| PathBuf::from(uri))
+}
+
+impl TryFrom<(Config, Option<ProgressReporter>)> for Box<dyn MemoryBackend + Send + Sync> {
     type Error = anyhow::Error;
 
-    fn try_from(configuration: Config) -> Result<Self, Self::Error> {
+    fn try_from(
+        (configuration, progress): (Config, Option<ProgressReporter>),
+    ) -> Result<Self, Self::Error> {
         match configuration.config.memory.clone() {
             ValidMemoryBackend::FileStore(file_store_config) => Ok(Box::new(
                 file_store::FileStore::new(file_store_config, configuration)?,
             )),
-            ValidMemoryBackend::PostgresML(postgresml_config) => Ok(Box::new(
-                postgresml::PostgresML::new(postgresml_config, configuration)?,
-            )),
+            ValidMemoryBackend::PostgresML(postgresml_config) => {
+                let required = postgresml_config.required;
+                let crawl = postgresml_config.crawl.clone();
+                match postgresml::PostgresML::new(postgresml_config, configuration.clone()) {
+                    Ok(postgresml) => Ok(Box::new(postgresml)),
+                    // Editing keeps working without the semantic context
+                    Err(e) if !required => {
+                        error!("PostgresML is unavailable, falling back to the file store: {e:?}");
+                        let file_store_config = config::FileStore {
+                            crawl,
+                            ..config::FileStore::new_without_crawl()
+                        };
+                        Ok(Box::new(file_store::FileStore::new(
+                            file_store_config,
+                            configuration,
+                        )?))
+                    }
+                    Err(e) => Err(e),
+                }
+            }
             ValidMemoryBackend::VectorStore(vector_store_config) => Ok(Box::new(
-                vector_store::VectorStore::new(vector_store_config, configuration)?,
+                vector_store::VectorStore::new(vector_store_config, configuration, progress)?,
             )),
         }
     }
 }
 
+// Indexes the workspace into the `postgresml` memory backend. Connection errors are returned
+// instead of falling back to the file store, and the startup resync and crawl of the server are
+// skipped as indexing crawls every file itself
+pub(crate) fn index_workspace(configuration: Config) -> anyhow::Result<IndexSummary> {
+    let ValidMemoryBackend::PostgresML(postgresml_config) = configuration.config.memory.clone()
+    else {
+        anyhow::bail!("only the `postgresml` memory backend keeps an index after the server exits")
+    };
+    let postgresml = postgresml::PostgresML::new_without_sync(postgresml_config, configuration)?;
+    TOKIO_RUNTIME.block_on(postgresml.index_workspace())
+}
+
 // This makes testing much easier. Every transformer backend takes in a prompt. When verifying they work, its
 // easier to just pass in a default prompt.
 #[cfg(test)]
@@ -146,6 +383,9 @@ impl Prompt {
             context: r#"def test_context():\n    pass"#.to_string(),
             code: r#"def test_code():\n    <CURSOR>"#.to_string(),
             selected_text: None,
+            attached_files: None,
+            diagnostics: None,
+            position: None,
         })
     }
 
@@ -161,6 +401,95 @@ impl Prompt {
             context: r#"def test_context():\n    pass"#.to_string(),
             code: r#"def test_code():\n    "#.to_string(),
             selected_text: None,
+            attached_files: None,
+            diagnostics: None,
+            position: None,
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retrieval_limit_caps_the_budget() {
+        // 8000 characters of 500 character chunks leave room for 15 chunks
+        assert_eq!(get_retrieval_limit(8000, 500, None), 15);
+        assert_eq!(get_retrieval_limit(8000, 500, Some(5)), 5);
+        // The budget still applies when it allows fewer chunks than the limit
+        assert_eq!(get_retrieval_limit(1500, 500, Some(5)), 2);
+        assert_eq!(get_retrieval_limit(400, 500, Some(5)), 0);
+    }
+
+    #[test]
+    fn join_context_chunks_in_order() {
+        let chunks: Vec<ContextChunk> = [("best", 0.9), ("worst", 0.1)]
+            .into_iter()
+            .map(|(text, score)| ContextChunk {
+                uri: "file:///a.py".to_string(),
+                text: text.to_string(),
+                score,
+                start_line: None,
+            })
+            .collect();
+        assert_eq!(
+            join_context_chunks(&chunks, config::ContextOrder::RelevanceDesc),
+            "best\n\nworst"
+        );
+        assert_eq!(
+            join_context_chunks(&chunks, config::ContextOrder::RelevanceAsc),
+            "worst\n\nbest"
+        );
+    }
+
+    #[test]
+    fn context_line_numbers_skip_headers() {
+        let chunk = |text: &str, start_line: Option<usize>| ContextChunk {
+            uri: "file:///a.py".to_string(),
+            text: text.to_string(),
+            score: 1.,
+            start_line,
+        };
+        let mut chunks = vec![
+            chunk("--a.py--\ndef a():\n    pass", Some(4)),
+            chunk("--a.py--\nx = 1", None),
+        ];
+        add_context_line_numbers(&mut chunks, None);
+        assert_eq!(chunks[0].text, "--a.py--\n5: def a():\n6:     pass");
+        assert_eq!(chunks[1].text, "--a.py--\nx = 1");
+
+        let mut chunks = vec![chunk("# a.py\n```python\nx = 1", Some(0))];
+        add_context_line_numbers(&mut chunks, Some("# {path}\n```{language_id}"));
+        assert_eq!(chunks[0].text, "# a.py\n```python\n1: x = 1");
+    }
+
+    #[test]
+    fn retrieval_exclusions_match_chunk_and_current_files() -> anyhow::Result<()> {
+        let rules: Vec<config::RetrievalExclusion> = serde_json::from_value(serde_json::json!([
+            { "globs": ["**/tests/**"], "for_files": ["src/**"] },
+            { "globs": ["*.lock"] }
+        ]))?;
+        let exclusions = RetrievalExclusions::new(Some("file:///project"), &rules)?;
+        let test_file = "file:///project/tests/parse.rs";
+        assert!(exclusions.excludes("file:///project/src/lib.rs", test_file));
+        // Test files still get context from other test files
+        assert!(!exclusions.excludes("file:///project/tests/lex.rs", test_file));
+        assert!(!exclusions.excludes("file:///project/src/lib.rs", "file:///project/src/parse.rs"));
+        assert!(exclusions.excludes("file:///project/tests/lex.rs", "file:///project/Cargo.lock"));
+        assert!(exclusions.apply_to("file:///project/tests/lex.rs"));
+        assert!(!RetrievalExclusions::default().apply_to("file:///project/src/lib.rs"));
+
+        // Escaped characters in the workspace and file uris are decoded before matching
+        let exclusions = RetrievalExclusions::new(Some("file:///my%20project"), &rules)?;
+        assert!(exclusions.excludes(
+            "file:///my%20project/src/lib.rs",
+            "file:///my%20project/tests/parse%20utils.rs"
+        ));
+        assert!(!exclusions.excludes(
+            "file:///my%20project/tests/lex.rs",
+            "file:///my%20project/tests/parse%20utils.rs"
+        ));
+        Ok(())
+    }
+}
@@ -6,45 +6,69 @@ use rand::{distributions::Alphanumeric, Rng};
 use serde_json::{json, Value};
 use std::{
     collections::HashSet,
-    io::Read,
+    path::Path,
     sync::{
         mpsc::{self, Sender},
         Arc,
     },
     time::Duration,
 };
-use tokio::time;
+use tokio::{sync::Semaphore, task::JoinHandle, time};
 use tracing::{error, instrument, warn};
 
 use crate::{
     config::{self, Config},
     crawl::Crawl,
-    splitters::{Chunk, Splitter},
-    utils::{chunk_to_id, format_file_chunk, tokens_to_estimated_characters, TOKIO_RUNTIME},
+    embedding_models::EmbeddingPurpose,
+    splitters::{build_splitter, Chunk, Splitter},
+    utils::{
+        chunk_to_id, format_file_chunk, is_binary, read_file_with_size_cap, relative_path,
+        tokens_to_estimated_characters, TOKIO_RUNTIME,
+    },
 };
 
 use super::{
+    add_context_line_numbers,
     file_store::{AdditionalFileStoreParams, FileStore},
-    ContextAndCodePrompt, FIMPrompt, MemoryBackend, MemoryRunParams, Prompt, PromptType,
+    get_retrieval_limit, join_context_chunks, uri_to_path, ContextAndCodePrompt, ContextChunk,
+    FIMPrompt, IndexSummary, MemoryBackend, MemoryRunParams, Prompt, PromptType,
+    RetrievalExclusions,
 };
 
-const RESYNC_MAX_FILE_SIZE: u64 = 10_000_000;
-
-fn chunk_to_document(uri: &str, chunk: Chunk, root_uri: Option<&str>) -> Value {
+// Documents are keyed by their path in the workspace so every checkout of a workspace sharing a
+// collection agrees on them. Files outside of the workspace keep their full uri
+fn chunk_to_document(
+    uri: &str,
+    chunk: Chunk,
+    root_uri: Option<&str>,
+    header_template: Option<&str>,
+) -> Value {
+    let document_uri = relative_path(uri, root_uri);
     json!({
-        "id": chunk_to_id(uri, &chunk),
-        "uri": uri,
-        "text": format_file_chunk(uri, &chunk.text, root_uri),
+        "id": chunk_to_id(document_uri, &chunk),
+        "uri": document_uri,
+        "text": format_file_chunk(uri, &chunk.text, root_uri, header_template),
         "range": chunk.range
     })
 }
 
+// The uri of the file a document was built from in this checkout of the workspace
+fn document_to_uri(document_uri: &str, root_uri: Option<&str>) -> String {
+    match root_uri {
+        Some(root_uri) if !document_uri.contains("://") => {
+            format!("{}/{document_uri}", root_uri.trim_end_matches('/'))
+        }
+        _ => document_uri.to_string(),
+    }
+}
+
 async fn split_and_upsert_file(
     uri: &str,
     collection: &mut Collection,
     file_store: Arc<FileStore>,
     splitter: Arc<Box<dyn Splitter + Send + Sync>>,
     root_uri: Option<&str>,
+    header_template: Option<&str>,
 ) -> anyhow::Result<()> {
     // We need to make sure we don't hold the file_store lock while performing a network call
     let chunks = {
@@ -57,7 +81,7 @@ async fn split_and_upsert_file(
     let chunks = chunks.with_context(|| format!("file not found for splitting: {uri}"))?;
     let documents = chunks
         .into_iter()
-        .map(|chunk| chunk_to_document(uri, chunk, root_uri).into())
+        .map(|chunk| chunk_to_document(uri, chunk, root_uri, header_template).into())
         .collect();
     collection
         .upsert_documents(documents, None)
@@ -65,6 +89,57 @@ async fn split_and_upsert_file(
         .context("PGML - Error upserting documents")
 }
 
+// The url of the `origin` remote in a `.git/config` file
+fn parse_git_remote_url(git_config: &str) -> Option<&str> {
+    git_config
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != r#"[remote "origin"]"#)
+        .skip(1)
+        .take_while(|line| !line.starts_with('['))
+        .find_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            (key.trim() == "url").then(|| value.trim())
+        })
+}
+
+// Identifies the workspace the same way on every machine so a collection can be reused and shared.
+// Uses the contents of a `.lsp-ai` file at the root, then the git `origin` remote, then the `root_uri`
+fn workspace_identity(root_uri: &str) -> String {
+    let root = uri_to_path(root_uri);
+    if let Ok(marker) = std::fs::read_to_string(root.join(".lsp-ai")) {
+        if !marker.trim().is_empty() {
+            return marker.trim().to_string();
+        }
+    }
+    if let Ok(git_config) = std::fs::read_to_string(root.join(".git").join("config")) {
+        if let Some(url) = parse_git_remote_url(&git_config) {
+            return url.to_string();
+        }
+    }
+    root_uri.to_string()
+}
+
+// Bumped when the way documents are keyed changes. Collections built with older keys would
+// otherwise get every chunk twice when resynced. 2: keyed by the path in the workspace
+const DOCUMENT_KEYS_VERSION: u32 = 2;
+
+// When building the collection name we include the Pipeline schema
+// If the user changes the Pipeline schema, it will take affect without them having to delete the old files
+fn default_collection_name(root_uri: &str, pipeline: &Value) -> anyhow::Result<String> {
+    Ok(format!(
+        "{:x}",
+        md5::compute(
+            format!(
+                "{}_{}_keys-v{DOCUMENT_KEYS_VERSION}",
+                workspace_identity(root_uri),
+                serde_json::to_string(pipeline)?
+            )
+            .as_bytes()
+        )
+    ))
+}
+
 #[derive(Clone)]
 pub(crate) struct PostgresML {
     config: Config,
@@ -75,11 +150,35 @@ pub(crate) struct PostgresML {
     debounce_tx: Sender<String>,
     crawl: Option<Arc<Mutex<Crawl>>>,
     splitter: Arc<Box<dyn Splitter + Send + Sync>>,
+    // Bounds the batches upserted at once while crawling and resyncing
+    upsert_permits: Arc<Semaphore>,
+    retrieval_exclusions: Arc<RetrievalExclusions>,
 }
 
 impl PostgresML {
     #[instrument]
     pub(crate) fn new(
+        postgresml_config: config::PostgresML,
+        configuration: Config,
+    ) -> anyhow::Result<Self> {
+        let s = Self::new_without_sync(postgresml_config, configuration)?;
+
+        // Resync our Collection
+        let task_s = s.clone();
+        TOKIO_RUNTIME.spawn(async move {
+            if let Err(e) = task_s.resync().await {
+                error!("{e:?}")
+            }
+        });
+
+        if let Err(e) = s.maybe_do_crawl(None) {
+            error!("{e:?}")
+        }
+        Ok(s)
+    }
+
+    // Connects to the collection without resyncing or crawling it
+    pub(crate) fn new_without_sync(
         mut postgresml_config: config::PostgresML,
         configuration: Config,
     ) -> anyhow::Result<Self> {
@@ -88,8 +187,10 @@ impl PostgresML {
             .take()
             .map(|x| Arc::new(Mutex::new(Crawl::new(x, configuration.clone()))));
 
-        let splitter: Arc<Box<dyn Splitter + Send + Sync>> =
-            Arc::new(postgresml_config.splitter.clone().try_into()?);
+        let splitter: Arc<Box<dyn Splitter + Send + Sync>> = Arc::new(build_splitter(
+            postgresml_config.splitter.clone(),
+            configuration.config.disable_tree_sitter,
+        )?);
 
         let file_store = Arc::new(FileStore::new_with_params(
             config::FileStore::new_without_crawl(),
@@ -104,41 +205,32 @@ impl PostgresML {
         };
 
         // Build our pipeline schema
-        let pipeline = match &postgresml_config.embedding_model {
-            Some(embedding_model) => {
-                json!({
-                    "text": {
-                        "semantic_search": {
-                            "model": embedding_model.model,
-                            "parameters": embedding_model.embed_parameters
-                        }
-                    }
-                })
-            }
-            None => {
-                json!({
-                    "text": {
-                        "semantic_search": {
-                            "model": "intfloat/e5-small-v2",
-                            "parameters": {
-                                "prompt": "passage: "
-                            }
-                        }
-                    }
-                })
-            }
+        let (model, embed_parameters) = match &postgresml_config.embedding_model {
+            Some(embedding_model) => (
+                embedding_model.model.as_str(),
+                embedding_model.embed_parameters.as_ref(),
+            ),
+            None => (DEFAULT_EMBEDDING_MODEL, None),
         };
+        let pipeline = json!({
+            "text": {
+                "semantic_search": {
+                    "model": model,
+                    "parameters": embedding_parameters(
+                        embed_parameters,
+                        EmbeddingPurpose::Storage.prefix(&embedding_prefix(&postgresml_config))
+                    )
+                }
+            }
+        });
 
-        // When building the collection name we include the Pipeline schema
-        // If the user changes the Pipeline schema, it will take affect without them having to delete the old files
-        let collection_name = match configuration.client_params.root_uri.clone() {
-            Some(root_uri) => format!(
-                "{:x}",
-                md5::compute(
-                    format!("{root_uri}_{}", serde_json::to_string(&pipeline)?).as_bytes()
-                )
-            ),
-            None => {
+        let collection_name = match (
+            postgresml_config.collection_name.clone(),
+            configuration.client_params.root_uri.as_deref(),
+        ) {
+            (Some(collection_name), _) => collection_name,
+            (None, Some(root_uri)) => default_collection_name(root_uri, &pipeline)?,
+            (None, None) => {
                 warn!("no root_uri provided in server configuration - generating random string for collection name");
                 rand::thread_rng()
                     .sample_iter(&Alphanumeric)
@@ -164,6 +256,7 @@ impl PostgresML {
         let task_file_store = file_store.clone();
         let task_splitter = splitter.clone();
         let task_root_uri = configuration.client_params.root_uri.clone();
+        let task_header_template = postgresml_config.chunk_header_template.clone();
         TOKIO_RUNTIME.spawn(async move {
             let duration = Duration::from_millis(500);
             let mut file_uris = Vec::new();
@@ -203,6 +296,7 @@ impl PostgresML {
                         .iter()
                         .zip(&chunks)
                         .map(|(uri, chunks)| {
+                            let uri = relative_path(uri, task_root_uri.as_deref());
                             let ids: Vec<String> =
                                 chunks.iter().map(|c| chunk_to_id(uri, c)).collect();
                             json!({
@@ -236,7 +330,12 @@ impl PostgresML {
                             chunks
                                 .into_iter()
                                 .map(|chunk| {
-                                    chunk_to_document(uri, chunk, task_root_uri.as_deref())
+                                    chunk_to_document(
+                                        uri,
+                                        chunk,
+                                        task_root_uri.as_deref(),
+                                        task_header_template.as_deref(),
+                                    )
                                 })
                                 .collect::<Vec<Value>>()
                         })
@@ -256,7 +355,12 @@ impl PostgresML {
             }
         });
 
-        let s = Self {
+        let upsert_permits = Arc::new(Semaphore::new(postgresml_config.upsert_concurrency.max(1)));
+        let retrieval_exclusions = Arc::new(RetrievalExclusions::new(
+            configuration.client_params.root_uri.as_deref(),
+            &postgresml_config.retrieval_exclude,
+        )?);
+        Ok(Self {
             config: configuration,
             postgresml_config,
             file_store,
@@ -265,24 +369,79 @@ impl PostgresML {
             debounce_tx,
             crawl,
             splitter,
+            upsert_permits,
+            retrieval_exclusions,
+        })
+    }
+
+    #[instrument(skip(self))]
+    // Crawls and stores every file in the workspace, returning once all of them are stored
+    pub(crate) async fn index_workspace(&self) -> anyhow::Result<IndexSummary> {
+        // Every file is indexed, not only those with the extension of an opened file
+        let crawl_config = match &self.crawl {
+            Some(crawl) => crawl.lock().crawl_config().clone(),
+            None => config::Crawl::default(),
         };
+        let mut crawl = Crawl::new(
+            config::Crawl {
+                all_files: true,
+                ..crawl_config
+            },
+            self.config.clone(),
+        );
+        let mut batches = vec![];
+        let summary =
+            self.crawl_documents(&mut crawl, None, |documents| batches.push(documents))?;
+        let mut upserts = vec![];
+        for documents in batches {
+            upserts.push(self.spawn_upsert(documents).await?);
+        }
+        for upsert in upserts {
+            upsert
+                .await?
+                .context("PGML - error upserting documents while indexing")?;
+        }
+        Ok(summary)
+    }
 
-        // Resync our Collection
-        let task_s = s.clone();
+    // Waits for a free upsert slot then upserts the documents in the background
+    async fn spawn_upsert(
+        &self,
+        documents: Vec<pgml::types::Json>,
+    ) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+        let permit = self.upsert_permits.clone().acquire_owned().await?;
+        let mut collection = self.collection.clone();
+        Ok(TOKIO_RUNTIME.spawn(async move {
+            let result = collection
+                .upsert_documents(documents, None)
+                .await
+                .context("PGML - error upserting documents");
+            drop(permit);
+            result
+        }))
+    }
+
+    // Like `spawn_upsert` but callable outside of the runtime. Blocks the crawl until a slot is
+    // free so it can't run ahead of the upserts
+    fn spawn_crawl_upsert(&self, documents: Vec<pgml::types::Json>) {
+        let permit = TOKIO_RUNTIME
+            .block_on(self.upsert_permits.clone().acquire_owned())
+            .expect("the upsert semaphore is never closed");
+        let mut collection = self.collection.clone();
         TOKIO_RUNTIME.spawn(async move {
-            if let Err(e) = task_s.resync().await {
-                error!("{e:?}")
+            let _permit = permit;
+            if let Err(e) = collection
+                .upsert_documents(documents, None)
+                .await
+                .context("PGML - error upserting crawled files")
+            {
+                error!("{e:?}");
             }
         });
-
-        if let Err(e) = s.maybe_do_crawl(None) {
-            error!("{e:?}")
-        }
-        Ok(s)
     }
 
     async fn resync(&self) -> anyhow::Result<()> {
-        let mut collection = self.collection.clone();
+        let collection = self.collection.clone();
 
         let documents = collection
             .get_documents(Some(
@@ -294,77 +453,86 @@ impl PostgresML {
             ))
             .await?;
 
-        let try_get_file_contents = |path: &std::path::Path| {
-            // Open the file and see if it is small enough to read
-            let mut f = std::fs::File::open(path)?;
-            let metadata = f.metadata()?;
-            if metadata.len() > RESYNC_MAX_FILE_SIZE {
-                anyhow::bail!("file size is greater than: {RESYNC_MAX_FILE_SIZE}")
-            }
-            // Read the file contents
-            let mut contents = vec![];
-            f.read_to_end(&mut contents)?;
-            anyhow::Ok(String::from_utf8(contents)?)
+        let max_file_size = self.postgresml_config.resync_max_file_size;
+        let try_get_file_contents = |path: &Path| {
+            read_file_with_size_cap(path, max_file_size)?.with_context(|| {
+                format!("file is binary or its size is greater than: {max_file_size}")
+            })
         };
 
+        let root_uri = self.config.client_params.root_uri.as_deref();
         let mut documents_to_delete = vec![];
+        let mut upserts = vec![];
         let mut chunks_to_upsert = vec![];
         let mut current_chunks_bytes = 0;
         let mut checked_uris = HashSet::new();
         for document in documents.into_iter() {
-            let uri = match document["document"]["uri"].as_str() {
+            let document_uri = match document["document"]["uri"].as_str() {
                 Some(uri) => uri,
                 None => continue, // This should never happen, but is really bad as we now have a document with essentially no way to delete it
             };
 
             // Check if we have already loaded in this file
-            if checked_uris.contains(uri) {
+            if checked_uris.contains(document_uri) {
                 continue;
             }
-            checked_uris.insert(uri.to_string());
+            checked_uris.insert(document_uri.to_string());
 
+            let uri = document_to_uri(document_uri, root_uri);
             let path = uri.replace("file://", "");
-            let path = std::path::Path::new(&path);
+            let path = Path::new(&path);
             if !path.exists() {
-                documents_to_delete.push(uri.to_string());
+                // Files outside of the workspace may only exist in another checkout sharing the
+                // collection
+                if root_uri.is_some() && document_uri == uri {
+                    continue;
+                }
+                documents_to_delete.push(document_uri.to_string());
             } else {
                 // Try to read the file. If we fail delete it
                 let contents = match try_get_file_contents(path) {
                     Ok(contents) => contents,
                     Err(e) => {
                         error!("{e:?}");
-                        documents_to_delete.push(uri.to_string());
+                        documents_to_delete.push(document_uri.to_string());
                         continue;
                     }
                 };
                 // Split the file into chunks
                 current_chunks_bytes += contents.len();
+                let language_id = self.file_store.language_id(&uri);
                 let chunks: Vec<pgml::types::Json> = self
                     .splitter
-                    .split_file_contents(uri, &contents)
+                    .split_file_contents(&uri, &contents, language_id.as_deref())
                     .into_iter()
                     .map(|chunk| {
-                        chunk_to_document(uri, chunk, self.config.client_params.root_uri.as_deref())
-                            .into()
+                        chunk_to_document(
+                            &uri,
+                            chunk,
+                            root_uri,
+                            self.postgresml_config.chunk_header_template.as_deref(),
+                        )
+                        .into()
                     })
                     .collect();
                 chunks_to_upsert.extend(chunks);
-                // If we have over 10 mega bytes of chunks do the upsert
-                if current_chunks_bytes > 10_000_000 {
-                    collection
-                        .upsert_documents(chunks_to_upsert, None)
-                        .await
-                        .context("PGML - error upserting documents during resync")?;
-                    chunks_to_upsert = vec![];
+                // Once the batch is full upsert it
+                if current_chunks_bytes > self.postgresml_config.upsert_batch_bytes {
+                    upserts.push(
+                        self.spawn_upsert(std::mem::take(&mut chunks_to_upsert))
+                            .await?,
+                    );
                     current_chunks_bytes = 0;
                 }
             }
         }
         // Upsert any remaining chunks
-        if chunks_to_upsert.is_empty() {
-            collection
-                .upsert_documents(chunks_to_upsert, None)
-                .await
+        if !chunks_to_upsert.is_empty() {
+            upserts.push(self.spawn_upsert(chunks_to_upsert).await?);
+        }
+        for upsert in upserts {
+            upsert
+                .await?
                 .context("PGML - error upserting documents during resync")?;
         }
         // Delete documents
@@ -384,85 +552,76 @@ impl PostgresML {
         Ok(())
     }
 
+    // Crawls and splits files, handing the documents to `on_batch` in batches of about
+    // `upsert_batch_bytes`
+    fn crawl_documents(
+        &self,
+        crawl: &mut Crawl,
+        triggered_file: Option<String>,
+        mut on_batch: impl FnMut(Vec<pgml::types::Json>),
+    ) -> anyhow::Result<IndexSummary> {
+        let mut summary = IndexSummary::default();
+        let mut documents = vec![];
+        let mut total_bytes = 0;
+        let mut current_bytes = 0;
+        crawl.maybe_do_crawl(triggered_file, |config, path| {
+            // Break if total bytes is over the max crawl memory
+            if total_bytes as u64 >= config.max_crawl_memory {
+                warn!("Ending crawl early due to `max_crawl_memory` restraint");
+                return Ok(false);
+            }
+            // This means it has been opened before
+            let uri = format!("file://{path}");
+            if self.file_store.contains_file(&uri) {
+                return Ok(true);
+            }
+            // Read the file if it is small enough
+            let Some(contents) = read_file_with_size_cap(Path::new(path), config.max_file_size)?
+            else {
+                warn!("Skipping file: {path} because it is too large or binary");
+                return Ok(true);
+            };
+            current_bytes += contents.len();
+            total_bytes += contents.len();
+            // Files the editor opened were skipped above, so the grammar comes from the extension
+            let chunks: Vec<pgml::types::Json> = self
+                .splitter
+                .split_file_contents(&uri, &contents, None)
+                .into_iter()
+                .map(|chunk| {
+                    chunk_to_document(
+                        &uri,
+                        chunk,
+                        self.config.client_params.root_uri.as_deref(),
+                        self.postgresml_config.chunk_header_template.as_deref(),
+                    )
+                    .into()
+                })
+                .collect();
+            summary.files += 1;
+            summary.chunks += chunks.len();
+            documents.extend(chunks);
+            // Once the batch is full hand it off
+            if current_bytes >= self.postgresml_config.upsert_batch_bytes
+                || total_bytes as u64 >= config.max_crawl_memory
+            {
+                on_batch(std::mem::take(&mut documents));
+                current_bytes = 0;
+            }
+            Ok(true)
+        })?;
+        // Hand off any remaining documents
+        if !documents.is_empty() {
+            on_batch(documents);
+        }
+        Ok(summary)
+    }
+
     fn maybe_do_crawl(&self, triggered_file: Option<String>) -> anyhow::Result<()> {
         if let Some(crawl) = &self.crawl {
-            let mut documents = vec![];
-            let mut total_bytes = 0;
-            let mut current_bytes = 0;
-            crawl
-                .lock()
-                .maybe_do_crawl(triggered_file, |config, path| {
-                    // Break if total bytes is over the max crawl memory
-                    if total_bytes as u64 >= config.max_crawl_memory {
-                        warn!("Ending crawl early due to `max_crawl_memory` restraint");
-                        return Ok(false);
-                    }
-                    // This means it has been opened before
-                    let uri = format!("file://{path}");
-                    if self.file_store.contains_file(&uri) {
-                        return Ok(true);
-                    }
-                    // Open the file and see if it is small enough to read
-                    let mut f = std::fs::File::open(path)?;
-                    let metadata = f.metadata()?;
-                    if metadata.len() > config.max_file_size {
-                        warn!("Skipping file: {path} because it is too large");
-                        return Ok(true);
-                    }
-                    // Read the file contents
-                    let mut contents = vec![];
-                    f.read_to_end(&mut contents)?;
-                    let contents = String::from_utf8(contents)?;
-                    current_bytes += contents.len();
-                    total_bytes += contents.len();
-                    let chunks: Vec<pgml::types::Json> = self
-                        .splitter
-                        .split_file_contents(&uri, &contents)
-                        .into_iter()
-                        .map(|chunk| {
-                            chunk_to_document(
-                                &uri,
-                                chunk,
-                                self.config.client_params.root_uri.as_deref(),
-                            )
-                            .into()
-                        })
-                        .collect();
-                    documents.extend(chunks);
-                    // If we have over 10 mega bytes of data do the upsert
-                    if current_bytes >= 10_000_000 || total_bytes as u64 >= config.max_crawl_memory
-                    {
-                        // Upsert the documents
-                        let mut collection = self.collection.clone();
-                        let to_upsert_documents = std::mem::take(&mut documents);
-                        TOKIO_RUNTIME.spawn(async move {
-                            if let Err(e) = collection
-                                .upsert_documents(to_upsert_documents, None)
-                                .await
-                                .context("PGML - error upserting changed files")
-                            {
-                                error!("{e:?}");
-                            }
-                        });
-                        // Reset everything
-                        current_bytes = 0;
-                        documents = vec![];
-                    }
-                    Ok(true)
-                })?;
-            // Upsert any remaining documents
-            if documents.is_empty() {
-                let mut collection = self.collection.clone();
-                TOKIO_RUNTIME.spawn(async move {
-                    if let Err(e) = collection
-                        .upsert_documents(documents, None)
-                        .await
-                        .context("PGML - error upserting changed files")
-                    {
-                        error!("{e:?}");
-                    }
-                });
-            }
+            self.crawl_documents(&mut crawl.lock(), triggered_file, |documents| {
+                self.spawn_crawl_upsert(documents)
+            })?;
         }
         Ok(())
     }
@@ -486,6 +645,11 @@ impl MemoryBackend for PostgresML {
         self.file_store.get_filter_text(position)
     }
 
+    #[instrument(skip(self))]
+    fn get_replace_range(&self, position: &TextDocumentPositionParams) -> anyhow::Result<Range> {
+        self.file_store.get_replace_range(position)
+    }
+
     #[instrument(skip(self))]
     fn file_request(
         &self,
@@ -501,9 +665,25 @@ impl MemoryBackend for PostgresML {
         prompt_type: PromptType,
         params: &Value,
     ) -> anyhow::Result<Prompt> {
-        let params: MemoryRunParams = params.into();
+        Ok(self
+            .build_prompt_with_context(position, prompt_type, params)
+            .await?
+            .0)
+    }
+
+    #[instrument(skip(self))]
+    async fn build_prompt_with_context(
+        &self,
+        position: &TextDocumentPositionParams,
+        prompt_type: PromptType,
+        params: &Value,
+    ) -> anyhow::Result<(Prompt, Vec<ContextChunk>)> {
+        let params = MemoryRunParams::new(params, &prompt_type);
         let chunk_size = self.splitter.chunk_size();
         let total_allowed_characters = tokens_to_estimated_characters(params.max_context);
+        // Line numbers break FIM prompts
+        let include_line_numbers =
+            params.include_line_numbers && matches!(prompt_type, PromptType::ContextAndCode);
 
         // Build the query
         let query = self
@@ -521,17 +701,27 @@ impl MemoryBackend for PostgresML {
         let cursor_byte = self.file_store.position_to_byte(position)?;
 
         // Get the context
-        let limit = (total_allowed_characters / chunk_size).saturating_sub(1);
-        let parameters = match self
-            .postgresml_config
-            .embedding_model
-            .as_ref()
-            .and_then(|m| m.query_parameters.clone())
-        {
-            Some(query_parameters) => query_parameters,
-            None => json!({
-                "prompt": "query: "
-            }),
+        let limit = get_retrieval_limit(
+            total_allowed_characters,
+            chunk_size,
+            self.postgresml_config.retrieval_limit,
+        );
+        // The collection can't match globs so excluded chunks are filtered out of a larger search
+        let root_uri = self.config.client_params.root_uri.as_deref();
+        let current_uri = position.text_document.uri.as_str();
+        let search_limit = match self.retrieval_exclusions.apply_to(current_uri) {
+            true => limit * EXCLUDED_SEARCH_LIMIT_MULTIPLIER,
+            false => limit,
+        };
+        let parameters = match embedding_parameters(
+            self.postgresml_config
+                .embedding_model
+                .as_ref()
+                .and_then(|m| m.query_parameters.as_ref()),
+            EmbeddingPurpose::Retrieval.prefix(&embedding_prefix(&self.postgresml_config)),
+        ) {
+            Value::Null => json!({}),
+            parameters => parameters,
         };
         let res = self
             .collection
@@ -548,7 +738,7 @@ impl MemoryBackend for PostgresML {
                             "$or": [
                                 {
                                     "uri": {
-                                        "$ne": position.text_document.uri.to_string()
+                                        "$ne": relative_path(current_uri, root_uri)
                                     }
                                 },
                                 {
@@ -568,26 +758,56 @@ impl MemoryBackend for PostgresML {
                             ]
                         }
                     },
-                    "limit": limit
+                    "limit": search_limit
                 })
                 .into(),
                 &self.pipeline,
             )
             .await?;
-        let context = res
+        let min_score = self.postgresml_config.min_score;
+        let mut context_chunks = res
             .into_iter()
+            .filter(|c| {
+                !min_score.is_some_and(|min_score| {
+                    (c["score"].as_f64().unwrap_or_default() as f32) < min_score
+                })
+            })
             .map(|c| {
-                c["chunk"]
-                    .as_str()
-                    .map(|t| t.to_owned())
-                    .context("PGML - Error getting chunk from vector search")
+                let uri =
+                    document_to_uri(c["document"]["uri"].as_str().unwrap_or_default(), root_uri);
+                (uri, c)
             })
-            .collect::<anyhow::Result<Vec<String>>>()?
-            .join("\n\n");
-        let context = &context[..(total_allowed_characters - chunk_size).min(context.len())];
+            .filter(|(uri, _)| !self.retrieval_exclusions.excludes(current_uri, uri))
+            .take(limit)
+            .map(|(uri, c)| {
+                Ok(ContextChunk {
+                    uri,
+                    text: c["chunk"]
+                        .as_str()
+                        .map(|t| t.to_owned())
+                        .context("PGML - Error getting chunk from vector search")?,
+                    score: c["score"].as_f64().unwrap_or_default() as f32,
+                    start_line: c["document"]["range"]["start_line"]
+                        .as_u64()
+                        .map(|start_line| start_line as usize),
+                })
+            })
+            .collect::<anyhow::Result<Vec<ContextChunk>>>()?;
+        if include_line_numbers {
+            add_context_line_numbers(
+                &mut context_chunks,
+                self.postgresml_config.chunk_header_template.as_deref(),
+            );
+        }
+        let context = join_context_chunks(&context_chunks, self.postgresml_config.context_order);
+        let context = truncate_context(
+            &context,
+            total_allowed_characters.saturating_sub(chunk_size),
+            self.postgresml_config.context_order,
+        );
 
         // Reconstruct the Prompts
-        Ok(match code {
+        let prompt = match code {
             Prompt::ContextAndCode(context_and_code) => {
                 Prompt::ContextAndCode(ContextAndCodePrompt {
                     context: context.to_owned(),
@@ -595,15 +815,20 @@ impl MemoryBackend for PostgresML {
                         position.text_document.uri.as_ref(),
                         &context_and_code.code,
                         self.config.client_params.root_uri.as_deref(),
+                        self.postgresml_config.chunk_header_template.as_deref(),
                     ),
                     selected_text: None,
+                    attached_files: None,
+                    diagnostics: None,
+                    position: context_and_code.position,
                 })
             }
             Prompt::FIM(fim) => Prompt::FIM(FIMPrompt {
                 prompt: format!("{context}\n\n{}", fim.prompt),
                 suffix: fim.suffix,
             }),
-        })
+        };
+        Ok((prompt, context_chunks))
     }
 
     #[instrument(skip(self))]
@@ -611,6 +836,10 @@ impl MemoryBackend for PostgresML {
         &self,
         params: lsp_types::DidOpenTextDocumentParams,
     ) -> anyhow::Result<()> {
+        // The file store does not track binary files
+        if is_binary(params.text_document.text.as_bytes()) {
+            return self.file_store.opened_text_document(params);
+        }
         self.file_store.opened_text_document(params.clone())?;
 
         let saved_uri = params.text_document.uri.to_string();
@@ -619,6 +848,7 @@ impl MemoryBackend for PostgresML {
         let file_store = self.file_store.clone();
         let splitter = self.splitter.clone();
         let root_uri = self.config.client_params.root_uri.clone();
+        let header_template = self.postgresml_config.chunk_header_template.clone();
         TOKIO_RUNTIME.spawn(async move {
             let uri = params.text_document.uri.to_string();
             if let Err(e) = split_and_upsert_file(
@@ -627,6 +857,7 @@ impl MemoryBackend for PostgresML {
                 file_store,
                 splitter,
                 root_uri.as_deref(),
+                header_template.as_deref(),
             )
             .await
             {
@@ -640,6 +871,14 @@ impl MemoryBackend for PostgresML {
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    fn closed_text_document(
+        &self,
+        params: lsp_types::DidCloseTextDocumentParams,
+    ) -> anyhow::Result<()> {
+        self.file_store.closed_text_document(params)
+    }
+
     #[instrument(skip(self))]
     fn changed_text_document(
         &self,
@@ -651,6 +890,17 @@ impl MemoryBackend for PostgresML {
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    fn saved_text_document(
+        &self,
+        params: lsp_types::DidSaveTextDocumentParams,
+    ) -> anyhow::Result<()> {
+        let uri = params.text_document.uri.to_string();
+        self.file_store.saved_text_document(params)?;
+        self.debounce_tx.send(uri)?;
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     fn renamed_files(&self, params: lsp_types::RenameFilesParams) -> anyhow::Result<()> {
         self.file_store.renamed_files(params.clone())?;
@@ -659,13 +909,14 @@ impl MemoryBackend for PostgresML {
         let file_store = self.file_store.clone();
         let splitter = self.splitter.clone();
         let root_uri = self.config.client_params.root_uri.clone();
+        let header_template = self.postgresml_config.chunk_header_template.clone();
         TOKIO_RUNTIME.spawn(async move {
             for file in params.files {
                 if let Err(e) = collection
                     .delete_documents(
                         json!({
                             "uri": {
-                                "$eq": file.old_uri
+                                "$eq": relative_path(&file.old_uri, root_uri.as_deref())
                             }
                         })
                         .into(),
@@ -680,6 +931,7 @@ impl MemoryBackend for PostgresML {
                     file_store.clone(),
                     splitter.clone(),
                     root_uri.as_deref(),
+                    header_template.as_deref(),
                 )
                 .await
                 {
@@ -689,4 +941,224 @@ impl MemoryBackend for PostgresML {
         });
         Ok(())
     }
+
+    #[instrument(skip(self))]
+    fn deleted_files(&self, params: lsp_types::DeleteFilesParams) -> anyhow::Result<()> {
+        self.file_store.deleted_files(params.clone())?;
+
+        let collection = self.collection.clone();
+        let root_uri = self.config.client_params.root_uri.clone();
+        TOKIO_RUNTIME.spawn(async move {
+            for file in params.files {
+                if let Err(e) = collection
+                    .delete_documents(
+                        json!({
+                            "uri": {
+                                "$eq": relative_path(&file.uri, root_uri.as_deref())
+                            }
+                        })
+                        .into(),
+                    )
+                    .await
+                {
+                    error!("PGML - Error deleting file: {e:?}");
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+const DEFAULT_EMBEDDING_MODEL: &str = "intfloat/e5-small-v2";
+
+// How many more chunks are searched for when some may be excluded from the context
+const EXCLUDED_SEARCH_LIMIT_MULTIPLIER: usize = 4;
+
+// Cuts the least relevant end of the context when it has more than `max_chars` characters
+fn truncate_context(context: &str, max_chars: usize, order: config::ContextOrder) -> &str {
+    let chars = context.chars().count();
+    if chars <= max_chars {
+        return context;
+    }
+    match order {
+        config::ContextOrder::RelevanceDesc => {
+            let (end, _) = context.char_indices().nth(max_chars).unwrap();
+            &context[..end]
+        }
+        config::ContextOrder::RelevanceAsc => {
+            let (start, _) = context.char_indices().nth(chars - max_chars).unwrap();
+            &context[start..]
+        }
+    }
+}
+
+// e5 models are trained with these prefixes. Other models only get the configured prefix
+fn embedding_prefix(postgresml_config: &config::PostgresML) -> config::EmbeddingPrefix {
+    match &postgresml_config.embedding_model {
+        Some(embedding_model) => embedding_model.prefix.clone(),
+        None => config::EmbeddingPrefix {
+            storage: "passage: ".to_string(),
+            retrieval: "query: ".to_string(),
+        },
+    }
+}
+
+// The pgml embedding parameters with the prefix as the `prompt` unless they already set one
+fn embedding_parameters(parameters: Option<&Value>, prefix: &str) -> Value {
+    let mut parameters = parameters.cloned().unwrap_or(Value::Null);
+    if !prefix.is_empty() {
+        match &mut parameters {
+            Value::Object(parameters) => {
+                parameters.entry("prompt").or_insert_with(|| json!(prefix));
+            }
+            Value::Null => parameters = json!({ "prompt": prefix }),
+            _ => (),
+        }
+    }
+    parameters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::splitters::ByteRange;
+
+    #[test]
+    fn truncate_context_on_char_boundaries() {
+        let context = "héllo\n\nwörld";
+        assert_eq!(
+            truncate_context(context, 4, config::ContextOrder::RelevanceDesc),
+            "héll"
+        );
+        assert_eq!(
+            truncate_context(context, 4, config::ContextOrder::RelevanceAsc),
+            "örld"
+        );
+        assert_eq!(
+            truncate_context(context, 12, config::ContextOrder::RelevanceAsc),
+            context
+        );
+        assert_eq!(
+            truncate_context(context, 0, config::ContextOrder::RelevanceDesc),
+            ""
+        );
+    }
+
+    #[test]
+    fn embedding_prefix_by_purpose() -> anyhow::Result<()> {
+        let postgresml_config: config::PostgresML = serde_json::from_value(json!({}))?;
+        let prefix = embedding_prefix(&postgresml_config);
+        assert_eq!(
+            embedding_parameters(None, EmbeddingPurpose::Storage.prefix(&prefix)),
+            json!({ "prompt": "passage: " })
+        );
+        assert_eq!(
+            embedding_parameters(None, EmbeddingPurpose::Retrieval.prefix(&prefix)),
+            json!({ "prompt": "query: " })
+        );
+
+        let postgresml_config: config::PostgresML = serde_json::from_value(json!({
+            "embedding_model": {
+                "model": "nomic-ai/nomic-embed-text-v1.5",
+                "embed_parameters": { "trust_remote_code": true },
+                "query_parameters": { "prompt": "search_query: " },
+                "prefix": {
+                    "storage": "search_document: ",
+                    "retrieval": "query: "
+                }
+            }
+        }))?;
+        let prefix = embedding_prefix(&postgresml_config);
+        let embedding_model = postgresml_config.embedding_model.as_ref().unwrap();
+        assert_eq!(
+            embedding_parameters(
+                embedding_model.embed_parameters.as_ref(),
+                EmbeddingPurpose::Storage.prefix(&prefix)
+            ),
+            json!({ "trust_remote_code": true, "prompt": "search_document: " })
+        );
+        // An explicit prompt takes precedence over the prefix
+        assert_eq!(
+            embedding_parameters(
+                embedding_model.query_parameters.as_ref(),
+                EmbeddingPurpose::Retrieval.prefix(&prefix)
+            ),
+            json!({ "prompt": "search_query: " })
+        );
+
+        // Without a prefix the parameters are unchanged
+        assert_eq!(embedding_parameters(None, ""), Value::Null);
+        Ok(())
+    }
+
+    #[test]
+    fn documents_use_workspace_relative_uris() {
+        let chunk = || Chunk {
+            text: "fn a() {}".to_string(),
+            range: ByteRange::new(0, 9),
+        };
+        let document = chunk_to_document(
+            "file:///home/a/project/src/main.rs",
+            chunk(),
+            Some("file:///home/a/project"),
+            None,
+        );
+        assert_eq!(document["uri"], "src/main.rs");
+        assert_eq!(document["id"], "src/main.rs#0-9");
+        assert_eq!(document["text"], "--src/main.rs--\nfn a() {}");
+        // Another checkout reads the same document from its own root
+        assert_eq!(
+            document_to_uri("src/main.rs", Some("file:///home/b/project/")),
+            "file:///home/b/project/src/main.rs"
+        );
+
+        // Files outside of the workspace keep their full uri
+        let document = chunk_to_document(
+            "file:///tmp/scratch.rs",
+            chunk(),
+            Some("file:///home/a/project"),
+            None,
+        );
+        assert_eq!(document["uri"], "file:///tmp/scratch.rs");
+        assert_eq!(
+            document_to_uri("file:///tmp/scratch.rs", Some("file:///home/b/project")),
+            "file:///tmp/scratch.rs"
+        );
+        assert_eq!(document_to_uri("src/main.rs", None), "src/main.rs");
+    }
+
+    #[test]
+    fn parse_origin_url_from_git_config() {
+        let git_config = r#"[core]
+	bare = false
+[remote "upstream"]
+	url = https://example.com/upstream.git
+[remote "origin"]
+	fetch = +refs/heads/*:refs/remotes/origin/*
+	url = git@example.com:team/project.git
+[branch "main"]
+	remote = origin
+"#;
+        assert_eq!(
+            parse_git_remote_url(git_config),
+            Some("git@example.com:team/project.git")
+        );
+        assert_eq!(parse_git_remote_url("[core]\n\tbare = false\n"), None);
+    }
+
+    #[test]
+    fn collection_name_changes_with_document_keys() -> anyhow::Result<()> {
+        // Without a marker or a git remote the identity is the root uri as before
+        let root_uri = "file:///home/a/lsp-ai-missing-project";
+        let pipeline = json!({"text": {"semantic_search": {"model": "intfloat/e5-small-v2"}}});
+        // The name used when documents were keyed by their full uri
+        let absolute_keys_name = format!(
+            "{:x}",
+            md5::compute(format!("{root_uri}_{}", serde_json::to_string(&pipeline)?).as_bytes())
+        );
+        let name = default_collection_name(root_uri, &pipeline)?;
+        assert_ne!(name, absolute_keys_name);
+        assert_eq!(name, default_collection_name(root_uri, &pipeline)?);
+        Ok(())
+    }
 }
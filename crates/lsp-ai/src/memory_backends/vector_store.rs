@@ -1,15 +1,17 @@
 use anyhow::Context;
 use fxhash::FxBuildHasher;
 use lsp_types::{
-    DidChangeTextDocumentParams, DidOpenTextDocumentParams, Range, RenameFilesParams,
+    DeleteFilesParams, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DidSaveTextDocumentParams, Range, RenameFilesParams,
     TextDocumentIdentifier, TextDocumentPositionParams,
 };
 use ordered_float::OrderedFloat;
 use parking_lot::{Mutex, RwLock};
 use serde_json::Value;
 use std::{
-    collections::BTreeMap,
-    io::Read,
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    path::Path,
     sync::{
         mpsc::{self, Sender},
         Arc,
@@ -23,24 +25,44 @@ use tracing::{error, instrument, warn};
 use simsimd::{BinarySimilarity, SpatialSimilarity};
 
 #[cfg(feature = "rayon")]
-use rayon::iter::ParallelIterator;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
 use crate::{
-    config::{self, Config, VectorDataType},
+    config::{self, Config, Similarity, VectorDataType},
     crawl::Crawl,
-    embedding_models::{EmbeddingModel, EmbeddingPurpose},
+    embedding_models::{normalize, EmbeddingModel, EmbeddingPurpose},
     memory_backends::MemoryRunParams,
-    splitters::{ByteRange, Chunk, Splitter},
-    utils::{format_file_chunk, tokens_to_estimated_characters, TOKIO_RUNTIME},
+    progress::ProgressReporter,
+    rerankers::{rerank_chunks, Reranker},
+    splitters::{build_splitter, ByteRange, Chunk, Splitter},
+    utils::{
+        format_file_chunk, is_binary, read_file_with_size_cap, tokens_to_estimated_characters,
+        TOKIO_RUNTIME,
+    },
 };
 
 use super::{
+    add_context_line_numbers,
+    bm25::Bm25Index,
     file_store::{AdditionalFileStoreParams, FileStore},
-    ContextAndCodePrompt, FIMPrompt, MemoryBackend, Prompt, PromptType,
+    get_retrieval_limit, join_context_chunks, ContextAndCodePrompt, ContextChunk, FIMPrompt,
+    MemoryBackend, Prompt, PromptType, RetrievalExclusions,
 };
 
 type IndexMap<K, V> = indexmap::IndexMap<K, V, FxBuildHasher>;
 
+// Chunks retrieved for the reranker per chunk put in the context when `rerank_candidates` is not set
+const RERANK_CANDIDATES_PER_CHUNK: usize = 4;
+
+// Chunks taken from each ranking per chunk put in the context when searching with `hybrid`
+const HYBRID_CANDIDATES_PER_CHUNK: usize = 4;
+
+// Chunks scored for `keyword_boost` per chunk kept from the vector search
+const KEYWORD_CANDIDATES_PER_CHUNK: usize = 4;
+
+// Dampens the weight of the top ranks in reciprocal rank fusion. 60 is the usual choice
+const RRF_K: f32 = 60.;
+
 #[cfg(not(feature = "simsimd"))]
 fn dot_product(a: &[f32], b: &[f32]) -> f32 {
     a.iter().zip(b.iter()).map(|(&x, &y)| x * y).sum()
@@ -77,19 +99,28 @@ impl StoredChunkUpsert {
     }
 }
 
+// 1-bit quantization. Bit j of each byte is set when component j is positive
+// Embeddings whose length is not a multiple of 8 have their last byte padded with zeros
 fn quantize(embedding: &[f32]) -> Vec<u8> {
-    assert!(embedding.len() % 8 == 0);
-    let bytes: Vec<u8> = embedding.iter().map(|x| x.clamp(0., 1.) as u8).collect();
-    let mut quantised = Vec::with_capacity(embedding.len() / 8);
-    for i in (0..bytes.len()).step_by(8) {
-        let mut byte = 0u8;
-        for j in 0..8 {
-            byte |= bytes[i + j] << j;
-        }
-        quantised.push(byte);
-    }
+    embedding
+        .chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0u8, |byte, (j, x)| byte | (((*x > 0.) as u8) << j))
+        })
+        .collect()
+}
 
-    quantised
+// Unpacks a quantized vector into 0s and 1s using the same bit order as `quantize`
+fn dequantize(quantized: &[u8], len: usize) -> Vec<f32> {
+    let mut embedding: Vec<f32> = quantized
+        .iter()
+        .flat_map(|byte| (0..8).map(move |j| ((byte >> j) & 1) as f32))
+        .collect();
+    embedding.truncate(len);
+    embedding
 }
 
 enum StoredChunkVec {
@@ -124,19 +155,170 @@ impl StoredChunk {
     }
 }
 
+// Search results are ordered by score. Ties are broken by uri and then position so equally scored
+// chunks are all kept and results are reproducible
+type SearchKey<'a> = (OrderedFloat<f32>, Reverse<&'a str>, Reverse<usize>);
+
+fn search_key(score: OrderedFloat<f32>, chunk: &StoredChunk) -> SearchKey<'_> {
+    (
+        score,
+        Reverse(chunk.uri.as_str()),
+        Reverse(chunk.range.start_byte),
+    )
+}
+
+// The chunk the cursor is in, or any chunk of the current file with `exclude_current_file`
+fn is_current_chunk(
+    chunk: &StoredChunk,
+    current_uri: &str,
+    current_byte: usize,
+    exclude_current_file: bool,
+) -> bool {
+    chunk.uri == current_uri
+        && (exclude_current_file
+            || (chunk.range.start_byte <= current_byte && chunk.range.end_byte >= current_byte))
+}
+
+// Whether `word` appears in `text` and is not part of a longer identifier
+fn contains_word(text: &str, word: &str) -> bool {
+    let is_identifier_char = |c: char| c.is_alphanumeric() || c == '_';
+    text.match_indices(word).any(|(start, _)| {
+        !text[..start].ends_with(is_identifier_char)
+            && !text[start + word.len()..].starts_with(is_identifier_char)
+    })
+}
+
+// Reciprocal rank fusion. Chunks score `1 / (RRF_K + rank)` in each ranking they appear in
+// Chunks are told apart by their uri and text
+fn fuse_rankings(rankings: Vec<Vec<ContextChunk>>, limit: usize) -> Vec<ContextChunk> {
+    let mut fused: IndexMap<(String, String), (f32, Option<usize>)> = IndexMap::default();
+    for ranking in rankings {
+        for (rank, chunk) in ranking.into_iter().enumerate() {
+            fused
+                .entry((chunk.uri, chunk.text))
+                .or_insert((0., chunk.start_line))
+                .0 += 1. / (RRF_K + rank as f32 + 1.);
+        }
+    }
+    // The sort is stable so ties keep the order of the first ranking
+    fused.sort_by(|_, (a, _), _, (b, _)| b.total_cmp(a));
+    fused
+        .into_iter()
+        .take(limit)
+        .map(|((uri, text), (score, start_line))| ContextChunk {
+            uri,
+            text,
+            score,
+            start_line,
+        })
+        .collect()
+}
+
+// With cosine similarity vectors are normalized so the dot product of two vectors is their cosine similarity
+fn prepare_embedding(similarity: Similarity, embedding: Vec<f32>) -> Vec<f32> {
+    match similarity {
+        Similarity::Dot => embedding,
+        Similarity::Cosine => normalize(embedding),
+    }
+}
+
+// Every embedding in the store must have the same dimensions. The first embedding stored sets them
+fn check_dimensions(
+    dimensions: &mut Option<usize>,
+    embedding: Vec<f32>,
+) -> anyhow::Result<Vec<f32>> {
+    match *dimensions {
+        Some(expected) if expected != embedding.len() => anyhow::bail!(
+            "embedding has {} dimensions but the vector store holds embeddings with {expected} dimensions - restart the language server after changing embedding models",
+            embedding.len()
+        ),
+        Some(_) => (),
+        None => *dimensions = Some(embedding.len()),
+    }
+    Ok(embedding)
+}
+
+// Sign quantization hashes with a hyperplane per dimension so the fraction of differing bits
+// approximates the angle between the vectors over π. This turns a similarity threshold into a
+// minimum binary score where binary scores are `dimensions - hamming distance`
+fn binary_min_score(min_score: f32, dimensions: usize) -> f32 {
+    let max_hamming_distance =
+        dimensions as f32 * min_score.clamp(-1., 1.).acos() / std::f32::consts::PI;
+    dimensions as f32 - max_hamming_distance
+}
+
 struct VectorStoreInner {
     store: IndexMap<String, Vec<StoredChunk>>,
     data_type: VectorDataType,
+    similarity: Similarity,
+    dimensions: Option<usize>,
+    min_score: Option<f32>,
+    exclusions: RetrievalExclusions,
+    // Only kept with `hybrid`
+    lexical_index: Option<Bm25Index>,
+    keyword_boost: Option<f32>,
 }
 
 impl VectorStoreInner {
-    fn new(data_type: VectorDataType) -> Self {
+    fn new(data_type: VectorDataType, similarity: Similarity) -> Self {
         Self {
             data_type,
+            similarity,
+            dimensions: None,
+            min_score: None,
+            exclusions: RetrievalExclusions::default(),
+            lexical_index: None,
+            keyword_boost: None,
             store: IndexMap::default(),
         }
     }
 
+    fn with_min_score(mut self, min_score: Option<f32>) -> Self {
+        self.min_score = min_score;
+        self
+    }
+
+    fn with_exclusions(mut self, exclusions: RetrievalExclusions) -> Self {
+        self.exclusions = exclusions;
+        self
+    }
+
+    fn with_hybrid(mut self, hybrid: bool) -> Self {
+        self.lexical_index = hybrid.then(Bm25Index::default);
+        self
+    }
+
+    fn with_keyword_boost(mut self, keyword_boost: Option<f32>) -> Self {
+        self.keyword_boost = keyword_boost;
+        self
+    }
+
+    // `keyword_boost` times the fraction of the keywords the chunk contains
+    fn keyword_bonus(&self, chunk: &StoredChunk, keywords: &[String]) -> f32 {
+        match self.keyword_boost {
+            Some(keyword_boost) if !keywords.is_empty() => {
+                let found = keywords
+                    .iter()
+                    .filter(|keyword| contains_word(&chunk.text, keyword))
+                    .count();
+                keyword_boost * found as f32 / keywords.len() as f32
+            }
+            _ => 0.,
+        }
+    }
+
+    // Chunks keep their index in the file so the lexical index is rebuilt for the whole file
+    fn index_file_terms(&mut self, uri: &str) {
+        if let Some(lexical_index) = &mut self.lexical_index {
+            match self.store.get(uri) {
+                Some(chunks) => {
+                    lexical_index.index_file(uri, chunks.iter().map(|chunk| chunk.text.as_str()))
+                }
+                None => lexical_index.remove_file(uri),
+            }
+        }
+    }
+
     fn sync_file_chunks(
         &mut self,
         uri: &str,
@@ -152,7 +334,13 @@ impl VectorStoreInner {
                         (Some(index), Some(vec), Some(text)) => {
                             chunks[index] = StoredChunk::new(
                                 uri.to_string(),
-                                StoredChunkVec::new(self.data_type, vec),
+                                StoredChunkVec::new(
+                                    self.data_type,
+                                    prepare_embedding(
+                                        self.similarity,
+                                        check_dimensions(&mut self.dimensions, vec)?,
+                                    ),
+                                ),
                                 text,
                                 chunk.range,
                             )
@@ -160,7 +348,13 @@ impl VectorStoreInner {
                         // If we don't supply the index, push the chunk on the end
                         (None, Some(vec), Some(text)) => chunks.push(StoredChunk::new(
                             uri.to_string(),
-                            StoredChunkVec::new(self.data_type, vec),
+                            StoredChunkVec::new(
+                                self.data_type,
+                                prepare_embedding(
+                                    self.similarity,
+                                    check_dimensions(&mut self.dimensions, vec)?,
+                                ),
+                            ),
                             text,
                             chunk.range,
                         )),
@@ -172,6 +366,7 @@ impl VectorStoreInner {
                 if let Some(size) = limit_chunks {
                     chunks.truncate(size)
                 }
+                self.index_file_terms(uri);
             }
             None => {
                 let chunks: anyhow::Result<Vec<StoredChunk>> = chunks_to_upsert
@@ -181,8 +376,15 @@ impl VectorStoreInner {
                             uri.to_string(),
                             StoredChunkVec::new(
                                 self.data_type,
-                                c.vec
-                                    .context("the vec for new StoredChunks cannot be empty")?,
+                                prepare_embedding(
+                                    self.similarity,
+                                    check_dimensions(
+                                        &mut self.dimensions,
+                                        c.vec.context(
+                                            "the vec for new StoredChunks cannot be empty",
+                                        )?,
+                                    )?,
+                                ),
                             ),
                             c.text
                                 .context("the text for new StoredChunks cannot be empty")?,
@@ -191,38 +393,216 @@ impl VectorStoreInner {
                     })
                     .collect();
                 self.store.insert(uri.to_string(), chunks?);
+                self.index_file_terms(uri);
             }
         }
         Ok(())
     }
 
+    // Replaces all chunks for a file. Upserts with an index reuse the existing chunk and its embedding
+    fn replace_file_chunks(
+        &mut self,
+        uri: &str,
+        chunks_to_upsert: Vec<StoredChunkUpsert>,
+    ) -> anyhow::Result<()> {
+        let mut existing_chunks: Vec<Option<StoredChunk>> = self
+            .store
+            .get_mut(uri)
+            .map(|chunks| std::mem::take(chunks).into_iter().map(Some).collect())
+            .unwrap_or_default();
+        let chunks: anyhow::Result<Vec<StoredChunk>> = chunks_to_upsert
+            .into_iter()
+            .map(|chunk| match (chunk.index, chunk.vec, chunk.text) {
+                (Some(index), None, None) => {
+                    let mut existing_chunk = existing_chunks
+                        .get_mut(index)
+                        .and_then(Option::take)
+                        .context(
+                            "StoredChunkUpsert index must reference an unused existing chunk",
+                        )?;
+                    existing_chunk.range = chunk.range;
+                    Ok(existing_chunk)
+                }
+                (None, Some(vec), Some(text)) => Ok(StoredChunk::new(
+                    uri.to_string(),
+                    StoredChunkVec::new(
+                        self.data_type,
+                        prepare_embedding(
+                            self.similarity,
+                            check_dimensions(&mut self.dimensions, vec)?,
+                        ),
+                    ),
+                    text,
+                    chunk.range,
+                )),
+                _ => anyhow::bail!(
+                    "malformed StoredChunkUpsert - upsert must have index or vec and text"
+                ),
+            })
+            .collect();
+        self.store.insert(uri.to_string(), chunks?);
+        self.index_file_terms(uri);
+        Ok(())
+    }
+
     fn rename_file(&mut self, old_uri: &str, new_uri: &str) -> anyhow::Result<()> {
         let old_chunks = self
             .store
             .swap_remove(old_uri)
             .with_context(|| format!("cannot rename non-existing file: {old_uri}"))?;
         self.store.insert(new_uri.to_string(), old_chunks);
+        if let Some(lexical_index) = &mut self.lexical_index {
+            lexical_index.rename_file(old_uri, new_uri);
+        }
         Ok(())
     }
 
+    fn delete_file(&mut self, uri: &str) {
+        self.store.swap_remove(uri);
+        if let Some(lexical_index) = &mut self.lexical_index {
+            lexical_index.remove_file(uri);
+        }
+    }
+
+    // Fuses the vector search with a BM25 search for `query`. Only the vector search is used
+    // when the store is not `hybrid`
+    #[allow(clippy::too_many_arguments)]
+    fn hybrid_search(
+        &self,
+        limit: usize,
+        rerank_top_k: Option<usize>,
+        embedding: Vec<f32>,
+        query: &str,
+        keywords: &[String],
+        current_uri: &str,
+        current_byte: usize,
+        exclude_current_file: bool,
+    ) -> anyhow::Result<Vec<ContextChunk>> {
+        if self.lexical_index.is_none() {
+            return self.search(
+                limit,
+                rerank_top_k,
+                embedding,
+                keywords,
+                current_uri,
+                current_byte,
+                exclude_current_file,
+            );
+        }
+        let candidates = limit * HYBRID_CANDIDATES_PER_CHUNK;
+        let dense = self.search(
+            candidates,
+            rerank_top_k,
+            embedding,
+            keywords,
+            current_uri,
+            current_byte,
+            exclude_current_file,
+        )?;
+        let lexical = self.lexical_search(
+            candidates,
+            query,
+            current_uri,
+            current_byte,
+            exclude_current_file,
+        );
+        Ok(fuse_rankings(vec![dense, lexical], limit))
+    }
+
+    // `min_score` only applies to vector similarity and is ignored here
+    fn lexical_search(
+        &self,
+        limit: usize,
+        query: &str,
+        current_uri: &str,
+        current_byte: usize,
+        exclude_current_file: bool,
+    ) -> Vec<ContextChunk> {
+        let Some(lexical_index) = &self.lexical_index else {
+            return vec![];
+        };
+        let mut results: Vec<(SearchKey, &StoredChunk)> = lexical_index
+            .search(query)
+            .into_iter()
+            .filter(|(uri, _, _)| !self.exclusions.excludes(current_uri, uri))
+            .filter_map(|(uri, index, score)| {
+                let chunk = self.store.get(uri)?.get(index)?;
+                Some((search_key(OrderedFloat(score), chunk), chunk))
+            })
+            .filter(|(_, chunk)| {
+                !is_current_chunk(chunk, current_uri, current_byte, exclude_current_file)
+            })
+            .collect();
+        results.sort_by(|(a, _), (b, _)| b.cmp(a));
+        results
+            .into_iter()
+            .take(limit)
+            .map(|((score, _, _), chunk)| ContextChunk {
+                uri: chunk.uri.clone(),
+                text: chunk.text.clone(),
+                score: score.into_inner(),
+                start_line: Some(chunk.range.start_line),
+            })
+            .collect()
+    }
+
+    // Chunks containing `keywords` get a bonus with `keyword_boost`
+    #[allow(clippy::too_many_arguments)]
     fn search(
         &self,
         limit: usize,
         rerank_top_k: Option<usize>,
         embedding: Vec<f32>,
+        keywords: &[String],
         current_uri: &str,
         current_byte: usize,
-    ) -> anyhow::Result<Vec<String>> {
+        exclude_current_file: bool,
+    ) -> anyhow::Result<Vec<ContextChunk>> {
+        if let Some(dimensions) = self.dimensions {
+            if dimensions != embedding.len() {
+                anyhow::bail!(
+                    "query embedding has {} dimensions but the vector store holds embeddings with {dimensions} dimensions",
+                    embedding.len()
+                );
+            }
+        }
+        let embedding = prepare_embedding(self.similarity, embedding);
         let scv_embedding = StoredChunkVec::new(self.data_type, embedding.clone());
         let find_limit = match rerank_top_k {
-            Some(rerank) => rerank,
+            Some(rerank) => rerank.max(limit),
             None => limit,
         };
+        // Compared against the scores before reranking
+        let min_score = self.min_score.map(|min_score| match self.data_type {
+            VectorDataType::F32 => min_score,
+            VectorDataType::Binary => binary_min_score(min_score, embedding.len()),
+        });
+        // The keyword bonus is scaled to the highest score possible so it weighs the same against
+        // every kind of score. Binary scores count matching bits and reranked scores are the dot
+        // product of the dequantized bits with the query
+        let keyword_bonus_scale = match self.data_type {
+            VectorDataType::F32 => 1.,
+            VectorDataType::Binary => embedding.len() as f32,
+        };
+        let rerank_keyword_bonus_scale: f32 = embedding.iter().map(|x| x.max(0.)).sum();
+        // Only the best vector matches are checked for keywords
+        let candidate_limit = match self.keyword_boost {
+            Some(_) if !keywords.is_empty() => find_limit * KEYWORD_CANDIDATES_PER_CHUNK,
+            _ => find_limit,
+        };
         let results: anyhow::Result<Vec<BTreeMap<_, _>>> =
             self.store
-                .par_values()
-                .try_fold_with(BTreeMap::new(), |mut acc, chunks| {
+                .par_iter()
+                .try_fold_with(BTreeMap::new(), |mut acc, (uri, chunks)| {
+                    if self.exclusions.excludes(current_uri, uri) {
+                        return Ok(acc);
+                    }
                     for chunk in chunks {
+                        // Filtered before the cap so excluded chunks do not take up places
+                        if is_current_chunk(chunk, current_uri, current_byte, exclude_current_file)
+                        {
+                            continue;
+                        }
                         let score = match (&chunk.vec, &scv_embedding) {
                             (StoredChunkVec::F32(vec1), StoredChunkVec::F32(vec2)) => {
                                 #[cfg(feature = "simsimd")]
@@ -254,81 +634,147 @@ impl VectorStoreInner {
                             }
                             _ => anyhow::bail!("mismatch between vector data types in search"),
                         };
-                        if acc.is_empty() {
-                            acc.insert(score, chunk);
-                        } else if acc.first_key_value().unwrap().0 < &score {
-                            // We want to get limit + 1 here in case the limit is 1 and then we filter the chunk out later
-                            if acc.len() == find_limit + 1 {
-                                acc.pop_first();
-                            }
-                            acc.insert(score, chunk);
+                        if min_score.is_some_and(|min_score| score.into_inner() < min_score) {
+                            continue;
+                        }
+                        let key = search_key(score, chunk);
+                        if acc.len() < candidate_limit {
+                            acc.insert(key, chunk);
+                        } else if acc.first_key_value().unwrap().0 < &key {
+                            acc.pop_first();
+                            acc.insert(key, chunk);
                         }
                     }
                     Ok(acc)
                 })
                 .collect();
+        let mut candidates: Vec<(SearchKey, &StoredChunk, f32)> = results?
+            .into_iter()
+            .flatten()
+            .map(|((score, _, _), chunk)| {
+                let keyword_bonus = self.keyword_bonus(chunk, keywords);
+                let score = OrderedFloat(score.into_inner() + keyword_bonus_scale * keyword_bonus);
+                (search_key(score, chunk), chunk, keyword_bonus)
+            })
+            .collect();
+        candidates.sort_by(|(a, _, _), (b, _, _)| b.cmp(a));
+        candidates.truncate(find_limit);
+
         let mut top_results = BTreeMap::new();
-        for result in results? {
-            for (sub_result_score, sub_result_chunk) in result {
-                let sub_result_score = if rerank_top_k.is_some() {
-                    match &sub_result_chunk.vec {
-                        StoredChunkVec::Binary(b) => {
-                            // Convert binary vector to f32 vec
-                            let mut b_f32 = vec![];
-                            for byte in b {
-                                for i in 0..8 {
-                                    let x = byte >> (8 - i) & 1;
-                                    b_f32.push(x as f32);
-                                }
-                            }
-                            b_f32.truncate(embedding.len());
-                            #[cfg(feature = "simsimd")]
-                            {
-                                OrderedFloat(
-                                    SpatialSimilarity::dot(&b_f32, &embedding)
-                                        .context("mismatch in vector length when taking the dot product when re-ranking")?
-                                        as f32,
-                                )
-                            }
-                            #[cfg(not(feature = "simsimd"))]
-                            {
-                                OrderedFloat(dot_product(&b_f32, &embedding) as f32)
-                            }
-                        }
-                        StoredChunkVec::F32(_) => {
-                            warn!("Not reranking in vector_store because vectors are not binary");
-                            sub_result_score
-                        }
+        for ((score, _, _), chunk, keyword_bonus) in candidates {
+            let score = if rerank_top_k.is_some() {
+                match &chunk.vec {
+                    StoredChunkVec::Binary(b) => {
+                        // Convert binary vector to f32 vec
+                        let b_f32 = dequantize(b, embedding.len());
+                        #[cfg(feature = "simsimd")]
+                        let score = SpatialSimilarity::dot(&b_f32, &embedding).context(
+                            "mismatch in vector length when taking the dot product when re-ranking",
+                        )? as f32;
+                        #[cfg(not(feature = "simsimd"))]
+                        let score = dot_product(&b_f32, &embedding);
+                        OrderedFloat(score + rerank_keyword_bonus_scale * keyword_bonus)
                     }
-                } else {
-                    sub_result_score
-                };
-
-                // Filter out chunks that are in the current chunk
-                if sub_result_chunk.uri == current_uri
-                    && sub_result_chunk.range.start_byte <= current_byte
-                    && sub_result_chunk.range.end_byte >= current_byte
-                {
-                    continue;
-                }
-                if top_results.is_empty() {
-                    top_results.insert(sub_result_score, sub_result_chunk);
-                } else if top_results.first_key_value().unwrap().0 < &sub_result_score {
-                    if top_results.len() == limit {
-                        top_results.pop_first();
+                    StoredChunkVec::F32(_) => {
+                        warn!("Not reranking in vector_store because vectors are not binary");
+                        score
                     }
-                    top_results.insert(sub_result_score, sub_result_chunk);
                 }
+            } else {
+                score
+            };
+
+            let key = search_key(score, chunk);
+            if top_results.len() < limit {
+                top_results.insert(key, chunk);
+            } else if top_results
+                .first_key_value()
+                .is_some_and(|(min_key, _)| min_key < &key)
+            {
+                top_results.pop_first();
+                top_results.insert(key, chunk);
             }
         }
         Ok(top_results
             .into_iter()
             .rev()
-            .map(|(_, chunk)| chunk.text.to_string())
+            .map(|((score, _, _), chunk)| ContextChunk {
+                uri: chunk.uri.clone(),
+                text: chunk.text.clone(),
+                score: score.into_inner(),
+                start_line: Some(chunk.range.start_line),
+            })
             .collect())
     }
 }
 
+// Match chunks on their content rather than their position so an edit near the top of a file
+// only re-embeds the chunks that actually changed
+fn plan_chunk_upserts(
+    uri: &str,
+    chunks: Vec<Chunk>,
+    existing_chunks: Option<&[StoredChunk]>,
+    root_uri: Option<&str>,
+    header_template: Option<&str>,
+) -> Vec<StoredChunkUpsert> {
+    let mut existing_by_text: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, existing_chunk) in existing_chunks.unwrap_or_default().iter().enumerate().rev() {
+        existing_by_text
+            .entry(existing_chunk.text.as_str())
+            .or_default()
+            .push(i);
+    }
+    chunks
+        .into_iter()
+        .map(|chunk| {
+            let text = format_file_chunk(uri, &chunk.text, root_uri, header_template);
+            match existing_by_text
+                .get_mut(text.as_str())
+                .and_then(|indices| indices.pop())
+            {
+                Some(index) => StoredChunkUpsert::new(chunk.range, Some(index), None, None),
+                None => StoredChunkUpsert::new(chunk.range, None, None, Some(text)),
+            }
+        })
+        .collect()
+}
+
+async fn embed_and_store_chunks(
+    uri: &str,
+    chunks: Vec<Chunk>,
+    embedding_model: Arc<Box<dyn EmbeddingModel + Send + Sync>>,
+    vector_store: Arc<RwLock<VectorStoreInner>>,
+    root_uri: Option<&str>,
+    header_template: Option<&str>,
+) -> anyhow::Result<()> {
+    let embeddings = embedding_model
+        .embed(
+            chunks.iter().map(|c| c.text.as_str()).collect(),
+            EmbeddingPurpose::Storage,
+        )
+        .await?;
+    let embedded_chunks: Vec<StoredChunkUpsert> = chunks
+        .into_iter()
+        .zip(embeddings)
+        .map(|(chunk, embedding)| {
+            StoredChunkUpsert::new(
+                chunk.range,
+                None,
+                Some(embedding),
+                Some(format_file_chunk(
+                    uri,
+                    &chunk.text,
+                    root_uri,
+                    header_template,
+                )),
+            )
+        })
+        .collect();
+    vector_store
+        .write()
+        .sync_file_chunks(uri, embedded_chunks, None)
+}
+
 pub(crate) struct VectorStore {
     file_store: Arc<FileStore>,
     crawl: Option<Arc<Mutex<Crawl>>>,
@@ -337,29 +783,68 @@ pub(crate) struct VectorStore {
     vector_store: Arc<RwLock<VectorStoreInner>>,
     config: Config,
     debounce_tx: Sender<String>,
+    progress: Option<ProgressReporter>,
+    exclude_current_file: bool,
+    rerank_top_k: Option<usize>,
+    retrieval_limit: Option<usize>,
+    reranker: Option<Box<dyn Reranker + Send + Sync>>,
+    rerank_candidates: Option<usize>,
+    keyword_boost: Option<f32>,
+    context_order: config::ContextOrder,
+    chunk_header_template: Option<String>,
 }
 
 impl VectorStore {
     pub(crate) fn new(
         mut vector_store_config: config::VectorStore,
         config: Config,
+        progress: Option<ProgressReporter>,
     ) -> anyhow::Result<Self> {
         let crawl = vector_store_config
             .crawl
             .take()
             .map(|x| Arc::new(Mutex::new(Crawl::new(x, config.clone()))));
-        let splitter: Arc<Box<dyn Splitter + Send + Sync>> =
-            Arc::new(vector_store_config.splitter.clone().try_into()?);
+        let splitter: Arc<Box<dyn Splitter + Send + Sync>> = Arc::new(build_splitter(
+            vector_store_config.splitter.clone(),
+            config.config.disable_tree_sitter,
+        )?);
         let embedding_model: Arc<Box<dyn EmbeddingModel + Send + Sync>> =
             Arc::new(vector_store_config.embedding_model.try_into()?);
         let file_store = Arc::new(FileStore::new_with_params(
-            config::FileStore::new_without_crawl(),
+            config::FileStore {
+                pinned_context_files: vector_store_config.pinned_context_files.take(),
+                ..config::FileStore::new_without_crawl()
+            },
             config.clone(),
             AdditionalFileStoreParams::new(splitter.does_use_tree_sitter()),
         )?);
-        let vector_store = Arc::new(RwLock::new(VectorStoreInner::new(
-            vector_store_config.data_type,
-        )));
+        let rerank_top_k = match vector_store_config.data_type {
+            VectorDataType::Binary => vector_store_config.rerank_top_k,
+            VectorDataType::F32 => {
+                if vector_store_config.rerank_top_k.is_some() {
+                    warn!("rerank_top_k is only used when the vector store data_type is binary");
+                }
+                None
+            }
+        };
+        let similarity =
+            vector_store_config
+                .similarity
+                .unwrap_or(if embedding_model.returns_normalized() {
+                    Similarity::Dot
+                } else {
+                    Similarity::Cosine
+                });
+        let vector_store = Arc::new(RwLock::new(
+            VectorStoreInner::new(vector_store_config.data_type, similarity)
+                .with_min_score(vector_store_config.min_score)
+                .with_hybrid(vector_store_config.hybrid)
+                .with_keyword_boost(vector_store_config.keyword_boost)
+                .with_exclusions(RetrievalExclusions::new(
+                    config.client_params.root_uri.as_deref(),
+                    &vector_store_config.retrieval_exclude,
+                )?),
+        ));
 
         // Debounce document changes to reduce the number of embeddings we perform
         let (debounce_tx, debounce_rx) = mpsc::channel::<String>();
@@ -368,6 +853,7 @@ impl VectorStore {
         let task_file_store = file_store.clone();
         let task_splitter = splitter.clone();
         let task_root_uri = config.client_params.root_uri.clone();
+        let task_header_template = vector_store_config.chunk_header_template.clone();
         TOKIO_RUNTIME.spawn(async move {
             let duration = Duration::from_millis(500);
             let mut file_uris = Vec::new();
@@ -400,73 +886,17 @@ impl VectorStore {
                             };
                             task_splitter.split(file)
                         };
-                        let chunks_size = chunks.len();
-
-                        // This is not as efficient as it could be, but it is ok for now
-                        // We may want a better system than string comparing constantly
-                        let chunks_to_upsert = match task_vector_store.read().store.get(&uri) {
-                            Some(existing_chunks) => {
-                                let mut chunks_to_upsert = vec![];
-                                for (i, chunk) in chunks.into_iter().enumerate() {
-                                    if let Some(existing_chunk) = existing_chunks.get(i) {
-                                        // Edit chunk start and end byte
-                                        let has_chunk_changed = chunk.text != existing_chunk.text;
-                                        if !has_chunk_changed {
-                                            if chunk.range.start_byte
-                                                != existing_chunk.range.start_byte
-                                                || chunk.range.end_byte
-                                                    != existing_chunk.range.end_byte
-                                            {
-                                                chunks_to_upsert.push(StoredChunkUpsert::new(
-                                                    chunk.range,
-                                                    Some(i),
-                                                    None,
-                                                    None,
-                                                ));
-                                            }
-                                        } else {
-                                            chunks_to_upsert.push(StoredChunkUpsert::new(
-                                                chunk.range,
-                                                Some(i),
-                                                None,
-                                                Some(format_file_chunk(
-                                                    &uri,
-                                                    &chunk.text,
-                                                    task_root_uri.as_deref(),
-                                                )),
-                                            ));
-                                        }
-                                    } else {
-                                        chunks_to_upsert.push(StoredChunkUpsert::new(
-                                            chunk.range,
-                                            None,
-                                            None,
-                                            Some(format_file_chunk(
-                                                &uri,
-                                                &chunk.text,
-                                                task_root_uri.as_deref(),
-                                            )),
-                                        ));
-                                    }
-                                }
-                                chunks_to_upsert
-                            }
-                            None => chunks
-                                .into_iter()
-                                .map(|chunk| {
-                                    StoredChunkUpsert::new(
-                                        chunk.range,
-                                        None,
-                                        None,
-                                        Some(format_file_chunk(
-                                            &uri,
-                                            &chunk.text,
-                                            task_root_uri.as_deref(),
-                                        )),
-                                    )
-                                })
-                                .collect(),
-                        };
+                        let chunks_to_upsert = plan_chunk_upserts(
+                            &uri,
+                            chunks,
+                            task_vector_store
+                                .read()
+                                .store
+                                .get(&uri)
+                                .map(|c| c.as_slice()),
+                            task_root_uri.as_deref(),
+                            task_header_template.as_deref(),
+                        );
                         // Embed all chunks with text
                         match task_embedding_model
                             .embed(
@@ -489,11 +919,10 @@ impl VectorStore {
                                         c
                                     })
                                     .collect();
-                                if let Err(e) = task_vector_store.write().sync_file_chunks(
-                                    &uri,
-                                    chunks_to_upsert,
-                                    Some(chunks_size),
-                                ) {
+                                if let Err(e) = task_vector_store
+                                    .write()
+                                    .replace_file_chunks(&uri, chunks_to_upsert)
+                                {
                                     error!("{e:?}");
                                 }
                             }
@@ -516,6 +945,18 @@ impl VectorStore {
             vector_store,
             config,
             debounce_tx,
+            progress,
+            exclude_current_file: vector_store_config.exclude_current_file,
+            rerank_top_k,
+            retrieval_limit: vector_store_config.retrieval_limit,
+            reranker: vector_store_config
+                .reranker
+                .map(|reranker| reranker.try_into())
+                .transpose()?,
+            rerank_candidates: vector_store_config.rerank_candidates,
+            keyword_boost: vector_store_config.keyword_boost,
+            context_order: vector_store_config.context_order,
+            chunk_header_template: vector_store_config.chunk_header_template,
         };
         if let Err(e) = s.maybe_do_crawl(None) {
             error!("{e:?}")
@@ -528,42 +969,19 @@ impl VectorStore {
         let task_embedding_model = self.embedding_model.clone();
         let task_vector_store = self.vector_store.clone();
         let root_uri = self.config.client_params.root_uri.clone();
+        let header_template = self.chunk_header_template.clone();
         TOKIO_RUNTIME.spawn(async move {
-            match task_embedding_model
-                .embed(
-                    chunks.iter().map(|c| c.text.as_str()).collect(),
-                    EmbeddingPurpose::Storage,
-                )
-                .await
+            if let Err(e) = embed_and_store_chunks(
+                &task_uri,
+                chunks,
+                task_embedding_model,
+                task_vector_store,
+                root_uri.as_deref(),
+                header_template.as_deref(),
+            )
+            .await
             {
-                Ok(embeddings) => {
-                    let embedded_chunks: Vec<StoredChunkUpsert> = chunks
-                        .into_iter()
-                        .zip(embeddings)
-                        .map(|(chunk, embedding)| {
-                            StoredChunkUpsert::new(
-                                chunk.range,
-                                None,
-                                Some(embedding),
-                                Some(format_file_chunk(
-                                    &task_uri,
-                                    &chunk.text,
-                                    root_uri.as_deref(),
-                                )),
-                            )
-                        })
-                        .collect();
-                    if let Err(e) =
-                        task_vector_store
-                            .write()
-                            .sync_file_chunks(&task_uri, embedded_chunks, None)
-                    {
-                        error!("{e:?}");
-                    }
-                }
-                Err(e) => {
-                    error!("{e:?}");
-                }
+                error!("{e:?}");
             }
         });
     }
@@ -571,6 +989,7 @@ impl VectorStore {
     fn maybe_do_crawl(&self, triggered_file: Option<String>) -> anyhow::Result<()> {
         if let Some(crawl) = &self.crawl {
             let mut total_bytes = 0;
+            let mut crawled_files = vec![];
             crawl
                 .lock()
                 .maybe_do_crawl(triggered_file, |config, path| {
@@ -586,25 +1005,68 @@ impl VectorStore {
                         return Ok(true);
                     }
 
-                    // Open the file and see if it is small enough to read
-                    let mut f = std::fs::File::open(path)?;
-                    let metadata = f.metadata()?;
-                    if metadata.len() > config.max_file_size {
-                        warn!("Skipping file: {path} because it is too large");
+                    // Read the file if it is small enough
+                    let Some(contents) =
+                        read_file_with_size_cap(Path::new(path), config.max_file_size)?
+                    else {
+                        warn!("Skipping file: {path} because it is too large or binary");
                         return Ok(true);
-                    }
-
-                    // Read the file contents
-                    let mut contents = vec![];
-                    f.read_to_end(&mut contents)?;
-                    let contents = String::from_utf8(contents)?;
+                    };
                     total_bytes += contents.len();
 
-                    // Store the file
-                    let chunks = self.splitter.split_file_contents(&uri, &contents);
-                    self.upsert_chunks(&uri, chunks);
+                    // Split the file and store it for embedding once the crawl is done. Files the
+                    // editor opened were skipped above, so the grammar comes from the extension
+                    let chunks = self.splitter.split_file_contents(&uri, &contents, None);
+                    crawled_files.push((uri, chunks));
                     Ok(true)
                 })?;
+
+            if crawled_files.is_empty() {
+                return Ok(());
+            }
+
+            // Embed the crawled files one at a time so we can report our progress to the client
+            let progress = self.progress.clone();
+            let task_embedding_model = self.embedding_model.clone();
+            let task_vector_store = self.vector_store.clone();
+            let root_uri = self.config.client_params.root_uri.clone();
+            let header_template = self.chunk_header_template.clone();
+            TOKIO_RUNTIME.spawn(async move {
+                let progress = match progress {
+                    Some(progress) => match progress.begin("Indexing", crawled_files.len()).await {
+                        Ok(handle) => Some(handle),
+                        Err(e) => {
+                            error!("failed to begin indexing progress: {e:?}");
+                            None
+                        }
+                    },
+                    None => None,
+                };
+                for (i, (uri, chunks)) in crawled_files.into_iter().enumerate() {
+                    if let Err(e) = embed_and_store_chunks(
+                        &uri,
+                        chunks,
+                        task_embedding_model.clone(),
+                        task_vector_store.clone(),
+                        root_uri.as_deref(),
+                        header_template.as_deref(),
+                    )
+                    .await
+                    {
+                        error!("{e:?}");
+                    }
+                    if let Some(progress) = &progress {
+                        if let Err(e) = progress.report(i + 1) {
+                            error!("failed to report indexing progress: {e:?}");
+                        }
+                    }
+                }
+                if let Some(progress) = progress {
+                    if let Err(e) = progress.end() {
+                        error!("failed to end indexing progress: {e:?}");
+                    }
+                }
+            });
         }
         Ok(())
     }
@@ -634,6 +1096,10 @@ impl MemoryBackend for VectorStore {
     #[instrument(skip(self))]
     fn opened_text_document(&self, params: DidOpenTextDocumentParams) -> anyhow::Result<()> {
         let uri = params.text_document.uri.to_string();
+        // The file store does not track binary files
+        if is_binary(params.text_document.text.as_bytes()) {
+            return self.file_store.opened_text_document(params);
+        }
         self.file_store.opened_text_document(params)?;
 
         let file_map = self.file_store.file_map().read();
@@ -647,6 +1113,11 @@ impl MemoryBackend for VectorStore {
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    fn closed_text_document(&self, params: DidCloseTextDocumentParams) -> anyhow::Result<()> {
+        self.file_store.closed_text_document(params)
+    }
+
     #[instrument(skip(self))]
     fn changed_text_document(&self, params: DidChangeTextDocumentParams) -> anyhow::Result<()> {
         let uri = params.text_document.uri.to_string();
@@ -655,6 +1126,14 @@ impl MemoryBackend for VectorStore {
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    fn saved_text_document(&self, params: DidSaveTextDocumentParams) -> anyhow::Result<()> {
+        let uri = params.text_document.uri.to_string();
+        self.file_store.saved_text_document(params)?;
+        self.debounce_tx.send(uri)?;
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     fn renamed_files(&self, params: RenameFilesParams) -> anyhow::Result<()> {
         // TODO: Finish this
@@ -669,11 +1148,26 @@ impl MemoryBackend for VectorStore {
         Ok(())
     }
 
+    #[instrument(skip(self))]
+    fn deleted_files(&self, params: DeleteFilesParams) -> anyhow::Result<()> {
+        self.file_store.deleted_files(params.clone())?;
+        let mut vector_store = self.vector_store.write();
+        for file in params.files {
+            vector_store.delete_file(&file.uri);
+        }
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     fn get_filter_text(&self, position: &TextDocumentPositionParams) -> anyhow::Result<String> {
         self.file_store.get_filter_text(position)
     }
 
+    #[instrument(skip(self))]
+    fn get_replace_range(&self, position: &TextDocumentPositionParams) -> anyhow::Result<Range> {
+        self.file_store.get_replace_range(position)
+    }
+
     #[instrument(skip(self))]
     async fn build_prompt(
         &self,
@@ -681,14 +1175,36 @@ impl MemoryBackend for VectorStore {
         prompt_type: PromptType,
         params: &Value,
     ) -> anyhow::Result<Prompt> {
-        let params: MemoryRunParams = params.try_into()?;
+        Ok(self
+            .build_prompt_with_context(position, prompt_type, params)
+            .await?
+            .0)
+    }
+
+    #[instrument(skip(self))]
+    async fn build_prompt_with_context(
+        &self,
+        position: &TextDocumentPositionParams,
+        prompt_type: PromptType,
+        params: &Value,
+    ) -> anyhow::Result<(Prompt, Vec<ContextChunk>)> {
+        let params = MemoryRunParams::new(params, &prompt_type);
         let chunk_size = self.splitter.chunk_size();
         let total_allowed_characters = tokens_to_estimated_characters(params.max_context);
+        // Line numbers break FIM prompts
+        let include_line_numbers =
+            params.include_line_numbers && matches!(prompt_type, PromptType::ContextAndCode);
 
         // Build the query
         let query = self
             .file_store
             .get_characters_around_position(position, chunk_size)?;
+        let keywords = match self.keyword_boost {
+            Some(_) => self
+                .file_store
+                .get_identifiers_around_position(position, chunk_size)?,
+            None => vec![],
+        };
 
         // Build the prompt
         let mut file_store_params = params.clone();
@@ -710,21 +1226,55 @@ impl MemoryBackend for VectorStore {
             .context("no embeddings returned")?;
 
         // Get the context
-        let limit = (total_allowed_characters / chunk_size).saturating_sub(1);
-        let context = self
-            .vector_store
-            .read()
-            .search(
-                limit,
-                None,
-                embedding,
-                position.text_document.uri.as_ref(),
-                cursor_byte,
-            )?
+        let limit = get_retrieval_limit(total_allowed_characters, chunk_size, self.retrieval_limit);
+        let candidates = match &self.reranker {
+            Some(_) => self
+                .rerank_candidates
+                .unwrap_or(limit * RERANK_CANDIDATES_PER_CHUNK)
+                .max(limit),
+            None => limit,
+        };
+        let context_chunks = self.vector_store.read().hybrid_search(
+            candidates,
+            self.rerank_top_k,
+            embedding,
+            &query,
+            &keywords,
+            position.text_document.uri.as_ref(),
+            cursor_byte,
+            self.exclude_current_file,
+        )?;
+        let context_chunks = match &self.reranker {
+            Some(reranker) => {
+                match rerank_chunks(reranker.as_ref(), &query, context_chunks.clone(), limit).await
+                {
+                    Ok(context_chunks) => context_chunks,
+                    // The vector search order is still a reasonable context
+                    Err(e) => {
+                        warn!("reranking the retrieved chunks: {e:?}");
+                        context_chunks.into_iter().take(limit).collect()
+                    }
+                }
+            }
+            None => context_chunks,
+        };
+        let mut context_chunks = context_chunks;
+        if include_line_numbers {
+            add_context_line_numbers(&mut context_chunks, self.chunk_header_template.as_deref());
+        }
+        // Pinned files go before the retrieved chunks
+        let pinned_context = self
+            .file_store
+            .get_pinned_context(position.text_document.uri.as_str());
+        let retrieved_context = join_context_chunks(&context_chunks, self.context_order);
+        let context = [pinned_context.as_str(), retrieved_context.as_str()]
+            .into_iter()
+            .filter(|context| !context.is_empty())
+            .collect::<Vec<_>>()
             .join("\n\n");
 
         // Reconstruct the prompts
-        Ok(match code {
+        let prompt = match code {
             Prompt::ContextAndCode(context_and_code) => {
                 Prompt::ContextAndCode(ContextAndCodePrompt {
                     context: context.to_owned(),
@@ -732,15 +1282,20 @@ impl MemoryBackend for VectorStore {
                         position.text_document.uri.as_ref(),
                         &context_and_code.code,
                         self.config.client_params.root_uri.as_deref(),
+                        self.chunk_header_template.as_deref(),
                     ),
                     selected_text: None,
+                    attached_files: None,
+                    diagnostics: None,
+                    position: context_and_code.position,
                 })
             }
             Prompt::FIM(fim) => Prompt::FIM(FIMPrompt {
                 prompt: format!("{context}\n\n{}", fim.prompt),
                 suffix: fim.suffix,
             }),
-        })
+        };
+        Ok((prompt, context_chunks))
     }
 }
 
@@ -758,7 +1313,43 @@ mod tests {
     fn can_quantize() {
         let v = vec![0.0, 0.5, 1.0, 0.3, 0.8, 0.2, 0.9, 0.1];
         let quantized = quantize(&v);
-        assert_eq!(quantized, vec![4]);
+        assert_eq!(quantized, vec![0b11111110]);
+
+        let v = vec![-0.1, 0.2, -0.3, 0.4, -0.5, 0.6, -0.7, 0.8];
+        let quantized = quantize(&v);
+        assert_eq!(quantized, vec![0b10101010]);
+
+        let v = vec![1.0, -1.0, 1.0, -1.0, 1.0, -1.0, 1.0, -1.0, 1.0, 1.0];
+        let quantized = quantize(&v);
+        assert_eq!(quantized, vec![0b01010101, 0b00000011]);
+        assert_eq!(dequantize(&quantized, v.len()).len(), 10);
+    }
+
+    #[test]
+    fn can_dequantize() {
+        let v: Vec<f32> = (0..24)
+            .map(|x| if x % 3 == 0 { 0.5 } else { -0.5 })
+            .collect();
+        let dequantized = dequantize(&quantize(&v), v.len());
+        let expected: Vec<f32> = v.iter().map(|x| if *x > 0. { 1. } else { 0. }).collect();
+        assert_eq!(dequantized, expected);
+    }
+
+    #[test]
+    fn similar_vectors_quantize_to_similar_bits() {
+        let distance = |a: &[u8], b: &[u8]| -> u32 {
+            a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum()
+        };
+        let v1: Vec<f32> = (0..64).map(|x| ((x as f32) * 0.7).sin() * 0.1).collect();
+        let v2: Vec<f32> = v1
+            .iter()
+            .enumerate()
+            .map(|(i, x)| x + if i % 2 == 0 { 0.001 } else { -0.001 })
+            .collect();
+        let v3: Vec<f32> = v1.iter().map(|x| -x).collect();
+        let (q1, q2, q3) = (quantize(&v1), quantize(&v2), quantize(&v3));
+        assert!(distance(&q1, &q2) <= 4);
+        assert!(distance(&q1, &q3) >= 60);
     }
 
     fn generate_base_vector_store() -> anyhow::Result<VectorStore> {
@@ -777,7 +1368,7 @@ mod tests {
             "data_type": "f32"
         }))?;
         let config = Config::default_with_vector_store(vector_store_config.clone());
-        VectorStore::new(vector_store_config, config)
+        VectorStore::new(vector_store_config, config, None)
     }
 
     fn generate_filler_text_document(uri: Option<&str>, text: Option<&str>) -> TextDocumentItem {
@@ -1019,11 +1610,598 @@ assert multiply_two_numbers(2, 3) == 6
         Ok(())
     }
 
+    #[tokio::test]
+    async fn embeds_chunks_and_queries_with_their_prefixes() -> anyhow::Result<()> {
+        let texts = Arc::new(Mutex::new(vec![]));
+        let mut vector_store = generate_base_vector_store()?;
+        vector_store.embedding_model = Arc::new(crate::embedding_models::with_prefix(
+            Box::new(crate::embedding_models::RecordingEmbeddingModel {
+                texts: texts.clone(),
+            }),
+            serde_json::from_value(json!({
+                "storage": "search_document: ",
+                "retrieval": "search_query: "
+            }))?,
+        ));
+        let text_document = generate_filler_text_document(None, None);
+        vector_store.opened_text_document(DidOpenTextDocumentParams {
+            text_document: text_document.clone(),
+        })?;
+        // Wait for the chunks to be embedded and stored in the background so no storage texts
+        // are recorded after the snapshot
+        let uri = text_document.uri.to_string();
+        for _ in 0..50 {
+            if vector_store
+                .vector_store
+                .read()
+                .store
+                .get(&uri)
+                .is_some_and(|chunks| !chunks.is_empty())
+            {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        let stored = std::mem::take(&mut *texts.lock());
+        assert!(!stored.is_empty());
+        assert!(stored
+            .iter()
+            .all(|text| text.starts_with("search_document: ")));
+
+        vector_store
+            .build_prompt(
+                &TextDocumentPositionParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: text_document.uri.clone(),
+                    },
+                    position: Position {
+                        line: 0,
+                        character: 10,
+                    },
+                },
+                PromptType::ContextAndCode,
+                &json!({}),
+            )
+            .await?;
+        let queried = texts.lock().clone();
+        assert_eq!(queried.len(), 1);
+        assert!(queried[0].starts_with("search_query: "));
+        Ok(())
+    }
+
+    #[test]
+    fn can_search_with_scores() -> anyhow::Result<()> {
+        let mut vector_store = VectorStoreInner::new(VectorDataType::F32, Similarity::Dot);
+        for (uri, vec, text) in [
+            ("file:///a.py", vec![0.5, 0.], "a"),
+            ("file:///b.py", vec![1., 0.], "b"),
+            ("file:///c.py", vec![0.25, 0.], "c"),
+        ] {
+            vector_store.sync_file_chunks(
+                uri,
+                vec![StoredChunkUpsert::new(
+                    ByteRange::new(0, 1),
+                    None,
+                    Some(vec),
+                    Some(text.to_string()),
+                )],
+                None,
+            )?;
+        }
+        let results = vector_store.search(2, None, vec![1., 0.], &[], "file:///d.py", 0, false)?;
+        assert_eq!(
+            results,
+            vec![
+                ContextChunk {
+                    uri: "file:///b.py".to_string(),
+                    text: "b".to_string(),
+                    score: 1.,
+                    start_line: Some(0)
+                },
+                ContextChunk {
+                    uri: "file:///a.py".to_string(),
+                    text: "a".to_string(),
+                    score: 0.5,
+                    start_line: Some(0)
+                }
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn can_search_with_min_score() -> anyhow::Result<()> {
+        for data_type in [VectorDataType::F32, VectorDataType::Binary] {
+            let mut vector_store =
+                VectorStoreInner::new(data_type, Similarity::Cosine).with_min_score(Some(0.5));
+            for (uri, vec, text) in [
+                ("file:///a.py", vec![-1., 1., -1., 1.], "a"),
+                ("file:///b.py", vec![-1., -1., -1., -1.], "b"),
+            ] {
+                vector_store.sync_file_chunks(
+                    uri,
+                    vec![StoredChunkUpsert::new(
+                        ByteRange::new(0, 1),
+                        None,
+                        Some(vec),
+                        Some(text.to_string()),
+                    )],
+                    None,
+                )?;
+            }
+            // Nothing in the store is related to the query
+            let results = vector_store.search(2, None, vec![1., 1., 1., 1.], &[], "", 0, false)?;
+            assert!(results.is_empty());
+
+            let results =
+                vector_store.search(2, None, vec![-1., 1., -1., 0.8], &[], "", 0, false)?;
+            let texts: Vec<&str> = results.iter().map(|c| c.text.as_str()).collect();
+            assert_eq!(texts, vec!["a"]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn can_rerank_binary_search() -> anyhow::Result<()> {
+        let mut vector_store = VectorStoreInner::new(VectorDataType::Binary, Similarity::Dot);
+        for (uri, vec, text) in [
+            (
+                "file:///a.py",
+                vec![1., 1., -1., -1., 1., -1., 1., -1.],
+                "a",
+            ),
+            ("file:///b.py", vec![1., 1., 1., -1., 1., -1., 1., -1.], "b"),
+            (
+                "file:///c.py",
+                vec![-1., -1., 1., 1., -1., 1., -1., 1.],
+                "c",
+            ),
+        ] {
+            vector_store.sync_file_chunks(
+                uri,
+                vec![StoredChunkUpsert::new(
+                    ByteRange::new(0, 1),
+                    None,
+                    Some(vec),
+                    Some(text.to_string()),
+                )],
+                None,
+            )?;
+        }
+        let query = vec![1., 1., -0.5, -1., 1., -1., 1., -1.];
+        for rerank_top_k in [None, Some(1), Some(3)] {
+            let results = vector_store.search(2, rerank_top_k, query.clone(), &[], "", 0, false)?;
+            let texts: Vec<&str> = results.iter().map(|c| c.text.as_str()).collect();
+            // A rerank_top_k below the limit still returns limit results
+            assert_eq!(texts, vec!["a", "b"]);
+            if rerank_top_k.is_some() {
+                assert_eq!(results[0].score, 4.);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn can_search_with_duplicate_scores() -> anyhow::Result<()> {
+        let mut vector_store = VectorStoreInner::new(VectorDataType::F32, Similarity::Dot);
+        for uri in ["file:///c.py", "file:///a.py", "file:///b.py"] {
+            vector_store.sync_file_chunks(
+                uri,
+                vec![
+                    StoredChunkUpsert::new(
+                        ByteRange::new(10, 20),
+                        None,
+                        Some(vec![1., 0.]),
+                        Some(format!("{uri} 2")),
+                    ),
+                    StoredChunkUpsert::new(
+                        ByteRange::new(0, 10),
+                        None,
+                        Some(vec![1., 0.]),
+                        Some(format!("{uri} 1")),
+                    ),
+                ],
+                None,
+            )?;
+        }
+        let expected = vec![
+            "file:///a.py 1",
+            "file:///a.py 2",
+            "file:///b.py 1",
+            "file:///b.py 2",
+            "file:///c.py 1",
+        ];
+        for _ in 0..10 {
+            let results = vector_store.search(5, None, vec![1., 0.], &[], "", 0, false)?;
+            let texts: Vec<&str> = results.iter().map(|c| c.text.as_str()).collect();
+            assert_eq!(texts, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn can_search_with_cosine_similarity() -> anyhow::Result<()> {
+        for (similarity, expected) in [
+            (Similarity::Dot, vec!["long", "aligned"]),
+            (Similarity::Cosine, vec!["aligned", "long"]),
+        ] {
+            let mut vector_store = VectorStoreInner::new(VectorDataType::F32, similarity);
+            for (uri, vec) in [
+                ("file:///aligned.py", vec![1., 0.]),
+                ("file:///long.py", vec![3., 3.]),
+            ] {
+                vector_store.sync_file_chunks(
+                    uri,
+                    vec![StoredChunkUpsert::new(
+                        ByteRange::new(0, 1),
+                        None,
+                        Some(vec),
+                        Some(
+                            uri.trim_start_matches("file:///")
+                                .trim_end_matches(".py")
+                                .to_string(),
+                        ),
+                    )],
+                    None,
+                )?;
+            }
+            let results = vector_store.search(2, None, vec![2., 0.], &[], "", 0, false)?;
+            let texts: Vec<&str> = results.iter().map(|c| c.text.as_str()).collect();
+            assert_eq!(texts, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn can_reembed_only_changed_chunks() -> anyhow::Result<()> {
+        let uri = "file:///filler.py";
+        let lines: Vec<String> = (0..10).map(|i| format!("line {i}")).collect();
+        let to_chunks = |lines: &[String]| -> Vec<Chunk> {
+            let mut start_byte = 0;
+            lines
+                .iter()
+                .map(|line| {
+                    let chunk = Chunk {
+                        text: line.clone(),
+                        range: ByteRange::new(start_byte, start_byte + line.len()),
+                    };
+                    start_byte += line.len() + 1;
+                    chunk
+                })
+                .collect()
+        };
+        let mut vector_store = VectorStoreInner::new(VectorDataType::F32, Similarity::Dot);
+        let upserts = plan_chunk_upserts(uri, to_chunks(&lines), None, None, None);
+        assert_eq!(upserts.iter().filter(|c| c.text.is_some()).count(), 10);
+        vector_store.replace_file_chunks(
+            uri,
+            upserts
+                .into_iter()
+                .enumerate()
+                .map(|(i, mut c)| {
+                    c.vec = Some(vec![i as f32]);
+                    c
+                })
+                .collect(),
+        )?;
+
+        // Edit the first line which shifts the byte range of every following chunk
+        let mut edited_lines = lines.clone();
+        edited_lines[0] = "edited line 0".to_string();
+        let upserts = plan_chunk_upserts(
+            uri,
+            to_chunks(&edited_lines),
+            vector_store.store.get(uri).map(|c| c.as_slice()),
+            None,
+            None,
+        );
+        assert_eq!(upserts.len(), 10);
+        let reembedded: Vec<&str> = upserts.iter().filter_map(|c| c.text.as_deref()).collect();
+        assert_eq!(reembedded, vec!["--file:///filler.py--\nedited line 0"]);
+        assert_eq!(
+            upserts
+                .iter()
+                .filter_map(|c| c.index)
+                .collect::<Vec<usize>>(),
+            (1..10).collect::<Vec<usize>>()
+        );
+        vector_store.replace_file_chunks(
+            uri,
+            upserts
+                .into_iter()
+                .map(|mut c| {
+                    if c.text.is_some() {
+                        c.vec = Some(vec![100.]);
+                    }
+                    c
+                })
+                .collect(),
+        )?;
+        let chunks = vector_store.store.get(uri).unwrap();
+        assert_eq!(chunks.len(), 10);
+        assert_eq!(chunks[0].text, "--file:///filler.py--\nedited line 0");
+        assert_eq!(chunks[1].text, "--file:///filler.py--\nline 1");
+        assert_eq!(chunks[1].range.start_byte, 14);
+        assert!(matches!(&chunks[1].vec, StoredChunkVec::F32(v) if v == &vec![1.]));
+
+        // Inserting a chunk at the top only embeds the new chunk
+        let mut inserted_lines = vec!["new line".to_string()];
+        inserted_lines.extend(edited_lines);
+        let upserts = plan_chunk_upserts(
+            uri,
+            to_chunks(&inserted_lines),
+            vector_store.store.get(uri).map(|c| c.as_slice()),
+            None,
+            None,
+        );
+        assert_eq!(upserts.len(), 11);
+        assert_eq!(upserts.iter().filter(|c| c.text.is_some()).count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn errors_on_dimension_mismatch() -> anyhow::Result<()> {
+        let mut vector_store = VectorStoreInner::new(VectorDataType::Binary, Similarity::Dot);
+        vector_store.sync_file_chunks(
+            "file:///a.py",
+            vec![StoredChunkUpsert::new(
+                ByteRange::new(0, 1),
+                None,
+                Some(vec![1.; 12]),
+                Some("a".to_string()),
+            )],
+            None,
+        )?;
+        let error = vector_store
+            .sync_file_chunks(
+                "file:///b.py",
+                vec![StoredChunkUpsert::new(
+                    ByteRange::new(0, 1),
+                    None,
+                    Some(vec![1.; 16]),
+                    Some("b".to_string()),
+                )],
+                None,
+            )
+            .unwrap_err();
+        assert!(error.to_string().starts_with(
+            "embedding has 16 dimensions but the vector store holds embeddings with 12 dimensions"
+        ));
+        assert!(vector_store
+            .search(1, None, vec![1.; 16], &[], "", 0, false)
+            .is_err());
+        let results = vector_store.search(1, None, vec![1.; 12], &[], "", 0, false)?;
+        assert_eq!(results.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn can_exclude_current_file_from_search() -> anyhow::Result<()> {
+        let mut vector_store = VectorStoreInner::new(VectorDataType::F32, Similarity::Dot);
+        vector_store.sync_file_chunks(
+            "file:///current.py",
+            vec![
+                StoredChunkUpsert::new(
+                    ByteRange::new(0, 10),
+                    None,
+                    Some(vec![1., 0.]),
+                    Some("current 1".to_string()),
+                ),
+                StoredChunkUpsert::new(
+                    ByteRange::new(10, 20),
+                    None,
+                    Some(vec![0.75, 0.]),
+                    Some("current 2".to_string()),
+                ),
+                StoredChunkUpsert::new(
+                    ByteRange::new(20, 30),
+                    None,
+                    Some(vec![0.5, 0.]),
+                    Some("current 3".to_string()),
+                ),
+            ],
+            None,
+        )?;
+        vector_store.sync_file_chunks(
+            "file:///other.py",
+            vec![StoredChunkUpsert::new(
+                ByteRange::new(0, 10),
+                None,
+                Some(vec![0.25, 0.]),
+                Some("other".to_string()),
+            )],
+            None,
+        )?;
+
+        // By default only the chunk containing the cursor is filtered out
+        let results =
+            vector_store.search(3, None, vec![1., 0.], &[], "file:///current.py", 5, false)?;
+        let texts: Vec<&str> = results.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, vec!["current 2", "current 3", "other"]);
+
+        let results =
+            vector_store.search(3, None, vec![1., 0.], &[], "file:///current.py", 5, true)?;
+        let texts: Vec<&str> = results.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, vec!["other"]);
+
+        // Chunks of the current file that score higher do not crowd out the others
+        let results =
+            vector_store.search(1, None, vec![1., 0.], &[], "file:///current.py", 5, true)?;
+        let texts: Vec<&str> = results.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, vec!["other"]);
+        Ok(())
+    }
+
+    #[test]
+    fn can_exclude_files_from_search() -> anyhow::Result<()> {
+        let rules: Vec<config::RetrievalExclusion> = serde_json::from_value(json!([
+            { "globs": ["tests/**"], "for_files": ["src/**"] }
+        ]))?;
+        let mut vector_store = VectorStoreInner::new(VectorDataType::F32, Similarity::Dot)
+            .with_exclusions(RetrievalExclusions::new(Some("file:///project"), &rules)?);
+        for (uri, score) in [
+            ("file:///project/tests/test_parse.py", 1.),
+            ("file:///project/src/parse.py", 0.5),
+        ] {
+            vector_store.sync_file_chunks(
+                uri,
+                vec![StoredChunkUpsert::new(
+                    ByteRange::new(0, 10),
+                    None,
+                    Some(vec![score, 0.]),
+                    Some(uri.to_string()),
+                )],
+                None,
+            )?;
+        }
+
+        // The excluded chunk does not take the place of the next best one
+        let current_uri = "file:///project/src/lib.py";
+        let results = vector_store.search(1, None, vec![1., 0.], &[], current_uri, 0, false)?;
+        let texts: Vec<&str> = results.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, vec!["file:///project/src/parse.py"]);
+
+        // Test files still get context from other test files
+        let current_uri = "file:///project/tests/test_lib.py";
+        let results = vector_store.search(1, None, vec![1., 0.], &[], current_uri, 0, false)?;
+        let texts: Vec<&str> = results.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, vec!["file:///project/tests/test_parse.py"]);
+        Ok(())
+    }
+
+    #[test]
+    fn hybrid_search_favors_identifier_matches() -> anyhow::Result<()> {
+        let mut vector_store =
+            VectorStoreInner::new(VectorDataType::F32, Similarity::Dot).with_hybrid(true);
+        for (uri, text, score) in [
+            ("file:///settings.py", "def load_settings(path):", 1.),
+            ("file:///users.py", "def parse_user_config(raw):", 0.5),
+            ("file:///db.py", "def connect(url):", 0.25),
+        ] {
+            vector_store.sync_file_chunks(
+                uri,
+                vec![StoredChunkUpsert::new(
+                    ByteRange::new(0, 10),
+                    None,
+                    Some(vec![score, 0.]),
+                    Some(text.to_string()),
+                )],
+                None,
+            )?;
+        }
+        let query = "config = parse_user_config(raw_config)";
+
+        let results = vector_store.search(2, None, vec![1., 0.], &[], "", 0, false)?;
+        let texts: Vec<&str> = results.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec!["def load_settings(path):", "def parse_user_config(raw):"]
+        );
+
+        // The chunk defining the identifier in the query ranks first once both rankings are fused
+        let results =
+            vector_store.hybrid_search(2, None, vec![1., 0.], query, &[], "", 0, false)?;
+        let texts: Vec<&str> = results.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec!["def parse_user_config(raw):", "def load_settings(path):"]
+        );
+
+        // Deleted files leave the lexical index too
+        vector_store.delete_file("file:///users.py");
+        let results =
+            vector_store.hybrid_search(1, None, vec![1., 0.], query, &[], "", 0, false)?;
+        let texts: Vec<&str> = results.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, vec!["def load_settings(path):"]);
+        Ok(())
+    }
+
+    #[test]
+    fn keyword_boost_favors_chunks_with_identifiers_near_the_cursor() -> anyhow::Result<()> {
+        let chunks = [
+            ("file:///settings.py", "def load_settings(path):", 1.),
+            ("file:///legacy.py", "def parse_user_config_v2(raw):", 0.95),
+            ("file:///users.py", "def parse_user_config(raw):", 0.9),
+        ];
+        let keywords = vec!["parse_user_config".to_string(), "raw_config".to_string()];
+        for keyword_boost in [None, Some(0.5)] {
+            let mut vector_store = VectorStoreInner::new(VectorDataType::F32, Similarity::Dot)
+                .with_keyword_boost(keyword_boost);
+            for (uri, text, score) in chunks {
+                vector_store.sync_file_chunks(
+                    uri,
+                    vec![StoredChunkUpsert::new(
+                        ByteRange::new(0, 10),
+                        None,
+                        Some(vec![score, 0.]),
+                        Some(text.to_string()),
+                    )],
+                    None,
+                )?;
+            }
+            let results = vector_store.search(3, None, vec![1., 0.], &keywords, "", 0, false)?;
+            let texts: Vec<&str> = results.iter().map(|c| c.text.as_str()).collect();
+            let expected = match keyword_boost {
+                None => vec![
+                    "def load_settings(path):",
+                    "def parse_user_config_v2(raw):",
+                    "def parse_user_config(raw):",
+                ],
+                // Half of the keywords match so the bonus is 0.25. Longer identifiers don't match
+                Some(_) => vec![
+                    "def parse_user_config(raw):",
+                    "def load_settings(path):",
+                    "def parse_user_config_v2(raw):",
+                ],
+            };
+            assert_eq!(texts, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn keyword_boost_is_scaled_when_reranking_binary_search() -> anyhow::Result<()> {
+        let mut vector_store = VectorStoreInner::new(VectorDataType::Binary, Similarity::Dot)
+            .with_keyword_boost(Some(0.5));
+        for (uri, vec, text) in [
+            (
+                "file:///a.py",
+                vec![1., 1., -1., -1., 1., -1., 1., -1.],
+                "def load_settings(path):",
+            ),
+            (
+                "file:///b.py",
+                vec![1., 1., 1., -1., 1., -1., 1., -1.],
+                "def parse_user_config(raw):",
+            ),
+        ] {
+            vector_store.sync_file_chunks(
+                uri,
+                vec![StoredChunkUpsert::new(
+                    ByteRange::new(0, 1),
+                    None,
+                    Some(vec),
+                    Some(text.to_string()),
+                )],
+                None,
+            )?;
+        }
+        let query = vec![1., 1., -0.5, -1., 1., -1., 1., -1.];
+        let keywords = vec!["parse_user_config".to_string()];
+        // The bonus outweighs the one bit or the half point `b.py` is behind either way
+        for rerank_top_k in [None, Some(2)] {
+            let results =
+                vector_store.search(1, rerank_top_k, query.clone(), &keywords, "", 0, false)?;
+            assert_eq!(results[0].text, "def parse_user_config(raw):");
+        }
+        Ok(())
+    }
+
     // Switch to the criterion crate for stress tests
     #[test]
     #[cfg(feature = "stress_test")]
     fn stress_test_f32() -> anyhow::Result<()> {
-        let mut vector_store = VectorStoreInner::new(VectorDataType::F32);
+        let mut vector_store = VectorStoreInner::new(VectorDataType::F32, Similarity::Dot);
         let embedding: Vec<f32> = (0..1024).map(|x| x as f32).collect();
         // Time insert
         // Insert 100_000 files each with 10 chunks
@@ -1049,7 +2227,7 @@ assert multiply_two_numbers(2, 3) == 6
         println!("Insert took {} milliseconds.", elapsed_time.as_millis());
         // Time search
         let now = std::time::Instant::now();
-        vector_store.search(5, None, embedding, "", 0)?;
+        vector_store.search(5, None, embedding, &[], "", 0, false)?;
         let elapsed_time = now.elapsed();
         println!("Search took {} milliseconds.", elapsed_time.as_millis());
         Ok(())
@@ -1058,7 +2236,7 @@ assert multiply_two_numbers(2, 3) == 6
     #[test]
     #[cfg(feature = "stress_test")]
     fn stress_test_binary() -> anyhow::Result<()> {
-        let mut vector_store = VectorStoreInner::new(VectorDataType::Binary);
+        let mut vector_store = VectorStoreInner::new(VectorDataType::Binary, Similarity::Dot);
         let embedding: Vec<f32> = (0..1024).map(|x| x as f32).collect();
         // Time insert
         // Insert 1_000_000 files each with 10 chunks
@@ -1084,7 +2262,7 @@ assert multiply_two_numbers(2, 3) == 6
         println!("Insert took {} milliseconds.", elapsed_time.as_millis());
         // Time search
         let now = std::time::Instant::now();
-        vector_store.search(5, Some(100), embedding, "", 0)?;
+        vector_store.search(5, Some(100), embedding, &[], "", 0, false)?;
         let elapsed_time = now.elapsed();
         println!("Search took {} milliseconds.", elapsed_time.as_millis());
         Ok(())
@@ -1,14 +1,15 @@
 use std::sync::Arc;
 
 use lsp_types::{
-    DidChangeTextDocumentParams, DidOpenTextDocumentParams, Range, RenameFilesParams,
+    DeleteFilesParams, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DidSaveTextDocumentParams, Range, RenameFilesParams,
     TextDocumentIdentifier, TextDocumentPositionParams,
 };
 use serde_json::Value;
 use tracing::error;
 
 use crate::{
-    memory_backends::{MemoryBackend, Prompt, PromptType},
+    memory_backends::{ContextChunk, MemoryBackend, Prompt, PromptType},
     utils::TOKIO_RUNTIME,
 };
 
@@ -36,6 +37,30 @@ impl PromptRequest {
     }
 }
 
+#[derive(Debug)]
+pub(crate) struct PromptWithContextRequest {
+    position: TextDocumentPositionParams,
+    prompt_type: PromptType,
+    params: Value,
+    tx: tokio::sync::oneshot::Sender<(Prompt, Vec<ContextChunk>)>,
+}
+
+impl PromptWithContextRequest {
+    pub(crate) fn new(
+        position: TextDocumentPositionParams,
+        prompt_type: PromptType,
+        params: Value,
+        tx: tokio::sync::oneshot::Sender<(Prompt, Vec<ContextChunk>)>,
+    ) -> Self {
+        Self {
+            position,
+            prompt_type,
+            params,
+            tx,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct FilterRequest {
     position: TextDocumentPositionParams,
@@ -93,15 +118,35 @@ impl FileRequest {
     }
 }
 
+#[derive(Debug)]
+pub(crate) struct ReplaceRangeRequest {
+    position: TextDocumentPositionParams,
+    tx: tokio::sync::oneshot::Sender<Range>,
+}
+
+impl ReplaceRangeRequest {
+    pub(crate) fn new(
+        position: TextDocumentPositionParams,
+        tx: tokio::sync::oneshot::Sender<Range>,
+    ) -> Self {
+        Self { position, tx }
+    }
+}
+
 pub(crate) enum WorkerRequest {
     Shutdown,
     FilterText(FilterRequest),
+    ReplaceRange(ReplaceRangeRequest),
     File(FileRequest),
     Prompt(PromptRequest),
+    PromptWithContext(PromptWithContextRequest),
     CodeActionRequest(CodeActionRequest),
     DidOpenTextDocument(DidOpenTextDocumentParams),
+    DidCloseTextDocument(DidCloseTextDocumentParams),
     DidChangeTextDocument(DidChangeTextDocumentParams),
     DidRenameFiles(RenameFilesParams),
+    DidDeleteFiles(DeleteFilesParams),
+    DidSaveTextDocument(DidSaveTextDocumentParams),
 }
 
 async fn do_build_prompt(
@@ -117,6 +162,19 @@ async fn do_build_prompt(
         .map_err(|_| anyhow::anyhow!("sending on channel failed"))
 }
 
+async fn do_build_prompt_with_context(
+    params: PromptWithContextRequest,
+    memory_backend: Arc<Box<dyn MemoryBackend + Send + Sync>>,
+) -> anyhow::Result<()> {
+    let prompt_with_context = memory_backend
+        .build_prompt_with_context(&params.position, params.prompt_type, &params.params)
+        .await?;
+    params
+        .tx
+        .send(prompt_with_context)
+        .map_err(|_| anyhow::anyhow!("sending on channel failed"))
+}
+
 fn do_task(
     request: WorkerRequest,
     memory_backend: Arc<Box<dyn MemoryBackend + Send + Sync>>,
@@ -129,6 +187,13 @@ fn do_task(
                 .send(filter_text)
                 .map_err(|_| anyhow::anyhow!("sending on channel failed"))?;
         }
+        WorkerRequest::ReplaceRange(params) => {
+            let replace_range = memory_backend.get_replace_range(&params.position)?;
+            params
+                .tx
+                .send(replace_range)
+                .map_err(|_| anyhow::anyhow!("sending on channel failed"))?;
+        }
         WorkerRequest::Prompt(params) => {
             TOKIO_RUNTIME.spawn(async move {
                 if let Err(e) = do_build_prompt(params, memory_backend).await {
@@ -136,6 +201,13 @@ fn do_task(
                 }
             });
         }
+        WorkerRequest::PromptWithContext(params) => {
+            TOKIO_RUNTIME.spawn(async move {
+                if let Err(e) = do_build_prompt_with_context(params, memory_backend).await {
+                    error!("error in memory worker building prompt: {e}")
+                }
+            });
+        }
         WorkerRequest::CodeActionRequest(params) => {
             let res = memory_backend.code_action_request(
                 &params.text_document_identifier,
@@ -157,10 +229,15 @@ fn do_task(
         WorkerRequest::DidOpenTextDocument(params) => {
             memory_backend.opened_text_document(params)?;
         }
+        WorkerRequest::DidCloseTextDocument(params) => {
+            memory_backend.closed_text_document(params)?;
+        }
         WorkerRequest::DidChangeTextDocument(params) => {
             memory_backend.changed_text_document(params)?;
         }
         WorkerRequest::DidRenameFiles(params) => memory_backend.renamed_files(params)?,
+        WorkerRequest::DidDeleteFiles(params) => memory_backend.deleted_files(params)?,
+        WorkerRequest::DidSaveTextDocument(params) => memory_backend.saved_text_document(params)?,
         WorkerRequest::Shutdown => unreachable!(),
     }
     anyhow::Ok(())
@@ -0,0 +1,202 @@
+use anyhow::Context;
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::{
+    notification::Progress, request::WorkDoneProgressCreate, NumberOrString, ProgressParams,
+    ProgressParamsValue, ProgressToken, WorkDoneProgress, WorkDoneProgressBegin,
+    WorkDoneProgressCreateParams, WorkDoneProgressEnd, WorkDoneProgressReport,
+};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+static NEXT_PROGRESS_ID: AtomicUsize = AtomicUsize::new(0);
+
+// The `window/workDoneProgress/create` requests waiting for the client's response
+static PENDING_CREATES: Lazy<Mutex<HashMap<RequestId, oneshot::Sender<Response>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// How long to wait for the client to create the token before giving up on reporting progress
+const CREATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Hands the response to the `begin` waiting for it. Returns responses to other requests
+pub(crate) fn handle_client_response(response: Response) -> Option<Response> {
+    match PENDING_CREATES.lock().remove(&response.id) {
+        Some(tx) => {
+            // `begin` may have stopped waiting
+            let _ = tx.send(response);
+            None
+        }
+        None => Some(response),
+    }
+}
+
+// Creates server initiated work done progress reports
+// Only construct this if the client has `window.workDoneProgress` set in its capabilities
+#[derive(Clone)]
+pub(crate) struct ProgressReporter {
+    connection: Arc<Connection>,
+}
+
+impl ProgressReporter {
+    pub(crate) fn new(connection: Arc<Connection>) -> Self {
+        Self { connection }
+    }
+
+    // The client must create the token before we report progress with it so this waits for its
+    // response
+    pub(crate) async fn begin(&self, title: &str, total: usize) -> anyhow::Result<ProgressHandle> {
+        let id = NEXT_PROGRESS_ID.fetch_add(1, Ordering::Relaxed);
+        let token = NumberOrString::String(format!("lsp-ai/progress/{id}"));
+        let request_id = RequestId::from(format!("lsp-ai/progress/create/{id}"));
+        let (tx, rx) = oneshot::channel();
+        PENDING_CREATES.lock().insert(request_id.clone(), tx);
+        let sent = self.connection.sender.send(Message::Request(Request::new(
+            request_id.clone(),
+            <WorkDoneProgressCreate as lsp_types::request::Request>::METHOD.to_string(),
+            WorkDoneProgressCreateParams {
+                token: token.clone(),
+            },
+        )));
+        if let Err(e) = sent {
+            PENDING_CREATES.lock().remove(&request_id);
+            return Err(e.into());
+        }
+
+        let response = match tokio::time::timeout(CREATE_TIMEOUT, rx).await {
+            Ok(response) => {
+                response.context("the connection closed before the client created the progress")?
+            }
+            // The request stays pending so a late response is still consumed quietly
+            Err(_) => anyhow::bail!("the client did not create the progress in time"),
+        };
+        if let Some(error) = response.error {
+            anyhow::bail!(
+                "the client failed to create the progress: {}",
+                error.message
+            )
+        }
+        let handle = ProgressHandle {
+            connection: self.connection.clone(),
+            token,
+            total,
+        };
+        handle.send(WorkDoneProgress::Begin(WorkDoneProgressBegin {
+            title: title.to_string(),
+            cancellable: Some(false),
+            message: Some(format!("0/{total} files")),
+            percentage: Some(0),
+        }))?;
+        Ok(handle)
+    }
+}
+
+pub(crate) struct ProgressHandle {
+    connection: Arc<Connection>,
+    token: ProgressToken,
+    total: usize,
+}
+
+impl ProgressHandle {
+    fn send(&self, value: WorkDoneProgress) -> anyhow::Result<()> {
+        self.connection
+            .sender
+            .send(Message::Notification(Notification::new(
+                <Progress as lsp_types::notification::Notification>::METHOD.to_string(),
+                ProgressParams {
+                    token: self.token.clone(),
+                    value: ProgressParamsValue::WorkDone(value),
+                },
+            )))?;
+        Ok(())
+    }
+
+    pub(crate) fn report(&self, done: usize) -> anyhow::Result<()> {
+        let percentage = (done.min(self.total) * 100)
+            .checked_div(self.total)
+            .unwrap_or(100) as u32;
+        self.send(WorkDoneProgress::Report(WorkDoneProgressReport {
+            cancellable: Some(false),
+            message: Some(format!("{done}/{} files", self.total)),
+            percentage: Some(percentage),
+        }))
+    }
+
+    pub(crate) fn end(self) -> anyhow::Result<()> {
+        self.send(WorkDoneProgress::End(WorkDoneProgressEnd {
+            message: Some(format!("{} files", self.total)),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn can_report_progress() -> anyhow::Result<()> {
+        let (server, client) = Connection::memory();
+        let reporter = ProgressReporter::new(Arc::new(server));
+        let begin = tokio::spawn(async move { reporter.begin("Indexing", 4).await });
+
+        // Nothing is reported until the client creates the token
+        let create = match client.receiver.recv_timeout(Duration::from_secs(5))? {
+            Message::Request(req) => req,
+            _ => anyhow::bail!("expected a workDoneProgress/create request"),
+        };
+        assert_eq!(create.method, "window/workDoneProgress/create");
+        assert!(client.receiver.try_recv().is_err());
+        assert!(handle_client_response(Response::new_ok(create.id, ())).is_none());
+
+        let handle = begin.await??;
+        handle.report(2)?;
+        handle.end()?;
+
+        let messages: Vec<Message> = client.receiver.try_iter().collect();
+        assert_eq!(messages.len(), 3);
+        let values: Vec<serde_json::Value> = messages
+            .iter()
+            .map(|m| match m {
+                Message::Notification(not) => Ok(not.params["value"].clone()),
+                _ => anyhow::bail!("expected a $/progress notification"),
+            })
+            .collect::<anyhow::Result<_>>()?;
+        assert_eq!(values[0]["kind"], "begin");
+        assert_eq!(values[0]["title"], "Indexing");
+        assert_eq!(values[1]["kind"], "report");
+        assert_eq!(values[1]["message"], "2/4 files");
+        assert_eq!(values[1]["percentage"], 50);
+        assert_eq!(values[2]["kind"], "end");
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn does_not_report_progress_the_client_refused() -> anyhow::Result<()> {
+        let (server, client) = Connection::memory();
+        let reporter = ProgressReporter::new(Arc::new(server));
+        let begin = tokio::spawn(async move { reporter.begin("Indexing", 4).await });
+
+        let Message::Request(create) = client.receiver.recv_timeout(Duration::from_secs(5))? else {
+            anyhow::bail!("expected a workDoneProgress/create request")
+        };
+        handle_client_response(Response::new_err(
+            create.id,
+            lsp_server::ErrorCode::InternalError as i32,
+            "no progress".to_string(),
+        ));
+        assert!(begin.await?.is_err());
+        assert!(client.receiver.try_recv().is_err());
+
+        // Responses to other requests are handed back
+        let other = Response::new_ok(RequestId::from("lsp-ai/apply-edit/0".to_string()), ());
+        assert!(handle_client_response(other).is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::instrument;
+
+use crate::{config, utils::http_client};
+
+use super::Reranker;
+
+#[derive(Debug, Deserialize)]
+struct RerankResult {
+    index: usize,
+    relevance_score: f32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RerankResponse {
+    Success { results: Vec<RerankResult> },
+    Error(Value),
+}
+
+// Works with any API matching Cohere's `/v1/rerank` like Jina AI and Voyage AI
+pub(crate) struct Cohere {
+    config: config::CohereReranker,
+}
+
+impl Cohere {
+    pub(crate) fn new(config: config::CohereReranker) -> Self {
+        Self { config }
+    }
+
+    fn get_token(&self) -> anyhow::Result<String> {
+        if let Some(env_var_name) = &self.config.auth_token_env_var_name {
+            Ok(std::env::var(env_var_name)?)
+        } else if let Some(token) = &self.config.auth_token {
+            Ok(token.to_string())
+        } else {
+            anyhow::bail!("set `auth_token_env_var_name` or `auth_token` to use a Cohere compatible rerank API")
+        }
+    }
+}
+
+// The results are sorted by relevance so they are put back in the order of the documents
+fn scores_in_document_order(
+    results: Vec<RerankResult>,
+    documents: usize,
+) -> anyhow::Result<Vec<f32>> {
+    let mut scores = vec![None; documents];
+    for result in results {
+        *scores.get_mut(result.index).ok_or_else(|| {
+            anyhow::anyhow!(
+                "rerank result index {} is out of range for {documents} documents",
+                result.index
+            )
+        })? = Some(result.relevance_score);
+    }
+    scores
+        .into_iter()
+        .enumerate()
+        .map(|(i, score)| {
+            score.ok_or_else(|| anyhow::anyhow!("rerank results are missing document {i}"))
+        })
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl Reranker for Cohere {
+    #[instrument(skip(self, documents))]
+    async fn rerank(&self, query: &str, documents: Vec<&str>) -> anyhow::Result<Vec<f32>> {
+        let count = documents.len();
+        let res: RerankResponse = http_client()
+            .post(
+                self.config
+                    .endpoint
+                    .as_deref()
+                    .unwrap_or("https://api.cohere.com/v1/rerank"),
+            )
+            .bearer_auth(self.get_token()?)
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json")
+            .json(&json!({
+                "model": self.config.model,
+                "query": query,
+                "documents": documents,
+            }))
+            .send()
+            .await?
+            .json()
+            .await?;
+        match res {
+            RerankResponse::Success { results } => scores_in_document_order(results, count),
+            RerankResponse::Error(error) => {
+                anyhow::bail!("unknown error while making rerank request: {error}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_rerank_response() -> anyhow::Result<()> {
+        let res: RerankResponse = serde_json::from_value(json!({
+            "id": "07734bd2-2473-4f07-94e1-0d9f0e6843cf",
+            "results": [
+                {"index": 2, "relevance_score": 0.9},
+                {"index": 0, "relevance_score": 0.5},
+                {"index": 1, "relevance_score": 0.1}
+            ],
+            "meta": {"api_version": {"version": "1"}}
+        }))?;
+        let RerankResponse::Success { results } = res else {
+            anyhow::bail!("expected a successful response")
+        };
+        assert_eq!(scores_in_document_order(results, 3)?, vec![0.5, 0.1, 0.9]);
+
+        let results = vec![RerankResult {
+            index: 0,
+            relevance_score: 0.5,
+        }];
+        assert!(scores_in_document_order(results, 2).is_err());
+        Ok(())
+    }
+}
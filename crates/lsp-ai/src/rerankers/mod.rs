@@ -0,0 +1,98 @@
+use crate::{config::ValidReranker, memory_backends::ContextChunk};
+
+mod cohere;
+
+// Scores how relevant each document is to the query by reading them together, which is more
+// precise than comparing embeddings but too slow to run over the whole index
+#[async_trait::async_trait]
+pub(crate) trait Reranker {
+    // Returns one score per document in the order they were given. Higher is more relevant
+    async fn rerank(&self, query: &str, documents: Vec<&str>) -> anyhow::Result<Vec<f32>>;
+}
+
+impl TryFrom<ValidReranker> for Box<dyn Reranker + Send + Sync> {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ValidReranker) -> Result<Self, Self::Error> {
+        match value {
+            ValidReranker::Cohere(config) => Ok(Box::new(cohere::Cohere::new(config))),
+        }
+    }
+}
+
+// Orders the chunks by their reranker score and keeps the best `limit`
+// The score of each chunk is replaced with its reranker score
+pub(crate) async fn rerank_chunks(
+    reranker: &(dyn Reranker + Send + Sync),
+    query: &str,
+    chunks: Vec<ContextChunk>,
+    limit: usize,
+) -> anyhow::Result<Vec<ContextChunk>> {
+    if chunks.is_empty() {
+        return Ok(chunks);
+    }
+    let scores = reranker
+        .rerank(
+            query,
+            chunks.iter().map(|chunk| chunk.text.as_str()).collect(),
+        )
+        .await?;
+    anyhow::ensure!(
+        scores.len() == chunks.len(),
+        "reranker returned {} scores for {} chunks",
+        scores.len(),
+        chunks.len()
+    );
+    let mut chunks: Vec<ContextChunk> = chunks
+        .into_iter()
+        .zip(scores)
+        .map(|(chunk, score)| ContextChunk { score, ..chunk })
+        .collect();
+    // Stable so chunks with the same score keep their vector search order
+    chunks.sort_by(|a, b| b.score.total_cmp(&a.score));
+    chunks.truncate(limit);
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Scores documents by how many times they contain the query
+    struct CountingReranker;
+
+    #[async_trait::async_trait]
+    impl Reranker for CountingReranker {
+        async fn rerank(&self, query: &str, documents: Vec<&str>) -> anyhow::Result<Vec<f32>> {
+            Ok(documents
+                .iter()
+                .map(|document| document.matches(query).count() as f32)
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn rerank_chunks_by_score() -> anyhow::Result<()> {
+        let chunk = |text: &str, score: f32| ContextChunk {
+            uri: "file:///a.py".to_string(),
+            text: text.to_string(),
+            score,
+            start_line: None,
+        };
+        let chunks = vec![
+            chunk("b", 0.9),
+            chunk("a a", 0.8),
+            chunk("a", 0.7),
+            chunk("a a a", 0.6),
+        ];
+        let reranked = rerank_chunks(&CountingReranker, "a", chunks, 3).await?;
+        assert_eq!(
+            reranked,
+            vec![chunk("a a a", 3.), chunk("a a", 2.), chunk("a", 1.)]
+        );
+        assert!(rerank_chunks(&CountingReranker, "a", vec![], 3)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
 use serde::Serialize;
 
-use crate::{config::ValidSplitter, memory_backends::file_store::File};
+use crate::{
+    config::{self, ValidSplitter},
+    memory_backends::file_store::File,
+};
 
 mod text_splitter;
 mod tree_sitter;
@@ -9,6 +12,8 @@ mod tree_sitter;
 pub(crate) struct ByteRange {
     pub(crate) start_byte: usize,
     pub(crate) end_byte: usize,
+    // The 0-based line `start_byte` is on. Used to number the lines of retrieved chunks
+    pub(crate) start_line: usize,
 }
 
 impl ByteRange {
@@ -16,8 +21,43 @@ impl ByteRange {
         Self {
             start_byte,
             end_byte,
+            start_line: 0,
         }
     }
+
+    pub(crate) fn with_start_line(mut self, start_line: usize) -> Self {
+        self.start_line = start_line;
+        self
+    }
+}
+
+// Finds the line of each chunk's start. Chunks start in order so only the bytes between two
+// starts are counted
+struct LineCounter<'a> {
+    contents: &'a [u8],
+    byte: usize,
+    line: usize,
+}
+
+impl<'a> LineCounter<'a> {
+    fn new(contents: &'a [u8]) -> Self {
+        Self {
+            contents,
+            byte: 0,
+            line: 0,
+        }
+    }
+
+    fn line_of(&mut self, byte: usize) -> usize {
+        let newlines = |bytes: &[u8]| bytes.iter().filter(|b| **b == b'\n').count();
+        if byte >= self.byte {
+            self.line += newlines(&self.contents[self.byte..byte]);
+        } else {
+            self.line -= newlines(&self.contents[byte..self.byte]);
+        }
+        self.byte = byte;
+        self.line
+    }
 }
 
 #[derive(Serialize)]
@@ -34,7 +74,13 @@ impl Chunk {
 
 pub(crate) trait Splitter {
     fn split(&self, file: &File) -> Vec<Chunk>;
-    fn split_file_contents(&self, uri: &str, contents: &str) -> Vec<Chunk>;
+    // `language_id` is the editor's language id for the file when it has one
+    fn split_file_contents(
+        &self,
+        uri: &str,
+        contents: &str,
+        language_id: Option<&str>,
+    ) -> Vec<Chunk>;
 
     fn does_use_tree_sitter(&self) -> bool {
         false
@@ -43,17 +89,171 @@ pub(crate) trait Splitter {
     fn chunk_size(&self) -> usize;
 }
 
+// The splitter crates reject these too but without naming the settings
+fn validate_chunk_config(chunk_size: usize, chunk_overlap: usize) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        chunk_size > 0,
+        "splitter `chunk_size` must be greater than 0"
+    );
+    anyhow::ensure!(
+        chunk_overlap < chunk_size,
+        "splitter `chunk_overlap` ({chunk_overlap}) must be less than `chunk_size` ({chunk_size})"
+    );
+    Ok(())
+}
+
 impl TryFrom<ValidSplitter> for Box<dyn Splitter + Send + Sync> {
     type Error = anyhow::Error;
 
     fn try_from(value: ValidSplitter) -> Result<Self, Self::Error> {
         match value {
             ValidSplitter::TreeSitter(config) => {
+                validate_chunk_config(config.chunk_size, config.chunk_overlap)?;
                 Ok(Box::new(tree_sitter::TreeSitter::new(config)?))
             }
             ValidSplitter::TextSplitter(config) => {
-                Ok(Box::new(text_splitter::TextSplitter::new(config)))
+                validate_chunk_config(config.chunk_size, config.chunk_overlap)?;
+                Ok(Box::new(text_splitter::TextSplitter::new(config)?))
+            }
+        }
+    }
+}
+
+// With `disable_tree_sitter` the tree_sitter splitter is swapped for the text splitter with the
+// same chunk settings
+pub(crate) fn build_splitter(
+    config: ValidSplitter,
+    disable_tree_sitter: bool,
+) -> anyhow::Result<Box<dyn Splitter + Send + Sync>> {
+    match config {
+        ValidSplitter::TreeSitter(config) if disable_tree_sitter => {
+            ValidSplitter::TextSplitter(config::TextSplitter {
+                chunk_size: config.chunk_size,
+                chunk_overlap: config.chunk_overlap,
+            })
+            .try_into()
+        }
+        config => config.try_into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn splitter_from_json(
+        config: serde_json::Value,
+    ) -> anyhow::Result<Box<dyn Splitter + Send + Sync>> {
+        serde_json::from_value::<ValidSplitter>(config)?.try_into()
+    }
+
+    fn overlaps(chunks: &[Chunk]) -> bool {
+        chunks
+            .windows(2)
+            .any(|pair| pair[1].range.start_byte < pair[0].range.end_byte)
+    }
+
+    #[test]
+    fn can_build_splitters_with_overlap() -> anyhow::Result<()> {
+        let splitter = splitter_from_json(json!({
+            "type": "text_splitter",
+            "chunk_size": 10,
+            "chunk_overlap": 5
+        }))?;
+        assert_eq!(splitter.chunk_size(), 10);
+        let chunks = splitter.split_file_contents("", "aaaa bbbb cccc dddd eeee", None);
+        assert!(chunks.len() > 1);
+        assert!(overlaps(&chunks));
+
+        let splitter = splitter_from_json(json!({
+            "type": "tree_sitter",
+            "chunk_size": 20,
+            "chunk_overlap": 5
+        }))?;
+        assert_eq!(splitter.chunk_size(), 20);
+        let chunks = splitter.split_file_contents(
+            "file:///test.txt",
+            "aaaa bbbb cccc dddd eeee ffff gggg",
+            None,
+        );
+        assert!(overlaps(&chunks));
+        Ok(())
+    }
+
+    #[test]
+    fn tree_sitter_splitter_uses_language_id() -> anyhow::Result<()> {
+        let splitter = splitter_from_json(json!({
+            "type": "tree_sitter",
+            "chunk_size": 20
+        }))?;
+        let contents = "x = [1, 2, 3]; y = [4, 5, 6]; z = [7, 8, 9]";
+        let ranges = |chunks: Vec<Chunk>| -> Vec<(usize, usize)> {
+            chunks
+                .iter()
+                .map(|chunk| (chunk.range.start_byte, chunk.range.end_byte))
+                .collect()
+        };
+        let by_extension = ranges(splitter.split_file_contents("file:///a.py", contents, None));
+        assert_eq!(
+            ranges(splitter.split_file_contents("file:///a", contents, Some("python"))),
+            by_extension
+        );
+        // Without either the text splitter is used
+        assert_ne!(
+            ranges(splitter.split_file_contents("file:///a", contents, None)),
+            by_extension
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn splitters_track_start_lines() -> anyhow::Result<()> {
+        let contents =
+            "def a():\n    return 1\n\ndef b():\n    return 2\n\ndef c():\n    return 3\n";
+        for splitter_type in ["text_splitter", "tree_sitter"] {
+            let splitter = splitter_from_json(json!({
+                "type": splitter_type,
+                "chunk_size": 24,
+                "chunk_overlap": 8
+            }))?;
+            let chunks = splitter.split_file_contents("file:///a.py", contents, None);
+            assert!(chunks.len() > 1);
+            for chunk in chunks {
+                let start_line = contents[..chunk.range.start_byte].matches('\n').count();
+                assert_eq!(chunk.range.start_line, start_line);
             }
         }
+        Ok(())
+    }
+
+    #[test]
+    fn can_disable_tree_sitter_splitter() -> anyhow::Result<()> {
+        let config: ValidSplitter = serde_json::from_value(json!({
+            "type": "tree_sitter",
+            "chunk_size": 20
+        }))?;
+        assert!(build_splitter(config.clone(), false)?.does_use_tree_sitter());
+        let splitter = build_splitter(config, true)?;
+        assert!(!splitter.does_use_tree_sitter());
+        assert_eq!(splitter.chunk_size(), 20);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_overlap_not_less_than_chunk_size() {
+        for splitter_type in ["text_splitter", "tree_sitter"] {
+            let error = splitter_from_json(json!({
+                "type": splitter_type,
+                "chunk_size": 10,
+                "chunk_overlap": 10
+            }))
+            .err()
+            .unwrap();
+            assert_eq!(
+                error.to_string(),
+                "splitter `chunk_overlap` (10) must be less than `chunk_size` (10)"
+            );
+        }
     }
 }
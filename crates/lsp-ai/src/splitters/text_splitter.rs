@@ -1,6 +1,6 @@
 use crate::{config, memory_backends::file_store::File};
 
-use super::{ByteRange, Chunk, Splitter};
+use super::{ByteRange, Chunk, LineCounter, Splitter};
 
 pub(crate) struct TextSplitter {
     chunk_size: usize,
@@ -8,34 +8,35 @@ pub(crate) struct TextSplitter {
 }
 
 impl TextSplitter {
-    pub(crate) fn new(config: config::TextSplitter) -> Self {
-        Self {
+    pub(crate) fn new(config: config::TextSplitter) -> anyhow::Result<Self> {
+        let chunk_config = text_splitter::ChunkConfig::new(config.chunk_size)
+            .with_overlap(config.chunk_overlap)?;
+        Ok(Self {
             chunk_size: config.chunk_size,
-            splitter: text_splitter::TextSplitter::new(config.chunk_size),
-        }
-    }
-
-    pub(crate) fn new_with_chunk_size(chunk_size: usize) -> Self {
-        Self {
-            chunk_size,
-            splitter: text_splitter::TextSplitter::new(chunk_size),
-        }
+            splitter: text_splitter::TextSplitter::new(chunk_config),
+        })
     }
 }
 
 impl Splitter for TextSplitter {
     fn split(&self, file: &File) -> Vec<Chunk> {
-        self.split_file_contents("", &file.rope().to_string())
+        self.split_file_contents("", &file.rope().to_string(), None)
     }
 
-    fn split_file_contents(&self, _uri: &str, contents: &str) -> Vec<Chunk> {
+    fn split_file_contents(
+        &self,
+        _uri: &str,
+        contents: &str,
+        _language_id: Option<&str>,
+    ) -> Vec<Chunk> {
+        let mut lines = LineCounter::new(contents.as_bytes());
         self.splitter
             .chunk_indices(contents)
             .fold(vec![], |mut acc, (start_byte, text)| {
                 let end_byte = start_byte + text.len();
                 acc.push(Chunk::new(
                     text.to_string(),
-                    ByteRange::new(start_byte, end_byte),
+                    ByteRange::new(start_byte, end_byte).with_start_line(lines.line_of(start_byte)),
                 ));
                 acc
             })
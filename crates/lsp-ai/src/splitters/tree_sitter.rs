@@ -4,33 +4,44 @@ use tree_sitter::Tree;
 
 use crate::{config, memory_backends::file_store::File, utils::parse_tree};
 
-use super::{text_splitter::TextSplitter, ByteRange, Chunk, Splitter};
+use super::{text_splitter::TextSplitter, ByteRange, Chunk, LineCounter, Splitter};
 
 pub(crate) struct TreeSitter {
     chunk_size: usize,
+    granularity: config::TreeSitterGranularity,
     splitter: TreeSitterCodeSplitter,
     text_splitter: TextSplitter,
 }
 
 impl TreeSitter {
     pub(crate) fn new(config: config::TreeSitter) -> anyhow::Result<Self> {
-        let text_splitter = TextSplitter::new_with_chunk_size(config.chunk_size);
+        let text_splitter = TextSplitter::new(config::TextSplitter {
+            chunk_size: config.chunk_size,
+            chunk_overlap: config.chunk_overlap,
+        })?;
         Ok(Self {
             chunk_size: config.chunk_size,
+            granularity: config.granularity,
             splitter: TreeSitterCodeSplitter::new(config.chunk_size, config.chunk_overlap)?,
             text_splitter,
         })
     }
 
     fn split_tree(&self, tree: &Tree, contents: &[u8]) -> anyhow::Result<Vec<Chunk>> {
-        Ok(self
-            .splitter
-            .split(tree, contents)?
+        let chunks = match self.granularity {
+            config::TreeSitterGranularity::Node => self.splitter.split(tree, contents)?,
+            config::TreeSitterGranularity::Declaration => {
+                self.splitter.split_by_declaration(tree, contents)?
+            }
+        };
+        let mut lines = LineCounter::new(contents);
+        Ok(chunks
             .into_iter()
             .map(|c| {
                 Chunk::new(
                     c.text.to_owned(),
-                    ByteRange::new(c.range.start_byte, c.range.end_byte),
+                    ByteRange::new(c.range.start_byte, c.range.end_byte)
+                        .with_start_line(lines.line_of(c.range.start_byte)),
                 )
             })
             .collect())
@@ -54,22 +65,29 @@ impl Splitter for TreeSitter {
         }
     }
 
-    fn split_file_contents(&self, uri: &str, contents: &str) -> Vec<Chunk> {
-        match parse_tree(uri, contents, None) {
+    fn split_file_contents(
+        &self,
+        uri: &str,
+        contents: &str,
+        language_id: Option<&str>,
+    ) -> Vec<Chunk> {
+        match parse_tree(uri, contents, None, language_id) {
             Ok(tree) => match self.split_tree(&tree, contents.as_bytes()) {
                 Ok(chunks) => chunks,
                 Err(e) => {
                     warn!(
                             "Failed to parse tree for file: {uri} with error: {e:?}. Falling back to default splitter.",
                         );
-                    self.text_splitter.split_file_contents(uri, contents)
+                    self.text_splitter
+                        .split_file_contents(uri, contents, language_id)
                 }
             },
             Err(e) => {
                 warn!(
                     "Failed to parse tree for file {uri} with error: {e:?}. Falling back to default splitter.",
                 );
-                self.text_splitter.split_file_contents(uri, contents)
+                self.text_splitter
+                    .split_file_contents(uri, contents, language_id)
             }
         }
     }
@@ -0,0 +1,232 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::custom_requests::stats::{LatencyBucket, ModelStats, StatsResult};
+
+// Upper bounds of the latency histogram buckets. Slower requests go in one final bucket
+const LATENCY_BUCKETS_MS: [u64; 7] = [100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+// Usage of every model since the server started or the stats were last reset
+pub(crate) static USAGE_STATS: Lazy<UsageStats> = Lazy::new(UsageStats::default);
+
+// The token usage a provider reports with its response
+// Deserializes from the OpenAI `usage` object. Other providers convert into it
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub(crate) struct Usage {
+    #[serde(default)]
+    pub(crate) prompt_tokens: u64,
+    #[serde(default)]
+    pub(crate) completion_tokens: u64,
+}
+
+impl Usage {
+    pub(crate) fn log(&self, provider: &str) {
+        info!(
+            "{provider} usage - prompt tokens: {}, completion tokens: {}",
+            self.prompt_tokens, self.completion_tokens
+        );
+    }
+}
+
+// Responses from models that may carry the token usage of the request
+pub(crate) trait ReportsUsage {
+    fn usage(&self) -> Option<Usage> {
+        None
+    }
+}
+
+// Streams report no usage
+impl ReportsUsage for () {}
+
+#[derive(Default)]
+struct ModelCounters {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    prompt_tokens: AtomicU64,
+    completion_tokens: AtomicU64,
+    total_latency_ms: AtomicU64,
+    latency_histogram: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+impl ModelCounters {
+    fn read(counter: &AtomicU64, reset: bool) -> u64 {
+        if reset {
+            counter.swap(0, Ordering::Relaxed)
+        } else {
+            counter.load(Ordering::Relaxed)
+        }
+    }
+
+    fn to_model_stats(&self, reset: bool) -> ModelStats {
+        let requests = Self::read(&self.requests, reset);
+        let total_latency_ms = Self::read(&self.total_latency_ms, reset);
+        ModelStats {
+            requests,
+            errors: Self::read(&self.errors, reset),
+            prompt_tokens: Self::read(&self.prompt_tokens, reset),
+            completion_tokens: Self::read(&self.completion_tokens, reset),
+            average_latency_ms: if requests > 0 {
+                total_latency_ms as f64 / requests as f64
+            } else {
+                0.
+            },
+            latency_histogram: self
+                .latency_histogram
+                .iter()
+                .enumerate()
+                .map(|(i, count)| LatencyBucket {
+                    le_ms: LATENCY_BUCKETS_MS.get(i).copied(),
+                    count: Self::read(count, reset),
+                })
+                .collect(),
+        }
+    }
+}
+
+// Counters are atomics so recording a request never waits on other requests
+#[derive(Default)]
+pub(crate) struct UsageStats {
+    models: RwLock<HashMap<String, Arc<ModelCounters>>>,
+}
+
+impl UsageStats {
+    fn get_counters(&self, model: &str) -> Arc<ModelCounters> {
+        if let Some(counters) = self.models.read().get(model) {
+            return counters.clone();
+        }
+        self.models
+            .write()
+            .entry(model.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub(crate) fn record(
+        &self,
+        model: &str,
+        latency: Duration,
+        usage: Option<Usage>,
+        is_error: bool,
+    ) {
+        let counters = self.get_counters(model);
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        if is_error {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(usage) = usage {
+            counters
+                .prompt_tokens
+                .fetch_add(usage.prompt_tokens, Ordering::Relaxed);
+            counters
+                .completion_tokens
+                .fetch_add(usage.completion_tokens, Ordering::Relaxed);
+        }
+        let latency_ms = latency.as_millis() as u64;
+        counters
+            .total_latency_ms
+            .fetch_add(latency_ms, Ordering::Relaxed);
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|le_ms| latency_ms <= *le_ms)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        counters.latency_histogram[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Times a call to `model` and records its outcome
+    pub(crate) async fn track<T: ReportsUsage>(
+        &self,
+        model: &str,
+        call: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let start = Instant::now();
+        let result = call.await;
+        self.record(
+            model,
+            start.elapsed(),
+            result.as_ref().ok().and_then(ReportsUsage::usage),
+            result.is_err(),
+        );
+        result
+    }
+
+    pub(crate) fn get_stats(&self, reset: bool) -> StatsResult {
+        StatsResult {
+            models: self
+                .models
+                .read()
+                .iter()
+                .map(|(model, counters)| (model.clone(), counters.to_model_stats(reset)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_and_reset_stats() {
+        let stats = UsageStats::default();
+        stats.record(
+            "model1",
+            Duration::from_millis(50),
+            Some(Usage {
+                prompt_tokens: 10,
+                completion_tokens: 5,
+            }),
+            false,
+        );
+        stats.record("model1", Duration::from_millis(650), None, true);
+        stats.record("model1", Duration::from_secs(20), None, false);
+
+        let stats_result = stats.get_stats(true);
+        let model_stats = &stats_result.models["model1"];
+        assert_eq!(model_stats.requests, 3);
+        assert_eq!(model_stats.errors, 1);
+        assert_eq!(model_stats.prompt_tokens, 10);
+        assert_eq!(model_stats.completion_tokens, 5);
+        assert_eq!(model_stats.average_latency_ms, 6900.);
+        let counts: Vec<u64> = model_stats
+            .latency_histogram
+            .iter()
+            .map(|bucket| bucket.count)
+            .collect();
+        assert_eq!(counts, vec![1, 0, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(model_stats.latency_histogram[7].le_ms, None);
+
+        assert_eq!(
+            stats.get_stats(false).models["model1"],
+            ModelCounters::default().to_model_stats(false)
+        );
+    }
+
+    #[test]
+    fn parse_usage() -> anyhow::Result<()> {
+        let usage: Usage = serde_json::from_value(serde_json::json!({
+            "prompt_tokens": 12,
+            "completion_tokens": 3,
+            "total_tokens": 15
+        }))?;
+        assert_eq!(
+            usage,
+            Usage {
+                prompt_tokens: 12,
+                completion_tokens: 3
+            }
+        );
+        Ok(())
+    }
+}
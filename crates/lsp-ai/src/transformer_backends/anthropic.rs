@@ -3,43 +3,73 @@ use std::collections::HashMap;
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use tokio::sync::mpsc::UnboundedSender;
 use tracing::{info, instrument};
 
 use crate::{
-    config::{self, ChatMessage},
+    config::{self, ChatMessage, FIMMode},
     memory_backends::Prompt,
-    transformer_worker::{
-        DoGenerationResponse, DoGenerationStreamResponse, GenerationStreamRequest,
-    },
-    utils::format_chat_messages,
+    stats::Usage,
+    transformer_worker::{DoGenerationResponse, DoGenerationStreamResponse},
+    utils::{format_chat_messages, http_client},
 };
 
-use super::TransformerBackend;
+use super::{
+    build_fim_instruct_messages, send_checked, sse::SseParser, BackendError, TransformerBackend,
+    GENERATION_TEMPERATURE_DEFAULT, GENERATION_TOP_P_DEFAULT,
+};
 
 const fn max_tokens_default() -> usize {
     64
 }
 
 const fn top_p_default() -> f32 {
-    0.95
+    GENERATION_TOP_P_DEFAULT
 }
 
 const fn temperature_default() -> f32 {
-    0.1
+    GENERATION_TEMPERATURE_DEFAULT
 }
 
 // NOTE: We cannot deny unknown fields as the provided parameters may contain other fields relevant to other processes
 #[derive(Debug, Deserialize)]
 pub(crate) struct AnthropicRunParams {
-    system: String,
+    // Required for chat prompts. FIM prompts use their own instruction
+    system: Option<String>,
     #[serde(default)]
     messages: Vec<ChatMessage>,
+    // FIM prompts can only be sent with the `instruct` mode as Anthropic has no completion endpoint
+    #[serde(default)]
+    pub(crate) fim_mode: FIMMode,
     #[serde(default = "max_tokens_default")]
     pub(crate) max_tokens: usize,
     #[serde(default = "top_p_default")]
     pub(crate) top_p: f32,
     #[serde(default = "temperature_default")]
     pub(crate) temperature: f32,
+    // Seeds the start of the assistant's reply, e.g. "```rust". The reply continues from it
+    prefill: Option<String>,
+    // Prepend the prefill to the returned text
+    #[serde(default)]
+    include_prefill: bool,
+}
+
+impl AnthropicRunParams {
+    // The final assistant message cannot end in whitespace
+    fn prefill(&self) -> Option<&str> {
+        self.prefill
+            .as_deref()
+            .map(str::trim_end)
+            .filter(|prefill| !prefill.is_empty())
+    }
+
+    fn returned_prefill(&self) -> Option<String> {
+        if self.include_prefill {
+            self.prefill().map(str::to_string)
+        } else {
+            None
+        }
+    }
 }
 
 pub(crate) struct Anthropic {
@@ -49,6 +79,20 @@ pub(crate) struct Anthropic {
 #[derive(Deserialize, Serialize)]
 struct AnthropicResponse {
     content: Vec<AnthropicChatMessage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: usize,
+    #[serde(default)]
+    output_tokens: usize,
+    #[serde(default)]
+    cache_creation_input_tokens: usize,
+    #[serde(default)]
+    cache_read_input_tokens: usize,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -69,63 +113,210 @@ enum ChatResponse {
     Other(HashMap<String, Value>),
 }
 
+#[derive(Deserialize)]
+struct AnthropicStreamMessage {
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicStreamDelta {
+    TextDelta {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+// See: https://docs.anthropic.com/en/api/messages-streaming
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicStreamEvent {
+    MessageStart {
+        message: AnthropicStreamMessage,
+    },
+    ContentBlockDelta {
+        delta: AnthropicStreamDelta,
+    },
+    MessageStop,
+    Ping,
+    Error {
+        error: Value,
+    },
+    // content_block_start, content_block_stop and message_delta
+    #[serde(other)]
+    Other,
+}
+
+// Cached input tokens are reported separately from `input_tokens` but are still part of the prompt
+impl From<&AnthropicUsage> for Usage {
+    fn from(usage: &AnthropicUsage) -> Self {
+        Self {
+            prompt_tokens: (usage.input_tokens
+                + usage.cache_creation_input_tokens
+                + usage.cache_read_input_tokens) as u64,
+            completion_tokens: usage.output_tokens as u64,
+        }
+    }
+}
+
+fn log_usage(usage: &AnthropicUsage) {
+    info!(
+        "Anthropic usage - input tokens: {}, output tokens: {}, cache creation input tokens: {}, cache read input tokens: {}",
+        usage.input_tokens,
+        usage.output_tokens,
+        usage.cache_creation_input_tokens,
+        usage.cache_read_input_tokens
+    );
+}
+
+// When prompt caching is enabled the system prompt and the last user message are sent as
+// content blocks marked with an ephemeral `cache_control` so the stable prefix can be reused
+fn build_system_and_messages(
+    system_prompt: String,
+    messages: Vec<ChatMessage>,
+    enable_prompt_caching: bool,
+) -> (Value, Value) {
+    if !enable_prompt_caching {
+        return (json!(system_prompt), json!(messages));
+    }
+    let system = json!([{
+        "type": "text",
+        "text": system_prompt,
+        "cache_control": {"type": "ephemeral"}
+    }]);
+    let last_user_message = messages.iter().rposition(|m| m.role == "user");
+    let messages = messages
+        .into_iter()
+        .enumerate()
+        .map(|(i, m)| {
+            if Some(i) == last_user_message {
+                json!({
+                    "role": m.role,
+                    "content": [{
+                        "type": "text",
+                        "text": m.content,
+                        "cache_control": {"type": "ephemeral"}
+                    }]
+                })
+            } else {
+                json!(m)
+            }
+        })
+        .collect::<Vec<Value>>();
+    (system, Value::Array(messages))
+}
+
 impl Anthropic {
     pub(crate) fn new(config: config::Anthropic) -> Self {
         Self { config }
     }
 
-    async fn get_chat(
-        &self,
-        system_prompt: String,
-        messages: Vec<ChatMessage>,
-        params: AnthropicRunParams,
-    ) -> anyhow::Result<String> {
-        let client = reqwest::Client::new();
-        let token = if let Some(env_var_name) = &self.config.auth_token_env_var_name {
-            std::env::var(env_var_name)?
+    fn get_token(&self) -> anyhow::Result<String> {
+        if let Some(env_var_name) = &self.config.auth_token_env_var_name {
+            Ok(std::env::var(env_var_name)?)
         } else if let Some(token) = &self.config.auth_token {
-            token.to_string()
+            Ok(token.to_string())
         } else {
             anyhow::bail!(
                 "Please set `auth_token_env_var_name` or `auth_token` to use an Anthropic"
             );
+        }
+    }
+
+    fn build_params(
+        &self,
+        prompt: &Prompt,
+        params: &AnthropicRunParams,
+        stream: bool,
+    ) -> anyhow::Result<Value> {
+        let mut messages = match prompt {
+            Prompt::ContextAndCode(context_and_code) => {
+                let system = params
+                    .system
+                    .clone()
+                    .context("`system` is required for Anthropic chat prompts")?;
+                let mut messages = vec![ChatMessage::new("system".to_string(), system)];
+                messages.extend_from_slice(&params.messages);
+                format_chat_messages(&messages, context_and_code)
+            }
+            Prompt::FIM(fim_prompt) if params.fim_mode == FIMMode::Instruct => {
+                build_fim_instruct_messages(fim_prompt)
+            }
+            Prompt::FIM(_) => anyhow::bail!(
+                "Anthropic does not support FIM tokens, set `fim_mode` to `instruct` to use FIM"
+            ),
         };
-        let params = json!({
+        let system_prompt = messages.remove(0).content;
+        let (system, mut messages) =
+            build_system_and_messages(system_prompt, messages, self.config.enable_prompt_caching);
+        if let (Some(prefill), Value::Array(messages)) = (params.prefill(), &mut messages) {
+            messages.push(json!({"role": "assistant", "content": prefill}));
+        }
+        let mut params = json!({
             "model": self.config.model,
-            "system": system_prompt,
+            "system": system,
             "max_tokens": params.max_tokens,
             "top_p": params.top_p,
             "temperature": params.temperature,
             "messages": messages
         });
+        if stream {
+            params["stream"] = Value::Bool(true);
+        }
         info!(
             "Calling Anthropic compatible API with parameters:\n{}",
             serde_json::to_string_pretty(&params).unwrap()
         );
-        let res: ChatResponse = client
-            .post(
-                self.config
-                    .chat_endpoint
-                    .as_ref()
-                    .context("must specify `completions_endpoint` to use completions")?,
-            )
-            .header("x-api-key", token)
-            .header("anthropic-version", "2023-06-01")
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .json(&params)
-            .send()
-            .await?
-            .json()
-            .await?;
+        Ok(params)
+    }
+
+    async fn send(&self, params: &Value) -> anyhow::Result<reqwest::Response> {
+        let client = http_client();
+        send_checked(
+            client
+                .post(
+                    self.config
+                        .chat_endpoint
+                        .as_ref()
+                        .context("must specify `chat_endpoint` to use chat")?,
+                )
+                .header("x-api-key", self.get_token()?)
+                .header("anthropic-version", "2023-06-01")
+                .header("Content-Type", "application/json")
+                .json(params),
+        )
+        .await
+    }
+
+    async fn do_get_chat(
+        &self,
+        prompt: &Prompt,
+        params: AnthropicRunParams,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let returned_prefill = params.returned_prefill();
+        let params = self.build_params(prompt, &params, false)?;
+        let res: ChatResponse = self.send(&params).await?.json().await?;
         info!(
             "Response from Anthropic compatible API:\n{}",
             serde_json::to_string_pretty(&res).unwrap()
         );
         match res {
-            ChatResponse::Success(mut resp) => Ok(std::mem::take(&mut resp.content[0].text)),
+            ChatResponse::Success(mut resp) => {
+                if let Some(usage) = &resp.usage {
+                    log_usage(usage);
+                }
+                let text = std::mem::take(&mut resp.content[0].text);
+                Ok(DoGenerationResponse {
+                    generated_text: match returned_prefill {
+                        Some(prefill) => format!("{prefill}{text}"),
+                        None => text,
+                    },
+                    usage: resp.usage.as_ref().map(Usage::from),
+                })
+            }
             ChatResponse::Error(error) => {
-                anyhow::bail!("making Anthropic request: {:?}", error.error.to_string())
+                Err(BackendError::from_provider_error(&error.error).into())
             }
             ChatResponse::Other(other) => {
                 anyhow::bail!("unknown error while making Anthropic request: {:?}", other)
@@ -133,19 +324,56 @@ impl Anthropic {
         }
     }
 
-    async fn do_get_chat(
+    async fn do_get_chat_stream(
         &self,
         prompt: &Prompt,
         params: AnthropicRunParams,
-    ) -> anyhow::Result<String> {
-        let mut messages = vec![ChatMessage::new(
-            "system".to_string(),
-            params.system.clone(),
-        )];
-        messages.extend_from_slice(&params.messages);
-        let mut messages = format_chat_messages(&messages, prompt.try_into()?);
-        let system_prompt = messages.remove(0).content;
-        self.get_chat(system_prompt, messages, params).await
+        tx: UnboundedSender<DoGenerationStreamResponse>,
+    ) -> anyhow::Result<()> {
+        let returned_prefill = params.returned_prefill();
+        let params = self.build_params(prompt, &params, true)?;
+        let mut res = self.send(&params).await?;
+        if let Some(generated_text) = returned_prefill {
+            if tx
+                .send(DoGenerationStreamResponse { generated_text })
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+        let mut parser = SseParser::default();
+        while let Some(chunk) = res.chunk().await.map_err(BackendError::from)? {
+            for event in parser.push(&chunk) {
+                match serde_json::from_str(&event.data)? {
+                    AnthropicStreamEvent::MessageStart { message } => {
+                        if let Some(usage) = &message.usage {
+                            log_usage(usage);
+                        }
+                    }
+                    AnthropicStreamEvent::ContentBlockDelta {
+                        delta: AnthropicStreamDelta::TextDelta { text },
+                    } => {
+                        // The receiver is gone if the request was dropped
+                        if tx
+                            .send(DoGenerationStreamResponse {
+                                generated_text: text,
+                            })
+                            .is_err()
+                        {
+                            return Ok(());
+                        }
+                    }
+                    AnthropicStreamEvent::MessageStop => return Ok(()),
+                    AnthropicStreamEvent::Error { error } => {
+                        return Err(BackendError::from_provider_error(&error).into())
+                    }
+                    AnthropicStreamEvent::ContentBlockDelta { .. }
+                    | AnthropicStreamEvent::Ping
+                    | AnthropicStreamEvent::Other => (),
+                }
+            }
+        }
+        Ok(())
     }
 }
 
@@ -158,17 +386,18 @@ impl TransformerBackend for Anthropic {
         params: Value,
     ) -> anyhow::Result<DoGenerationResponse> {
         let params: AnthropicRunParams = serde_json::from_value(params)?;
-        let generated_text = self.do_get_chat(prompt, params).await?;
-        Ok(DoGenerationResponse { generated_text })
+        self.do_get_chat(prompt, params).await
     }
 
     #[instrument(skip(self))]
     async fn do_generate_stream(
         &self,
-        request: &GenerationStreamRequest,
-        _params: Value,
-    ) -> anyhow::Result<DoGenerationStreamResponse> {
-        anyhow::bail!("GenerationStream is not yet implemented")
+        prompt: &Prompt,
+        params: Value,
+        tx: UnboundedSender<DoGenerationStreamResponse>,
+    ) -> anyhow::Result<()> {
+        let params: AnthropicRunParams = serde_json::from_value(params)?;
+        self.do_get_chat_stream(prompt, params, tx).await
     }
 }
 
@@ -177,6 +406,115 @@ mod test {
     use super::*;
     use serde_json::{from_value, json};
 
+    #[test]
+    fn anthropic_parse_usage() -> anyhow::Result<()> {
+        let res: ChatResponse = from_value(json!({
+            "id": "msg_123",
+            "content": [{"type": "text", "text": "Hello"}],
+            "usage": {
+                "input_tokens": 10,
+                "output_tokens": 25,
+                "cache_read_input_tokens": 100
+            }
+        }))?;
+        let ChatResponse::Success(resp) = res else {
+            anyhow::bail!("expected a successful response")
+        };
+        assert_eq!(
+            resp.usage.as_ref().map(Usage::from),
+            Some(Usage {
+                prompt_tokens: 110,
+                completion_tokens: 25
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn anthropic_build_system_and_messages() {
+        let messages = vec![
+            ChatMessage::new("user".to_string(), "Context".to_string()),
+            ChatMessage::new("assistant".to_string(), "Ok".to_string()),
+            ChatMessage::new("user".to_string(), "Code".to_string()),
+        ];
+
+        let (system, body) = build_system_and_messages("Test".to_string(), messages.clone(), false);
+        assert_eq!(system, json!("Test"));
+        assert_eq!(body[2], json!({"role": "user", "content": "Code"}));
+
+        let (system, body) = build_system_and_messages("Test".to_string(), messages, true);
+        assert_eq!(
+            system,
+            json!([{"type": "text", "text": "Test", "cache_control": {"type": "ephemeral"}}])
+        );
+        assert_eq!(body[0], json!({"role": "user", "content": "Context"}));
+        assert_eq!(
+            body[2],
+            json!({
+                "role": "user",
+                "content": [{"type": "text", "text": "Code", "cache_control": {"type": "ephemeral"}}]
+            })
+        );
+    }
+
+    #[test]
+    fn anthropic_build_params_with_prefill() -> anyhow::Result<()> {
+        let anthropic = Anthropic::new(from_value(json!({
+            "chat_endpoint": "https://api.anthropic.com/v1/messages",
+            "model": "claude-3-haiku-20240307",
+            "auth_token": "test"
+        }))?);
+        let params: AnthropicRunParams = from_value(json!({
+            "system": "Test",
+            "messages": [
+                {
+                    "role": "user",
+                    "content": "Test {CONTEXT} - {CODE}"
+                }
+            ],
+            "prefill": "```rust\n",
+            "include_prefill": true
+        }))?;
+        let body = anthropic.build_params(&Prompt::default_with_cursor(), &params, false)?;
+        assert_eq!(
+            body["messages"][1],
+            json!({"role": "assistant", "content": "```rust"})
+        );
+        assert_eq!(params.returned_prefill().as_deref(), Some("```rust"));
+
+        let params: AnthropicRunParams = from_value(json!({
+            "system": "Test",
+            "prefill": "```rust"
+        }))?;
+        assert_eq!(params.returned_prefill(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn anthropic_build_params_with_fim_instruct() -> anyhow::Result<()> {
+        let anthropic = Anthropic::new(from_value(json!({
+            "chat_endpoint": "https://api.anthropic.com/v1/messages",
+            "model": "claude-3-haiku-20240307",
+            "auth_token": "test"
+        }))?);
+        let params: AnthropicRunParams = from_value(json!({ "fim_mode": "instruct" }))?;
+        let body = anthropic.build_params(&Prompt::default_fim(), &params, false)?;
+        assert_eq!(
+            body["system"],
+            json!(super::super::FIM_INSTRUCT_SYSTEM_MESSAGE)
+        );
+        assert_eq!(body["messages"][0]["role"], "user");
+
+        let params: AnthropicRunParams = from_value(json!({}))?;
+        assert!(anthropic
+            .build_params(&Prompt::default_fim(), &params, false)
+            .is_err());
+        assert!(anthropic
+            .build_params(&Prompt::default_with_cursor(), &params, false)
+            .is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn anthropic_chat_do_generate() -> anyhow::Result<()> {
         let configuration: config::Anthropic = from_value(json!({
@@ -0,0 +1,326 @@
+use std::{fmt, time::Duration};
+
+use reqwest::{header::HeaderMap, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+// Failures of a request to a model provider the worker can act on
+// Backends return them inside `anyhow::Error`s so the worker finds them with `downcast_ref`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum BackendError {
+    // The API key is missing, invalid or not allowed to use the model
+    Auth {
+        message: String,
+    },
+    // `retry_after` is set when the provider says how long to wait
+    RateLimited {
+        retry_after: Option<Duration>,
+    },
+    // The provider rejected the request, usually because of the model or its parameters
+    BadRequest {
+        message: String,
+    },
+    // The provider could not be reached or the connection dropped
+    Network {
+        message: String,
+    },
+    Timeout,
+    // The provider failed or is overloaded (5xx, or 529 from Anthropic)
+    Unavailable {
+        message: String,
+    },
+    // Any other error the provider reported
+    Provider {
+        code: Option<String>,
+        message: String,
+    },
+}
+
+impl BackendError {
+    // Whether sending the same request again later may succeed
+    pub(crate) fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::RateLimited { .. }
+                | Self::Network { .. }
+                | Self::Timeout
+                | Self::Unavailable { .. }
+        )
+    }
+
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Self::Auth { .. } => "auth",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::BadRequest { .. } => "bad_request",
+            Self::Network { .. } => "network",
+            Self::Timeout => "timeout",
+            Self::Unavailable { .. } => "unavailable",
+            Self::Provider { .. } => "provider",
+        }
+    }
+
+    // Sent as the `data` of LSP error responses so clients can tell errors apart
+    pub(crate) fn to_response_data(&self) -> Value {
+        match self {
+            Self::RateLimited {
+                retry_after: Some(retry_after),
+            } => json!({ "kind": self.kind(), "retry_after": retry_after.as_secs_f32() }),
+            _ => json!({ "kind": self.kind() }),
+        }
+    }
+
+    // Maps the error object of a body like `{ "error": { "message": "...", "code": "..." } }`
+    // Every provider we support reports errors in roughly this shape, Ollama uses a plain string
+    pub(crate) fn from_provider_error(error: &Value) -> Self {
+        let (code, message) = openai_error_code_and_message(error);
+        Self::Provider { code, message }
+    }
+
+    // Maps a failed response from a provider. Bodies that are not JSON should be passed as a JSON
+    // string
+    pub(crate) fn from_response(status: StatusCode, headers: &HeaderMap, body: &Value) -> Self {
+        let error = match &body["error"] {
+            Value::Null => body,
+            error => error,
+        };
+        let (code, message) = openai_error_code_and_message(error);
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::Auth { message },
+            // An exhausted quota is reported as a rate limit but waiting does not help
+            StatusCode::TOO_MANY_REQUESTS if code.as_deref() != Some("insufficient_quota") => {
+                Self::RateLimited {
+                    retry_after: parse_retry_after(headers),
+                }
+            }
+            StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND | StatusCode::UNPROCESSABLE_ENTITY => {
+                Self::BadRequest { message }
+            }
+            StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => Self::Timeout,
+            _ if matches!(status.as_u16(), 500 | 502 | 503 | 529) => Self::Unavailable { message },
+            _ => Self::Provider {
+                code: code.or_else(|| Some(status.as_u16().to_string())),
+                message,
+            },
+        }
+    }
+}
+
+impl From<reqwest::Error> for BackendError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            Self::Timeout
+        } else {
+            Self::Network {
+                message: error.to_string(),
+            }
+        }
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auth { message } => {
+                write!(f, "authentication failed - check the API key: {message}")
+            }
+            Self::RateLimited {
+                retry_after: Some(retry_after),
+            } => write!(
+                f,
+                "rate limited - retry after {:.1}s",
+                retry_after.as_secs_f32()
+            ),
+            Self::RateLimited { retry_after: None } => write!(f, "rate limited"),
+            Self::BadRequest { message } => write!(f, "the request was rejected: {message}"),
+            Self::Network { message } => write!(f, "could not reach the model provider: {message}"),
+            Self::Timeout => write!(f, "the model provider timed out"),
+            Self::Unavailable { message } => {
+                write!(f, "the model provider is unavailable: {message}")
+            }
+            Self::Provider {
+                code: Some(code),
+                message,
+            } => write!(
+                f,
+                "the model provider returned an error ({code}): {message}"
+            ),
+            Self::Provider {
+                code: None,
+                message,
+            } => write!(f, "the model provider returned an error: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+// Sends the request, mapping failures and unsuccessful responses into `BackendError`s
+pub(crate) async fn send_checked(
+    request: reqwest::RequestBuilder,
+) -> anyhow::Result<reqwest::Response> {
+    let response = request.send().await.map_err(BackendError::from)?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let headers = response.headers().clone();
+    let text = response.text().await.map_err(BackendError::from)?;
+    let body = serde_json::from_str(&text).unwrap_or(Value::String(text));
+    Err(BackendError::from_response(status, &headers, &body).into())
+}
+
+// Like `send_checked` but parses the body of successful responses
+pub(crate) async fn send_request<T: DeserializeOwned>(
+    request: reqwest::RequestBuilder,
+) -> anyhow::Result<T> {
+    Ok(send_checked(request).await?.json().await?)
+}
+
+fn openai_error_code_and_message(error: &Value) -> (Option<String>, String) {
+    let code = match &error["code"] {
+        Value::String(code) => Some(code.clone()),
+        Value::Number(code) => Some(code.to_string()),
+        _ => error["type"].as_str().map(str::to_string),
+    };
+    let message = match error["message"].as_str().or(error.as_str()) {
+        Some(message) => message.to_string(),
+        None => error.to_string(),
+    };
+    (code, message)
+}
+
+// Reads `retry-after-ms` or `retry-after` in seconds. HTTP dates are not supported
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name| headers.get(name)?.to_str().ok()?.trim().parse::<f64>().ok();
+    header("retry-after-ms")
+        .map(|ms| ms / 1000.)
+        .or_else(|| header("retry-after"))
+        .filter(|secs| secs.is_finite() && *secs >= 0.)
+        .map(Duration::from_secs_f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::ToResponseError;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn map_openai_error_responses() {
+        let body = json!({
+            "error": {
+                "message": "Incorrect API key provided",
+                "type": "invalid_request_error",
+                "code": "invalid_api_key"
+            }
+        });
+        assert_eq!(
+            BackendError::from_response(StatusCode::UNAUTHORIZED, &HeaderMap::new(), &body),
+            BackendError::Auth {
+                message: "Incorrect API key provided".to_string()
+            }
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after-ms", HeaderValue::from_static("1500"));
+        let error = BackendError::from_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            &headers,
+            &json!({ "error": { "message": "Rate limit reached", "code": "rate_limit_exceeded" } }),
+        );
+        assert_eq!(
+            error,
+            BackendError::RateLimited {
+                retry_after: Some(Duration::from_millis(1500))
+            }
+        );
+        assert!(error.is_transient());
+        assert_eq!(
+            error.to_response_data(),
+            json!({ "kind": "rate_limited", "retry_after": 1.5 })
+        );
+
+        let error = BackendError::from_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            &HeaderMap::new(),
+            &json!({ "error": { "message": "You exceeded your quota", "code": "insufficient_quota" } }),
+        );
+        assert!(!error.is_transient());
+
+        assert_eq!(
+            BackendError::from_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &HeaderMap::new(),
+                &json!("upstream connect error")
+            ),
+            BackendError::Unavailable {
+                message: "upstream connect error".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn map_other_provider_error_responses() {
+        // Anthropic
+        let error = BackendError::from_response(
+            StatusCode::from_u16(529).unwrap(),
+            &HeaderMap::new(),
+            &json!({ "type": "error", "error": { "type": "overloaded_error", "message": "Overloaded" } }),
+        );
+        assert_eq!(
+            error,
+            BackendError::Unavailable {
+                message: "Overloaded".to_string()
+            }
+        );
+        assert!(error.is_transient());
+
+        // Ollama
+        assert_eq!(
+            BackendError::from_response(
+                StatusCode::NOT_FOUND,
+                &HeaderMap::new(),
+                &json!({ "error": "model \"llama9\" not found, try pulling it first" })
+            ),
+            BackendError::BadRequest {
+                message: "model \"llama9\" not found, try pulling it first".to_string()
+            }
+        );
+
+        // Gemini
+        assert_eq!(
+            BackendError::from_response(
+                StatusCode::FORBIDDEN,
+                &HeaderMap::new(),
+                &json!({ "error": { "code": 403, "message": "API key not valid", "status": "PERMISSION_DENIED" } })
+            ),
+            BackendError::Auth {
+                message: "API key not valid".to_string()
+            }
+        );
+
+        // llama.cpp server
+        let error = BackendError::from_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            &HeaderMap::new(),
+            &json!({ "error": { "code": 503, "message": "Loading model", "type": "unavailable_error" } }),
+        );
+        assert!(error.is_transient());
+        assert_eq!(error.to_response_data(), json!({ "kind": "unavailable" }));
+    }
+
+    #[test]
+    fn provider_message_reaches_response_error() {
+        let error: anyhow::Error = BackendError::from_provider_error(
+            &json!({ "message": "The model `gpt-5` does not exist", "code": "model_not_found" }),
+        )
+        .into();
+        let response_error = error.to_response_error(-32603);
+        assert_eq!(
+            response_error.message,
+            "the model provider returned an error (model_not_found): The model `gpt-5` does not exist"
+        );
+        assert_eq!(response_error.data, Some(json!({ "kind": "provider" })));
+    }
+}
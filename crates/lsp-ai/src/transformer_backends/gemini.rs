@@ -1,16 +1,15 @@
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use tokio::sync::mpsc::UnboundedSender;
 use tracing::{info, instrument};
 
-use super::TransformerBackend;
+use super::{build_fim_instruct_messages, send_request, BackendError, TransformerBackend};
 use crate::{
-    config,
+    config::{self, FIMMode},
     memory_backends::{ContextAndCodePrompt, Prompt},
-    transformer_worker::{
-        DoGenerationResponse, DoGenerationStreamResponse, GenerationStreamRequest,
-    },
-    utils::format_prompt_in_str,
+    transformer_worker::{DoGenerationResponse, DoGenerationStreamResponse},
+    utils::{format_prompt_in_str, http_client},
 };
 
 fn format_gemini_contents(
@@ -62,8 +61,8 @@ pub(crate) struct GeminiGenerationConfig {
     #[serde(rename = "stopSequences")]
     pub(crate) stop_sequences: Vec<String>,
     #[serde(rename = "maxOutputTokens")]
-    #[serde(default = "max_tokens_default")]
-    pub(crate) max_output_tokens: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_output_tokens: Option<usize>,
     pub(crate) temperature: Option<f32>,
     #[serde(rename = "topP")]
     pub(crate) top_p: Option<f32>,
@@ -72,14 +71,44 @@ pub(crate) struct GeminiGenerationConfig {
 }
 
 // NOTE: We cannot deny unknown fields as the provided parameters may contain other fields relevant to other processes
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Clone)]
 #[serde(rename = "camelCase")]
 pub(crate) struct GeminiRunParams {
+    #[serde(default)]
     contents: Vec<GeminiContent>,
+    // Required for chat prompts. FIM prompts use their own instruction
     #[serde(rename = "systemInstruction")]
-    system_instruction: GeminiContent,
+    system_instruction: Option<GeminiContent>,
+    // FIM prompts can only be sent with the `instruct` mode as Gemini has no completion endpoint
+    #[serde(default)]
+    fim_mode: FIMMode,
     #[serde(rename = "generationConfig")]
     generation_config: Option<GeminiGenerationConfig>,
+    // Mapped to `generationConfig.maxOutputTokens` if it is not set
+    max_tokens: Option<usize>,
+}
+
+impl GeminiRunParams {
+    fn generation_config(&self) -> Option<GeminiGenerationConfig> {
+        match (&self.generation_config, self.max_tokens) {
+            (Some(generation_config), max_tokens) => {
+                let mut generation_config = generation_config.clone();
+                generation_config.max_output_tokens = generation_config
+                    .max_output_tokens
+                    .or(max_tokens)
+                    .or(Some(max_tokens_default()));
+                Some(generation_config)
+            }
+            (None, Some(max_tokens)) => Some(GeminiGenerationConfig {
+                stop_sequences: vec![],
+                max_output_tokens: Some(max_tokens),
+                temperature: None,
+                top_p: None,
+                top_k: None,
+            }),
+            (None, None) => None,
+        }
+    }
 }
 
 pub(crate) struct Gemini {
@@ -105,39 +134,39 @@ impl Gemini {
 
     async fn get_chat(
         &self,
+        system_instruction: GeminiContent,
         messages: Vec<GeminiContent>,
         params: GeminiRunParams,
     ) -> anyhow::Result<String> {
-        let client = reqwest::Client::new();
+        let client = http_client();
         let token = self.get_token()?;
         let params = json!({
              "contents": messages,
-             "systemInstruction": params.system_instruction,
-             "generationConfig": params.generation_config,
+             "systemInstruction": system_instruction,
+             "generationConfig": params.generation_config(),
         });
         info!(
             "Calling Gemini compatible chat API with parameters:\n{}",
             serde_json::to_string_pretty(&params).unwrap()
         );
-        let res: serde_json::Value = client
-            .post(
-                self.configuration
-                    .chat_endpoint
-                    .as_ref()
-                    .context("must specify `chat_endpoint` to use gemini")?
-                    .to_owned()
-                    + self.configuration.model.as_ref()
-                    + ":generateContent?key="
-                    + token.as_ref(),
-            )
-            .header("Content-Type", "application/json")
-            .json(&params)
-            .send()
-            .await?
-            .json()
-            .await?;
+        let res: serde_json::Value = send_request(
+            client
+                .post(
+                    self.configuration
+                        .chat_endpoint
+                        .as_ref()
+                        .context("must specify `chat_endpoint` to use gemini")?
+                        .to_owned()
+                        + self.configuration.model.as_ref()
+                        + ":generateContent?key="
+                        + token.as_ref(),
+                )
+                .header("Content-Type", "application/json")
+                .json(&params),
+        )
+        .await?;
         if let Some(error) = res.get("error") {
-            anyhow::bail!("{:?}", error.to_string())
+            Err(BackendError::from_provider_error(error).into())
         } else if let Some(candidates) = res.get("candidates") {
             Ok(candidates
                 .get(0)
@@ -161,13 +190,34 @@ impl Gemini {
         prompt: &Prompt,
         params: GeminiRunParams,
     ) -> anyhow::Result<String> {
-        match prompt {
-            Prompt::ContextAndCode(code_and_context) => {
-                let messages = format_gemini_contents(&params.contents, code_and_context);
-                self.get_chat(messages, params).await
-            }
-            _ => anyhow::bail!("Google Gemini backend does not yet support FIM"),
+        let (system_instruction, messages) = build_gemini_contents(prompt, &params)?;
+        self.get_chat(system_instruction, messages, params).await
+    }
+}
+
+// The system instruction and contents sent for a prompt
+fn build_gemini_contents(
+    prompt: &Prompt,
+    params: &GeminiRunParams,
+) -> anyhow::Result<(GeminiContent, Vec<GeminiContent>)> {
+    match prompt {
+        Prompt::ContextAndCode(code_and_context) => Ok((
+            params
+                .system_instruction
+                .clone()
+                .context("`systemInstruction` is required for Gemini chat prompts")?,
+            format_gemini_contents(&params.contents, code_and_context),
+        )),
+        Prompt::FIM(fim_prompt) if params.fim_mode == FIMMode::Instruct => {
+            let mut messages = build_fim_instruct_messages(fim_prompt)
+                .into_iter()
+                .map(|m| GeminiContent::new(m.role, vec![Part { text: m.content }]));
+            let system_instruction = messages.next().context("missing FIM instruction")?;
+            Ok((system_instruction, messages.collect()))
         }
+        Prompt::FIM(_) => anyhow::bail!(
+            "Gemini does not support FIM tokens, set `fim_mode` to `instruct` to use FIM"
+        ),
     }
 }
 
@@ -181,15 +231,19 @@ impl TransformerBackend for Gemini {
     ) -> anyhow::Result<DoGenerationResponse> {
         let params: GeminiRunParams = serde_json::from_value(params)?;
         let generated_text = self.do_chat_completion(prompt, params).await?;
-        Ok(DoGenerationResponse { generated_text })
+        Ok(DoGenerationResponse {
+            generated_text,
+            usage: None,
+        })
     }
 
     #[instrument(skip(self))]
     async fn do_generate_stream(
         &self,
-        request: &GenerationStreamRequest,
+        _prompt: &Prompt,
         _params: Value,
-    ) -> anyhow::Result<DoGenerationStreamResponse> {
+        _tx: UnboundedSender<DoGenerationStreamResponse>,
+    ) -> anyhow::Result<()> {
         anyhow::bail!("GenerationStream is not yet implemented")
     }
 }
@@ -199,6 +253,65 @@ mod test {
     use super::*;
     use serde_json::json;
 
+    #[test]
+    fn gemini_max_tokens_maps_to_max_output_tokens() -> anyhow::Result<()> {
+        let system_instruction = json!({
+            "role": "system",
+            "parts": [{ "text": "Test" }]
+        });
+        let params: GeminiRunParams = serde_json::from_value(json!({
+            "systemInstruction": system_instruction,
+            "contents": [],
+            "max_tokens": 32
+        }))?;
+        assert_eq!(
+            params.generation_config().unwrap().max_output_tokens,
+            Some(32)
+        );
+
+        let params: GeminiRunParams = serde_json::from_value(json!({
+            "systemInstruction": system_instruction,
+            "contents": [],
+            "max_tokens": 32,
+            "generationConfig": {
+                "maxOutputTokens": 10
+            }
+        }))?;
+        assert_eq!(
+            params.generation_config().unwrap().max_output_tokens,
+            Some(10)
+        );
+
+        let params: GeminiRunParams = serde_json::from_value(json!({
+            "systemInstruction": system_instruction,
+            "contents": [],
+            "generationConfig": {}
+        }))?;
+        assert_eq!(
+            params.generation_config().unwrap().max_output_tokens,
+            Some(64)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn gemini_build_contents_with_fim_instruct() -> anyhow::Result<()> {
+        let params: GeminiRunParams = serde_json::from_value(json!({ "fim_mode": "instruct" }))?;
+        let (system_instruction, contents) =
+            build_gemini_contents(&Prompt::default_fim(), &params)?;
+        assert_eq!(
+            system_instruction.parts[0].text,
+            super::super::FIM_INSTRUCT_SYSTEM_MESSAGE
+        );
+        assert_eq!(contents.len(), 1);
+        assert_eq!(contents[0].role, "user");
+
+        let params: GeminiRunParams = serde_json::from_value(json!({}))?;
+        assert!(build_gemini_contents(&Prompt::default_fim(), &params).is_err());
+        assert!(build_gemini_contents(&Prompt::default_with_cursor(), &params).is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn gemini_chat_do_generate() -> anyhow::Result<()> {
         let configuration: config::Gemini = serde_json::from_value(json!({
@@ -0,0 +1,212 @@
+use anyhow::Context;
+use serde_json::Value;
+
+// Rules shared by every compiled schema. Adapted from llama.cpp's grammars/json.gbnf
+const PRIMITIVE_RULES: [(&str, &str); 9] = [
+    ("ws", r#"[ \t\n]*"#),
+    (
+        "value",
+        r#"( object | array | string | number | boolean | null )"#,
+    ),
+    (
+        "object",
+        r#""{" ws ( string ":" ws value ( "," ws string ":" ws value )* )? "}" ws"#,
+    ),
+    ("array", r#""[" ws ( value ( "," ws value )* )? "]" ws"#),
+    (
+        "string",
+        r#""\"" ( [^"\\\x7F\x00-\x1F] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] ) )* "\"" ws"#,
+    ),
+    (
+        "number",
+        r#""-"? ( [0-9] | [1-9] [0-9]* ) ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )? ws"#,
+    ),
+    ("integer", r#""-"? ( [0-9] | [1-9] [0-9]* ) ws"#),
+    ("boolean", r#"( "true" | "false" ) ws"#),
+    ("null", r#""null" ws"#),
+];
+
+fn gbnf_literal(text: &str) -> String {
+    let mut literal = String::with_capacity(text.len() + 2);
+    literal.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn json_literal(value: &Value) -> String {
+    format!("{} ws", gbnf_literal(&value.to_string()))
+}
+
+#[derive(Default)]
+struct SchemaConverter {
+    rules: Vec<(String, String)>,
+}
+
+impl SchemaConverter {
+    fn add_rule(&mut self, name: &str, body: String) -> String {
+        let base_name: String = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        let mut rule_name = base_name.clone();
+        let mut i = 1;
+        while self
+            .rules
+            .iter()
+            .any(|(existing, _)| *existing == rule_name)
+            || PRIMITIVE_RULES
+                .iter()
+                .any(|(existing, _)| *existing == rule_name)
+        {
+            rule_name = format!("{base_name}{i}");
+            i += 1;
+        }
+        self.rules.push((rule_name.clone(), body));
+        rule_name
+    }
+
+    fn alternatives(&mut self, schemas: &[Value], name: &str) -> anyhow::Result<String> {
+        let alternatives = schemas
+            .iter()
+            .enumerate()
+            .map(|(i, schema)| self.visit(schema, &format!("{name}-{i}")))
+            .collect::<anyhow::Result<Vec<String>>>()?;
+        Ok(format!("( {} )", alternatives.join(" | ")))
+    }
+
+    // Returns a GBNF expression matching the schema
+    fn visit(&mut self, schema: &Value, name: &str) -> anyhow::Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".to_string()),
+            Value::Object(schema) => schema,
+            _ => anyhow::bail!("unsupported JSON schema: {schema}"),
+        };
+        if schema.contains_key("$ref") {
+            anyhow::bail!("`$ref` is not supported in `json_schema`")
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(json_literal(value));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values.as_array().context("`enum` must be an array")?;
+            let alternatives: Vec<String> = values.iter().map(json_literal).collect();
+            return Ok(format!("( {} )", alternatives.join(" | ")));
+        }
+        if let Some(schemas) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            let schemas = schemas.as_array().context("`anyOf` must be an array")?;
+            return self.alternatives(schemas, name);
+        }
+        match schema.get("type") {
+            None => Ok("value".to_string()),
+            Some(Value::Array(types)) => {
+                let schemas: Vec<Value> = types
+                    .iter()
+                    .map(|t| {
+                        let mut schema = schema.clone();
+                        schema.insert("type".to_string(), t.clone());
+                        Value::Object(schema)
+                    })
+                    .collect();
+                self.alternatives(&schemas, name)
+            }
+            Some(Value::String(t)) => match t.as_str() {
+                "object" => match schema.get("properties").and_then(Value::as_object) {
+                    Some(properties) if !properties.is_empty() => {
+                        // Every property is generated in order so the output always has the same shape
+                        let properties = properties
+                            .iter()
+                            .map(|(key, property)| {
+                                Ok(format!(
+                                    "{} \":\" ws {}",
+                                    json_literal(&Value::String(key.clone())),
+                                    self.visit(property, &format!("{name}-{key}"))?
+                                ))
+                            })
+                            .collect::<anyhow::Result<Vec<String>>>()?;
+                        let body = format!("\"{{\" ws {} \"}}\" ws", properties.join(" \",\" ws "));
+                        Ok(self.add_rule(name, body))
+                    }
+                    _ => Ok("object".to_string()),
+                },
+                "array" => match schema.get("items") {
+                    Some(items) => {
+                        let item = self.visit(items, &format!("{name}-item"))?;
+                        let body = format!("\"[\" ws ( {item} ( \",\" ws {item} )* )? \"]\" ws");
+                        Ok(self.add_rule(name, body))
+                    }
+                    None => Ok("array".to_string()),
+                },
+                "string" | "number" | "integer" | "boolean" | "null" => Ok(t.to_string()),
+                _ => anyhow::bail!("unsupported type `{t}` in `json_schema`"),
+            },
+            Some(t) => anyhow::bail!("unsupported type `{t}` in `json_schema`"),
+        }
+    }
+}
+
+// Compiles the subset of JSON schema commonly used for structured output into a GBNF grammar
+pub(crate) fn json_schema_to_gbnf(schema: &Value) -> anyhow::Result<String> {
+    let mut converter = SchemaConverter::default();
+    let root = converter.visit(schema, "schema")?;
+    let mut grammar = format!("root ::= ws {root}\n");
+    for (name, body) in converter
+        .rules
+        .iter()
+        .map(|(name, body)| (name.as_str(), body.as_str()))
+        .chain(PRIMITIVE_RULES)
+    {
+        grammar.push_str(&format!("{name} ::= {body}\n"));
+    }
+    Ok(grammar)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn can_convert_json_schema_to_gbnf() -> anyhow::Result<()> {
+        let grammar = json_schema_to_gbnf(&json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "tags": { "type": "array", "items": { "enum": ["a", "b\"c"] } }
+            }
+        }))?;
+        let rules: Vec<&str> = grammar.lines().take(3).collect();
+        assert_eq!(
+            rules,
+            vec![
+                r#"root ::= ws schema"#,
+                r#"schema-tags ::= "[" ws ( ( "\"a\"" ws | "\"b\\\"c\"" ws ) ( "," ws ( "\"a\"" ws | "\"b\\\"c\"" ws ) )* )? "]" ws"#,
+                r#"schema ::= "{" ws "\"name\"" ws ":" ws string "," ws "\"tags\"" ws ":" ws schema-tags "}" ws"#,
+            ]
+        );
+        assert!(grammar.contains("\nstring ::= "));
+        Ok(())
+    }
+
+    #[test]
+    fn can_convert_primitive_json_schema_to_gbnf() -> anyhow::Result<()> {
+        let grammar = json_schema_to_gbnf(&json!({ "type": ["integer", "null"] }))?;
+        assert!(grammar.starts_with("root ::= ws ( integer | null )\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unsupported_json_schema() {
+        assert!(json_schema_to_gbnf(&json!({ "$ref": "#/definitions/a" })).is_err());
+        assert!(json_schema_to_gbnf(&json!({ "type": "tuple" })).is_err());
+    }
+}
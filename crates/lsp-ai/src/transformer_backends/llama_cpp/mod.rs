@@ -1,19 +1,19 @@
 use super::TransformerBackend;
 use crate::{
-    config::{self, ChatMessage, FIM},
+    config::{self, ChatMessage, FIMTemplate, FIM},
     memory_backends::Prompt,
     template::apply_chat_template,
-    transformer_worker::{
-        DoCompletionResponse, DoGenerationResponse, DoGenerationStreamResponse,
-        GenerationStreamRequest,
-    },
+    transformer_worker::{DoCompletionResponse, DoGenerationResponse, DoGenerationStreamResponse},
     utils::format_chat_messages,
 };
 use hf_hub::api::sync::ApiBuilder;
 use serde::Deserialize;
 use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
 use tracing::{error, instrument};
 
+mod grammar;
 mod model;
 use model::Model;
 
@@ -25,16 +25,31 @@ const fn max_new_tokens_default() -> usize {
 #[derive(Debug, Deserialize)]
 pub(crate) struct LLaMACPPRunParams {
     pub(crate) fim: Option<FIM>,
+    pub(crate) fim_template: Option<FIMTemplate>,
     messages: Option<Vec<ChatMessage>>,
     chat_template: Option<String>, // A Jinja template
     chat_format: Option<String>,   // The name of a template in llamacpp
-    #[serde(default = "max_new_tokens_default")]
+    #[serde(default = "max_new_tokens_default", alias = "max_new_tokens")]
     pub(crate) max_tokens: usize,
+    // Constrain the output with a GBNF grammar or a JSON schema that is compiled to one
+    grammar: Option<String>,
+    json_schema: Option<Value>,
     // TODO: Explore other arguments
 }
 
+impl LLaMACPPRunParams {
+    pub(crate) fn grammar(&self) -> anyhow::Result<Option<String>> {
+        match (&self.grammar, &self.json_schema) {
+            (Some(_), Some(_)) => anyhow::bail!("set only one of `grammar` and `json_schema`"),
+            (Some(grammar), None) => Ok(Some(grammar.clone())),
+            (None, Some(json_schema)) => grammar::json_schema_to_gbnf(json_schema).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
 pub(crate) struct LLaMACPP {
-    model: Model,
+    model: Arc<Model>,
 }
 
 impl LLaMACPP {
@@ -56,7 +71,7 @@ impl LLaMACPP {
                 "To use llama.cpp provide either `file_path` or `repository` and `name`"
             ),
         };
-        let model = Model::new(model_path, &configuration)?;
+        let model = Arc::new(Model::new(model_path, &configuration)?);
         Ok(Self { model })
     }
 
@@ -81,17 +96,21 @@ impl LLaMACPP {
                 }
                 None => context_and_code.code.clone(),
             }),
-            Prompt::FIM(fim) => Ok(match &params.fim {
-                Some(fim_params) => {
-                    format!(
-                        "{}{}{}{}{}",
-                        fim_params.start, fim.prompt, fim_params.middle, fim.suffix, fim_params.end
-                    )
-                }
+            Prompt::FIM(fim) => match FIM::resolve(params.fim.as_ref(), params.fim_template) {
+                Some(fim_params) => Ok(format!(
+                    "{}{}{}{}{}",
+                    fim_params.start, fim.prompt, fim_params.middle, fim.suffix, fim_params.end
+                )),
                 None => anyhow::bail!("Prompt type is FIM but no FIM parameters provided"),
-            }),
+            },
         }
     }
+
+    // Inference blocks so it runs off the async workers to not stall other requests
+    async fn complete(&self, prompt: String, params: LLaMACPPRunParams) -> anyhow::Result<String> {
+        let model = self.model.clone();
+        tokio::task::spawn_blocking(move || model.complete(&prompt, params)).await?
+    }
 }
 
 #[async_trait::async_trait]
@@ -104,9 +123,13 @@ impl TransformerBackend for LLaMACPP {
     ) -> anyhow::Result<DoCompletionResponse> {
         let params: LLaMACPPRunParams = serde_json::from_value(params)?;
         let prompt = self.get_prompt_string(prompt, &params)?;
-        self.model
-            .complete(&prompt, params)
-            .map(|insert_text| DoCompletionResponse { insert_text })
+        self.complete(prompt, params)
+            .await
+            .map(|insert_text| DoCompletionResponse {
+                insert_text,
+                alternatives: vec![],
+                usage: None,
+            })
     }
 
     #[instrument(skip(self))]
@@ -117,17 +140,21 @@ impl TransformerBackend for LLaMACPP {
     ) -> anyhow::Result<DoGenerationResponse> {
         let params: LLaMACPPRunParams = serde_json::from_value(params)?;
         let prompt = self.get_prompt_string(prompt, &params)?;
-        self.model
-            .complete(&prompt, params)
-            .map(|generated_text| DoGenerationResponse { generated_text })
+        self.complete(prompt, params)
+            .await
+            .map(|generated_text| DoGenerationResponse {
+                generated_text,
+                usage: None,
+            })
     }
 
     #[instrument(skip(self))]
     async fn do_generate_stream(
         &self,
-        _request: &GenerationStreamRequest,
+        _prompt: &Prompt,
         _params: Value,
-    ) -> anyhow::Result<DoGenerationStreamResponse> {
+        _tx: UnboundedSender<DoGenerationStreamResponse>,
+    ) -> anyhow::Result<()> {
         anyhow::bail!("GenerationStream is not yet implemented")
     }
 }
@@ -166,6 +193,38 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn llama_cpp_do_generate_with_grammar() -> anyhow::Result<()> {
+        let configuration: config::LLaMACPP = serde_json::from_value(json!({
+            "repository": "stabilityai/stable-code-3b",
+            "name": "stable-code-3b-Q5_K_M.gguf",
+            "n_ctx": 2048,
+            "n_gpu_layers": 35,
+        }))?;
+        let llama_cpp = LLaMACPP::new(configuration).unwrap();
+        let prompt = Prompt::default_without_cursor();
+        let run_params = json!({
+            "grammar": "root ::= (\"yes\" | \"no\")",
+            "max_tokens": 4
+        });
+        let response = llama_cpp.do_generate(&prompt, run_params).await?;
+        assert!(["yes", "no"].contains(&response.generated_text.as_str()));
+
+        let run_params = json!({
+            "json_schema": {
+                "type": "object",
+                "properties": {
+                    "done": { "type": "boolean" }
+                }
+            },
+            "max_tokens": 16
+        });
+        let response = llama_cpp.do_generate(&prompt, run_params).await?;
+        let generated: Value = serde_json::from_str(&response.generated_text)?;
+        assert!(generated["done"].is_boolean());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn llama_cpp_do_completion_fim() -> anyhow::Result<()> {
         let configuration: config::LLaMACPP = serde_json::from_value(json!({
@@ -2,13 +2,14 @@ use anyhow::Context;
 use llama_cpp_2::{
     context::params::LlamaContextParams,
     ggml_time_us,
+    grammar::LlamaGrammar,
     llama_backend::LlamaBackend,
     llama_batch::LlamaBatch,
     model::{params::LlamaModelParams, AddBos, LlamaChatMessage, LlamaModel, Special},
     token::data_array::LlamaTokenDataArray,
 };
 use once_cell::sync::Lazy;
-use std::{num::NonZeroU32, path::PathBuf, time::Duration};
+use std::{num::NonZeroU32, path::PathBuf, str::FromStr, time::Duration};
 use tracing::{info, instrument};
 
 use crate::config::{self, ChatMessage};
@@ -20,6 +21,11 @@ static BACKEND: Lazy<LlamaBackend> = Lazy::new(|| LlamaBackend::init().unwrap())
 pub(crate) struct Model {
     model: LlamaModel,
     n_ctx: NonZeroU32,
+    n_threads: Option<i32>,
+    n_batch: Option<u32>,
+    rope_freq_base: Option<f32>,
+    rope_freq_scale: Option<f32>,
+    flash_attn: Option<bool>,
 }
 
 impl Model {
@@ -38,15 +44,51 @@ impl Model {
         Ok(Model {
             model,
             n_ctx: NonZeroU32::new(config.n_ctx).context("`n_ctx` must be non zero")?,
+            n_threads: config.n_threads,
+            n_batch: config.n_batch,
+            rope_freq_base: config.rope_freq_base,
+            rope_freq_scale: config.rope_freq_scale,
+            flash_attn: config.flash_attn,
         })
     }
 
+    // Only override the parameters that are set so everything else keeps llama.cpp's defaults
+    fn context_params(&self) -> LlamaContextParams {
+        let mut ctx_params = LlamaContextParams::default().with_n_ctx(Some(self.n_ctx));
+        if let Some(n_threads) = self.n_threads {
+            ctx_params = ctx_params
+                .with_n_threads(n_threads)
+                .with_n_threads_batch(n_threads);
+        }
+        if let Some(n_batch) = self.n_batch {
+            ctx_params = ctx_params.with_n_batch(n_batch);
+        }
+        if let Some(rope_freq_base) = self.rope_freq_base {
+            ctx_params = ctx_params.with_rope_freq_base(rope_freq_base);
+        }
+        if let Some(rope_freq_scale) = self.rope_freq_scale {
+            ctx_params = ctx_params.with_rope_freq_scale(rope_freq_scale);
+        }
+        if let Some(flash_attn) = self.flash_attn {
+            ctx_params = ctx_params.with_flash_attention(flash_attn);
+        }
+        ctx_params
+    }
+
     #[instrument(skip(self))]
     pub(crate) fn complete(&self, prompt: &str, params: LLaMACPPRunParams) -> anyhow::Result<String> {
         info!("Completing with llama.cpp with prompt:\n{prompt}");
 
+        let mut grammar = params
+            .grammar()?
+            .map(|grammar| {
+                LlamaGrammar::from_str(&grammar)
+                    .with_context(|| format!("failed to parse grammar:\n{grammar}"))
+            })
+            .transpose()?;
+
         // initialize the context
-        let ctx_params = LlamaContextParams::default().with_n_ctx(Some(self.n_ctx));
+        let ctx_params = self.context_params();
 
         let mut ctx = self
             .model
@@ -96,7 +138,12 @@ impl Model {
             // sample the next token
             {
                 let candidates = ctx.candidates_ith(batch.n_tokens() - 1);
-                let candidates_p = LlamaTokenDataArray::from_iter(candidates, false);
+                let mut candidates_p = LlamaTokenDataArray::from_iter(candidates, false);
+
+                // mask out the tokens the grammar does not allow
+                if let Some(grammar) = &grammar {
+                    ctx.sample_grammar(&mut candidates_p, grammar);
+                }
 
                 // sample the most likely token
                 let new_token_id = ctx.sample_token_greedy(candidates_p);
@@ -106,6 +153,10 @@ impl Model {
                     break;
                 }
 
+                if let Some(grammar) = &mut grammar {
+                    ctx.grammar_accept_token(grammar, new_token_id);
+                }
+
                 output.push(self.model.token_to_str(new_token_id, Special::Tokenize)?);
                 batch.clear();
                 batch.add(new_token_id, n_cur, &[0], true)?;
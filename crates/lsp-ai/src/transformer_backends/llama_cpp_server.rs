@@ -0,0 +1,449 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{info, instrument};
+
+use crate::{
+    config::{self, ChatMessage, FIMTemplate, FIM},
+    memory_backends::{Prompt, PromptType},
+    transformer_worker::{DoGenerationResponse, DoGenerationStreamResponse},
+    utils::{format_chat_messages, format_prompt, http_client},
+};
+
+use super::{send_checked, sse::SseParser, BackendError, TransformerBackend};
+
+const fn max_tokens_default() -> usize {
+    64
+}
+
+// NOTE: We cannot deny unknown fields as the provided parameters may contain other fields relevant to other processes
+#[derive(Debug, Deserialize)]
+pub(crate) struct LLaMACPPServerRunParams {
+    // Use the server's `/infill` endpoint which applies the model's own FIM tokens
+    #[serde(default)]
+    pub(crate) infill: bool,
+    pub(crate) fim: Option<FIM>,
+    pub(crate) fim_template: Option<FIMTemplate>,
+    messages: Option<Vec<ChatMessage>>,
+    // Maps to `n_predict`
+    #[serde(default = "max_tokens_default")]
+    max_tokens: usize,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    top_k: Option<usize>,
+    seed: Option<i64>,
+    stop: Option<Vec<String>>,
+    // Passed through to the server as is. Values set here take precedence over the parameters above
+    // See: https://github.com/ggerganov/llama.cpp/tree/master/examples/server#api-endpoints
+    #[serde(default)]
+    options: Map<String, Value>,
+}
+
+impl LLaMACPPServerRunParams {
+    fn sampling_params(&self) -> Map<String, Value> {
+        let mut params = Map::new();
+        let top_level = [
+            ("n_predict", Some(json!(self.max_tokens))),
+            ("temperature", self.temperature.map(|x| json!(x))),
+            ("top_p", self.top_p.map(|x| json!(x))),
+            ("top_k", self.top_k.map(|x| json!(x))),
+            ("seed", self.seed.map(|x| json!(x))),
+            ("stop", self.stop.as_ref().map(|x| json!(x))),
+        ];
+        for (key, value) in top_level {
+            if let Some(value) = value {
+                params.insert(key.to_string(), value);
+            }
+        }
+        params.extend(self.options.clone());
+        params
+    }
+}
+
+pub(crate) struct LLaMACPPServer {
+    configuration: config::LLaMACPPServer,
+}
+
+#[derive(Debug, PartialEq)]
+enum LLaMACPPServerRequest {
+    Completion(Value),
+    Infill(Value),
+    Chat(Value),
+}
+
+#[derive(Deserialize, Serialize)]
+struct LLaMACPPServerValidCompletionResponse {
+    content: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct LLaMACPPServerError {
+    error: Value,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum LLaMACPPServerCompletionResponse {
+    Success(LLaMACPPServerValidCompletionResponse),
+    Error(LLaMACPPServerError),
+    Other(HashMap<String, Value>),
+}
+
+#[derive(Deserialize, Serialize)]
+struct LLaMACPPServerChatMessage {
+    content: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct LLaMACPPServerChatChoice {
+    message: LLaMACPPServerChatMessage,
+}
+
+#[derive(Deserialize, Serialize)]
+struct LLaMACPPServerValidChatResponse {
+    choices: Vec<LLaMACPPServerChatChoice>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum LLaMACPPServerChatResponse {
+    Success(LLaMACPPServerValidChatResponse),
+    Error(LLaMACPPServerError),
+    Other(HashMap<String, Value>),
+}
+
+#[derive(Deserialize)]
+struct LLaMACPPServerStreamDelta {
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LLaMACPPServerStreamChoice {
+    delta: LLaMACPPServerStreamDelta,
+    finish_reason: Option<String>,
+}
+
+// The completion and infill endpoints stream `content` while the chat endpoint streams OpenAI style `choices`
+#[derive(Deserialize)]
+struct LLaMACPPServerStreamChunk {
+    content: Option<String>,
+    #[serde(default)]
+    stop: bool,
+    choices: Option<Vec<LLaMACPPServerStreamChoice>>,
+    error: Option<Value>,
+}
+
+impl LLaMACPPServerStreamChunk {
+    // Returns the generated text and whether this is the last chunk
+    fn into_text(self) -> anyhow::Result<(String, bool)> {
+        if let Some(error) = self.error {
+            return Err(BackendError::from_provider_error(&error).into());
+        }
+        match self.choices {
+            Some(choices) => {
+                let choice = choices.into_iter().next();
+                let done = choice
+                    .as_ref()
+                    .is_some_and(|choice| choice.finish_reason.is_some());
+                let text = choice
+                    .and_then(|choice| choice.delta.content)
+                    .unwrap_or_default();
+                Ok((text, done))
+            }
+            None => Ok((self.content.unwrap_or_default(), self.stop)),
+        }
+    }
+}
+
+impl LLaMACPPServer {
+    #[instrument]
+    pub(crate) fn new(configuration: config::LLaMACPPServer) -> Self {
+        Self { configuration }
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!(
+            "{}{path}",
+            self.configuration
+                .endpoint
+                .as_deref()
+                .unwrap_or("http://localhost:8080")
+                .trim_end_matches('/')
+        )
+    }
+
+    fn build_request(
+        &self,
+        prompt: &Prompt,
+        params: &LLaMACPPServerRunParams,
+        stream: bool,
+    ) -> anyhow::Result<LLaMACPPServerRequest> {
+        let mut body = params.sampling_params();
+        body.insert("stream".to_string(), json!(stream));
+        let request = match prompt {
+            Prompt::ContextAndCode(code_and_context) => match &params.messages {
+                Some(completion_messages) => {
+                    let messages = format_chat_messages(completion_messages, code_and_context);
+                    // The chat endpoint is OpenAI compatible and expects `max_tokens`
+                    if let Some(n_predict) = body.remove("n_predict") {
+                        body.insert("max_tokens".to_string(), n_predict);
+                    }
+                    body.insert("messages".to_string(), json!(messages));
+                    LLaMACPPServerRequest::Chat(Value::Object(body))
+                }
+                None => {
+                    body.insert("prompt".to_string(), json!(format_prompt(code_and_context)));
+                    LLaMACPPServerRequest::Completion(Value::Object(body))
+                }
+            },
+            Prompt::FIM(fim) => match FIM::resolve(params.fim.as_ref(), params.fim_template) {
+                Some(fim_params) => {
+                    body.insert(
+                        "prompt".to_string(),
+                        json!(format!(
+                            "{}{}{}{}{}",
+                            fim_params.start,
+                            fim.prompt,
+                            fim_params.middle,
+                            fim.suffix,
+                            fim_params.end
+                        )),
+                    );
+                    LLaMACPPServerRequest::Completion(Value::Object(body))
+                }
+                None if params.infill => {
+                    body.insert("input_prefix".to_string(), json!(fim.prompt));
+                    body.insert("input_suffix".to_string(), json!(fim.suffix));
+                    LLaMACPPServerRequest::Infill(Value::Object(body))
+                }
+                None => anyhow::bail!(
+                    "Prompt type is FIM but neither `infill` nor FIM parameters are provided"
+                ),
+            },
+        };
+        info!("Calling llama.cpp server with request:\n{:?}", request);
+        Ok(request)
+    }
+
+    async fn send(&self, request: &LLaMACPPServerRequest) -> anyhow::Result<reqwest::Response> {
+        let (endpoint, body) = match request {
+            LLaMACPPServerRequest::Completion(body) => (self.endpoint("/completion"), body),
+            LLaMACPPServerRequest::Infill(body) => (self.endpoint("/infill"), body),
+            LLaMACPPServerRequest::Chat(body) => (self.endpoint("/v1/chat/completions"), body),
+        };
+        send_checked(
+            http_client()
+                .post(&endpoint)
+                .header("Content-Type", "application/json")
+                .json(body),
+        )
+        .await
+    }
+
+    async fn get_completion(&self, request: LLaMACPPServerRequest) -> anyhow::Result<String> {
+        let res: LLaMACPPServerCompletionResponse = self.send(&request).await?.json().await?;
+        info!(
+            "Response from llama.cpp server completion API:\n{}",
+            serde_json::to_string_pretty(&res).unwrap()
+        );
+        match res {
+            LLaMACPPServerCompletionResponse::Success(resp) => Ok(resp.content),
+            LLaMACPPServerCompletionResponse::Error(error) => {
+                Err(BackendError::from_provider_error(&error.error).into())
+            }
+            LLaMACPPServerCompletionResponse::Other(other) => {
+                anyhow::bail!(
+                    "unknown error while making llama.cpp server completion request: {:?}",
+                    other
+                )
+            }
+        }
+    }
+
+    async fn get_chat(&self, request: LLaMACPPServerRequest) -> anyhow::Result<String> {
+        let res: LLaMACPPServerChatResponse = self.send(&request).await?.json().await?;
+        info!(
+            "Response from llama.cpp server chat API:\n{}",
+            serde_json::to_string_pretty(&res).unwrap()
+        );
+        match res {
+            LLaMACPPServerChatResponse::Success(resp) => Ok(resp
+                .choices
+                .into_iter()
+                .next()
+                .context("llama.cpp server chat response has no choices")?
+                .message
+                .content),
+            LLaMACPPServerChatResponse::Error(error) => {
+                Err(BackendError::from_provider_error(&error.error).into())
+            }
+            LLaMACPPServerChatResponse::Other(other) => {
+                anyhow::bail!(
+                    "unknown error while making llama.cpp server chat request: {:?}",
+                    other
+                )
+            }
+        }
+    }
+
+    async fn do_stream(
+        &self,
+        prompt: &Prompt,
+        params: LLaMACPPServerRunParams,
+        tx: UnboundedSender<DoGenerationStreamResponse>,
+    ) -> anyhow::Result<()> {
+        let request = self.build_request(prompt, &params, true)?;
+        let mut res = self.send(&request).await?;
+        let mut parser = SseParser::default();
+        while let Some(chunk) = res.chunk().await.map_err(BackendError::from)? {
+            for event in parser.push(&chunk) {
+                if event.data == "[DONE]" {
+                    return Ok(());
+                }
+                let chunk: LLaMACPPServerStreamChunk = serde_json::from_str(&event.data)?;
+                let (generated_text, done) = chunk.into_text()?;
+                // The receiver is gone if the request was dropped
+                if !generated_text.is_empty()
+                    && tx
+                        .send(DoGenerationStreamResponse { generated_text })
+                        .is_err()
+                {
+                    return Ok(());
+                }
+                if done {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl TransformerBackend for LLaMACPPServer {
+    #[instrument(skip(self))]
+    async fn do_generate(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let params: LLaMACPPServerRunParams = serde_json::from_value(params)?;
+        let generated_text = match self.build_request(prompt, &params, false)? {
+            request @ LLaMACPPServerRequest::Chat(_) => self.get_chat(request).await?,
+            request => self.get_completion(request).await?,
+        };
+        Ok(DoGenerationResponse {
+            generated_text,
+            usage: None,
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn do_generate_stream(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+        tx: UnboundedSender<DoGenerationStreamResponse>,
+    ) -> anyhow::Result<()> {
+        let params: LLaMACPPServerRunParams = serde_json::from_value(params)?;
+        self.do_stream(prompt, params, tx).await
+    }
+
+    fn get_prompt_type(&self, params: &Value) -> anyhow::Result<PromptType> {
+        let params = params.as_object().context("params must be a JSON object")?;
+        if params
+            .get("infill")
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+            || params.contains_key("fim")
+            || params.contains_key("fim_template")
+        {
+            Ok(PromptType::FIM)
+        } else {
+            Ok(PromptType::ContextAndCode)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory_backends::FIMPrompt;
+    use serde_json::from_value;
+
+    fn llama_cpp_server() -> anyhow::Result<LLaMACPPServer> {
+        Ok(LLaMACPPServer::new(from_value(json!({}))?))
+    }
+
+    #[test]
+    fn llama_cpp_server_infill_request() -> anyhow::Result<()> {
+        let server = llama_cpp_server()?;
+        let params: LLaMACPPServerRunParams = from_value(json!({
+            "infill": true,
+            "max_tokens": 16,
+            "options": {
+                "n_predict": 8,
+                "cache_prompt": true
+            }
+        }))?;
+        assert!(matches!(
+            server.get_prompt_type(&json!({ "infill": true }))?,
+            PromptType::FIM
+        ));
+        let prompt = Prompt::FIM(FIMPrompt {
+            prompt: "def test".to_string(),
+            suffix: "\n\nprint(test())".to_string(),
+        });
+        assert_eq!(
+            server.build_request(&prompt, &params, true)?,
+            LLaMACPPServerRequest::Infill(json!({
+                "input_prefix": "def test",
+                "input_suffix": "\n\nprint(test())",
+                "n_predict": 8,
+                "cache_prompt": true,
+                "stream": true
+            }))
+        );
+        assert_eq!(server.endpoint("/infill"), "http://localhost:8080/infill");
+        Ok(())
+    }
+
+    #[test]
+    fn llama_cpp_server_chat_request() -> anyhow::Result<()> {
+        let server = llama_cpp_server()?;
+        let params: LLaMACPPServerRunParams = from_value(json!({
+            "messages": [
+                {
+                    "role": "user",
+                    "content": "Test {CONTEXT} - {CODE}"
+                }
+            ],
+            "max_tokens": 16
+        }))?;
+        let LLaMACPPServerRequest::Chat(body) =
+            server.build_request(&Prompt::default_with_cursor(), &params, false)?
+        else {
+            panic!("expected a chat request");
+        };
+        assert_eq!(body["max_tokens"], json!(16));
+        assert!(body.get("n_predict").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn llama_cpp_server_stream_chunks() -> anyhow::Result<()> {
+        let chunk: LLaMACPPServerStreamChunk =
+            serde_json::from_str(r#"{"content": "a", "stop": false}"#)?;
+        assert_eq!(chunk.into_text()?, ("a".to_string(), false));
+        let chunk: LLaMACPPServerStreamChunk = serde_json::from_str(
+            r#"{"choices": [{"delta": {"content": "b"}, "finish_reason": "stop"}]}"#,
+        )?;
+        assert_eq!(chunk.into_text()?, ("b".to_string(), true));
+        let chunk: LLaMACPPServerStreamChunk =
+            serde_json::from_str(r#"{"error": {"message": "bad"}}"#)?;
+        assert!(chunk.into_text().is_err());
+        Ok(())
+    }
+}
@@ -1,15 +1,18 @@
 use anyhow::Context;
 use serde::Deserialize;
 use serde_json::{json, Value};
+use tokio::sync::mpsc::UnboundedSender;
 use tracing::{info, instrument};
 
-use super::{open_ai::OpenAIChatResponse, TransformerBackend};
+use super::{
+    open_ai::OpenAIChatResponse, send_request, BackendError, TransformerBackend,
+    COMPLETION_TEMPERATURE_DEFAULT, COMPLETION_TOP_P_DEFAULT,
+};
 use crate::{
     config::{self},
     memory_backends::{FIMPrompt, Prompt, PromptType},
-    transformer_worker::{
-        DoGenerationResponse, DoGenerationStreamResponse, GenerationStreamRequest,
-    },
+    transformer_worker::{DoGenerationResponse, DoGenerationStreamResponse},
+    utils::http_client,
 };
 
 const fn max_tokens_default() -> usize {
@@ -17,11 +20,11 @@ const fn max_tokens_default() -> usize {
 }
 
 const fn top_p_default() -> f32 {
-    0.95
+    COMPLETION_TOP_P_DEFAULT
 }
 
 const fn temperature_default() -> f32 {
-    0.1
+    COMPLETION_TEMPERATURE_DEFAULT
 }
 
 // NOTE: We cannot deny unknown fields as the provided parameters may contain other fields relevant to other processes
@@ -64,8 +67,8 @@ impl MistralFIM {
         &self,
         prompt: &FIMPrompt,
         params: MistralFIMRunParams,
-    ) -> anyhow::Result<String> {
-        let client = reqwest::Client::new();
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let client = http_client();
         let token = self.get_token()?;
         let params = json!({
             "prompt": prompt.prompt,
@@ -82,21 +85,20 @@ impl MistralFIM {
             "Calling Mistral compatible FIM API with parameters:\n{}",
             serde_json::to_string_pretty(&params).unwrap()
         );
-        let res: OpenAIChatResponse = client
-            .post(
-                self.config
-                    .fim_endpoint
-                    .as_ref()
-                    .context("must specify `fim_endpoint` to use fim")?,
-            )
-            .bearer_auth(token)
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .json(&params)
-            .send()
-            .await?
-            .json()
-            .await?;
+        let res: OpenAIChatResponse = send_request(
+            client
+                .post(
+                    self.config
+                        .fim_endpoint
+                        .as_ref()
+                        .context("must specify `fim_endpoint` to use fim")?,
+                )
+                .bearer_auth(token)
+                .header("Content-Type", "application/json")
+                .header("Accept", "application/json")
+                .json(&params),
+        )
+        .await?;
 
         info!(
             "Response from Mistral compatible FIM API:\n{}",
@@ -104,10 +106,16 @@ impl MistralFIM {
         );
         match res {
             OpenAIChatResponse::Success(mut resp) => {
-                Ok(std::mem::take(&mut resp.choices[0].message.content))
+                if let Some(usage) = &resp.usage {
+                    usage.log("Mistral FIM");
+                }
+                Ok(DoGenerationResponse {
+                    generated_text: std::mem::take(&mut resp.choices[0].message.content),
+                    usage: resp.usage,
+                })
             }
             OpenAIChatResponse::Error(error) => {
-                anyhow::bail!("making Mistral FIM request: {:?}", error.error.to_string())
+                Err(BackendError::from_provider_error(&error.error).into())
             }
             OpenAIChatResponse::Other(other) => {
                 anyhow::bail!(
@@ -128,16 +136,16 @@ impl TransformerBackend for MistralFIM {
         params: Value,
     ) -> anyhow::Result<DoGenerationResponse> {
         let params: MistralFIMRunParams = serde_json::from_value(params)?;
-        let generated_text = self.do_fim(prompt.try_into()?, params).await?;
-        Ok(DoGenerationResponse { generated_text })
+        self.do_fim(prompt.try_into()?, params).await
     }
 
     #[instrument(skip(self))]
     async fn do_generate_stream(
         &self,
-        request: &GenerationStreamRequest,
+        _prompt: &Prompt,
         _params: Value,
-    ) -> anyhow::Result<DoGenerationStreamResponse> {
+        _tx: UnboundedSender<DoGenerationStreamResponse>,
+    ) -> anyhow::Result<()> {
         anyhow::bail!("GenerationStream is not yet implemented")
     }
 
@@ -0,0 +1,77 @@
+use serde_json::Value;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::instrument;
+
+use super::TransformerBackend;
+use crate::{
+    config,
+    memory_backends::Prompt,
+    transformer_worker::{DoGenerationResponse, DoGenerationStreamResponse},
+};
+
+// A backend returning canned responses so the request flow can be tested without a running model
+pub(crate) struct Mock {
+    config: config::Mock,
+}
+
+impl Mock {
+    pub(crate) fn new(config: config::Mock) -> Self {
+        Self { config }
+    }
+
+    fn get_response(&self, prompt: &Prompt) -> String {
+        match (&self.config.response, prompt) {
+            (Some(response), _) => response.clone(),
+            (None, Prompt::ContextAndCode(context_and_code)) => context_and_code.code.clone(),
+            (None, Prompt::FIM(fim)) => fim.prompt.clone(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TransformerBackend for Mock {
+    #[instrument(skip(self))]
+    async fn do_generate(
+        &self,
+        prompt: &Prompt,
+        _params: Value,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        Ok(DoGenerationResponse {
+            generated_text: self.get_response(prompt),
+            usage: None,
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn do_generate_stream(
+        &self,
+        prompt: &Prompt,
+        _params: Value,
+        tx: UnboundedSender<DoGenerationStreamResponse>,
+    ) -> anyhow::Result<()> {
+        tx.send(DoGenerationStreamResponse {
+            generated_text: self.get_response(prompt),
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::{from_value, json};
+
+    #[tokio::test]
+    async fn mock_do_generate() -> anyhow::Result<()> {
+        let mock = Mock::new(from_value(json!({}))?);
+        let response = mock.do_generate(&Prompt::default_fim(), json!({})).await?;
+        assert_eq!(response.generated_text, r#"def test_context():\n    pass"#);
+
+        let mock = Mock::new(from_value(json!({"response": "x * y"}))?);
+        let response = mock
+            .do_generate(&Prompt::default_with_cursor(), json!({}))
+            .await?;
+        assert_eq!(response.generated_text, "x * y");
+        Ok(())
+    }
+}
@@ -1,22 +1,52 @@
 use anyhow::Context;
 use serde_json::Value;
+use std::collections::HashMap;
+use tokio::sync::mpsc::UnboundedSender;
 
 use crate::{
-    config::ValidModel,
-    memory_backends::{Prompt, PromptType},
-    transformer_worker::{
-        DoCompletionResponse, DoGenerationResponse, DoGenerationStreamResponse,
-        GenerationStreamRequest,
-    },
+    config::{ChatMessage, FIMMode, FIMTemplate, ValidModel, FIM},
+    memory_backends::{FIMPrompt, Prompt, PromptType},
+    transformer_worker::{DoCompletionResponse, DoGenerationResponse, DoGenerationStreamResponse},
+    utils::{format_chat_messages, format_prompt, warn_if_chat_model_with_fim},
 };
 
+pub(crate) use error::BackendError;
+use error::{send_checked, send_request};
+
 mod anthropic;
+mod error;
 mod gemini;
 #[cfg(feature = "llama_cpp")]
 mod llama_cpp;
+mod llama_cpp_server;
 mod mistral_fim;
+#[cfg(feature = "mock")]
+mod mock;
 mod ollama;
 mod open_ai;
+mod sse;
+
+// Every backend accepts the canonical `max_tokens` run parameter and maps it to the field its API expects
+// Backend specific fields can still be set directly and take precedence where noted
+//
+// | Backend          | Sent as                          | Raw passthrough                                    |
+// |------------------|----------------------------------|----------------------------------------------------|
+// | open_ai          | max_tokens                       | max_completion_tokens (sent instead when set)      |
+// | anthropic        | max_tokens                       |                                                    |
+// | mistral_fim      | max_tokens                       |                                                    |
+// | ollama           | options.num_predict              | options.num_predict (takes precedence)             |
+// | gemini           | generationConfig.maxOutputTokens | generationConfig.maxOutputTokens (takes precedence)|
+// | llama_cpp        | max_tokens                       | max_new_tokens (alias)                             |
+// | llama_cpp_server | n_predict (max_tokens for chat)  | options.n_predict (takes precedence)               |
+
+// Sampling defaults used when `temperature` or `top_p` are not set. The worker picks them by the
+// kind of request, whichever endpoint the model uses
+// Completions, including FIM, are deterministic so the same position gives the same suggestion
+// Generations and code actions are allowed some variety
+pub(crate) const COMPLETION_TEMPERATURE_DEFAULT: f32 = 0.;
+pub(crate) const COMPLETION_TOP_P_DEFAULT: f32 = 0.1;
+pub(crate) const GENERATION_TEMPERATURE_DEFAULT: f32 = 0.3;
+pub(crate) const GENERATION_TOP_P_DEFAULT: f32 = 0.95;
 
 #[async_trait::async_trait]
 pub(crate) trait TransformerBackend {
@@ -29,6 +59,8 @@ pub(crate) trait TransformerBackend {
             .await
             .map(|x| DoCompletionResponse {
                 insert_text: x.generated_text,
+                alternatives: vec![],
+                usage: x.usage,
             })
     }
 
@@ -38,18 +70,23 @@ pub(crate) trait TransformerBackend {
         params: Value,
     ) -> anyhow::Result<DoGenerationResponse>;
 
-    #[allow(dead_code)]
     async fn do_generate_stream(
         &self,
-        request: &GenerationStreamRequest,
+        prompt: &Prompt,
         params: Value,
-    ) -> anyhow::Result<DoGenerationStreamResponse>;
+        tx: UnboundedSender<DoGenerationStreamResponse>,
+    ) -> anyhow::Result<()>;
 
     fn get_prompt_type(&self, params: &Value) -> anyhow::Result<PromptType> {
-        if params
-            .as_object()
-            .context("params must be a JSON object")?
-            .contains_key("fim")
+        let params = params.as_object().context("params must be a JSON object")?;
+        let fim_mode: Option<FIMMode> = params
+            .get("fim_mode")
+            .map(|fim_mode| serde_json::from_value(fim_mode.clone()))
+            .transpose()
+            .context("invalid `fim_mode`")?;
+        if params.contains_key("fim")
+            || params.contains_key("fim_template")
+            || fim_mode == Some(FIMMode::Instruct)
         {
             Ok(PromptType::FIM)
         } else {
@@ -58,6 +95,70 @@ pub(crate) trait TransformerBackend {
     }
 }
 
+// What a backend that supports both chat and completions endpoints sends for a prompt
+#[derive(Debug)]
+pub(crate) enum ModelInput {
+    Chat(Vec<ChatMessage>),
+    Completion(String),
+}
+
+// - `ContextAndCode` prompts use the chat `messages` when they are provided and a raw completion otherwise
+// - `FIM` prompts are wrapped in the tokens from `fim` or `fim_template` and sent as a raw completion
+//   With the `instruct` `fim_mode` they are described in chat messages instead
+pub(crate) fn build_model_input(
+    prompt: &Prompt,
+    messages: Option<&[ChatMessage]>,
+    fim: Option<&FIM>,
+    fim_template: Option<FIMTemplate>,
+    fim_mode: FIMMode,
+    model: &str,
+) -> anyhow::Result<ModelInput> {
+    match prompt {
+        Prompt::ContextAndCode(context_and_code) => Ok(match messages {
+            Some(messages) => ModelInput::Chat(format_chat_messages(messages, context_and_code)),
+            None => ModelInput::Completion(format_prompt(context_and_code)),
+        }),
+        Prompt::FIM(fim_prompt) if fim_mode == FIMMode::Instruct => {
+            Ok(ModelInput::Chat(build_fim_instruct_messages(fim_prompt)))
+        }
+        Prompt::FIM(fim_prompt) => match FIM::resolve(fim, fim_template) {
+            Some(fim) => {
+                warn_if_chat_model_with_fim(model);
+                Ok(ModelInput::Completion(format!(
+                    "{}{}{}{}{}",
+                    fim.start, fim_prompt.prompt, fim.middle, fim_prompt.suffix, fim.end
+                )))
+            }
+            None => anyhow::bail!("Prompt type is FIM but no FIM parameters provided"),
+        },
+    }
+}
+
+const FIM_INSTRUCT_SYSTEM_MESSAGE: &str = "You are a code completion engine. Reply with only the code that replaces <HOLE>. Do not repeat the code before or after it and do not wrap your reply in a markdown code block.";
+
+fn build_fim_instruct_messages(fim_prompt: &FIMPrompt) -> Vec<ChatMessage> {
+    vec![
+        ChatMessage::new("system".to_string(), FIM_INSTRUCT_SYSTEM_MESSAGE.to_string()),
+        ChatMessage::new(
+            "user".to_string(),
+            format!(
+                "Complete the code between <PREFIX> and <SUFFIX> at <HOLE>.\n\n<PREFIX>{}<HOLE>{}<SUFFIX>",
+                fim_prompt.prompt, fim_prompt.suffix
+            ),
+        ),
+    ]
+}
+
+pub(crate) fn build_transformer_backends(
+    models: &HashMap<String, ValidModel>,
+) -> anyhow::Result<HashMap<String, Box<dyn TransformerBackend + Send + Sync>>> {
+    models
+        .clone()
+        .into_iter()
+        .map(|(key, value)| Ok((key, value.try_into()?)))
+        .collect()
+}
+
 impl TryFrom<ValidModel> for Box<dyn TransformerBackend + Send + Sync> {
     type Error = anyhow::Error;
 
@@ -65,9 +166,15 @@ impl TryFrom<ValidModel> for Box<dyn TransformerBackend + Send + Sync> {
         match valid_model {
             #[cfg(feature = "llama_cpp")]
             ValidModel::LLaMACPP(model_gguf) => Ok(Box::new(llama_cpp::LLaMACPP::new(model_gguf)?)),
+            ValidModel::LLaMACPPServer(llama_cpp_server_config) => Ok(Box::new(
+                llama_cpp_server::LLaMACPPServer::new(llama_cpp_server_config),
+            )),
             ValidModel::OpenAI(open_ai_config) => {
                 Ok(Box::new(open_ai::OpenAI::new(open_ai_config)))
             }
+            ValidModel::OpenAICompatible(open_ai_compatible_config) => Ok(Box::new(
+                open_ai::OpenAI::new_compatible(open_ai_compatible_config),
+            )),
             ValidModel::Gemini(gemini_config) => Ok(Box::new(gemini::Gemini::new(gemini_config))),
             ValidModel::Anthropic(anthropic_config) => {
                 Ok(Box::new(anthropic::Anthropic::new(anthropic_config)))
@@ -76,6 +183,113 @@ impl TryFrom<ValidModel> for Box<dyn TransformerBackend + Send + Sync> {
                 Ok(Box::new(mistral_fim::MistralFIM::new(mistral_fim)))
             }
             ValidModel::Ollama(ollama) => Ok(Box::new(ollama::Ollama::new(ollama))),
+            #[cfg(feature = "mock")]
+            ValidModel::Mock(mock) => Ok(Box::new(mock::Mock::new(mock))),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn build_model_input_for_each_prompt_type() -> anyhow::Result<()> {
+        let messages = vec![ChatMessage::new(
+            "user".to_string(),
+            "{CONTEXT} {CODE}".to_string(),
+        )];
+        let prompt = Prompt::default_with_cursor();
+        match build_model_input(
+            &prompt,
+            Some(&messages),
+            None,
+            None,
+            FIMMode::Tokens,
+            "model",
+        )? {
+            ModelInput::Chat(messages) => assert_eq!(
+                messages[0].content,
+                r#"def test_context():\n    pass def test_code():\n    <CURSOR>"#
+            ),
+            input => anyhow::bail!("expected chat input but got {input:?}"),
+        }
+        match build_model_input(&prompt, None, None, None, FIMMode::Tokens, "model")? {
+            ModelInput::Completion(text) => assert_eq!(
+                text,
+                "def test_context():\\n    pass\n\ndef test_code():\\n    <CURSOR>"
+            ),
+            input => anyhow::bail!("expected completion input but got {input:?}"),
+        }
+
+        // A `fim_template` takes precedence over explicit `fim` tokens
+        let fim: FIM = serde_json::from_value(json!({
+            "start": "<s>",
+            "middle": "<m>",
+            "end": "<e>"
+        }))?;
+        let prompt = Prompt::FIM(crate::memory_backends::FIMPrompt {
+            prompt: "a".to_string(),
+            suffix: "b".to_string(),
+        });
+        match build_model_input(
+            &prompt,
+            Some(&messages),
+            Some(&fim),
+            None,
+            FIMMode::Tokens,
+            "model",
+        )? {
+            ModelInput::Completion(text) => assert_eq!(text, "<s>a<m>b<e>"),
+            input => anyhow::bail!("expected completion input but got {input:?}"),
+        }
+        match build_model_input(
+            &prompt,
+            None,
+            Some(&fim),
+            Some(FIMTemplate::StarCoder),
+            FIMMode::Tokens,
+            "model",
+        )? {
+            ModelInput::Completion(text) => {
+                assert_eq!(text, "<fim_prefix>a<fim_suffix>b<fim_middle>")
+            }
+            input => anyhow::bail!("expected completion input but got {input:?}"),
+        }
+        assert!(build_model_input(&prompt, None, None, None, FIMMode::Tokens, "model").is_err());
+
+        // The instruct mode needs no FIM tokens and ignores them when set
+        match build_model_input(&prompt, None, Some(&fim), None, FIMMode::Instruct, "model")? {
+            ModelInput::Chat(messages) => {
+                assert_eq!(messages.len(), 2);
+                assert_eq!(messages[0].role, "system");
+                assert_eq!(
+                    messages[1].content,
+                    "Complete the code between <PREFIX> and <SUFFIX> at <HOLE>.\n\n<PREFIX>a<HOLE>b<SUFFIX>"
+                );
+            }
+            input => anyhow::bail!("expected chat input but got {input:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fim_mode_selects_fim_prompt_type() -> anyhow::Result<()> {
+        let backend = open_ai::OpenAI::new(serde_json::from_value(
+            json!({ "model": "gpt-4o-mini", "auth_token": "test" }),
+        )?);
+        assert!(matches!(
+            backend.get_prompt_type(&json!({ "fim_mode": "instruct" }))?,
+            PromptType::FIM
+        ));
+        assert!(matches!(
+            backend.get_prompt_type(&json!({ "fim_mode": "tokens" }))?,
+            PromptType::ContextAndCode
+        ));
+        assert!(backend
+            .get_prompt_type(&json!({ "fim_mode": "raw" }))
+            .is_err());
+        Ok(())
+    }
+}
@@ -1,29 +1,65 @@
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::collections::HashMap;
+use tokio::sync::mpsc::UnboundedSender;
 use tracing::{info, instrument};
 
 use crate::{
-    config::{self, ChatMessage, FIM},
+    config::{self, ChatMessage, FIMMode, FIMTemplate, FIM},
     memory_backends::Prompt,
-    transformer_worker::{
-        DoGenerationResponse, DoGenerationStreamResponse, GenerationStreamRequest,
-    },
-    utils::{format_chat_messages, format_prompt},
+    stats::Usage,
+    transformer_worker::{DoGenerationResponse, DoGenerationStreamResponse},
+    utils::http_client,
 };
 
-use super::TransformerBackend;
+use super::{build_model_input, send_checked, BackendError, ModelInput, TransformerBackend};
 
 // NOTE: We cannot deny unknown fields as the provided parameters may contain other fields relevant to other processes
 #[derive(Debug, Deserialize)]
 pub(crate) struct OllamaRunParams {
     pub(crate) fim: Option<FIM>,
+    pub(crate) fim_template: Option<FIMTemplate>,
+    #[serde(default)]
+    pub(crate) fim_mode: FIMMode,
     messages: Option<Vec<ChatMessage>>,
+    // Passed through to Ollama as is. Values set here take precedence over the top level
+    // sampling parameters below. See: https://github.com/ollama/ollama/blob/main/docs/modelfile.md#valid-parameters-and-values
     #[serde(default)]
     options: HashMap<String, Value>,
     system: Option<String>,
     template: Option<String>,
     keep_alive: Option<String>,
+    // Top level sampling parameters that are mapped into `options`
+    // `max_tokens` maps to `num_predict`
+    max_tokens: Option<usize>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    top_k: Option<usize>,
+    repeat_penalty: Option<f32>,
+    seed: Option<i64>,
+    stop: Option<Vec<String>>,
+}
+
+impl OllamaRunParams {
+    fn options(&self) -> HashMap<String, Value> {
+        let mut options = HashMap::new();
+        let top_level = [
+            ("num_predict", self.max_tokens.map(|x| json!(x))),
+            ("temperature", self.temperature.map(|x| json!(x))),
+            ("top_p", self.top_p.map(|x| json!(x))),
+            ("top_k", self.top_k.map(|x| json!(x))),
+            ("repeat_penalty", self.repeat_penalty.map(|x| json!(x))),
+            ("seed", self.seed.map(|x| json!(x))),
+            ("stop", self.stop.as_ref().map(|x| json!(x))),
+        ];
+        for (key, value) in top_level {
+            if let Some(value) = value {
+                options.insert(key.to_string(), value);
+            }
+        }
+        options.extend(self.options.clone());
+        options
+    }
 }
 
 pub(crate) struct Ollama {
@@ -33,6 +69,29 @@ pub(crate) struct Ollama {
 #[derive(Deserialize, Serialize)]
 struct OllamaValidCompletionsResponse {
     response: String,
+    #[serde(flatten)]
+    usage: OllamaUsage,
+}
+
+// Ollama reports token counts at the top level of the final response
+#[derive(Deserialize, Serialize)]
+struct OllamaUsage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prompt_eval_count: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    eval_count: Option<u64>,
+}
+
+impl OllamaUsage {
+    fn to_usage(&self) -> Option<Usage> {
+        if self.prompt_eval_count.is_none() && self.eval_count.is_none() {
+            return None;
+        }
+        Some(Usage {
+            prompt_tokens: self.prompt_eval_count.unwrap_or_default(),
+            completion_tokens: self.eval_count.unwrap_or_default(),
+        })
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -57,6 +116,8 @@ struct OllamaError {
 #[derive(Deserialize, Serialize)]
 struct OllamaValidChatResponse {
     message: OllamaChatMessage,
+    #[serde(flatten)]
+    usage: OllamaUsage,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -67,55 +128,154 @@ enum OllamaChatResponse {
     Other(HashMap<String, Value>),
 }
 
+// The chat API is used when `messages` are provided, otherwise the raw generate API
+enum OllamaRequest {
+    Completion(Value),
+    Chat(Value),
+}
+
+#[derive(Deserialize)]
+struct OllamaStreamChunk {
+    response: Option<String>,
+    message: Option<OllamaChatMessage>,
+    error: Option<Value>,
+    #[serde(default)]
+    done: bool,
+}
+
+// Ollama streams newline delimited JSON objects
+// Chunks may end in the middle of a line so we buffer until we see a newline
+fn take_lines(buffer: &mut Vec<u8>, chunk: &[u8]) -> Vec<String> {
+    buffer.extend_from_slice(chunk);
+    let mut lines = vec![];
+    while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+        let line: Vec<u8> = buffer.drain(..=pos).collect();
+        let line = String::from_utf8_lossy(&line).trim().to_string();
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+    lines
+}
+
 impl Ollama {
     #[instrument]
     pub(crate) fn new(configuration: config::Ollama) -> Self {
         Self { configuration }
     }
 
-    async fn get_completion(
+    fn build_completion_request(
         &self,
         prompt: &str,
-        params: OllamaRunParams,
-    ) -> anyhow::Result<String> {
-        let client = reqwest::Client::new();
+        params: &OllamaRunParams,
+        stream: bool,
+    ) -> OllamaRequest {
         let params = json!({
             "model": self.configuration.model,
             "prompt": prompt,
-            "options": params.options,
+            "options": params.options(),
             "keep_alive": params.keep_alive,
             "raw": true,
-            "stream": false
+            "stream": stream
         });
         info!(
             "Calling Ollama compatible completions API with parameters:\n{}",
             serde_json::to_string_pretty(&params).unwrap()
         );
-        let res: OllamaCompletionsResponse = client
-            .post(
+        OllamaRequest::Completion(params)
+    }
+
+    fn build_chat_request(
+        &self,
+        messages: Vec<ChatMessage>,
+        params: &OllamaRunParams,
+        stream: bool,
+    ) -> OllamaRequest {
+        let params = json!({
+            "model": self.configuration.model,
+            "system": params.system,
+            "template": params.template,
+            "messages": messages,
+            "options": params.options(),
+            "keep_alive": params.keep_alive,
+            "stream": stream
+        });
+        info!(
+            "Calling Ollama compatible chat API with parameters:\n{}",
+            serde_json::to_string_pretty(&params).unwrap()
+        );
+        OllamaRequest::Chat(params)
+    }
+
+    fn build_request(
+        &self,
+        prompt: &Prompt,
+        params: &OllamaRunParams,
+        stream: bool,
+    ) -> anyhow::Result<OllamaRequest> {
+        let input = build_model_input(
+            prompt,
+            params.messages.as_deref(),
+            params.fim.as_ref(),
+            params.fim_template,
+            params.fim_mode,
+            &self.configuration.model,
+        )?;
+        Ok(match input {
+            ModelInput::Chat(messages) => self.build_chat_request(messages, params, stream),
+            ModelInput::Completion(prompt) => {
+                self.build_completion_request(&prompt, params, stream)
+            }
+        })
+    }
+
+    async fn send(&self, request: &OllamaRequest) -> anyhow::Result<reqwest::Response> {
+        let client = http_client();
+        let (endpoint, params) = match request {
+            OllamaRequest::Completion(params) => (
                 self.configuration
                     .generate_endpoint
                     .as_deref()
                     .unwrap_or("http://localhost:11434/api/generate"),
-            )
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .json(&params)
-            .send()
-            .await?
-            .json()
-            .await?;
+                params,
+            ),
+            OllamaRequest::Chat(params) => (
+                self.configuration
+                    .chat_endpoint
+                    .as_deref()
+                    .unwrap_or("http://localhost:11434/api/chat"),
+                params,
+            ),
+        };
+        send_checked(
+            client
+                .post(endpoint)
+                .header("Content-Type", "application/json")
+                .header("Accept", "application/json")
+                .json(params),
+        )
+        .await
+    }
+
+    async fn get_completion(&self, request: OllamaRequest) -> anyhow::Result<DoGenerationResponse> {
+        let res: OllamaCompletionsResponse = self.send(&request).await?.json().await?;
         info!(
             "Response from Ollama compatible completions API:\n{}",
             serde_json::to_string_pretty(&res).unwrap()
         );
         match res {
-            OllamaCompletionsResponse::Success(mut resp) => Ok(std::mem::take(&mut resp.response)),
+            OllamaCompletionsResponse::Success(resp) => {
+                let usage = resp.usage.to_usage();
+                if let Some(usage) = &usage {
+                    usage.log("Ollama");
+                }
+                Ok(DoGenerationResponse {
+                    generated_text: resp.response,
+                    usage,
+                })
+            }
             OllamaCompletionsResponse::Error(error) => {
-                anyhow::bail!(
-                    "making Ollama completions request: {:?}",
-                    error.error.to_string()
-                )
+                Err(BackendError::from_provider_error(&error.error).into())
             }
             OllamaCompletionsResponse::Other(other) => {
                 anyhow::bail!(
@@ -126,47 +286,25 @@ impl Ollama {
         }
     }
 
-    async fn get_chat(
-        &self,
-        messages: Vec<ChatMessage>,
-        params: OllamaRunParams,
-    ) -> anyhow::Result<String> {
-        let client = reqwest::Client::new();
-        let params = json!({
-            "model": self.configuration.model,
-            "system": params.system,
-            "template": params.template,
-            "messages": messages,
-            "options": params.options,
-            "keep_alive": params.keep_alive,
-            "stream": false
-        });
-        info!(
-            "Calling Ollama compatible chat API with parameters:\n{}",
-            serde_json::to_string_pretty(&params).unwrap()
-        );
-        let res: OllamaChatResponse = client
-            .post(
-                self.configuration
-                    .chat_endpoint
-                    .as_deref()
-                    .unwrap_or("http://localhost:11434/api/chat"),
-            )
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .json(&params)
-            .send()
-            .await?
-            .json()
-            .await?;
+    async fn get_chat(&self, request: OllamaRequest) -> anyhow::Result<DoGenerationResponse> {
+        let res: OllamaChatResponse = self.send(&request).await?.json().await?;
         info!(
             "Response from Ollama compatible chat API:\n{}",
             serde_json::to_string_pretty(&res).unwrap()
         );
         match res {
-            OllamaChatResponse::Success(mut resp) => Ok(std::mem::take(&mut resp.message.content)),
+            OllamaChatResponse::Success(resp) => {
+                let usage = resp.usage.to_usage();
+                if let Some(usage) = &usage {
+                    usage.log("Ollama");
+                }
+                Ok(DoGenerationResponse {
+                    generated_text: resp.message.content,
+                    usage,
+                })
+            }
             OllamaChatResponse::Error(error) => {
-                anyhow::bail!("making Ollama chat request: {:?}", error.error.to_string())
+                Err(BackendError::from_provider_error(&error.error).into())
             }
             OllamaChatResponse::Other(other) => {
                 anyhow::bail!(
@@ -181,36 +319,46 @@ impl Ollama {
         &self,
         prompt: &Prompt,
         params: OllamaRunParams,
-    ) -> anyhow::Result<String> {
-        match prompt {
-            Prompt::ContextAndCode(code_and_context) => match &params.messages {
-                Some(completion_messages) => {
-                    let messages = format_chat_messages(completion_messages, code_and_context);
-                    self.get_chat(messages, params).await
+    ) -> anyhow::Result<DoGenerationResponse> {
+        match self.build_request(prompt, &params, false)? {
+            request @ OllamaRequest::Completion(_) => self.get_completion(request).await,
+            request @ OllamaRequest::Chat(_) => self.get_chat(request).await,
+        }
+    }
+
+    async fn do_chat_completion_stream(
+        &self,
+        prompt: &Prompt,
+        params: OllamaRunParams,
+        tx: UnboundedSender<DoGenerationStreamResponse>,
+    ) -> anyhow::Result<()> {
+        let request = self.build_request(prompt, &params, true)?;
+        let mut res = self.send(&request).await?;
+        let mut buffer = vec![];
+        while let Some(chunk) = res.chunk().await.map_err(BackendError::from)? {
+            for line in take_lines(&mut buffer, &chunk) {
+                let chunk: OllamaStreamChunk = serde_json::from_str(&line)?;
+                if let Some(error) = chunk.error {
+                    return Err(BackendError::from_provider_error(&error).into());
                 }
-                None => {
-                    self.get_completion(&format_prompt(&code_and_context), params)
-                        .await
+                let generated_text = chunk
+                    .response
+                    .or(chunk.message.map(|message| message.content))
+                    .unwrap_or_default();
+                // The receiver is gone if the request was dropped
+                if !generated_text.is_empty()
+                    && tx
+                        .send(DoGenerationStreamResponse { generated_text })
+                        .is_err()
+                {
+                    return Ok(());
                 }
-            },
-            Prompt::FIM(fim) => match &params.fim {
-                Some(fim_params) => {
-                    self.get_completion(
-                        &format!(
-                            "{}{}{}{}{}",
-                            fim_params.start,
-                            fim.prompt,
-                            fim_params.middle,
-                            fim.suffix,
-                            fim_params.end
-                        ),
-                        params,
-                    )
-                    .await
+                if chunk.done {
+                    return Ok(());
                 }
-                None => anyhow::bail!("Prompt type is FIM but no FIM parameters provided"),
-            },
+            }
         }
+        Ok(())
     }
 }
 
@@ -224,17 +372,18 @@ impl TransformerBackend for Ollama {
         params: Value,
     ) -> anyhow::Result<DoGenerationResponse> {
         let params: OllamaRunParams = serde_json::from_value(params)?;
-        let generated_text = self.do_chat_completion(prompt, params).await?;
-        Ok(DoGenerationResponse { generated_text })
+        self.do_chat_completion(prompt, params).await
     }
 
     #[instrument(skip(self))]
     async fn do_generate_stream(
         &self,
-        request: &GenerationStreamRequest,
-        _params: Value,
-    ) -> anyhow::Result<DoGenerationStreamResponse> {
-        anyhow::bail!("GenerationStream is not yet implemented")
+        prompt: &Prompt,
+        params: Value,
+        tx: UnboundedSender<DoGenerationStreamResponse>,
+    ) -> anyhow::Result<()> {
+        let params: OllamaRunParams = serde_json::from_value(params)?;
+        self.do_chat_completion_stream(prompt, params, tx).await
     }
 }
 
@@ -243,6 +392,162 @@ mod test {
     use super::*;
     use serde_json::{from_value, json};
 
+    #[test]
+    fn ollama_parse_usage() -> anyhow::Result<()> {
+        let res: OllamaChatResponse = from_value(json!({
+            "model": "llama3",
+            "message": {"role": "assistant", "content": "Hello"},
+            "done": true,
+            "prompt_eval_count": 26,
+            "eval_count": 290
+        }))?;
+        let OllamaChatResponse::Success(resp) = res else {
+            anyhow::bail!("expected a successful response")
+        };
+        assert_eq!(
+            resp.usage.to_usage(),
+            Some(Usage {
+                prompt_tokens: 26,
+                completion_tokens: 290
+            })
+        );
+
+        let res: OllamaCompletionsResponse = from_value(json!({"response": "Hello"}))?;
+        let OllamaCompletionsResponse::Success(resp) = res else {
+            anyhow::bail!("expected a successful response")
+        };
+        assert_eq!(resp.usage.to_usage(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn ollama_options_precedence() -> anyhow::Result<()> {
+        let params: OllamaRunParams = from_value(json!({
+            "max_tokens": 32,
+            "temperature": 0.5,
+            "seed": 42,
+            "stop": ["\n"],
+            "options": {
+                "num_predict": 4,
+                "num_ctx": 4096,
+                "mirostat": 1
+            }
+        }))?;
+        let options = params.options();
+        assert_eq!(options["num_predict"], json!(4));
+        assert_eq!(options["temperature"], json!(0.5));
+        assert_eq!(options["seed"], json!(42));
+        assert_eq!(options["stop"], json!(["\n"]));
+        assert_eq!(options["num_ctx"], json!(4096));
+        assert_eq!(options["mirostat"], json!(1));
+        assert!(!options.contains_key("top_k"));
+        Ok(())
+    }
+
+    #[test]
+    fn ollama_take_lines() {
+        let mut buffer = vec![];
+        let lines = take_lines(&mut buffer, b"{\"response\":\"a\",\"done\":false}\n{\"resp");
+        assert_eq!(lines, vec![r#"{"response":"a","done":false}"#]);
+        let lines = take_lines(&mut buffer, b"onse\":\"\",\"done\":true}\n");
+        assert_eq!(lines, vec![r#"{"response":"","done":true}"#]);
+        assert!(buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ollama_completion_do_generate_stream() -> anyhow::Result<()> {
+        let configuration: config::Ollama = from_value(json!({
+            "model": "llama3",
+        }))?;
+        let ollama = Ollama::new(configuration);
+        let prompt = Prompt::default_without_cursor();
+        let run_params = json!({
+            "options": {
+                "num_predict": 4
+            }
+        });
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        ollama.do_generate_stream(&prompt, run_params, tx).await?;
+        let mut generated_text = String::new();
+        while let Some(chunk) = rx.recv().await {
+            generated_text.push_str(&chunk.generated_text);
+        }
+        assert!(!generated_text.is_empty());
+        Ok(())
+    }
+
+    // `{"response":"x * y","prompt_eval_count":3,"eval_count":2}` gzipped
+    const GZIPPED_COMPLETION: [u8; 67] = [
+        31, 139, 8, 0, 0, 0, 0, 0, 2, 3, 171, 86, 42, 74, 45, 46, 200, 207, 43, 78, 85, 178, 82,
+        170, 80, 208, 82, 168, 84, 210, 81, 42, 40, 202, 207, 45, 40, 137, 79, 45, 75, 204, 137,
+        79, 206, 47, 205, 43, 81, 178, 50, 214, 81, 66, 230, 26, 213, 2, 0, 31, 87, 185, 131, 57,
+        0, 0, 0,
+    ];
+
+    // Answers a single request with a gzip encoded body like some gateways do
+    fn serve_gzipped_response(listener: std::net::TcpListener) -> anyhow::Result<()> {
+        use std::io::{Read, Write};
+        let (mut stream, _) = listener.accept()?;
+        let mut request = vec![];
+        let mut buffer = [0; 4096];
+        loop {
+            let read = stream.read(&mut buffer)?;
+            request.extend_from_slice(&buffer[..read]);
+            let request = String::from_utf8_lossy(&request);
+            if let Some((headers, body)) = request.split_once("\r\n\r\n") {
+                let content_length = headers
+                    .lines()
+                    .find_map(|line| {
+                        line.to_lowercase()
+                            .strip_prefix("content-length:")?
+                            .trim()
+                            .parse()
+                            .ok()
+                    })
+                    .unwrap_or(0);
+                if body.len() >= content_length {
+                    break;
+                }
+            }
+            if read == 0 {
+                break;
+            }
+        }
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            GZIPPED_COMPLETION.len()
+        )?;
+        stream.write_all(&GZIPPED_COMPLETION)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ollama_gzipped_response() -> anyhow::Result<()> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let endpoint = format!("http://{}/api/generate", listener.local_addr()?);
+        let server = std::thread::spawn(move || serve_gzipped_response(listener));
+
+        let configuration: config::Ollama = from_value(json!({
+            "model": "llama3",
+            "generate_endpoint": endpoint
+        }))?;
+        let ollama = Ollama::new(configuration);
+        let response = ollama
+            .do_generate(&Prompt::default_without_cursor(), json!({}))
+            .await?;
+        assert_eq!(response.generated_text, "x * y");
+        assert_eq!(
+            response.usage,
+            Some(Usage {
+                prompt_tokens: 3,
+                completion_tokens: 2
+            })
+        );
+        server.join().unwrap()?;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn ollama_completion_do_generate() -> anyhow::Result<()> {
         let configuration: config::Ollama = from_value(json!({
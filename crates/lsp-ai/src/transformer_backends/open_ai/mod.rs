@@ -1,27 +1,41 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use tracing::{info, instrument};
+use tokio::sync::{mpsc::UnboundedSender, OnceCell};
+use tracing::{info, instrument, warn};
 
 use crate::{
-    config::{self, ChatMessage, FIM},
+    config::{self, ChatMessage, FIMMode, FIMTemplate, FIM},
     memory_backends::Prompt,
-    transformer_worker::{
-        DoGenerationResponse, DoGenerationStreamResponse, GenerationStreamRequest,
-    },
-    utils::{format_chat_messages, format_prompt},
+    stats::Usage,
+    transformer_worker::{DoCompletionResponse, DoGenerationResponse, DoGenerationStreamResponse},
+    utils::{expand_env_vars, http_client, merge_json, TOKIO_RUNTIME},
 };
 
-use super::TransformerBackend;
+use super::{
+    build_model_input, send_request, BackendError, ModelInput, TransformerBackend,
+    GENERATION_TEMPERATURE_DEFAULT, GENERATION_TOP_P_DEFAULT,
+};
+
+// Listing the models runs before the first request so it must not hold it up for long
+const MODELS_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
 
 const fn max_tokens_default() -> usize {
     64
 }
 
+const fn n_default() -> usize {
+    1
+}
+
 const fn top_p_default() -> f32 {
-    0.95
+    GENERATION_TOP_P_DEFAULT
+}
+
+const fn temperature_default() -> f32 {
+    GENERATION_TEMPERATURE_DEFAULT
 }
 
 const fn presence_penalty_default() -> f32 {
@@ -32,34 +46,65 @@ const fn frequency_penalty_default() -> f32 {
     0.
 }
 
-const fn temperature_default() -> f32 {
-    0.1
-}
-
 // NOTE: We cannot deny unknown fields as the provided parameters may contain other fields relevant to other processes
 #[derive(Debug, Deserialize)]
 pub(crate) struct OpenAIRunParams {
     pub(crate) fim: Option<FIM>,
+    pub(crate) fim_template: Option<FIMTemplate>,
+    #[serde(default)]
+    pub(crate) fim_mode: FIMMode,
     messages: Option<Vec<ChatMessage>>,
     #[serde(default = "max_tokens_default")]
     pub(crate) max_tokens: usize,
+    // Some models (e.g. o1) reject `max_tokens` and require this instead
+    pub(crate) max_completion_tokens: Option<usize>,
+    // Set by the worker from the kind of request when not configured
     #[serde(default = "top_p_default")]
     pub(crate) top_p: f32,
     #[serde(default = "presence_penalty_default")]
     pub(crate) presence_penalty: f32,
     #[serde(default = "frequency_penalty_default")]
     pub(crate) frequency_penalty: f32,
+    // Set by the worker from the kind of request when not configured
     #[serde(default = "temperature_default")]
     pub(crate) temperature: f32,
+    // Sent as is when set, e.g. `{ "type": "json_object" }`. The response is then parsed as JSON
+    pub(crate) response_format: Option<Value>,
+    // Seeds the start of the reply. Appended to the prompt for completions and sent as a final
+    // assistant message for chat. OpenAI itself does not continue assistant messages but servers
+    // like llama.cpp and LM Studio do
+    pub(crate) prefill: Option<String>,
+    // Prepend the prefill to the returned text
+    #[serde(default)]
+    pub(crate) include_prefill: bool,
+    // Completion candidates to return. Only used with the completions endpoint
+    #[serde(default = "n_default")]
+    pub(crate) n: usize,
+}
+
+impl OpenAIRunParams {
+    fn with_returned_prefill(&self, text: String) -> String {
+        match (&self.prefill, self.include_prefill) {
+            (Some(prefill), true) => format!("{prefill}{text}"),
+            _ => text,
+        }
+    }
 }
 
 pub(crate) struct OpenAI {
     configuration: config::OpenAI,
+    // The '/v1/models' endpoint of `open_ai_compatible` servers
+    models_endpoint: Option<String>,
+    // The server's first loaded model, looked up on the first request when no `model` is set
+    loaded_model: OnceCell<String>,
 }
 
 #[derive(Deserialize, Serialize)]
 pub(crate) struct OpenAICompletionsChoice {
     text: String,
+    // With several prompts the choices for prompt `i` have the indices `i * n..(i + 1) * n`
+    #[serde(default)]
+    index: usize,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -70,6 +115,8 @@ pub(crate) struct OpenAIError {
 #[derive(Deserialize, Serialize)]
 pub(crate) struct OpenAIValidCompletionsResponse {
     pub(crate) choices: Vec<OpenAICompletionsChoice>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) usage: Option<Usage>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -94,6 +141,8 @@ pub(crate) struct OpenAIChatChoices {
 #[derive(Deserialize, Serialize)]
 pub(crate) struct OpenAIValidChatResponse {
     pub(crate) choices: Vec<OpenAIChatChoices>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) usage: Option<Usage>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -104,35 +153,199 @@ pub(crate) enum OpenAIChatResponse {
     Other(HashMap<String, Value>),
 }
 
+#[derive(Deserialize)]
+pub(crate) struct OpenAIModel {
+    id: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct OpenAIModelsResponse {
+    data: Vec<OpenAIModel>,
+}
+
+// Models sometimes wrap their output in a markdown code block even in JSON mode
+fn parse_json_response(text: &str) -> anyhow::Result<String> {
+    let trimmed = text.trim();
+    let json = trimmed
+        .strip_prefix("```json")
+        .or_else(|| trimmed.strip_prefix("```"))
+        .and_then(|t| t.strip_suffix("```"))
+        .unwrap_or(trimmed)
+        .trim();
+    serde_json::from_str::<Value>(json).with_context(|| {
+        format!("expected a JSON response because `response_format` is set but got:\n{text}")
+    })?;
+    Ok(json.to_string())
+}
+
+// Splits the choices of a batched completions request into `n` candidates for each prompt
+// Choices may arrive in any order so they are placed by their `index`
+fn group_choices_by_prompt(
+    choices: Vec<OpenAICompletionsChoice>,
+    prompts: usize,
+    n: usize,
+) -> anyhow::Result<Vec<Vec<String>>> {
+    anyhow::ensure!(n > 0, "at least one candidate per prompt must be requested");
+    let mut grouped: Vec<Vec<Option<String>>> = vec![vec![None; n]; prompts];
+    for choice in choices {
+        let slot = grouped
+            .get_mut(choice.index / n)
+            .and_then(|candidates| candidates.get_mut(choice.index % n))
+            .with_context(|| {
+                format!(
+                    "OpenAI completions response has a choice with index {} but only {prompts} prompt(s) with {n} candidate(s) each were sent",
+                    choice.index
+                )
+            })?;
+        *slot = Some(choice.text);
+    }
+    grouped
+        .into_iter()
+        .enumerate()
+        .map(|(i, candidates)| {
+            candidates
+                .into_iter()
+                .collect::<Option<Vec<_>>>()
+                .with_context(|| {
+                    format!("OpenAI completions response is missing choices for prompt {i}")
+                })
+        })
+        .collect()
+}
+
+async fn get_models(models_endpoint: &str, token: &str) -> anyhow::Result<OpenAIModelsResponse> {
+    let models = async {
+        http_client()
+            .get(models_endpoint)
+            .bearer_auth(token)
+            .header("Accept", "application/json")
+            .timeout(MODELS_REQUEST_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    };
+    models.await.with_context(|| {
+        format!("could not reach the OpenAI compatible server at `{models_endpoint}` - check `base_url`")
+    })
+}
+
 impl OpenAI {
     #[instrument]
     pub(crate) fn new(configuration: config::OpenAI) -> Self {
-        Self { configuration }
+        Self {
+            configuration,
+            models_endpoint: None,
+            loaded_model: OnceCell::new(),
+        }
+    }
+
+    // Checks the server is reachable in the background so a server that is not running yet does
+    // not stop lsp-ai from starting
+    #[instrument]
+    pub(crate) fn new_compatible(configuration: config::OpenAICompatible) -> Self {
+        let models_endpoint = format!("{}/models", configuration.api_url());
+        let open_ai = Self {
+            configuration: configuration.into_open_ai(),
+            models_endpoint: Some(models_endpoint.clone()),
+            loaded_model: OnceCell::new(),
+        };
+        // A missing token is reported by the first request
+        if let Ok(token) = open_ai.get_token() {
+            TOKIO_RUNTIME.spawn(async move {
+                if let Err(e) = get_models(&models_endpoint, &token).await {
+                    warn!("{e:?}");
+                }
+            });
+        }
+        open_ai
+    }
+
+    // The configured model, or the first model loaded by the `open_ai_compatible` server
+    async fn model(&self) -> anyhow::Result<&str> {
+        let Some(models_endpoint) = self
+            .models_endpoint
+            .as_ref()
+            .filter(|_| self.configuration.model.is_empty())
+        else {
+            return Ok(&self.configuration.model);
+        };
+        self.loaded_model
+            .get_or_try_init(|| async {
+                let model = get_models(models_endpoint, &self.get_token()?)
+                    .await?
+                    .data
+                    .into_iter()
+                    .next()
+                    .with_context(|| {
+                        format!(
+                            "no `model` set and the server at `{models_endpoint}` has no models loaded"
+                        )
+                    })?
+                    .id;
+                info!("using model `{model}` from the OpenAI compatible server");
+                anyhow::Ok(model)
+            })
+            .await
+            .map(String::as_str)
     }
 
     fn get_token(&self) -> anyhow::Result<String> {
         if let Some(env_var_name) = &self.configuration.auth_token_env_var_name {
-            Ok(std::env::var(env_var_name)?)
+            std::env::var(env_var_name).map_err(|_| {
+                BackendError::Auth {
+                    message: format!("the `{env_var_name}` environment variable is not set"),
+                }
+                .into()
+            })
         } else if let Some(token) = &self.configuration.auth_token {
             Ok(token.to_string())
         } else {
-            anyhow::bail!(
-                "set `auth_token_env_var_name` or `auth_token` to use an OpenAI compatible API"
-            )
+            Err(BackendError::Auth {
+                message:
+                    "set `auth_token_env_var_name` or `auth_token` to use an OpenAI compatible API"
+                        .to_string(),
+            }
+            .into())
         }
     }
 
-    async fn get_completion(
+    // Every request carries the token and the optional organization and project headers
+    fn post(&self, endpoint: &str, body: &Value) -> anyhow::Result<reqwest::RequestBuilder> {
+        let mut request = http_client()
+            .post(endpoint)
+            .bearer_auth(self.get_token()?)
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json");
+        if let Some(organization) = &self.configuration.organization {
+            request = request.header("OpenAI-Organization", expand_env_vars(organization)?);
+        }
+        if let Some(project) = &self.configuration.project {
+            request = request.header("OpenAI-Project", expand_env_vars(project)?);
+        }
+        Ok(request.json(body))
+    }
+
+    // A single prompt is sent as a string as not every OpenAI compatible server accepts an array
+    fn build_batch_completion_params(
         &self,
-        prompt: &str,
-        params: OpenAIRunParams,
-    ) -> anyhow::Result<String> {
-        let client = reqwest::Client::new();
-        let token = self.get_token()?;
-        let params = json!({
+        prompts: &[&str],
+        n: usize,
+        params: &OpenAIRunParams,
+    ) -> Value {
+        let prefill = params.prefill.as_deref().unwrap_or_default();
+        let prompt = match prompts {
+            [prompt] => json!(format!("{prompt}{prefill}")),
+            prompts => json!(prompts
+                .iter()
+                .map(|prompt| format!("{prompt}{prefill}"))
+                .collect::<Vec<_>>()),
+        };
+        let mut body = json!({
             "model": self.configuration.model,
             "max_tokens": params.max_tokens,
-            "n": 1,
+            "n": n,
             "top_p": params.top_p,
             "presence_penalty": params.presence_penalty,
             "frequency_penalty": params.frequency_penalty,
@@ -140,36 +353,107 @@ impl OpenAI {
             "echo": false,
             "prompt": prompt
         });
+        if let Some(response_format) = &params.response_format {
+            merge_json(&mut body, &json!({ "response_format": response_format }));
+        }
+        body
+    }
+
+    fn build_chat_params(&self, mut messages: Vec<ChatMessage>, params: &OpenAIRunParams) -> Value {
+        if let Some(prefill) = &params.prefill {
+            messages.push(ChatMessage::new("assistant".to_string(), prefill.clone()));
+        }
+        let max_tokens = match params.max_completion_tokens {
+            Some(max_completion_tokens) => {
+                json!({ "max_completion_tokens": max_completion_tokens })
+            }
+            None => json!({ "max_tokens": params.max_tokens }),
+        };
+        let mut body = json!({
+            "model": self.configuration.model,
+            "n": 1,
+            "top_p": params.top_p,
+            "presence_penalty": params.presence_penalty,
+            "frequency_penalty": params.frequency_penalty,
+            "temperature": params.temperature,
+            "messages": messages
+        });
+        merge_json(&mut body, &max_tokens);
+        if let Some(response_format) = &params.response_format {
+            merge_json(&mut body, &json!({ "response_format": response_format }));
+        }
+        body
+    }
+
+    async fn get_completion(
+        &self,
+        prompt: &str,
+        params: OpenAIRunParams,
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let (completions, usage) = self.get_completions(&[prompt], 1, params).await?;
+        let generated_text = completions
+            .into_iter()
+            .next()
+            .and_then(|candidates| candidates.into_iter().next())
+            .context("OpenAI completions response has no choices")?;
+        Ok(DoGenerationResponse {
+            generated_text,
+            usage,
+        })
+    }
+
+    // Completes every prompt in one request. Returns `n` candidates per prompt in prompt order
+    async fn get_completions(
+        &self,
+        prompts: &[&str],
+        n: usize,
+        params: OpenAIRunParams,
+    ) -> anyhow::Result<(Vec<Vec<String>>, Option<Usage>)> {
+        let run_params = params;
+        let mut params = self.build_batch_completion_params(prompts, n, &run_params);
+        params["model"] = json!(self.model().await?);
         info!(
             "Calling OpenAI compatible completions API with parameters:\n{}",
             serde_json::to_string_pretty(&params).unwrap()
         );
-        let res: OpenAICompletionsResponse = client
-            .post(
-                self.configuration
-                    .completions_endpoint
-                    .as_ref()
-                    .context("specify `completions_endpoint` to use completions. Wanted to use `chat` instead? Please specify `chat_endpoint` and `messages`.")?,
-            )
-            .bearer_auth(token)
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .json(&params)
-            .send().await?
-            .json().await?;
+        let request = self.post(
+            self.configuration
+                .completions_endpoint
+                .as_ref()
+                .context("specify `completions_endpoint` to use completions. Wanted to use `chat` instead? Please specify `chat_endpoint` and `messages`.")?,
+            &params,
+        )?;
+        let res: OpenAICompletionsResponse = send_request(request).await?;
         info!(
             "Response from OpenAI compatible completions API:\n{}",
             serde_json::to_string_pretty(&res).unwrap()
         );
         match res {
-            OpenAICompletionsResponse::Success(mut resp) => {
-                Ok(std::mem::take(&mut resp.choices[0].text))
+            OpenAICompletionsResponse::Success(resp) => {
+                if let Some(usage) = &resp.usage {
+                    usage.log("OpenAI");
+                }
+                let completions = group_choices_by_prompt(resp.choices, prompts.len(), n)?
+                    .into_iter()
+                    .map(|candidates| {
+                        candidates
+                            .into_iter()
+                            .map(|text| {
+                                let text = run_params.with_returned_prefill(text);
+                                if run_params.response_format.is_some() {
+                                    parse_json_response(&text)
+                                } else {
+                                    Ok(text)
+                                }
+                            })
+                            .collect()
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                Ok((completions, resp.usage))
             }
             OpenAICompletionsResponse::Error(error) => {
-                anyhow::bail!(
-                    "making OpenAI completions request: {:?}",
-                    error.error.to_string()
-                )
+                // Returned as is so the provider's message reaches the client
+                Err(BackendError::from_provider_error(&error.error).into())
             }
             OpenAICompletionsResponse::Other(other) => {
                 anyhow::bail!(
@@ -184,48 +468,46 @@ impl OpenAI {
         &self,
         messages: Vec<ChatMessage>,
         params: OpenAIRunParams,
-    ) -> anyhow::Result<String> {
-        let client = reqwest::Client::new();
-        let token = self.get_token()?;
-        let params = json!({
-            "model": self.configuration.model,
-            "max_tokens": params.max_tokens,
-            "n": 1,
-            "top_p": params.top_p,
-            "presence_penalty": params.presence_penalty,
-            "frequency_penalty": params.frequency_penalty,
-            "temperature": params.temperature,
-            "messages": messages
-        });
+    ) -> anyhow::Result<DoGenerationResponse> {
+        let run_params = params;
+        let mut params = self.build_chat_params(messages, &run_params);
+        params["model"] = json!(self.model().await?);
         info!(
             "Calling OpenAI compatible chat API with parameters:\n{}",
             serde_json::to_string_pretty(&params).unwrap()
         );
-        let res: OpenAIChatResponse = client
-            .post(
-                self.configuration
-                    .chat_endpoint
-                    .as_ref()
-                    .context("must specify `chat_endpoint` to use completions")?,
-            )
-            .bearer_auth(token)
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .json(&params)
-            .send()
-            .await?
-            .json()
-            .await?;
+        let request = self.post(
+            self.configuration
+                .chat_endpoint
+                .as_ref()
+                .context("must specify `chat_endpoint` to use completions")?,
+            &params,
+        )?;
+        let res: OpenAIChatResponse = send_request(request).await?;
         info!(
             "Response from OpenAI compatible chat API:\n{}",
             serde_json::to_string_pretty(&res).unwrap()
         );
         match res {
             OpenAIChatResponse::Success(mut resp) => {
-                Ok(std::mem::take(&mut resp.choices[0].message.content))
+                if let Some(usage) = &resp.usage {
+                    usage.log("OpenAI");
+                }
+                let text = run_params
+                    .with_returned_prefill(std::mem::take(&mut resp.choices[0].message.content));
+                let generated_text = if run_params.response_format.is_some() {
+                    parse_json_response(&text)?
+                } else {
+                    text
+                };
+                Ok(DoGenerationResponse {
+                    generated_text,
+                    usage: resp.usage,
+                })
             }
             OpenAIChatResponse::Error(error) => {
-                anyhow::bail!("making OpenAI chat request: {:?}", error.error.to_string())
+                // Returned as is so the provider's message reaches the client
+                Err(BackendError::from_provider_error(&error.error).into())
             }
             OpenAIChatResponse::Other(other) => {
                 anyhow::bail!(
@@ -240,41 +522,62 @@ impl OpenAI {
         &self,
         prompt: &Prompt,
         params: OpenAIRunParams,
-    ) -> anyhow::Result<String> {
-        match prompt {
-            Prompt::ContextAndCode(code_and_context) => match &params.messages {
-                Some(completion_messages) => {
-                    let messages = format_chat_messages(completion_messages, code_and_context);
-                    self.get_chat(messages, params).await
-                }
-                None => {
-                    self.get_completion(&format_prompt(&code_and_context), params)
-                        .await
-                }
-            },
-            Prompt::FIM(fim) => match &params.fim {
-                Some(fim_params) => {
-                    self.get_completion(
-                        &format!(
-                            "{}{}{}{}{}",
-                            fim_params.start,
-                            fim.prompt,
-                            fim_params.middle,
-                            fim.suffix,
-                            fim_params.end
-                        ),
-                        params,
-                    )
-                    .await
-                }
-                None => anyhow::bail!("Prompt type is FIM but no FIM parameters provided"),
-            },
+    ) -> anyhow::Result<DoGenerationResponse> {
+        match build_model_input(
+            prompt,
+            params.messages.as_deref(),
+            params.fim.as_ref(),
+            params.fim_template,
+            params.fim_mode,
+            &self.configuration.model,
+        )? {
+            ModelInput::Chat(messages) => self.get_chat(messages, params).await,
+            ModelInput::Completion(prompt) => self.get_completion(&prompt, params).await,
         }
     }
 }
 
 #[async_trait::async_trait]
 impl TransformerBackend for OpenAI {
+    // Asks the completions endpoint for `n` candidates in one request
+    #[instrument(skip(self))]
+    async fn do_completion(
+        &self,
+        prompt: &Prompt,
+        params: Value,
+    ) -> anyhow::Result<DoCompletionResponse> {
+        let params: OpenAIRunParams = serde_json::from_value(params)?;
+        match build_model_input(
+            prompt,
+            params.messages.as_deref(),
+            params.fim.as_ref(),
+            params.fim_template,
+            params.fim_mode,
+            &self.configuration.model,
+        )? {
+            ModelInput::Chat(messages) => {
+                let response = self.get_chat(messages, params).await?;
+                Ok(DoCompletionResponse {
+                    insert_text: response.generated_text,
+                    alternatives: vec![],
+                    usage: response.usage,
+                })
+            }
+            ModelInput::Completion(prompt) => {
+                let n = params.n.max(1);
+                let (completions, usage) = self.get_completions(&[&prompt], n, params).await?;
+                let mut candidates = completions.into_iter().flatten();
+                Ok(DoCompletionResponse {
+                    insert_text: candidates
+                        .next()
+                        .context("OpenAI completions response has no choices")?,
+                    alternatives: candidates.collect(),
+                    usage,
+                })
+            }
+        }
+    }
+
     #[instrument(skip(self))]
     async fn do_generate(
         &self,
@@ -283,16 +586,16 @@ impl TransformerBackend for OpenAI {
         params: Value,
     ) -> anyhow::Result<DoGenerationResponse> {
         let params: OpenAIRunParams = serde_json::from_value(params)?;
-        let generated_text = self.do_chat_completion(prompt, params).await?;
-        Ok(DoGenerationResponse { generated_text })
+        self.do_chat_completion(prompt, params).await
     }
 
     #[instrument(skip(self))]
     async fn do_generate_stream(
         &self,
-        request: &GenerationStreamRequest,
+        _prompt: &Prompt,
         _params: Value,
-    ) -> anyhow::Result<DoGenerationStreamResponse> {
+        _tx: UnboundedSender<DoGenerationStreamResponse>,
+    ) -> anyhow::Result<()> {
         anyhow::bail!("GenerationStream is not yet implemented")
     }
 }
@@ -302,6 +605,173 @@ mod test {
     use super::*;
     use serde_json::{from_value, json};
 
+    #[test]
+    fn open_ai_organization_and_project_headers() -> anyhow::Result<()> {
+        std::env::set_var("LSP_AI_TEST_OPENAI_PROJECT", "proj_123");
+        let open_ai = OpenAI::new(from_value(json!({
+            "auth_token": "token",
+            "model": "gpt-4o",
+            "organization": "org-abc",
+            "project": "${LSP_AI_TEST_OPENAI_PROJECT}"
+        }))?);
+        let request = open_ai
+            .post("https://api.openai.com/v1/chat/completions", &json!({}))?
+            .build()?;
+        assert_eq!(request.headers()["OpenAI-Organization"], "org-abc");
+        assert_eq!(request.headers()["OpenAI-Project"], "proj_123");
+        assert_eq!(request.headers()["Authorization"], "Bearer token");
+
+        let open_ai = OpenAI::new(from_value(json!({
+            "auth_token": "token",
+            "model": "gpt-4o"
+        }))?);
+        let request = open_ai
+            .post("https://api.openai.com/v1/chat/completions", &json!({}))?
+            .build()?;
+        assert!(!request.headers().contains_key("OpenAI-Organization"));
+        assert!(!request.headers().contains_key("OpenAI-Project"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_ai_compatible_server_not_running() -> anyhow::Result<()> {
+        // Nothing listens on the discard port so building the backend must not fail
+        let open_ai = OpenAI::new_compatible(from_value(json!({
+            "base_url": "http://127.0.0.1:9",
+            "model": "qwen"
+        }))?);
+        assert_eq!(open_ai.model().await?, "qwen");
+
+        // Without a `model` the first request looks it up and reports the unreachable server
+        let open_ai = OpenAI::new_compatible(from_value(json!({
+            "base_url": "http://127.0.0.1:9"
+        }))?);
+        let error = format!("{:?}", open_ai.model().await.unwrap_err());
+        assert!(error.contains("check `base_url`"));
+        Ok(())
+    }
+
+    #[test]
+    fn open_ai_parse_usage() -> anyhow::Result<()> {
+        let res: OpenAIChatResponse = from_value(json!({
+            "id": "chatcmpl-123",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "Hello"}}],
+            "usage": {"prompt_tokens": 9, "completion_tokens": 12, "total_tokens": 21}
+        }))?;
+        let OpenAIChatResponse::Success(resp) = res else {
+            anyhow::bail!("expected a successful response")
+        };
+        assert_eq!(
+            resp.usage,
+            Some(Usage {
+                prompt_tokens: 9,
+                completion_tokens: 12
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn open_ai_forwards_response_format() -> anyhow::Result<()> {
+        let open_ai = OpenAI::new(from_value(json!({
+            "chat_endpoint": "https://api.openai.com/v1/chat/completions",
+            "model": "gpt-4o-mini",
+            "auth_token": "test",
+        }))?);
+        let messages = vec![ChatMessage::new("user".to_string(), "Test".to_string())];
+        let params: OpenAIRunParams = from_value(json!({
+            "response_format": { "type": "json_object" }
+        }))?;
+        let body = open_ai.build_chat_params(messages.clone(), &params);
+        assert_eq!(body["response_format"], json!({ "type": "json_object" }));
+        let body = open_ai.build_batch_completion_params(&["Test"], 1, &params);
+        assert_eq!(body["response_format"], json!({ "type": "json_object" }));
+
+        let params: OpenAIRunParams = from_value(json!({}))?;
+        let body = open_ai.build_chat_params(messages, &params);
+        assert!(body.get("response_format").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn open_ai_prefill() -> anyhow::Result<()> {
+        let open_ai = OpenAI::new(from_value(json!({
+            "completions_endpoint": "https://api.openai.com/v1/completions",
+            "model": "gpt-3.5-turbo-instruct",
+            "auth_token": "test",
+        }))?);
+        let params: OpenAIRunParams = from_value(json!({
+            "prefill": "{",
+            "include_prefill": true
+        }))?;
+        let body = open_ai.build_batch_completion_params(&["Test "], 1, &params);
+        assert_eq!(body["prompt"], json!("Test {"));
+        let body = open_ai.build_chat_params(
+            vec![ChatMessage::new("user".to_string(), "Test".to_string())],
+            &params,
+        );
+        assert_eq!(
+            body["messages"][1],
+            json!({"role": "assistant", "content": "{"})
+        );
+        assert_eq!(params.with_returned_prefill("}".to_string()), "{}");
+        Ok(())
+    }
+
+    #[test]
+    fn open_ai_parse_json_response() -> anyhow::Result<()> {
+        assert_eq!(parse_json_response(" {\"a\": 1}\n")?, "{\"a\": 1}");
+        assert_eq!(
+            parse_json_response("```json\n{\"a\": 1}\n```")?,
+            "{\"a\": 1}"
+        );
+        assert!(parse_json_response("not json").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn open_ai_batch_completions() -> anyhow::Result<()> {
+        let open_ai = OpenAI::new(from_value(json!({
+            "completions_endpoint": "https://api.openai.com/v1/completions",
+            "model": "gpt-3.5-turbo-instruct",
+            "auth_token": "test",
+        }))?);
+        let params: OpenAIRunParams = from_value(json!({}))?;
+        let body = open_ai.build_batch_completion_params(&["def a():", "def b():"], 2, &params);
+        assert_eq!(body["prompt"], json!(["def a():", "def b():"]));
+        assert_eq!(body["n"], json!(2));
+        let body = open_ai.build_batch_completion_params(&["def a():"], 1, &params);
+        assert_eq!(body["prompt"], json!("def a():"));
+        assert_eq!(body["n"], json!(1));
+
+        // The choices of the second prompt come first and out of order
+        let res: OpenAICompletionsResponse = from_value(json!({
+            "choices": [
+                {"text": " return 'b1'", "index": 2},
+                {"text": " return 'a1'", "index": 0},
+                {"text": " return 'b2'", "index": 3},
+                {"text": " return 'a2'", "index": 1}
+            ]
+        }))?;
+        let OpenAICompletionsResponse::Success(resp) = res else {
+            anyhow::bail!("expected a successful response")
+        };
+        assert_eq!(
+            group_choices_by_prompt(resp.choices, 2, 2)?,
+            vec![
+                vec![" return 'a1'", " return 'a2'"],
+                vec![" return 'b1'", " return 'b2'"]
+            ]
+        );
+
+        let missing = vec![OpenAICompletionsChoice {
+            text: " return 'a1'".to_string(),
+            index: 0,
+        }];
+        assert!(group_choices_by_prompt(missing, 2, 1).is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn open_ai_completion_do_generate() -> anyhow::Result<()> {
         let configuration: config::OpenAI = from_value(json!({
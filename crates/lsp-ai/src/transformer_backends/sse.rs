@@ -0,0 +1,88 @@
+// A single Server-Sent Event as emitted by the streaming OpenAI and Anthropic APIs
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct SseEvent {
+    pub(crate) event: Option<String>,
+    pub(crate) data: String,
+}
+
+// Incrementally splits a byte stream into Server-Sent Events
+// Chunks may end in the middle of a line (or a UTF-8 character) so we buffer until we see a newline
+#[derive(Default)]
+pub(crate) struct SseParser {
+    buffer: Vec<u8>,
+    event: SseEvent,
+    has_data: bool,
+}
+
+impl SseParser {
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = vec![];
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+            // An empty line dispatches the event
+            if line.is_empty() {
+                let event = std::mem::take(&mut self.event);
+                if std::mem::take(&mut self.has_data) {
+                    events.push(event);
+                }
+                continue;
+            }
+            // Lines starting with a colon are comments
+            if line.starts_with(':') {
+                continue;
+            }
+            let (field, value) = match line.split_once(':') {
+                Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+                None => (line, ""),
+            };
+            match field {
+                "event" => self.event.event = Some(value.to_string()),
+                "data" => {
+                    if self.has_data {
+                        self.event.data.push('\n');
+                    }
+                    self.event.data.push_str(value);
+                    self.has_data = true;
+                }
+                _ => (),
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sse_parser_splits_events_across_chunks() {
+        let mut parser = SseParser::default();
+        let events =
+            parser.push(b"event: ping\ndata: {}\n\n: comment\nevent: content_block_delta\nda");
+        assert_eq!(
+            events,
+            vec![SseEvent {
+                event: Some("ping".to_string()),
+                data: "{}".to_string()
+            }]
+        );
+        let events = parser.push(b"ta: {\"a\": 1}\r\ndata: 2\r\n\r\ndata: [DONE]\n\n");
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    event: Some("content_block_delta".to_string()),
+                    data: "{\"a\": 1}\n2".to_string()
+                },
+                SseEvent {
+                    event: None,
+                    data: "[DONE]".to_string()
+                }
+            ]
+        );
+    }
+}
@@ -1,41 +1,147 @@
 use anyhow::Context;
-use lsp_server::{Connection, Message, RequestId, Response};
+use futures::future::{select, Either};
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
 use lsp_types::{
-    CodeAction, CodeActionParams, CompletionItem, CompletionItemKind, CompletionList,
-    CompletionParams, CompletionResponse, Position, Range, TextDocumentIdentifier,
-    TextDocumentPositionParams, TextEdit, WorkspaceEdit,
+    notification::ShowMessage, request::ApplyWorkspaceEdit, ApplyWorkspaceEditParams,
+    ApplyWorkspaceEditResponse, CodeAction, CodeActionParams, CompletionItem, CompletionItemKind,
+    CompletionList, CompletionParams, CompletionResponse, CompletionTextEdit, CreateFile,
+    Diagnostic, DiagnosticSeverity, DidChangeTextDocumentParams, DocumentChangeOperation,
+    DocumentChanges, InlineCompletionItem, InlineCompletionParams, InlineCompletionResponse,
+    InsertReplaceEdit, MarkupContent, MarkupKind, MessageType, OneOf,
+    OptionalVersionedTextDocumentIdentifier, Position, Range, ResourceOp, ShowMessageParams,
+    TextDocumentEdit, TextDocumentIdentifier, TextDocumentPositionParams, TextEdit, Url,
+    WorkspaceEdit,
 };
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 use regex::Regex;
+use ropey::Rope;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::{
+    borrow::Cow,
     collections::HashMap,
-    sync::{mpsc::RecvTimeoutError, Arc},
+    path::{Path, PathBuf},
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc,
+    },
     time::{Duration, SystemTime},
 };
-use tokio::sync::oneshot;
-use tracing::{error, info, instrument};
+use tokio::sync::{oneshot, Semaphore};
+use tracing::{error, info, instrument, warn};
 
 use crate::config::{self, Config};
+use crate::custom_requests::explanation::ExplanationData;
 use crate::custom_requests::generation::{GenerateResult, GenerationParams};
-use crate::custom_requests::generation_stream::GenerationStreamParams;
-use crate::memory_backends::Prompt;
-use crate::memory_worker::{self, FileRequest, FilterRequest, PromptRequest};
-use crate::transformer_backends::TransformerBackend;
-use crate::utils::{ToResponseError, TOKIO_RUNTIME};
+use crate::custom_requests::generation_stream::{
+    GenerationStreamParams, GenerationStreamProgressParams, GenerationStreamResult,
+};
+use crate::custom_requests::preview_prompt::{
+    PreviewPromptParams, PreviewPromptResult, PreviewedPrompt,
+};
+use crate::custom_requests::undo_generation::UndoGenerationParams;
+use crate::memory_backends::{MemoryRunParams, Prompt};
+use crate::memory_worker::{
+    self, FileRequest, FilterRequest, PromptRequest, PromptWithContextRequest, ReplaceRangeRequest,
+};
+use crate::stats::{ReportsUsage, Usage, USAGE_STATS};
+use crate::transformer_backends::{
+    build_transformer_backends, BackendError, TransformerBackend, COMPLETION_TEMPERATURE_DEFAULT,
+    COMPLETION_TOP_P_DEFAULT, GENERATION_TEMPERATURE_DEFAULT, GENERATION_TOP_P_DEFAULT,
+};
+use crate::utils::{
+    find_repetition, format_file_chunk, override_json, read_file_with_size_cap, strip_fim_markers,
+    tokens_to_estimated_characters, ToResponseError, TOKIO_RUNTIME,
+};
 
 static RE: Lazy<Mutex<HashMap<String, Regex>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
+// The last generated edit per document, after it has been applied
+static LAST_GENERATIONS: Lazy<Mutex<HashMap<Url, TextEdit>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// The prefetch for the cursor after the latest edit of each document
+static PREFETCHES: Lazy<Mutex<HashMap<Url, PrefetchedCompletion>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static NEXT_EDIT_ID: AtomicU64 = AtomicU64::new(0);
+
+static NEXT_APPLY_EDIT_ID: AtomicU64 = AtomicU64::new(0);
+
+// The `workspace/applyEdit` requests waiting for the client's response
+static PENDING_APPLY_EDITS: Lazy<Mutex<HashMap<RequestId, oneshot::Sender<Response>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct PrefetchedCompletion {
+    // Completions prefetched for older edits are dropped
+    edit_id: u64,
+    position: TextDocumentPositionParams,
+    // The filter text and the candidates to insert, once the prefetch is done
+    completion: Option<(String, Vec<String>)>,
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct CompletionRequest {
     id: RequestId,
     params: CompletionParams,
+    // The completion profile to use, default: the `completion` config
+    profile: Option<String>,
+    // A model from the `models` config to use instead of the profile's model
+    model: Option<String>,
 }
 
 impl CompletionRequest {
     pub(crate) fn new(id: RequestId, params: CompletionParams) -> Self {
-        Self { id, params }
+        Self {
+            id,
+            params,
+            profile: None,
+            model: None,
+        }
+    }
+
+    pub(crate) fn with_profile(mut self, profile: Option<String>) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub(crate) fn with_model(mut self, model: Option<String>) -> Self {
+        self.model = model;
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct InlineCompletionRequest {
+    id: RequestId,
+    params: InlineCompletionParams,
+    // The completion profile to use, default: the `completion` config
+    profile: Option<String>,
+    // A model from the `models` config to use instead of the profile's model
+    model: Option<String>,
+}
+
+impl InlineCompletionRequest {
+    pub(crate) fn new(id: RequestId, params: InlineCompletionParams) -> Self {
+        Self {
+            id,
+            params,
+            profile: None,
+            model: None,
+        }
+    }
+
+    pub(crate) fn with_profile(mut self, profile: Option<String>) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub(crate) fn with_model(mut self, model: Option<String>) -> Self {
+        self.model = model;
+        self
     }
 }
 
@@ -51,8 +157,6 @@ impl GenerationRequest {
     }
 }
 
-// The generate stream is not yet ready but we don't want to remove it
-#[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub(crate) struct GenerationStreamRequest {
     id: RequestId,
@@ -69,11 +173,22 @@ impl GenerationStreamRequest {
 pub(crate) struct CodeActionRequest {
     id: RequestId,
     params: CodeActionParams,
+    // A model from the `models` config to use instead of the model of the `chats`
+    model: Option<String>,
 }
 
 impl CodeActionRequest {
     pub(crate) fn new(id: RequestId, params: CodeActionParams) -> Self {
-        Self { id, params }
+        Self {
+            id,
+            params,
+            model: None,
+        }
+    }
+
+    pub(crate) fn with_model(mut self, model: Option<String>) -> Self {
+        self.model = model;
+        self
     }
 }
 
@@ -89,38 +204,139 @@ impl CodeActionResolveRequest {
     }
 }
 
+#[derive(Clone, Debug)]
+pub(crate) struct UndoGenerationRequest {
+    id: RequestId,
+    params: UndoGenerationParams,
+}
+
+impl UndoGenerationRequest {
+    pub(crate) fn new(id: RequestId, params: UndoGenerationParams) -> Self {
+        Self { id, params }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct PreviewPromptRequest {
+    id: RequestId,
+    params: PreviewPromptParams,
+}
+
+impl PreviewPromptRequest {
+    pub(crate) fn new(id: RequestId, params: PreviewPromptParams) -> Self {
+        Self { id, params }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) enum WorkerRequest {
     Shutdown,
     Completion(CompletionRequest),
+    InlineCompletion(InlineCompletionRequest),
     Generation(GenerationRequest),
     GenerationStream(GenerationStreamRequest),
     CodeActionRequest(CodeActionRequest),
     CodeActionResolveRequest(CodeActionResolveRequest),
+    UndoGeneration(UndoGenerationRequest),
+    PreviewPrompt(PreviewPromptRequest),
+    DidChangeConfiguration(Box<Config>),
+    // Invalidates prefetched completions and may start a new prefetch
+    DidChangeTextDocument(DidChangeTextDocumentParams),
 }
 
 impl WorkerRequest {
+    // The model the request is sent to when it is known before the request is handled
+    fn get_model<'a>(&'a self, config: &'a Config) -> Option<&'a str> {
+        match self {
+            WorkerRequest::Completion(_) | WorkerRequest::InlineCompletion(_) => {
+                self.get_model_override().or_else(|| {
+                    config
+                        .get_completion(self.get_profile())
+                        .ok()
+                        .map(|completion| completion.model.as_str())
+                })
+            }
+            WorkerRequest::Generation(r) => Some(&r.params.model),
+            WorkerRequest::GenerationStream(r) => Some(&r.params.model),
+            _ => None,
+        }
+    }
+
+    fn get_model_override(&self) -> Option<&str> {
+        match self {
+            WorkerRequest::Completion(r) => r.model.as_deref(),
+            WorkerRequest::InlineCompletion(r) => r.model.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn get_profile(&self) -> Option<&str> {
+        match self {
+            WorkerRequest::Completion(r) => r.profile.as_deref(),
+            WorkerRequest::InlineCompletion(r) => r.profile.as_deref(),
+            _ => None,
+        }
+    }
+
+    // Completions resolve to no items instead of an error when they can't be answered
+    fn get_empty_result(&self) -> Option<Value> {
+        match self {
+            WorkerRequest::Completion(_) => Some(
+                serde_json::to_value(Some(CompletionResponse::List(CompletionList {
+                    is_incomplete: false,
+                    items: vec![],
+                })))
+                .unwrap(),
+            ),
+            WorkerRequest::InlineCompletion(_) => {
+                Some(serde_json::to_value(Some(InlineCompletionResponse::Array(vec![]))).unwrap())
+            }
+            _ => None,
+        }
+    }
+
     fn get_id(&self) -> RequestId {
         match self {
-            WorkerRequest::Shutdown => unreachable!(),
+            WorkerRequest::Shutdown
+            | WorkerRequest::DidChangeConfiguration(_)
+            | WorkerRequest::DidChangeTextDocument(_) => unreachable!(),
             WorkerRequest::Completion(r) => r.id.clone(),
+            WorkerRequest::InlineCompletion(r) => r.id.clone(),
             WorkerRequest::Generation(r) => r.id.clone(),
             WorkerRequest::GenerationStream(r) => r.id.clone(),
             WorkerRequest::CodeActionRequest(r) => r.id.clone(),
             WorkerRequest::CodeActionResolveRequest(r) => r.id.clone(),
+            WorkerRequest::UndoGeneration(r) => r.id.clone(),
+            WorkerRequest::PreviewPrompt(r) => r.id.clone(),
         }
     }
 }
 
 pub(crate) struct DoCompletionResponse {
     pub(crate) insert_text: String,
+    // Further candidates when the backend was asked for several, e.g. with OpenAI's `n`
+    pub(crate) alternatives: Vec<String>,
+    pub(crate) usage: Option<Usage>,
+}
+
+impl ReportsUsage for DoCompletionResponse {
+    fn usage(&self) -> Option<Usage> {
+        self.usage
+    }
 }
 
 pub(crate) struct DoGenerationResponse {
     pub(crate) generated_text: String,
+    pub(crate) usage: Option<Usage>,
+}
+
+impl ReportsUsage for DoGenerationResponse {
+    fn usage(&self) -> Option<Usage> {
+        self.usage
+    }
 }
 
-#[allow(dead_code)]
+#[derive(Debug)]
 pub(crate) struct DoGenerationStreamResponse {
     pub(crate) generated_text: String,
 }
@@ -167,13 +383,32 @@ fn post_process_end(response: String, back: &str) -> String {
     }
 }
 
-// Some basic post processing that will clean up duplicate characters at the front and back
+// Cuts the response off at the first end of turn token and removes any other special tokens
+fn strip_special_tokens(mut response: String, special_tokens: &[String]) -> String {
+    let end_of_turn = special_tokens
+        .iter()
+        .filter(|token| config::END_OF_TURN_TOKENS.contains(&token.as_str()))
+        .filter_map(|token| response.find(token.as_str()))
+        .min();
+    if let Some(end_of_turn) = end_of_turn {
+        response.truncate(end_of_turn);
+    }
+    for token in special_tokens.iter().filter(|token| !token.is_empty()) {
+        if response.contains(token.as_str()) {
+            response = response.replace(token.as_str(), "");
+        }
+    }
+    response
+}
+
+// Some basic post processing that will clean up special tokens and duplicate characters at the front and back
 fn post_process_response(
     response: String,
     prompt: &Prompt,
     config: &config::PostProcess,
 ) -> String {
-    match prompt {
+    let response = strip_special_tokens(response, &config.special_tokens_to_strip);
+    let response = match prompt {
         Prompt::ContextAndCode(context_and_code) => {
             // First we need to extract
             let response = if let Some(extractor) = &config.extractor {
@@ -215,6 +450,14 @@ fn post_process_response(
             }
         }
         Prompt::FIM(fim) => {
+            // Echoed FIM markers are a sign the `fim` tokens don't match the model
+            let response = match strip_fim_markers(&response) {
+                Cow::Owned(stripped) => {
+                    warn!("stripped FIM markers from the response. Check the `fim` parameters match the model");
+                    stripped
+                }
+                Cow::Borrowed(_) => response,
+            };
             let response = if config.remove_duplicate_start {
                 post_process_start(response, &fim.prompt)
             } else {
@@ -226,6 +469,34 @@ fn post_process_response(
                 response
             }
         }
+    };
+    let response = break_repetition(response, config.repetition);
+    truncate_response(response, config.max_generated_chars)
+}
+
+// Keeps only the first copy of a block of lines the model got stuck repeating
+fn break_repetition(response: String, repetition: config::RepetitionDetection) -> String {
+    match find_repetition(
+        &response,
+        repetition.max_block_lines,
+        repetition.repetitions,
+    ) {
+        Some(end) => {
+            warn!("cut off a response that was repeating itself");
+            response[..end].to_string()
+        }
+        None => response,
+    }
+}
+
+// Cuts off runaway responses so they can't flood the buffer
+fn truncate_response(response: String, max_generated_chars: Option<usize>) -> String {
+    match max_generated_chars {
+        Some(max_chars) if response.chars().count() > max_chars => {
+            warn!("truncated a response longer than `max_generated_chars` ({max_chars})");
+            response.chars().take(max_chars).collect()
+        }
+        _ => response,
     }
 }
 
@@ -247,34 +518,107 @@ pub(crate) fn run(
     }
 }
 
+// Completions and prefetches share the `max_requests_per_second` budget
+#[derive(Clone)]
+struct RateLimiter {
+    max_requests_per_second: f32,
+    last_request_time: Arc<Mutex<SystemTime>>,
+}
+
+impl RateLimiter {
+    // Records the request unless the budget is used up
+    fn try_acquire(&self) -> bool {
+        let mut last_request_time = self.last_request_time.lock();
+        let elapsed = SystemTime::now()
+            .duration_since(*last_request_time)
+            .unwrap_or_default();
+        if elapsed.as_secs_f32() < 1. / self.max_requests_per_second {
+            return false;
+        }
+        *last_request_time = SystemTime::now();
+        true
+    }
+}
+
 fn do_run(
     transformer_backends: HashMap<String, Box<dyn TransformerBackend + Send + Sync>>,
     memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
     transformer_rx: std::sync::mpsc::Receiver<WorkerRequest>,
     connection: Arc<Connection>,
-    config: Config,
+    mut config: Config,
 ) -> anyhow::Result<()> {
-    let transformer_backends = Arc::new(transformer_backends);
+    // In flight requests keep the backends they started with when the configuration changes
+    let mut transformer_backends = Arc::new(transformer_backends);
 
+    let last_request_time = Arc::new(Mutex::new(SystemTime::now()));
+    let build_rate_limiter = |config: &Config| {
+        config
+            .get_completion_transformer_max_requests_per_second()
+            .map(|max_requests_per_second| RateLimiter {
+                max_requests_per_second,
+                last_request_time: last_request_time.clone(),
+            })
+    };
     // If this errors completion is disabled
-    let max_requests_per_second = config.get_completion_transformer_max_requests_per_second();
-    let mut last_completion_request_time = SystemTime::now();
+    let mut rate_limiter = build_rate_limiter(&config);
     let mut last_completion_request = None;
 
-    let run_dispatch_request = |request| {
-        let task_connection = connection.clone();
-        let task_transformer_backends = transformer_backends.clone();
+    // Limit the requests handled at once so a burst does not hit provider rate limits
+    // The semaphore is fair so queued requests are handled in the order they arrive
+    let mut semaphore = Arc::new(Semaphore::new(config.config.max_concurrent_requests.max(1)));
+
+    // Each request is handled in its own task so a slow request does not block the others
+    let run_dispatch_request =
+        |request, transformer_backends: &Arc<_>, config: &Config, semaphore: &Arc<Semaphore>| {
+            let task_connection = connection.clone();
+            let task_transformer_backends = Arc::clone(transformer_backends);
+            let task_memory_backend_tx = memory_backend_tx.clone();
+            let task_config = config.clone();
+            let task_semaphore = semaphore.clone();
+            TOKIO_RUNTIME.spawn(async move {
+                let _permit = task_semaphore
+                    .acquire_owned()
+                    .await
+                    .expect("the request semaphore is never closed");
+                dispatch_request(
+                    request,
+                    task_connection,
+                    task_transformer_backends,
+                    task_memory_backend_tx,
+                    task_config,
+                )
+                .await;
+            });
+        };
+
+    // Prefetches wait for editing to pause then take a request slot like any other request
+    let run_prefetch = |edit_id,
+                        position,
+                        transformer_backends: &Arc<_>,
+                        config: &Config,
+                        semaphore: &Arc<Semaphore>,
+                        rate_limiter: Option<&RateLimiter>| {
+        let Some(completion_config) = config.get_prefetch_completion().cloned() else {
+            return;
+        };
+        let task_transformer_backends = Arc::clone(transformer_backends);
         let task_memory_backend_tx = memory_backend_tx.clone();
-        let task_config = config.clone();
+        let task_semaphore = semaphore.clone();
+        let task_rate_limiter = rate_limiter.cloned();
         TOKIO_RUNTIME.spawn(async move {
-            dispatch_request(
-                request,
-                task_connection,
+            if let Err(e) = do_prefetch(
                 task_transformer_backends,
                 task_memory_backend_tx,
-                task_config,
+                task_semaphore,
+                task_rate_limiter,
+                edit_id,
+                position,
+                completion_config,
             )
-            .await;
+            .await
+            {
+                error!("prefetching completion: {e:?}");
+            }
         });
     };
 
@@ -287,44 +631,65 @@ fn do_run(
                 WorkerRequest::Shutdown => {
                     return Ok(());
                 }
-                WorkerRequest::Completion(completion_request) => {
-                    if max_requests_per_second.is_ok() {
+                WorkerRequest::DidChangeConfiguration(new_config) => {
+                    match build_transformer_backends(&new_config.config.models) {
+                        Ok(new_transformer_backends) => {
+                            info!("reloaded configuration");
+                            transformer_backends = Arc::new(new_transformer_backends);
+                            // In flight requests hold permits of the semaphore they started with
+                            if new_config.config.max_concurrent_requests
+                                != config.config.max_concurrent_requests
+                            {
+                                semaphore = Arc::new(Semaphore::new(
+                                    new_config.config.max_concurrent_requests.max(1),
+                                ));
+                            }
+                            config = (**new_config).clone();
+                            rate_limiter = build_rate_limiter(&config);
+                        }
+                        Err(e) => error!("reloading configuration: {e:?}"),
+                    }
+                }
+                WorkerRequest::DidChangeTextDocument(params) => {
+                    if let Some((edit_id, position)) = record_edit(params) {
+                        run_prefetch(
+                            edit_id,
+                            position,
+                            &transformer_backends,
+                            &config,
+                            &semaphore,
+                            rate_limiter.as_ref().ok(),
+                        );
+                    }
+                }
+                WorkerRequest::Completion(_) | WorkerRequest::InlineCompletion(_) => {
+                    if rate_limiter.is_ok() {
                         last_completion_request = Some(request);
+                    } else if request.get_profile().is_some() {
+                        // Profiles can be used without the rate limited default completion
+                        run_dispatch_request(request, &transformer_backends, &config, &semaphore);
                     } else {
                         // If completion is disabled return an empty response
-                        let completion_list = CompletionList {
-                            is_incomplete: false,
-                            items: vec![],
-                        };
-                        let result = Some(CompletionResponse::List(completion_list));
-                        let result = serde_json::to_value(result).unwrap();
                         if let Err(e) = connection.sender.send(Message::Response(Response {
-                            id: completion_request.id.clone(),
-                            result: Some(result),
+                            id: request.get_id(),
+                            result: request.get_empty_result(),
                             error: None,
                         })) {
                             error!("sending empty response for completion request: {e:?}");
                         }
                     }
                 }
-                _ => run_dispatch_request(request),
+                _ => run_dispatch_request(request, &transformer_backends, &config, &semaphore),
             },
             Err(RecvTimeoutError::Disconnected) => anyhow::bail!("channel disconnected"),
             _ => {}
         }
 
-        if let Ok(max_requests_per_second) = max_requests_per_second {
-            if SystemTime::now()
-                .duration_since(last_completion_request_time)?
-                .as_secs_f32()
-                < 1. / max_requests_per_second
-            {
-                continue;
-            }
-
-            if let Some(request) = last_completion_request.take() {
-                last_completion_request_time = SystemTime::now();
-                run_dispatch_request(request);
+        if let Ok(rate_limiter) = &rate_limiter {
+            if last_completion_request.is_some() && rate_limiter.try_acquire() {
+                if let Some(request) = last_completion_request.take() {
+                    run_dispatch_request(request, &transformer_backends, &config, &semaphore);
+                }
             }
         }
     }
@@ -338,15 +703,51 @@ async fn dispatch_request(
     memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
     config: Config,
 ) {
-    let response = match generate_response(
+    // A hung backend should not leave the editor waiting forever
+    let timeout = config.get_request_timeout(request.get_model(&config));
+    let response = generate_response(
         request.clone(),
+        connection.clone(),
         transformer_backends,
         memory_backend_tx,
         config,
-    )
-    .await
-    {
+    );
+    let response = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, response).await {
+            Ok(response) => response,
+            Err(_) => {
+                warn!("request timed out after {:.2}s", timeout.as_secs_f32());
+                match request.get_empty_result() {
+                    Some(result) => Ok(Response {
+                        id: request.get_id(),
+                        result: Some(result),
+                        error: None,
+                    }),
+                    None => Err(anyhow::anyhow!(
+                        "request timed out after {:.2}s",
+                        timeout.as_secs_f32()
+                    )),
+                }
+            }
+        },
+        None => response.await,
+    };
+    let response = match response {
         Ok(response) => response,
+        // Completions are requested while typing so a failure that clears up on its own is not
+        // worth an error in the editor
+        Err(e)
+            if e.downcast_ref::<BackendError>()
+                .is_some_and(BackendError::is_transient)
+                && request.get_empty_result().is_some() =>
+        {
+            warn!("generating response: {e:?}");
+            Response {
+                id: request.get_id(),
+                result: request.get_empty_result(),
+                error: None,
+            }
+        }
         Err(e) => {
             error!("generating response: {e:?}");
             Response {
@@ -364,6 +765,7 @@ async fn dispatch_request(
 
 async fn generate_response(
     request: WorkerRequest,
+    connection: Arc<Connection>,
     transformer_backends: Arc<HashMap<String, Box<dyn TransformerBackend + Send + Sync>>>,
     memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
     config: Config,
@@ -371,31 +773,64 @@ async fn generate_response(
     match request {
         WorkerRequest::Completion(request) => {
             let completion_config = config
-                .config
-                .completion
-                .as_ref()
-                .context("Completions is none")?;
+                .get_completion_with_model(request.profile.as_deref(), request.model.as_deref())?;
             let transformer_backend = transformer_backends
                 .get(&completion_config.model)
                 .with_context(|| format!("can't find model: {}", &completion_config.model))?;
             do_completion(transformer_backend, memory_backend_tx, &request, &config).await
         }
+        WorkerRequest::InlineCompletion(request) => {
+            let completion_config = config
+                .get_completion_with_model(request.profile.as_deref(), request.model.as_deref())?;
+            let transformer_backend = transformer_backends
+                .get(&completion_config.model)
+                .with_context(|| format!("can't find model: {}", &completion_config.model))?;
+            do_inline_completion(transformer_backend, memory_backend_tx, &request, &config).await
+        }
         WorkerRequest::Generation(request) => {
             let transformer_backend = transformer_backends
                 .get(&request.params.model)
                 .with_context(|| format!("can't find model: {}", &request.params.model))?;
-            do_generate(transformer_backend, memory_backend_tx, &request).await
+            do_generate(transformer_backend, memory_backend_tx, &request, &config).await
         }
-        WorkerRequest::GenerationStream(_) => {
-            anyhow::bail!("Streaming is not yet supported")
+        WorkerRequest::GenerationStream(request) => {
+            let transformer_backend = transformer_backends
+                .get(&request.params.model)
+                .with_context(|| format!("can't find model: {}", &request.params.model))?;
+            do_generate_stream(
+                transformer_backend,
+                memory_backend_tx,
+                &request,
+                connection,
+                &config,
+            )
+            .await
         }
         WorkerRequest::CodeActionRequest(request) => {
             do_code_action_request(memory_backend_tx, &request, &config).await
         }
         WorkerRequest::CodeActionResolveRequest(request) => {
-            do_code_action_resolve(transformer_backends, memory_backend_tx, &request, &config).await
+            do_code_action_resolve(
+                transformer_backends,
+                memory_backend_tx,
+                &request,
+                &connection,
+                &config,
+            )
+            .await
+        }
+        WorkerRequest::UndoGeneration(request) => {
+            do_undo_generation(memory_backend_tx, &request).await
+        }
+        WorkerRequest::PreviewPrompt(request) => {
+            let transformer_backend = transformer_backends
+                .get(&request.params.model)
+                .with_context(|| format!("can't find model: {}", &request.params.model))?;
+            do_preview_prompt(transformer_backend, memory_backend_tx, &request, &config).await
         }
-        WorkerRequest::Shutdown => unreachable!(),
+        WorkerRequest::Shutdown
+        | WorkerRequest::DidChangeConfiguration(_)
+        | WorkerRequest::DidChangeTextDocument(_) => unreachable!(),
     }
 }
 
@@ -403,6 +838,66 @@ async fn generate_response(
 struct CodeActionResolveData {
     text_document: TextDocumentIdentifier,
     range: Range,
+    // The diagnostics the editor sent with the code action request
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    diagnostics: Vec<Diagnostic>,
+    // The model override sent with the code action request. Only used by `chats`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+}
+
+// The char index of an LSP position. Characters are counted in UTF-16 code units and clamped to
+// the end of the line
+fn position_to_char(rope: &Rope, position: Position) -> usize {
+    let line_index = position.line as usize;
+    if line_index >= rope.len_lines() {
+        return rope.len_chars();
+    }
+    let line = rope.line(line_index);
+    let line_len = line.len_chars()
+        - line
+            .chars_at(line.len_chars())
+            .reversed()
+            .take_while(|c| *c == '\n' || *c == '\r')
+            .count();
+    let character = (position.character as usize).min(line.slice(..line_len).len_utf16_cu());
+    rope.line_to_char(line_index) + line.utf16_cu_to_char(character)
+}
+
+// The text spanned by `range`, the end is exclusive
+fn get_text_in_range(file_text: &str, range: Range) -> String {
+    let rope = Rope::from_str(file_text);
+    let start = position_to_char(&rope, range.start);
+    let end = position_to_char(&rope, range.end).max(start);
+    rope.slice(start..end).to_string()
+}
+
+// Each diagnostic's severity, position and message followed by the code it spans
+fn format_diagnostics(diagnostics: &[Diagnostic], file_text: &str) -> String {
+    diagnostics
+        .iter()
+        .map(|diagnostic| {
+            let severity = match diagnostic.severity {
+                Some(DiagnosticSeverity::WARNING) => "warning",
+                Some(DiagnosticSeverity::INFORMATION) => "info",
+                Some(DiagnosticSeverity::HINT) => "hint",
+                _ => "error",
+            };
+            let source = diagnostic
+                .source
+                .as_ref()
+                .map(|source| format!(" [{source}]"))
+                .unwrap_or_default();
+            format!(
+                "{severity}{source} at line {}, column {}: {}\n{}",
+                diagnostic.range.start.line + 1,
+                diagnostic.range.start.character + 1,
+                diagnostic.message,
+                get_text_in_range(file_text, diagnostic.range)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
 }
 
 async fn do_chat_code_action_resolve(
@@ -410,14 +905,8 @@ async fn do_chat_code_action_resolve(
     transformer_backends: Arc<HashMap<String, Box<dyn TransformerBackend + Send + Sync>>>,
     memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
     request: &CodeActionResolveRequest,
+    config: &Config,
 ) -> anyhow::Result<CodeAction> {
-    let transformer_backend = transformer_backends.get(&action.model).with_context(|| {
-        format!(
-            "model: {} not found when resolving code action",
-            action.model
-        )
-    })?;
-
     let data: CodeActionResolveData = serde_json::from_value(
         request
             .params
@@ -427,6 +916,11 @@ async fn do_chat_code_action_resolve(
     )
     .context("the `data` field could not be deserialized when resolving the code action")?;
 
+    let model = config.get_chat_model(action, data.model.as_deref())?;
+    let transformer_backend = transformer_backends
+        .get(model)
+        .with_context(|| format!("model: {model} not found when resolving code action"))?;
+
     // Get the file
     let (tx, rx) = oneshot::channel();
     memory_backend_tx.send(memory_worker::WorkerRequest::File(FileRequest::new(
@@ -515,7 +1009,10 @@ async fn do_chat_code_action_resolve(
         );
     }
 
-    let params = serde_json::to_value(&params).unwrap();
+    let params = with_sampling_defaults(
+        serde_json::to_value(&params).unwrap(),
+        RequestKind::Generation,
+    );
 
     // Build the prompt
     let (tx, rx) = oneshot::channel();
@@ -528,10 +1025,21 @@ async fn do_chat_code_action_resolve(
         params.clone(),
         tx,
     )))?;
-    let prompt = rx.await?;
+    let mut prompt = rx.await?;
+    if let Prompt::ContextAndCode(prompt) = &mut prompt {
+        if !action.attached_files.is_empty() {
+            prompt.attached_files = Some(read_attached_files(
+                &action.attached_files,
+                config.client_params.root_uri.as_deref(),
+                tokens_to_estimated_characters(action.max_attached_tokens),
+            ));
+        }
+    }
 
     // Get the response
-    let mut response = transformer_backend.do_completion(&prompt, params).await?;
+    let mut response = USAGE_STATS
+        .track(model, transformer_backend.do_completion(&prompt, params))
+        .await?;
     response.insert_text = format!("\n\n<|assistant|>\n{}\n\n<|user|>\n", response.insert_text);
 
     let edit = TextEdit::new(
@@ -541,6 +1049,7 @@ async fn do_chat_code_action_resolve(
         ),
         response.insert_text.clone(),
     );
+    record_generation(&data.text_document.uri, &edit);
     let changes = HashMap::from([(data.text_document.uri, vec![edit])]);
 
     Ok(CodeAction {
@@ -553,11 +1062,61 @@ async fn do_chat_code_action_resolve(
     })
 }
 
+// Larger attached files are not read
+const ATTACHED_FILE_MAX_SIZE: u64 = 10_000_000;
+
+// Reads the files in order, each under its own header, until `max_characters` is used up
+// Files that can't be read are skipped so a moved file doesn't break the action
+fn read_attached_files(files: &[String], root_uri: Option<&str>, max_characters: usize) -> String {
+    let root = root_uri.and_then(|root_uri| Url::parse(root_uri).ok()?.to_file_path().ok());
+    let mut remaining = max_characters;
+    let mut attached = vec![];
+    for file in files {
+        if remaining == 0 {
+            warn!("Skipping attached file: {file} as the `max_attached_tokens` budget is used up");
+            continue;
+        }
+        let path = match &root {
+            Some(root) if Path::new(file).is_relative() => root.join(file),
+            _ => PathBuf::from(file),
+        };
+        let contents = match read_file_with_size_cap(&path, ATTACHED_FILE_MAX_SIZE) {
+            Ok(Some(contents)) => contents,
+            Ok(None) => {
+                warn!(
+                    "Skipping attached file: {} as it is too large or binary",
+                    path.display()
+                );
+                continue;
+            }
+            Err(e) => {
+                warn!(
+                    "Skipping attached file: {} as it can't be read: {e:?}",
+                    path.display()
+                );
+                continue;
+            }
+        };
+        let excerpt: String = contents.chars().take(remaining).collect();
+        remaining -= excerpt.chars().count();
+        attached.push(format_file_chunk(
+            &format!("file://{}", path.display()),
+            &excerpt,
+            root_uri,
+            None,
+        ));
+    }
+    attached.join("\n\n")
+}
+
 async fn do_code_action_action_resolve(
     action: &config::Action,
     transformer_backends: Arc<HashMap<String, Box<dyn TransformerBackend + Send + Sync>>>,
     memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
     request: &CodeActionResolveRequest,
+    root_uri: Option<&str>,
+    connection: &Connection,
+    stream_actions: bool,
 ) -> anyhow::Result<CodeAction> {
     let transformer_backend = transformer_backends.get(&action.model).with_context(|| {
         format!(
@@ -575,7 +1134,10 @@ async fn do_code_action_action_resolve(
     )
     .context("the `data` field could not be deserialized when resolving the code action")?;
 
-    let params = serde_json::to_value(action.parameters.clone()).unwrap();
+    let params = with_sampling_defaults(
+        serde_json::to_value(action.parameters.clone()).unwrap(),
+        RequestKind::Generation,
+    );
 
     // Get the prompt
     let text_document_position = TextDocumentPositionParams {
@@ -591,115 +1153,345 @@ async fn do_code_action_action_resolve(
     )))?;
     let mut prompt = rx.await?;
 
-    // If they have some text highlighted and we aren't doing FIM  let's get it
-    if matches!(prompt, Prompt::ContextAndCode(_)) && data.range.start != data.range.end {
-        // Get the file
-        let (tx, rx) = oneshot::channel();
-        memory_backend_tx.send(memory_worker::WorkerRequest::File(FileRequest::new(
-            TextDocumentIdentifier {
-                uri: data.text_document.uri.clone(),
-            },
-            tx,
-        )))?;
-        let file_text = rx.await?;
-
-        // Get the text
-        let lines: Vec<&str> = file_text.lines().collect();
-        let mut result = String::new();
-        for (i, line) in lines
-            .iter()
-            .enumerate()
-            .skip(data.range.start.line as usize)
-            .take((data.range.end.line - data.range.start.line + 1) as usize)
-        {
-            let start_char = if i == data.range.start.line as usize {
-                data.range.start.character as usize
-            } else {
-                0
-            };
-            let end_char = if i == data.range.end.line as usize {
-                data.range.end.character as usize + 1
-            } else {
-                line.len()
-            };
+    // If they have some text highlighted or sent diagnostics and we aren't doing FIM let's get
+    // the text they span
+    let has_selection = data.range.start != data.range.end;
+    if let Prompt::ContextAndCode(prompt) = &mut prompt {
+        if has_selection || !data.diagnostics.is_empty() {
+            // Get the file
+            let (tx, rx) = oneshot::channel();
+            memory_backend_tx.send(memory_worker::WorkerRequest::File(FileRequest::new(
+                TextDocumentIdentifier {
+                    uri: data.text_document.uri.clone(),
+                },
+                tx,
+            )))?;
+            let file_text = rx.await?;
 
-            if start_char < line.len() {
-                result.push_str(&line[start_char..end_char.min(line.len())]);
+            if has_selection {
+                prompt.selected_text = Some(get_text_in_range(&file_text, data.range));
             }
-
-            if i != data.range.end.line as usize {
-                result.push('\n');
+            if !data.diagnostics.is_empty() {
+                prompt.diagnostics = Some(format_diagnostics(&data.diagnostics, &file_text));
             }
         }
+    }
 
-        // Update our prompt to include the selected text
-        if let Prompt::ContextAndCode(prompt) = &mut prompt {
-            prompt.selected_text = Some(result)
+    if let Prompt::ContextAndCode(prompt) = &mut prompt {
+        if !action.attached_files.is_empty() {
+            prompt.attached_files = Some(read_attached_files(
+                &action.attached_files,
+                root_uri,
+                tokens_to_estimated_characters(action.max_attached_tokens),
+            ));
         }
     }
 
+    // New documents are created in one edit and explanations are not edits so neither is streamed
+    let streamable = !matches!(
+        action.mode,
+        config::ActionMode::NewDocument | config::ActionMode::Explain
+    );
+    if stream_actions && streamable {
+        stream_action_edit(
+            connection,
+            transformer_backend,
+            &prompt,
+            params,
+            action,
+            &data,
+        )
+        .await?;
+        // The response is already in the document
+        return Ok(CodeAction {
+            title: action.action_display_name.clone(),
+            ..Default::default()
+        });
+    }
+
     // Get the response
-    let mut response = transformer_backend.do_completion(&prompt, params).await?;
+    let mut response = USAGE_STATS
+        .track(
+            &action.model,
+            transformer_backend.do_completion(&prompt, params),
+        )
+        .await?;
     response.insert_text =
         post_process_response(response.insert_text, &prompt, &action.post_process);
 
-    let edit = TextEdit::new(
-        Range::new(
-            Position::new(data.range.start.line, data.range.start.character),
-            Position::new(data.range.end.line, data.range.end.character),
-        ),
-        response.insert_text.clone(),
-    );
-    let changes = HashMap::from([(data.text_document.uri, vec![edit])]);
+    if action.mode == config::ActionMode::Explain {
+        return build_explanation_code_action(action, connection, data, response.insert_text);
+    }
 
     Ok(CodeAction {
         title: action.action_display_name.clone(),
-        edit: Some(WorkspaceEdit {
-            changes: Some(changes),
-            ..Default::default()
-        }),
+        edit: Some(build_action_workspace_edit(
+            action.mode,
+            &data,
+            response.insert_text,
+        )?),
         ..Default::default()
     })
 }
 
-// TODO: @silas we need to make this compatible with any llm backend
-async fn do_code_action_resolve(
-    transformer_backends: Arc<HashMap<String, Box<dyn TransformerBackend + Send + Sync>>>,
-    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
-    request: &CodeActionResolveRequest,
-    config: &Config,
-) -> anyhow::Result<Response> {
-    let action = if let Some(chat_action) = config
-        .get_chats()
-        .iter()
-        .find(|chat_action| chat_action.action_display_name == request.params.title)
-    {
-        do_chat_code_action_resolve(
-            chat_action,
-            transformer_backends,
-            memory_backend_tx,
-            request,
-        )
-        .await?
-    } else {
-        let action = config
-            .get_actions()
-            .iter()
-            .find(|action| action.action_display_name == request.params.title)
-            .with_context(|| {
-                format!(
-                    "action: {} does not exist in `chats` or `actions`",
-                    request.params.title
-                )
-            })?;
-        do_code_action_action_resolve(action, transformer_backends, memory_backend_tx, request)
-            .await?
-    };
-    Ok(Response {
-        id: request.id.clone(),
-        result: Some(serde_json::to_value(action).unwrap()),
-        error: None,
-    })
+// Hands a response from the client to the request waiting for it
+pub(crate) fn handle_client_response(response: Response) {
+    match PENDING_APPLY_EDITS.lock().remove(&response.id) {
+        Some(tx) => {
+            // The request may have stopped waiting
+            let _ = tx.send(response);
+        }
+        None => warn!(
+            "received a response to an unknown request: {:?}",
+            response.id
+        ),
+    }
+}
+
+// Asks the client to apply the edit and waits until it has. Errors if the client rejects it
+async fn send_apply_edit(connection: &Connection, uri: &Url, edit: TextEdit) -> anyhow::Result<()> {
+    let id = RequestId::from(format!(
+        "lsp-ai/apply-edit/{}",
+        NEXT_APPLY_EDIT_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    let (tx, rx) = oneshot::channel();
+    PENDING_APPLY_EDITS.lock().insert(id.clone(), tx);
+    let sent = connection.sender.send(Message::Request(Request::new(
+        id.clone(),
+        <ApplyWorkspaceEdit as lsp_types::request::Request>::METHOD.to_string(),
+        ApplyWorkspaceEditParams {
+            label: None,
+            edit: WorkspaceEdit {
+                changes: Some(HashMap::from([(uri.clone(), vec![edit])])),
+                ..Default::default()
+            },
+        },
+    )));
+    if let Err(e) = sent {
+        PENDING_APPLY_EDITS.lock().remove(&id);
+        return Err(e.into());
+    }
+
+    let response = rx
+        .await
+        .context("the connection closed before the client applied the edit")?;
+    if let Some(error) = response.error {
+        anyhow::bail!("the client failed to apply the edit: {}", error.message)
+    }
+    let result: ApplyWorkspaceEditResponse = serde_json::from_value(
+        response
+            .result
+            .context("the client sent no result for the applied edit")?,
+    )?;
+    if !result.applied {
+        anyhow::bail!(
+            "the client did not apply the edit: {}",
+            result
+                .failure_reason
+                .unwrap_or_else(|| "no reason given".to_string())
+        )
+    }
+    Ok(())
+}
+
+// Types the response into the document with `workspace/applyEdit` requests as it streams in
+// Each edit inserts the new chunks after the text applied so far. Post processing needs the whole
+// response so its result replaces the streamed text at the end if it differs
+async fn stream_action_edit(
+    connection: &Connection,
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    prompt: &Prompt,
+    params: Value,
+    action: &config::Action,
+    data: &CodeActionResolveData,
+) -> anyhow::Result<()> {
+    let uri = &data.text_document.uri;
+    let range = match action.mode {
+        config::ActionMode::Append => Range::new(data.range.end, data.range.end),
+        _ => data.range,
+    };
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let generation = USAGE_STATS.track(
+        &action.model,
+        transformer_backend.do_generate_stream(prompt, params, tx),
+    );
+    let apply = async {
+        let mut generated_text = String::new();
+        // Where the next chunk goes. The first edit replaces the range
+        let mut next_range = range;
+        while let Some(chunk) = rx.recv().await {
+            // Chunks that arrived while the last edit was sent go in one edit
+            let mut text = chunk.generated_text;
+            while let Ok(chunk) = rx.try_recv() {
+                text.push_str(&chunk.generated_text);
+            }
+            generated_text.push_str(&text);
+            let edit = TextEdit::new(next_range, text);
+            let end = applied_edit_range(&edit).end;
+            next_range = Range::new(end, end);
+            // Later edits are placed after this one so stop if it was not applied
+            send_apply_edit(connection, uri, edit).await?;
+        }
+        anyhow::Ok(generated_text)
+    };
+    let ((), generated_text) = futures::future::try_join(generation, apply).await?;
+
+    let text = post_process_response(generated_text.clone(), prompt, &action.post_process);
+    if text != generated_text {
+        let streamed_range = if generated_text.is_empty() {
+            range
+        } else {
+            applied_edit_range(&TextEdit::new(range, generated_text))
+        };
+        send_apply_edit(connection, uri, TextEdit::new(streamed_range, text.clone())).await?;
+    }
+    record_generation(uri, &TextEdit::new(range, text));
+    Ok(())
+}
+
+// Shows the text with `window/showMessage` and returns it in the action's `data` for editor
+// extensions that render it themselves
+fn build_explanation_code_action(
+    action: &config::Action,
+    connection: &Connection,
+    data: CodeActionResolveData,
+    text: String,
+) -> anyhow::Result<CodeAction> {
+    connection
+        .sender
+        .send(Message::Notification(Notification::new(
+            <ShowMessage as lsp_types::notification::Notification>::METHOD.to_string(),
+            ShowMessageParams {
+                typ: MessageType::INFO,
+                message: text.clone(),
+            },
+        )))?;
+    let explanation = ExplanationData {
+        text_document: data.text_document,
+        range: data.range,
+        contents: MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: text,
+        },
+    };
+    Ok(CodeAction {
+        title: action.action_display_name.clone(),
+        data: Some(serde_json::to_value(explanation)?),
+        ..Default::default()
+    })
+}
+
+// The extension of the file, kept so editors pick the same language for the new document
+fn new_document_extension(uri: &Url) -> String {
+    uri.to_file_path()
+        .ok()
+        .and_then(|path| {
+            path.extension()
+                .map(|extension| format!(".{}", extension.to_string_lossy()))
+        })
+        .unwrap_or_default()
+}
+
+fn build_action_workspace_edit(
+    mode: config::ActionMode,
+    data: &CodeActionResolveData,
+    text: String,
+) -> anyhow::Result<WorkspaceEdit> {
+    let range = match mode {
+        config::ActionMode::Explain => anyhow::bail!("`explain` actions do not edit the document"),
+        config::ActionMode::Replace => data.range,
+        config::ActionMode::Append => Range::new(data.range.end, data.range.end),
+        config::ActionMode::NewDocument => {
+            let extension = new_document_extension(&data.text_document.uri);
+            let path = std::env::temp_dir().join(format!(
+                "lsp-ai-{}{extension}",
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)?
+                    .as_millis()
+            ));
+            let uri = Url::from_file_path(&path)
+                .map_err(|_| anyhow::anyhow!("invalid new document path: {path:?}"))?;
+            return Ok(WorkspaceEdit {
+                document_changes: Some(DocumentChanges::Operations(vec![
+                    DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                        uri: uri.clone(),
+                        options: None,
+                        annotation_id: None,
+                    })),
+                    DocumentChangeOperation::Edit(TextDocumentEdit {
+                        text_document: OptionalVersionedTextDocumentIdentifier {
+                            uri,
+                            version: None,
+                        },
+                        edits: vec![OneOf::Left(TextEdit::new(
+                            Range::new(Position::new(0, 0), Position::new(0, 0)),
+                            text,
+                        ))],
+                    }),
+                ])),
+                ..Default::default()
+            });
+        }
+    };
+    let edit = TextEdit::new(range, text);
+    record_generation(&data.text_document.uri, &edit);
+    let changes = HashMap::from([(data.text_document.uri.clone(), vec![edit])]);
+    Ok(WorkspaceEdit {
+        changes: Some(changes),
+        ..Default::default()
+    })
+}
+
+// TODO: @silas we need to make this compatible with any llm backend
+async fn do_code_action_resolve(
+    transformer_backends: Arc<HashMap<String, Box<dyn TransformerBackend + Send + Sync>>>,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    request: &CodeActionResolveRequest,
+    connection: &Connection,
+    config: &Config,
+) -> anyhow::Result<Response> {
+    let action = if let Some(chat_action) = config
+        .get_chats()
+        .iter()
+        .find(|chat_action| chat_action.action_display_name == request.params.title)
+    {
+        do_chat_code_action_resolve(
+            chat_action,
+            transformer_backends,
+            memory_backend_tx,
+            request,
+            config,
+        )
+        .await?
+    } else {
+        let action = config
+            .get_actions()
+            .iter()
+            .find(|action| action.action_display_name == request.params.title)
+            .with_context(|| {
+                format!(
+                    "action: {} does not exist in `chats` or `actions`",
+                    request.params.title
+                )
+            })?;
+        do_code_action_action_resolve(
+            action,
+            transformer_backends,
+            memory_backend_tx,
+            request,
+            config.client_params.root_uri.as_deref(),
+            connection,
+            // Streaming needs `workspace/applyEdit`. Without it the edit is returned with the action
+            config.config.stream_actions && config.client_params.capabilities.workspace.apply_edit,
+        )
+        .await?
+    };
+    Ok(Response {
+        id: request.id.clone(),
+        result: Some(serde_json::to_value(action).unwrap()),
+        error: None,
+    })
 }
 
 async fn do_code_action_request(
@@ -709,6 +1501,12 @@ async fn do_code_action_request(
 ) -> anyhow::Result<Response> {
     let actions = config.get_actions();
     let chats = config.get_chats();
+    if let Some(model) = &request.model {
+        anyhow::ensure!(
+            config.config.models.contains_key(model),
+            "`{model}` not found in `models` config"
+        );
+    }
 
     let enabled_chats = futures::future::join_all(chats.iter().map(|chat| async {
         let (tx, rx) = oneshot::channel();
@@ -738,6 +1536,8 @@ async fn do_code_action_request(
                 serde_json::to_value(CodeActionResolveData {
                     text_document: request.params.text_document.clone(),
                     range: request.params.range,
+                    diagnostics: vec![],
+                    model: request.model.clone(),
                 })
                 .unwrap(),
             ),
@@ -745,19 +1545,25 @@ async fn do_code_action_request(
         })
         .collect();
 
-    code_actions.extend(actions.into_iter().map(|action| {
-        CodeAction {
-            title: action.action_display_name.to_owned(),
-            data: Some(
-                serde_json::to_value(CodeActionResolveData {
-                    text_document: request.params.text_document.clone(),
-                    range: request.params.range,
-                })
-                .unwrap(),
-            ),
-            ..Default::default()
-        }
-    }));
+    let diagnostics = &request.params.context.diagnostics;
+    code_actions.extend(
+        actions
+            .into_iter()
+            .filter(|action| !action.only_with_diagnostics || !diagnostics.is_empty())
+            .map(|action| CodeAction {
+                title: action.action_display_name.to_owned(),
+                data: Some(
+                    serde_json::to_value(CodeActionResolveData {
+                        text_document: request.params.text_document.clone(),
+                        range: request.params.range,
+                        diagnostics: diagnostics.clone(),
+                        model: None,
+                    })
+                    .unwrap(),
+                ),
+                ..Default::default()
+            }),
+    );
 
     Ok(Response {
         id: request.id.clone(),
@@ -766,71 +1572,406 @@ async fn do_code_action_request(
     })
 }
 
-async fn do_completion(
-    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+// Returns the range the text of an edit occupies after the edit is applied. Characters are
+// counted in UTF-16 code units like LSP positions
+fn applied_edit_range(edit: &TextEdit) -> Range {
+    let start = edit.range.start;
+    let mut lines = edit.new_text.split('\n');
+    let first_line = lines.next().unwrap_or_default();
+    let end = match lines.next_back() {
+        Some(last_line) => Position::new(
+            start.line + edit.new_text.matches('\n').count() as u32,
+            last_line.encode_utf16().count() as u32,
+        ),
+        None => Position::new(
+            start.line,
+            start.character + first_line.encode_utf16().count() as u32,
+        ),
+    };
+    Range::new(start, end)
+}
+
+// Edits returned from a resolve are recorded before the client applies them. Undoing checks the
+// recorded range still holds the generated text
+fn record_generation(uri: &Url, edit: &TextEdit) {
+    LAST_GENERATIONS.lock().insert(
+        uri.clone(),
+        TextEdit::new(applied_edit_range(edit), edit.new_text.clone()),
+    );
+}
+
+async fn do_undo_generation(
     memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
-    request: &CompletionRequest,
-    config: &Config,
+    request: &UndoGenerationRequest,
 ) -> anyhow::Result<Response> {
-    let params = serde_json::to_value(
-        config
-            .config
-            .completion
-            .as_ref()
-            .context("Completions is None")?
-            .parameters
-            .clone(),
-    )
-    .unwrap();
+    let uri = &request.params.text_document.uri;
+    let last_generation = LAST_GENERATIONS
+        .lock()
+        .get(uri)
+        .cloned()
+        .with_context(|| format!("no generation to undo for: {uri}"))?;
 
-    // Build the prompt
+    // Get the file
     let (tx, rx) = oneshot::channel();
-    memory_backend_tx.send(memory_worker::WorkerRequest::Prompt(PromptRequest::new(
-        request.params.text_document_position.clone(),
-        transformer_backend.get_prompt_type(&params)?,
-        params.clone(),
+    memory_backend_tx.send(memory_worker::WorkerRequest::File(FileRequest::new(
+        request.params.text_document.clone(),
         tx,
     )))?;
-    let prompt = rx.await?;
+    let file_text = rx.await?;
+
+    // Make sure the generated text is still where we left it
+    let rope = ropey::Rope::from_str(&file_text);
+    let range = last_generation.range;
+    let current_text = rope
+        .get_slice(position_to_char(&rope, range.start)..position_to_char(&rope, range.end))
+        .map(|text| text.to_string());
+    // Either way this generation can no longer be undone
+    LAST_GENERATIONS.lock().remove(uri);
+    if current_text.as_deref() != Some(last_generation.new_text.as_str()) {
+        anyhow::bail!("the document has changed since the last generation was applied: {uri}")
+    }
+
+    let edit = TextEdit::new(range, String::new());
+    let result = WorkspaceEdit {
+        changes: Some(HashMap::from([(uri.clone(), vec![edit])])),
+        ..Default::default()
+    };
+    Ok(Response {
+        id: request.id.clone(),
+        result: Some(serde_json::to_value(result).unwrap()),
+        error: None,
+    })
+}
+
+fn should_complete(filter_text: &str, completion_config: &config::Completion) -> bool {
+    completion_config
+        .trigger_characters
+        .iter()
+        .any(|trigger| filter_text.ends_with(trigger.as_str()))
+        || filter_text.trim_start().chars().count() >= completion_config.min_prefix_chars
+}
+
+fn get_filter_text(line_prefix: String, strategy: config::FilterTextStrategy) -> Option<String> {
+    match strategy {
+        config::FilterTextStrategy::LinePrefix => Some(line_prefix),
+        config::FilterTextStrategy::WordPrefix => {
+            let word_length = line_prefix
+                .chars()
+                .rev()
+                .take_while(|c| c.is_alphanumeric() || *c == '_')
+                .count();
+            let word_start = line_prefix.chars().count() - word_length;
+            Some(line_prefix.chars().skip(word_start).collect())
+        }
+        config::FilterTextStrategy::None => None,
+    }
+}
+
+fn trim_completion(text: String, policy: config::TrimPolicy) -> String {
+    match policy {
+        config::TrimPolicy::None => text,
+        config::TrimPolicy::Trailing => text.trim_end().to_string(),
+        config::TrimPolicy::Both => text.trim().to_string(),
+    }
+}
+
+fn truncate_label(text: &str, max_length: usize) -> String {
+    if text.chars().count() > max_length {
+        format!("{}…", text.chars().take(max_length).collect::<String>())
+    } else {
+        text.to_string()
+    }
+}
+
+// Multi-line completions make for huge menu entries so by default only the first non blank line is shown
+fn format_completion_label(insert_text: &str, label_template: &str, max_length: usize) -> String {
+    let first_line = insert_text
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty())
+        .unwrap_or_default();
+    let insert_text = insert_text.trim().replace('\n', " ");
+    label_template
+        .replace("{first_line}", &truncate_label(first_line, max_length))
+        .replace("{insert_text}", &truncate_label(&insert_text, max_length))
+}
+
+// Inserts at the cursor. When a replace range is given accepting the completion replaces the rest
+// of a partially typed identifier instead of duplicating it
+fn build_completion_text_edit(
+    position: Position,
+    text: String,
+    replace_range: Option<Range>,
+) -> CompletionTextEdit {
+    let insert = Range::new(position, position);
+    match replace_range {
+        Some(replace) => CompletionTextEdit::InsertAndReplace(InsertReplaceEdit {
+            new_text: text,
+            insert,
+            replace,
+        }),
+        None => CompletionTextEdit::Edit(TextEdit::new(insert, text)),
+    }
+}
+
+// The filter text and the text to insert at the position
+// Returns None if the position should not be completed
+async fn get_completion_text(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: &std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    position: &TextDocumentPositionParams,
+    completion_config: &config::Completion,
+) -> anyhow::Result<Option<(String, Vec<String>)>> {
+    let params = with_sampling_defaults(
+        serde_json::to_value(completion_config.parameters.clone()).unwrap(),
+        RequestKind::Completion,
+    );
 
     // Get the filter text
     let (tx, rx) = oneshot::channel();
     memory_backend_tx.send(memory_worker::WorkerRequest::FilterText(
-        FilterRequest::new(request.params.text_document_position.clone(), tx),
+        FilterRequest::new(position.clone(), tx),
     ))?;
     let filter_text = rx.await?;
 
+    // Skip calling the model for trivial positions
+    if !should_complete(&filter_text, completion_config) {
+        return Ok(None);
+    }
+
+    // Build the prompt
+    let (tx, rx) = oneshot::channel();
+    memory_backend_tx.send(memory_worker::WorkerRequest::Prompt(PromptRequest::new(
+        position.clone(),
+        transformer_backend.get_prompt_type(&params)?,
+        params.clone(),
+        tx,
+    )))?;
+    let prompt = rx.await?;
+
     // Get the response
-    let mut response = transformer_backend.do_completion(&prompt, params).await?;
+    let response = USAGE_STATS
+        .track(
+            &completion_config.model,
+            transformer_backend.do_completion(&prompt, params),
+        )
+        .await?;
 
-    if let Some(post_process) = config.get_completions_post_process() {
-        response.insert_text = post_process_response(response.insert_text, &prompt, post_process);
+    // Candidates that are the same after post processing are only offered once
+    let mut candidates: Vec<String> = vec![];
+    for insert_text in std::iter::once(response.insert_text).chain(response.alternatives) {
+        let insert_text =
+            post_process_response(insert_text, &prompt, &completion_config.post_process);
+        let insert_text = trim_completion(insert_text, completion_config.trim);
+        if !candidates.contains(&insert_text) {
+            candidates.push(insert_text);
+        }
     }
 
-    // Build and send the response
-    let completion_text_edit = TextEdit::new(
-        Range::new(
-            Position::new(
-                request.params.text_document_position.position.line,
-                request.params.text_document_position.position.character,
-            ),
-            Position::new(
-                request.params.text_document_position.position.line,
-                request.params.text_document_position.position.character,
-            ),
-        ),
-        response.insert_text.clone(),
+    Ok(Some((filter_text, candidates)))
+}
+
+// The cursor after the last change, assuming it ends up after the inserted text
+fn cursor_after_changes(
+    params: &DidChangeTextDocumentParams,
+) -> Option<TextDocumentPositionParams> {
+    let change = params.content_changes.last()?;
+    let position = applied_edit_range(&TextEdit::new(change.range?, change.text.clone())).end;
+    Some(TextDocumentPositionParams::new(
+        TextDocumentIdentifier::new(params.text_document.uri.clone()),
+        position,
+    ))
+}
+
+// Drops the document's prefetch and returns the cursor to prefetch for next
+fn record_edit(params: &DidChangeTextDocumentParams) -> Option<(u64, TextDocumentPositionParams)> {
+    let mut prefetches = PREFETCHES.lock();
+    let Some(position) = cursor_after_changes(params) else {
+        prefetches.remove(&params.text_document.uri);
+        return None;
+    };
+    let edit_id = NEXT_EDIT_ID.fetch_add(1, Ordering::Relaxed);
+    prefetches.insert(
+        params.text_document.uri.clone(),
+        PrefetchedCompletion {
+            edit_id,
+            position: position.clone(),
+            completion: None,
+        },
     );
-    let item = CompletionItem {
-        label: format!("ai - {}", response.insert_text),
-        filter_text: Some(filter_text),
-        text_edit: Some(lsp_types::CompletionTextEdit::Edit(completion_text_edit)),
-        kind: Some(CompletionItemKind::TEXT),
-        ..Default::default()
+    Some((edit_id, position))
+}
+
+fn is_latest_edit(uri: &Url, edit_id: u64) -> bool {
+    PREFETCHES
+        .lock()
+        .get(uri)
+        .is_some_and(|prefetch| prefetch.edit_id == edit_id)
+}
+
+// Only stored if the document wasn't edited while the completion was generated
+fn store_prefetch(uri: &Url, edit_id: u64, completion: (String, Vec<String>)) {
+    if let Some(prefetch) = PREFETCHES
+        .lock()
+        .get_mut(uri)
+        .filter(|prefetch| prefetch.edit_id == edit_id)
+    {
+        prefetch.completion = Some(completion);
+    }
+}
+
+fn take_prefetch(position: &TextDocumentPositionParams) -> Option<(String, Vec<String>)> {
+    let mut prefetches = PREFETCHES.lock();
+    let uri = &position.text_document.uri;
+    if prefetches
+        .get(uri)
+        .is_some_and(|prefetch| prefetch.position == *position && prefetch.completion.is_some())
+    {
+        prefetches.remove(uri)?.completion
+    } else {
+        None
+    }
+}
+
+async fn do_prefetch(
+    transformer_backends: Arc<HashMap<String, Box<dyn TransformerBackend + Send + Sync>>>,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    semaphore: Arc<Semaphore>,
+    rate_limiter: Option<RateLimiter>,
+    edit_id: u64,
+    position: TextDocumentPositionParams,
+    completion_config: config::Completion,
+) -> anyhow::Result<()> {
+    let uri = &position.text_document.uri;
+    let idle = Duration::from_millis(completion_config.prefetch.as_ref().map_or(0, |p| p.idle_ms));
+    tokio::time::sleep(idle).await;
+    if !is_latest_edit(uri, edit_id) {
+        return Ok(());
+    }
+    if let Some(rate_limiter) = rate_limiter {
+        while !rate_limiter.try_acquire() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            if !is_latest_edit(uri, edit_id) {
+                return Ok(());
+            }
+        }
+    }
+    let _permit = semaphore.acquire_owned().await?;
+    if !is_latest_edit(uri, edit_id) {
+        return Ok(());
+    }
+    let transformer_backend = transformer_backends
+        .get(&completion_config.model)
+        .with_context(|| format!("can't find model: {}", &completion_config.model))?;
+    if let Some(completion) = get_completion_text(
+        transformer_backend,
+        &memory_backend_tx,
+        &position,
+        &completion_config,
+    )
+    .await?
+    {
+        store_prefetch(uri, edit_id, completion);
+    }
+    Ok(())
+}
+
+// Prefetches are made with the default completion config so requests with a profile or a model
+// override skip them
+async fn get_prefetched_or_completion_text(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: &std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    position: &TextDocumentPositionParams,
+    completion_config: &config::Completion,
+    use_prefetch: bool,
+) -> anyhow::Result<Option<(String, Vec<String>)>> {
+    if use_prefetch {
+        if let Some(completion) = take_prefetch(position) {
+            info!("using the prefetched completion");
+            return Ok(Some(completion));
+        }
+    }
+    get_completion_text(
+        transformer_backend,
+        memory_backend_tx,
+        position,
+        completion_config,
+    )
+    .await
+}
+
+async fn do_completion(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    request: &CompletionRequest,
+    config: &Config,
+) -> anyhow::Result<Response> {
+    let completion_config =
+        config.get_completion_with_model(request.profile.as_deref(), request.model.as_deref())?;
+
+    let Some((filter_text, candidates)) = get_prefetched_or_completion_text(
+        transformer_backend,
+        &memory_backend_tx,
+        &request.params.text_document_position,
+        &completion_config,
+        request.profile.is_none() && request.model.is_none(),
+    )
+    .await?
+    else {
+        let completion_list = CompletionList {
+            is_incomplete: true,
+            items: vec![],
+        };
+        let result = Some(CompletionResponse::List(completion_list));
+        let result = serde_json::to_value(result).unwrap();
+        return Ok(Response {
+            id: request.id.clone(),
+            result: Some(result),
+            error: None,
+        });
+    };
+
+    // Get the range of the identifier after the cursor if the client can replace it
+    let replace_range = if config
+        .client_params
+        .capabilities
+        .text_document
+        .completion
+        .completion_item
+        .insert_replace_support
+    {
+        let (tx, rx) = oneshot::channel();
+        memory_backend_tx.send(memory_worker::WorkerRequest::ReplaceRange(
+            ReplaceRangeRequest::new(request.params.text_document_position.clone(), tx),
+        ))?;
+        Some(rx.await?)
+    } else {
+        None
     };
+
+    // Build and send the response
+    let filter_text = get_filter_text(filter_text, completion_config.filter_text_strategy);
+    let items = candidates
+        .into_iter()
+        .map(|insert_text| CompletionItem {
+            label: format_completion_label(
+                &insert_text,
+                &completion_config.label_template,
+                completion_config.label_max_length,
+            ),
+            filter_text: filter_text.clone(),
+            text_edit: Some(build_completion_text_edit(
+                request.params.text_document_position.position,
+                insert_text,
+                replace_range,
+            )),
+            kind: Some(CompletionItemKind::TEXT),
+            ..Default::default()
+        })
+        .collect();
     let completion_list = CompletionList {
         is_incomplete: false,
-        items: vec![item],
+        items,
     };
     let result = Some(CompletionResponse::List(completion_list));
     let result = serde_json::to_value(result).unwrap();
@@ -841,14 +1982,189 @@ async fn do_completion(
     })
 }
 
-async fn do_generate(
+async fn do_inline_completion(
     transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
     memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
-    request: &GenerationRequest,
+    request: &InlineCompletionRequest,
+    config: &Config,
 ) -> anyhow::Result<Response> {
-    let params = serde_json::to_value(request.params.parameters.clone()).unwrap();
+    let completion_config =
+        config.get_completion_with_model(request.profile.as_deref(), request.model.as_deref())?;
+    let position = &request.params.text_document_position;
+    let items = match get_prefetched_or_completion_text(
+        transformer_backend,
+        &memory_backend_tx,
+        position,
+        &completion_config,
+        request.profile.is_none() && request.model.is_none(),
+    )
+    .await?
+    {
+        // Inline completions are ghost text inserted at the cursor
+        Some((filter_text, candidates)) => {
+            let filter_text = get_filter_text(filter_text, completion_config.filter_text_strategy);
+            candidates
+                .into_iter()
+                .map(|insert_text| InlineCompletionItem {
+                    insert_text,
+                    filter_text: filter_text.clone(),
+                    range: Some(Range::new(position.position, position.position)),
+                    command: None,
+                    insert_text_format: None,
+                })
+                .collect()
+        }
+        None => vec![],
+    };
+    let result = Some(InlineCompletionResponse::Array(items));
+    let result = serde_json::to_value(result).unwrap();
+    Ok(Response {
+        id: request.id.clone(),
+        result: Some(result),
+        error: None,
+    })
+}
 
-    let (tx, rx) = oneshot::channel();
+// Parameters sent with a generation request override the parameters configured for the model
+fn get_generation_params(config: &Config, model: &str, request_params: &Value) -> Value {
+    let mut params = config
+        .get_model_parameters(model)
+        .map(|params| serde_json::to_value(params).unwrap())
+        .unwrap_or_else(|| serde_json::json!({}));
+    override_json(&mut params, request_params);
+    with_sampling_defaults(params, RequestKind::Generation)
+}
+
+#[derive(Clone, Copy)]
+enum RequestKind {
+    // Completions and inline completions, including FIM
+    Completion,
+    // Generations and code actions
+    Generation,
+}
+
+// Completions default to deterministic sampling so the same position gives the same suggestion.
+// Generations and code actions are allowed some variety. Configured values are kept
+fn with_sampling_defaults(mut params: Value, kind: RequestKind) -> Value {
+    let (temperature, top_p) = match kind {
+        RequestKind::Completion => (COMPLETION_TEMPERATURE_DEFAULT, COMPLETION_TOP_P_DEFAULT),
+        RequestKind::Generation => (GENERATION_TEMPERATURE_DEFAULT, GENERATION_TOP_P_DEFAULT),
+    };
+    if let Some(params) = params.as_object_mut() {
+        params
+            .entry("temperature")
+            .or_insert_with(|| json!(temperature));
+        params.entry("top_p").or_insert_with(|| json!(top_p));
+    }
+    params
+}
+
+// Builds the prompt a generation request would send without calling the model
+async fn do_preview_prompt(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    request: &PreviewPromptRequest,
+    config: &Config,
+) -> anyhow::Result<Response> {
+    let params = get_generation_params(config, &request.params.model, &request.params.parameters);
+    let prompt_type = match &request.params.prompt_type {
+        Some(prompt_type) => prompt_type.clone(),
+        None => transformer_backend.get_prompt_type(&params)?,
+    };
+
+    let (tx, rx) = oneshot::channel();
+    memory_backend_tx.send(memory_worker::WorkerRequest::PromptWithContext(
+        PromptWithContextRequest::new(
+            request.params.text_document_position.clone(),
+            prompt_type.clone(),
+            params.clone(),
+            tx,
+        ),
+    ))?;
+    let (prompt, retrieved_chunks) = rx.await?;
+
+    let memory_run_params = MemoryRunParams::new(&params, &prompt_type);
+    let prompt: PreviewedPrompt = prompt.into();
+    let result = PreviewPromptResult {
+        used_characters: prompt.characters(),
+        prompt,
+        max_context: memory_run_params.max_context,
+        max_context_characters: tokens_to_estimated_characters(memory_run_params.max_context),
+        retrieved_chunks,
+    };
+    let result = serde_json::to_value(result).unwrap();
+    Ok(Response {
+        id: request.id.clone(),
+        result: Some(result),
+        error: None,
+    })
+}
+
+async fn do_generate(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    request: &GenerationRequest,
+    config: &Config,
+) -> anyhow::Result<Response> {
+    let params = get_generation_params(config, &request.params.model, &request.params.parameters);
+
+    let (prompt, context_chunks) = if request.params.include_context {
+        let (tx, rx) = oneshot::channel();
+        memory_backend_tx.send(memory_worker::WorkerRequest::PromptWithContext(
+            PromptWithContextRequest::new(
+                request.params.text_document_position.clone(),
+                transformer_backend.get_prompt_type(&params)?,
+                params.clone(),
+                tx,
+            ),
+        ))?;
+        let (prompt, context_chunks) = rx.await?;
+        (prompt, Some(context_chunks))
+    } else {
+        let (tx, rx) = oneshot::channel();
+        memory_backend_tx.send(memory_worker::WorkerRequest::Prompt(PromptRequest::new(
+            request.params.text_document_position.clone(),
+            transformer_backend.get_prompt_type(&params)?,
+            params.clone(),
+            tx,
+        )))?;
+        (rx.await?, None)
+    };
+
+    let mut response = USAGE_STATS
+        .track(
+            &request.params.model,
+            transformer_backend.do_generate(&prompt, params),
+        )
+        .await?;
+    response.generated_text = post_process_response(
+        response.generated_text,
+        &prompt,
+        &request.params.post_process,
+    );
+
+    let result = GenerateResult {
+        generated_text: response.generated_text,
+        context_chunks,
+    };
+    let result = serde_json::to_value(result).unwrap();
+    Ok(Response {
+        id: request.id.clone(),
+        result: Some(result),
+        error: None,
+    })
+}
+
+async fn do_generate_stream(
+    transformer_backend: &Box<dyn TransformerBackend + Send + Sync>,
+    memory_backend_tx: std::sync::mpsc::Sender<memory_worker::WorkerRequest>,
+    request: &GenerationStreamRequest,
+    connection: Arc<Connection>,
+    config: &Config,
+) -> anyhow::Result<Response> {
+    let params = get_generation_params(config, &request.params.model, &request.params.parameters);
+
+    let (tx, rx) = oneshot::channel();
     memory_backend_tx.send(memory_worker::WorkerRequest::Prompt(PromptRequest::new(
         request.params.text_document_position.clone(),
         transformer_backend.get_prompt_type(&params)?,
@@ -857,15 +2173,85 @@ async fn do_generate(
     )))?;
     let prompt = rx.await?;
 
-    let mut response = transformer_backend.do_generate(&prompt, params).await?;
-    response.generated_text = post_process_response(
-        response.generated_text,
-        &prompt,
-        &request.params.post_process,
+    // Each chunk is sent to the client as a partial result for the request's token
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let generation = USAGE_STATS.track(
+        &request.params.model,
+        transformer_backend.do_generate_stream(&prompt, params, tx),
     );
+    let repetition = request.params.repetition;
+    let max_generated_chars = request.params.max_generated_chars;
+    // Resolves early with true if the generation starts looping or runs too long
+    let forward = async {
+        let mut generated_text = String::new();
+        let mut generated_chars = 0;
+        while let Some(mut chunk) = rx.recv().await {
+            generated_text.push_str(&chunk.generated_text);
+            if let Some(end) = find_repetition(
+                &generated_text,
+                repetition.max_block_lines,
+                repetition.repetitions,
+            ) {
+                warn!("stopped a generation stream that was repeating itself");
+                generated_text.truncate(end);
+                return (generated_text, true);
+            }
+            // The part of the chunk past `max_generated_chars` is not sent
+            generated_chars += chunk.generated_text.chars().count();
+            let too_long = match max_generated_chars {
+                Some(max_chars) if generated_chars > max_chars => {
+                    warn!("stopped a generation stream longer than `max_generated_chars` ({max_chars})");
+                    let end = generated_text
+                        .char_indices()
+                        .nth(max_chars)
+                        .map_or(generated_text.len(), |(end, _)| end);
+                    let cut = generated_text.len() - end;
+                    chunk
+                        .generated_text
+                        .truncate(chunk.generated_text.len().saturating_sub(cut));
+                    generated_text.truncate(end);
+                    true
+                }
+                _ => false,
+            };
+            let progress = GenerationStreamProgressParams {
+                token: request.params.partial_result_token.clone(),
+                value: GenerationStreamResult {
+                    generated_text: chunk.generated_text,
+                    partial_result_token: request.params.partial_result_token.clone(),
+                },
+            };
+            if let Err(e) = connection
+                .sender
+                .send(Message::Notification(Notification::new(
+                    "$/progress".to_string(),
+                    progress,
+                )))
+            {
+                error!("sending generation stream partial result: {e:?}");
+            }
+            if too_long {
+                return (generated_text, true);
+            }
+        }
+        (generated_text, false)
+    };
+    // Dropping the generation when the stream is stopped early stops the backend's request
+    let generated_text = match select(pin!(generation), pin!(forward)).await {
+        Either::Left((generation, forward)) => {
+            generation?;
+            forward.await.0
+        }
+        Either::Right(((generated_text, true), _)) => generated_text,
+        Either::Right(((generated_text, false), generation)) => {
+            generation.await?;
+            generated_text
+        }
+    };
 
-    let result = GenerateResult {
-        generated_text: response.generated_text,
+    let result = GenerationStreamResult {
+        generated_text,
+        partial_result_token: request.params.partial_result_token.clone(),
     };
     let result = serde_json::to_value(result).unwrap();
     Ok(Response {
@@ -881,6 +2267,8 @@ mod tests {
     use crate::memory_backends::{
         file_store::FileStore, ContextAndCodePrompt, FIMPrompt, MemoryBackend,
     };
+    use crate::utils::format_prompt_in_str;
+    use lsp_types::{DidOpenTextDocumentParams, TextDocumentItem};
     use serde_json::json;
     use std::{sync::mpsc, thread};
 
@@ -939,6 +2327,191 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_do_inline_completion() -> anyhow::Result<()> {
+        let (memory_tx, memory_rx) = mpsc::channel();
+        let memory_backend: Box<dyn MemoryBackend + Send + Sync> =
+            Box::new(FileStore::default_with_filler_file()?);
+        thread::spawn(move || memory_worker::run(memory_backend, memory_rx));
+
+        let transformer_backend: Box<dyn TransformerBackend + Send + Sync> =
+            config::ValidModel::Ollama(serde_json::from_value(
+                json!({"model": "deepseek-coder:1.3b-base"}),
+            )?)
+            .try_into()?;
+        let inline_completion_request = InlineCompletionRequest::new(
+            serde_json::from_value(json!(0))?,
+            serde_json::from_value(json!({
+                "position": {"character":10, "line":2},
+                "textDocument": {
+                    "uri": "file:///filler.py"
+                },
+                "context": {
+                    "triggerKind": 2
+                }
+            }))?,
+        );
+        let mut config = config::Config::default_with_file_store_without_models();
+        config.config.completion = Some(serde_json::from_value(json!({
+            "model": "model1",
+            "parameters": {
+                "options": {
+                    "temperature": 0
+                }
+            }
+        }))?);
+
+        let result = do_inline_completion(
+            &transformer_backend,
+            memory_tx,
+            &inline_completion_request,
+            &config,
+        )
+        .await?;
+
+        let item = &result.result.unwrap()[0];
+        assert_eq!(" x * y", item["insertText"].as_str().unwrap());
+        assert_eq!(
+            json!({"start": {"line": 2, "character": 10}, "end": {"line": 2, "character": 10}}),
+            item["range"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_generation_params() -> anyhow::Result<()> {
+        let mut config = config::Config::default_with_file_store_without_models();
+        config.config.completion = Some(serde_json::from_value(json!({
+            "model": "model1",
+            "parameters": {
+                "max_tokens": 64,
+                "max_context": 1024,
+                "fim": {"start": "<s>", "middle": "<m>", "end": "<e>"},
+                "messages": [{"role": "system", "content": "configured"}],
+                "options": {
+                    "temperature": 0,
+                    "top_p": 0.9
+                }
+            }
+        }))?);
+
+        // Request parameters take precedence and arrays are replaced
+        let params = get_generation_params(
+            &config,
+            "model1",
+            &json!({
+                "messages": [{"role": "user", "content": "requested"}],
+                "options": {
+                    "temperature": 0.8
+                }
+            }),
+        );
+        assert_eq!(
+            params,
+            json!({
+                "max_tokens": 64,
+                "messages": [{"role": "user", "content": "requested"}],
+                "options": {
+                    "temperature": 0.8,
+                    "top_p": 0.9
+                },
+                "temperature": GENERATION_TEMPERATURE_DEFAULT,
+                "top_p": GENERATION_TOP_P_DEFAULT
+            })
+        );
+
+        // No parameters in the request uses the configured ones. Those that build completion
+        // prompts are not inherited
+        let params = get_generation_params(&config, "model1", &Value::Null);
+        assert_eq!(params["max_tokens"], 64);
+        assert_eq!(params["options"]["top_p"], 0.9);
+        for key in ["max_context", "fim", "messages"] {
+            assert!(params.get(key).is_none(), "{key} was inherited");
+        }
+
+        // Only parameters configured for the same model are used
+        let params = get_generation_params(&config, "model2", &json!({"max_tokens": 8}));
+        assert_eq!(params["max_tokens"], 8);
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_sampling_defaults() {
+        let params = with_sampling_defaults(json!({}), RequestKind::Completion);
+        assert_eq!(
+            params,
+            json!({
+                "temperature": COMPLETION_TEMPERATURE_DEFAULT,
+                "top_p": COMPLETION_TOP_P_DEFAULT
+            })
+        );
+        let params = with_sampling_defaults(json!({"messages": []}), RequestKind::Generation);
+        assert_eq!(params["temperature"], json!(GENERATION_TEMPERATURE_DEFAULT));
+        assert_eq!(params["top_p"], json!(GENERATION_TOP_P_DEFAULT));
+
+        // Configured values are kept
+        let params = with_sampling_defaults(
+            json!({"temperature": 0.5, "top_p": 0.5}),
+            RequestKind::Completion,
+        );
+        assert_eq!(params, json!({"temperature": 0.5, "top_p": 0.5}));
+    }
+
+    #[tokio::test]
+    async fn test_do_preview_prompt() -> anyhow::Result<()> {
+        let (memory_tx, memory_rx) = mpsc::channel();
+        let memory_backend: Box<dyn MemoryBackend + Send + Sync> =
+            Box::new(FileStore::default_with_filler_file()?);
+        thread::spawn(move || memory_worker::run(memory_backend, memory_rx));
+
+        let transformer_backend: Box<dyn TransformerBackend + Send + Sync> =
+            config::ValidModel::Ollama(serde_json::from_value(
+                json!({"model": "deepseek-coder:1.3b-base"}),
+            )?)
+            .try_into()?;
+        let config = config::Config::default_with_file_store_without_models();
+
+        let preview_prompt_request = PreviewPromptRequest::new(
+            serde_json::from_value(json!(0))?,
+            serde_json::from_value(json!({
+                "position": {"character":10, "line":2},
+                "textDocument": {
+                    "uri": "file:///filler.py"
+                },
+                "model": "model1",
+                "parameters": {
+                    "max_context": 512
+                },
+                "promptType": "fim"
+            }))?,
+        );
+        let result = do_preview_prompt(
+            &transformer_backend,
+            memory_tx,
+            &preview_prompt_request,
+            &config,
+        )
+        .await?
+        .result
+        .unwrap();
+
+        assert_eq!(result["prompt"]["type"], "fim");
+        assert_eq!(
+            result["prompt"]["prompt"],
+            "# Multiplies two numbers\ndef multiply_two_numbers(x, y):\n    return"
+        );
+        assert_eq!(result["maxContext"], 512);
+        assert_eq!(
+            result["usedCharacters"],
+            result["prompt"]["prompt"].as_str().unwrap().chars().count()
+                + result["prompt"]["suffix"].as_str().unwrap().chars().count()
+        );
+        assert_eq!(result["retrievedChunks"], json!([]));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_do_generate() -> anyhow::Result<()> {
         let (memory_tx, memory_rx) = mpsc::channel();
@@ -966,7 +2539,14 @@ mod tests {
                 }
             }))?,
         );
-        let result = do_generate(&transformer_backend, memory_tx, &generation_request).await?;
+        let config = config::Config::default_with_file_store_without_models();
+        let result = do_generate(
+            &transformer_backend,
+            memory_tx,
+            &generation_request,
+            &config,
+        )
+        .await?;
 
         assert_eq!(
             " x * y",
@@ -976,25 +2556,1108 @@ mod tests {
         Ok(())
     }
 
-    #[test]
-    fn test_post_process_fim() {
-        let config = config::PostProcess::default();
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn test_do_generate_stream() -> anyhow::Result<()> {
+        let (memory_tx, memory_rx) = mpsc::channel();
+        let memory_backend: Box<dyn MemoryBackend + Send + Sync> =
+            Box::new(FileStore::default_with_filler_file()?);
+        thread::spawn(move || memory_worker::run(memory_backend, memory_rx));
 
-        let prompt = Prompt::FIM(FIMPrompt {
-            prompt: "test 1234 ".to_string(),
-            suffix: "ttabc".to_string(),
-        });
-        let response = "4 zz tta".to_string();
-        let new_response = post_process_response(response.clone(), &prompt, &config);
-        assert_eq!(new_response, "zz ");
+        let transformer_backend: Box<dyn TransformerBackend + Send + Sync> =
+            config::ValidModel::Mock(serde_json::from_value(json!({"response": "x * y"}))?)
+                .try_into()?;
+        let generation_stream_request = GenerationStreamRequest::new(
+            serde_json::from_value(json!(0))?,
+            serde_json::from_value(json!({
+                "partialResultToken": "stream-1",
+                "position": {"character":10, "line":2},
+                "textDocument": {
+                    "uri": "file:///filler.py"
+                },
+                "model": "model1"
+            }))?,
+        );
+        let (server, client) = Connection::memory();
+        let config = config::Config::default_with_file_store_without_models();
+        let result = do_generate_stream(
+            &transformer_backend,
+            memory_tx,
+            &generation_stream_request,
+            Arc::new(server),
+            &config,
+        )
+        .await?;
 
-        let prompt = Prompt::FIM(FIMPrompt {
-            prompt: "test".to_string(),
-            suffix: "test".to_string(),
-        });
-        let response = "zzzz".to_string();
-        let new_response = post_process_response(response.clone(), &prompt, &config);
-        assert_eq!(new_response, "zzzz");
+        match client.receiver.try_recv()? {
+            Message::Notification(notification) => {
+                assert_eq!(notification.method, "$/progress");
+                let progress: GenerationStreamProgressParams =
+                    serde_json::from_value(notification.params)?;
+                assert_eq!(
+                    progress.token,
+                    lsp_types::ProgressToken::String("stream-1".to_string())
+                );
+                assert_eq!(progress.value.generated_text, "x * y");
+            }
+            _ => anyhow::bail!("expected a progress notification"),
+        }
+        assert_eq!(
+            result.result.unwrap(),
+            json!({"generatedText": "x * y", "partialResultToken": "stream-1"})
+        );
+
+        Ok(())
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn test_do_generate_stream_stops_at_max_generated_chars() -> anyhow::Result<()> {
+        let (memory_tx, memory_rx) = mpsc::channel();
+        let memory_backend: Box<dyn MemoryBackend + Send + Sync> =
+            Box::new(FileStore::default_with_filler_file()?);
+        thread::spawn(move || memory_worker::run(memory_backend, memory_rx));
+
+        let transformer_backend: Box<dyn TransformerBackend + Send + Sync> =
+            config::ValidModel::Mock(serde_json::from_value(json!({"response": "x * y"}))?)
+                .try_into()?;
+        let generation_stream_request = GenerationStreamRequest::new(
+            serde_json::from_value(json!(0))?,
+            serde_json::from_value(json!({
+                "partialResultToken": "stream-1",
+                "position": {"character":10, "line":2},
+                "textDocument": {
+                    "uri": "file:///filler.py"
+                },
+                "model": "model1",
+                "maxGeneratedChars": 3
+            }))?,
+        );
+        let (server, client) = Connection::memory();
+        let config = config::Config::default_with_file_store_without_models();
+        let result = do_generate_stream(
+            &transformer_backend,
+            memory_tx,
+            &generation_stream_request,
+            Arc::new(server),
+            &config,
+        )
+        .await?;
+
+        // Only the characters within the limit are streamed
+        match client.receiver.try_recv()? {
+            Message::Notification(notification) => {
+                let progress: GenerationStreamProgressParams =
+                    serde_json::from_value(notification.params)?;
+                assert_eq!(progress.value.generated_text, "x *");
+            }
+            _ => anyhow::bail!("expected a progress notification"),
+        }
+        assert_eq!(
+            result.result.unwrap(),
+            json!({"generatedText": "x *", "partialResultToken": "stream-1"})
+        );
+        Ok(())
+    }
+
+    // Answers the `workspace/applyEdit` requests sent to `client` until the server side is dropped,
+    // returning the edits of each
+    #[cfg(feature = "mock")]
+    fn answer_apply_edits(
+        client: Connection,
+        uri: &Url,
+        applied: bool,
+    ) -> thread::JoinHandle<anyhow::Result<Vec<Vec<TextEdit>>>> {
+        let uri = uri.clone();
+        thread::spawn(move || {
+            client
+                .receiver
+                .iter()
+                .map(|message| match message {
+                    Message::Request(request) => {
+                        assert_eq!(request.method, "workspace/applyEdit");
+                        let params: ApplyWorkspaceEditParams =
+                            serde_json::from_value(request.params)?;
+                        handle_client_response(Response::new_ok(
+                            request.id,
+                            ApplyWorkspaceEditResponse {
+                                applied,
+                                failure_reason: (!applied).then(|| "rejected".to_string()),
+                                failed_change: None,
+                            },
+                        ));
+                        let mut changes = params.edit.changes.context("expected changes")?;
+                        Ok(changes.remove(&uri).unwrap_or_default())
+                    }
+                    _ => anyhow::bail!("expected an apply edit request"),
+                })
+                .collect()
+        })
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn test_stream_action_edit_stops_when_not_applied() -> anyhow::Result<()> {
+        let transformer_backend: Box<dyn TransformerBackend + Send + Sync> =
+            config::ValidModel::Mock(serde_json::from_value(json!({"response": "x * y"}))?)
+                .try_into()?;
+        let action: config::Action = serde_json::from_value(json!({
+            "action_display_name": "Rewrite",
+            "model": "model1",
+            "post_process": {
+                "extractor": "x \\* (y)",
+                "remove_duplicate_start": false,
+                "remove_duplicate_end": false
+            }
+        }))?;
+        let data: CodeActionResolveData = serde_json::from_value(json!({
+            "text_document": {
+                "uri": "file:///stream_rejected.py"
+            },
+            "range": {
+                "start": {"line": 1, "character": 4},
+                "end": {"line": 1, "character": 8}
+            }
+        }))?;
+        let (server, client) = Connection::memory();
+        let answers = answer_apply_edits(client, &data.text_document.uri, false);
+        let result = stream_action_edit(
+            &server,
+            &transformer_backend,
+            &Prompt::default_with_cursor(),
+            json!({}),
+            &action,
+            &data,
+        )
+        .await;
+        drop(server);
+
+        assert!(format!("{:?}", result.unwrap_err()).contains("rejected"));
+        // The post processed edit is not sent after the streamed one was rejected
+        assert_eq!(answers.join().unwrap()?.len(), 1);
+        assert!(!LAST_GENERATIONS
+            .lock()
+            .contains_key(&data.text_document.uri));
+        Ok(())
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn test_stream_action_edit() -> anyhow::Result<()> {
+        let transformer_backend: Box<dyn TransformerBackend + Send + Sync> =
+            config::ValidModel::Mock(serde_json::from_value(json!({"response": "x * y"}))?)
+                .try_into()?;
+        let action: config::Action = serde_json::from_value(json!({
+            "action_display_name": "Rewrite",
+            "model": "model1",
+            "post_process": {
+                "extractor": "x \\* (y)",
+                "remove_duplicate_start": false,
+                "remove_duplicate_end": false
+            }
+        }))?;
+        let data: CodeActionResolveData = serde_json::from_value(json!({
+            "text_document": {
+                "uri": "file:///stream.py"
+            },
+            "range": {
+                "start": {"line": 1, "character": 4},
+                "end": {"line": 1, "character": 8}
+            }
+        }))?;
+        let (server, client) = Connection::memory();
+        let answers = answer_apply_edits(client, &data.text_document.uri, true);
+        stream_action_edit(
+            &server,
+            &transformer_backend,
+            &Prompt::default_with_cursor(),
+            json!({}),
+            &action,
+            &data,
+        )
+        .await?;
+        drop(server);
+
+        let edits = answers.join().unwrap()?;
+        // The streamed chunk replaces the selection, then the post processed response replaces
+        // the streamed text
+        assert_eq!(
+            edits,
+            vec![
+                vec![TextEdit::new(data.range, "x * y".to_string())],
+                vec![TextEdit::new(
+                    Range::new(Position::new(1, 4), Position::new(1, 9)),
+                    "y".to_string()
+                )],
+            ]
+        );
+        assert_eq!(
+            LAST_GENERATIONS.lock().get(&data.text_document.uri),
+            Some(&TextEdit::new(
+                Range::new(Position::new(1, 4), Position::new(1, 5)),
+                "y".to_string()
+            ))
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn test_stream_action_edit_after_non_bmp_text() -> anyhow::Result<()> {
+        let transformer_backend: Box<dyn TransformerBackend + Send + Sync> =
+            config::ValidModel::Mock(serde_json::from_value(json!({"response": "😀 * y"}))?)
+                .try_into()?;
+        let action: config::Action = serde_json::from_value(json!({
+            "action_display_name": "Rewrite",
+            "model": "model1",
+            "post_process": {
+                "extractor": "😀 \\* (y)",
+                "remove_duplicate_start": false,
+                "remove_duplicate_end": false
+            }
+        }))?;
+        let data: CodeActionResolveData = serde_json::from_value(json!({
+            "text_document": {
+                "uri": "file:///stream_non_bmp.py"
+            },
+            "range": {
+                "start": {"line": 1, "character": 4},
+                "end": {"line": 1, "character": 8}
+            }
+        }))?;
+        let (server, client) = Connection::memory();
+        let answers = answer_apply_edits(client, &data.text_document.uri, true);
+        stream_action_edit(
+            &server,
+            &transformer_backend,
+            &Prompt::default_with_cursor(),
+            json!({}),
+            &action,
+            &data,
+        )
+        .await?;
+        drop(server);
+
+        // The emoji takes two UTF-16 code units so the streamed text ends at character 10
+        let edits = answers.join().unwrap()?;
+        assert_eq!(
+            edits,
+            vec![
+                vec![TextEdit::new(data.range, "😀 * y".to_string())],
+                vec![TextEdit::new(
+                    Range::new(Position::new(1, 4), Position::new(1, 10)),
+                    "y".to_string()
+                )],
+            ]
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "mock")]
+    #[tokio::test]
+    async fn test_do_generate_truncates_long_response() -> anyhow::Result<()> {
+        let (memory_tx, memory_rx) = mpsc::channel();
+        let memory_backend: Box<dyn MemoryBackend + Send + Sync> =
+            Box::new(FileStore::default_with_filler_file()?);
+        thread::spawn(move || memory_worker::run(memory_backend, memory_rx));
+
+        let transformer_backend: Box<dyn TransformerBackend + Send + Sync> =
+            config::ValidModel::Mock(serde_json::from_value(
+                json!({"response": "x * y ".repeat(100)}),
+            )?)
+            .try_into()?;
+        let generation_request = GenerationRequest::new(
+            serde_json::from_value(json!(0))?,
+            serde_json::from_value(json!({
+                "position": {"character":10, "line":2},
+                "textDocument": {
+                    "uri": "file:///filler.py"
+                },
+                "model": "model1",
+                "postProcess": {
+                    "max_generated_chars": 10
+                }
+            }))?,
+        );
+        let config = config::Config::default_with_file_store_without_models();
+        let result = do_generate(
+            &transformer_backend,
+            memory_tx,
+            &generation_request,
+            &config,
+        )
+        .await?;
+
+        assert_eq!(
+            "x * y x * ",
+            result.result.unwrap()["generatedText"].as_str().unwrap()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_request_sends_one_response() -> anyhow::Result<()> {
+        let (server, client) = Connection::memory();
+        let (memory_tx, _memory_rx) = mpsc::channel();
+        let request = GenerationRequest::new(
+            serde_json::from_value(json!(1))?,
+            serde_json::from_value(json!({
+                "textDocument": {
+                    "uri": "file:///filler.py"
+                },
+                "position": {
+                    "line": 0,
+                    "character": 0
+                },
+                "model": "does-not-exist"
+            }))?,
+        );
+        dispatch_request(
+            WorkerRequest::Generation(request),
+            Arc::new(server),
+            Arc::new(HashMap::new()),
+            memory_tx,
+            Config::default_with_file_store_without_models(),
+        )
+        .await;
+
+        let messages: Vec<Message> = client.receiver.try_iter().collect();
+        assert_eq!(messages.len(), 1);
+        match &messages[0] {
+            Message::Response(response) => {
+                assert_eq!(response.id, serde_json::from_value(json!(1))?);
+                assert!(response.error.is_some());
+            }
+            _ => anyhow::bail!("expected a single response"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_model_override() -> anyhow::Result<()> {
+        let config = Config::default_with_file_store_without_models();
+        let params: CompletionParams = serde_json::from_value(json!({
+            "textDocument": {
+                "uri": "file:///filler.py"
+            },
+            "position": {
+                "line": 0,
+                "character": 0
+            }
+        }))?;
+        let request = CompletionRequest::new(serde_json::from_value(json!(1))?, params);
+        assert_eq!(
+            WorkerRequest::Completion(request.clone()).get_model(&config),
+            None
+        );
+        let request = WorkerRequest::Completion(request.with_model(Some("model2".to_string())));
+        assert_eq!(request.get_model(&config), Some("model2"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chat_code_action_model_override() -> anyhow::Result<()> {
+        let (memory_tx, memory_rx) = mpsc::channel();
+        let memory_backend: Box<dyn MemoryBackend + Send + Sync> =
+            Box::new(FileStore::default_with_filler_file()?);
+        thread::spawn(move || memory_worker::run(memory_backend, memory_rx));
+
+        let mut config = config::Config::default_with_file_store_without_models();
+        for model in ["model1", "model2"] {
+            config.config.models.insert(
+                model.to_string(),
+                serde_json::from_value(json!({
+                    "type": "ollama",
+                    "model": "llama3"
+                }))?,
+            );
+        }
+        config.config.chats = vec![serde_json::from_value(json!({
+            "trigger": "",
+            "action_display_name": "Chat",
+            "model": "model1"
+        }))?];
+        let params: CodeActionParams = serde_json::from_value(json!({
+            "textDocument": {
+                "uri": "file:///filler.py"
+            },
+            "range": {
+                "start": {"line": 0, "character": 0},
+                "end": {"line": 0, "character": 0}
+            },
+            "context": {
+                "diagnostics": []
+            }
+        }))?;
+        let request = CodeActionRequest::new(serde_json::from_value(json!(1))?, params);
+
+        // The override is sent back with the action so the resolve uses it
+        let response = do_code_action_request(
+            memory_tx.clone(),
+            &request.clone().with_model(Some("model2".to_string())),
+            &config,
+        )
+        .await?;
+        let actions: Vec<CodeAction> = serde_json::from_value(response.result.unwrap())?;
+        let data: CodeActionResolveData = serde_json::from_value(actions[0].data.clone().unwrap())?;
+        assert_eq!(
+            config.get_chat_model(&config.config.chats[0], data.model.as_deref())?,
+            "model2"
+        );
+        assert_eq!(
+            config.get_chat_model(&config.config.chats[0], None)?,
+            "model1"
+        );
+
+        assert!(do_code_action_request(
+            memory_tx,
+            &request.with_model(Some("model3".to_string())),
+            &config,
+        )
+        .await
+        .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_request_timeout() -> anyhow::Result<()> {
+        let (memory_tx, memory_rx) = mpsc::channel();
+        let memory_backend: Box<dyn MemoryBackend + Send + Sync> =
+            Box::new(FileStore::default_with_filler_file()?);
+        thread::spawn(move || memory_worker::run(memory_backend, memory_rx));
+
+        // A server that accepts connections and never responds
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let endpoint = format!("http://{}", listener.local_addr()?);
+        thread::spawn(move || {
+            let _streams: Vec<_> = listener.incoming().collect();
+        });
+
+        let mut config = config::Config::default_with_file_store_without_models();
+        config.config.request_timeout = Some(0.2);
+        config.config.completion = Some(serde_json::from_value(json!({
+            "model": "model1",
+            "parameters": {}
+        }))?);
+        let model: config::ValidModel = serde_json::from_value(json!({
+            "type": "llama_cpp_server",
+            "endpoint": endpoint
+        }))?;
+        let mut transformer_backends: HashMap<String, Box<dyn TransformerBackend + Send + Sync>> =
+            HashMap::new();
+        transformer_backends.insert("model1".to_string(), model.clone().try_into()?);
+        config.config.models.insert("model1".to_string(), model);
+        let transformer_backends = Arc::new(transformer_backends);
+
+        // Completions time out with no items
+        let (server, client) = Connection::memory();
+        let completion_request = CompletionRequest::new(
+            serde_json::from_value(json!(0))?,
+            serde_json::from_value(json!({
+                "position": {"character":10, "line":2},
+                "textDocument": {
+                    "uri": "file:///filler.py"
+                }
+            }))?,
+        );
+        dispatch_request(
+            WorkerRequest::Completion(completion_request),
+            Arc::new(server),
+            transformer_backends.clone(),
+            memory_tx.clone(),
+            config.clone(),
+        )
+        .await;
+        match client.receiver.try_recv()? {
+            Message::Response(response) => {
+                assert!(response.error.is_none());
+                assert_eq!(response.result.unwrap()["items"], json!([]));
+            }
+            _ => anyhow::bail!("expected a response"),
+        }
+
+        // Generations time out with an error
+        let (server, client) = Connection::memory();
+        let generation_request = GenerationRequest::new(
+            serde_json::from_value(json!(1))?,
+            serde_json::from_value(json!({
+                "textDocument": {
+                    "uri": "file:///filler.py"
+                },
+                "position": {"character":10, "line":2},
+                "model": "model1",
+                "parameters": {}
+            }))?,
+        );
+        dispatch_request(
+            WorkerRequest::Generation(generation_request),
+            Arc::new(server),
+            transformer_backends,
+            memory_tx,
+            config,
+        )
+        .await;
+        match client.receiver.try_recv()? {
+            Message::Response(response) => {
+                assert!(response.result.is_none());
+                assert!(response.error.unwrap().message.contains("timed out"));
+            }
+            _ => anyhow::bail!("expected a response"),
+        }
+        Ok(())
+    }
+
+    fn did_change(uri: &str, range: Option<Range>, text: &str) -> DidChangeTextDocumentParams {
+        serde_json::from_value(json!({
+            "textDocument": {"uri": uri, "version": 1},
+            "contentChanges": [{"range": range, "text": text}]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_cursor_after_changes() {
+        let start = Position::new(2, 4);
+        let position = |params| cursor_after_changes(&params).map(|p| p.position);
+        assert_eq!(
+            position(did_change(
+                "file:///a.py",
+                Some(Range::new(start, start)),
+                "abc"
+            )),
+            Some(Position::new(2, 7))
+        );
+        assert_eq!(
+            position(did_change(
+                "file:///a.py",
+                Some(Range::new(start, start)),
+                "x\n  y"
+            )),
+            Some(Position::new(3, 3))
+        );
+        assert_eq!(
+            position(did_change(
+                "file:///a.py",
+                Some(Range::new(start, Position::new(2, 8))),
+                ""
+            )),
+            Some(start)
+        );
+        // Positions are in UTF-16 code units
+        assert_eq!(
+            position(did_change(
+                "file:///a.py",
+                Some(Range::new(start, start)),
+                "😀b"
+            )),
+            Some(Position::new(2, 7))
+        );
+        assert_eq!(
+            position(did_change("file:///a.py", None, "full text")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let rate_limiter = RateLimiter {
+            max_requests_per_second: 1.,
+            last_request_time: Arc::new(Mutex::new(SystemTime::UNIX_EPOCH)),
+        };
+        // Prefetches share the limiter with completions so only one of them gets through
+        let prefetch_rate_limiter = rate_limiter.clone();
+        assert!(prefetch_rate_limiter.try_acquire());
+        assert!(!rate_limiter.try_acquire());
+        assert!(!prefetch_rate_limiter.try_acquire());
+    }
+
+    #[test]
+    fn test_prefetch_invalidation() {
+        let uri = "file:///prefetch.py";
+        let start = Position::new(0, 0);
+        let (edit_id, position) =
+            record_edit(&did_change(uri, Some(Range::new(start, start)), "a")).unwrap();
+        store_prefetch(
+            &position.text_document.uri,
+            edit_id,
+            ("a".to_string(), vec!["bc".to_string()]),
+        );
+        // Only a completion request at the prefetched cursor takes it
+        let mut other_position = position.clone();
+        other_position.position = start;
+        assert_eq!(take_prefetch(&other_position), None);
+        assert_eq!(
+            take_prefetch(&position),
+            Some(("a".to_string(), vec!["bc".to_string()]))
+        );
+        assert_eq!(take_prefetch(&position), None);
+
+        // A prefetch finishing after another edit is dropped
+        let (edit_id, position) =
+            record_edit(&did_change(uri, Some(Range::new(start, start)), "a")).unwrap();
+        record_edit(&did_change(uri, Some(Range::new(start, start)), "b")).unwrap();
+        assert!(!is_latest_edit(&position.text_document.uri, edit_id));
+        store_prefetch(
+            &position.text_document.uri,
+            edit_id,
+            ("a".to_string(), vec!["bc".to_string()]),
+        );
+        assert_eq!(take_prefetch(&position), None);
+    }
+
+    #[test]
+    fn test_get_filter_text() {
+        let line_prefix = "    let x = foo_bar".to_string();
+        assert_eq!(
+            get_filter_text(line_prefix.clone(), config::FilterTextStrategy::LinePrefix),
+            Some("    let x = foo_bar".to_string())
+        );
+        assert_eq!(
+            get_filter_text(line_prefix.clone(), config::FilterTextStrategy::WordPrefix),
+            Some("foo_bar".to_string())
+        );
+        assert_eq!(
+            get_filter_text("foo.".to_string(), config::FilterTextStrategy::WordPrefix),
+            Some(String::new())
+        );
+        assert_eq!(
+            get_filter_text(line_prefix, config::FilterTextStrategy::None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_read_attached_files() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("lsp-ai-attached-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("docs"))?;
+        std::fs::write(root.join("docs/style.md"), "Use tabs")?;
+        std::fs::write(root.join("api.txt"), "GET /users")?;
+        let root_uri = format!("file://{}", root.display());
+        let files = vec![
+            "docs/style.md".to_string(),
+            root.join("api.txt").display().to_string(),
+            "never_read.txt".to_string(),
+        ];
+        // The budget runs out in the second file
+        assert_eq!(
+            read_attached_files(&files, Some(&root_uri), 11),
+            "--docs/style.md--\nUse tabs\n\n--api.txt--\nGET"
+        );
+        // Missing files are skipped
+        assert_eq!(
+            read_attached_files(&files, Some(&root_uri), 100),
+            "--docs/style.md--\nUse tabs\n\n--api.txt--\nGET /users"
+        );
+        std::fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_trim_completion() {
+        let text = "    x * y\n\n".to_string();
+        assert_eq!(
+            trim_completion(text.clone(), config::TrimPolicy::default()),
+            "    x * y"
+        );
+        assert_eq!(
+            trim_completion(text.clone(), config::TrimPolicy::Both),
+            "x * y"
+        );
+        assert_eq!(
+            trim_completion(text.clone(), config::TrimPolicy::None),
+            text
+        );
+    }
+
+    #[test]
+    fn test_should_complete() -> anyhow::Result<()> {
+        let completion_config: config::Completion = serde_json::from_value(json!({
+            "model": "model1"
+        }))?;
+        assert!(should_complete("", &completion_config));
+
+        let completion_config: config::Completion = serde_json::from_value(json!({
+            "model": "model1",
+            "min_prefix_chars": 3,
+            "trigger_characters": [".", "::"]
+        }))?;
+        assert!(!should_complete("", &completion_config));
+        assert!(!should_complete("    ab", &completion_config));
+        assert!(should_complete("    abc", &completion_config));
+        assert!(should_complete("a.", &completion_config));
+        assert!(should_complete("a::", &completion_config));
+        assert!(!should_complete("a:", &completion_config));
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_completion_label() {
+        let template = "ai - {first_line}";
+        assert_eq!(
+            format_completion_label(" x * y", template, 50),
+            "ai - x * y"
+        );
+        assert_eq!(
+            format_completion_label("\n\n    return x\nfoo", template, 50),
+            "ai - return x"
+        );
+        assert_eq!(
+            format_completion_label("abcdefghij", template, 4),
+            "ai - abcd…"
+        );
+        assert_eq!(
+            format_completion_label("a\nb", "{insert_text} ({first_line})", 50),
+            "a b (a)"
+        );
+    }
+
+    #[test]
+    fn test_get_text_in_range() {
+        let file_text = "let s = \"héllo 😀 world\";\nnext line\n";
+        // Characters are UTF-16 code units and the end is exclusive
+        let range = Range::new(Position::new(0, 9), Position::new(0, 17));
+        assert_eq!(get_text_in_range(file_text, range), "héllo 😀");
+        let range = Range::new(Position::new(0, 18), Position::new(1, 4));
+        assert_eq!(get_text_in_range(file_text, range), "world\";\nnext");
+        // Characters past the end of the line are clamped to it
+        let range = Range::new(Position::new(1, 5), Position::new(1, 100));
+        assert_eq!(get_text_in_range(file_text, range), "line");
+        let range = Range::new(Position::new(1, 0), Position::new(5, 0));
+        assert_eq!(get_text_in_range(file_text, range), "next line\n");
+    }
+
+    #[test]
+    fn test_format_diagnostics_in_prompt() -> anyhow::Result<()> {
+        let file_text = "def f(x):\n    return y\n";
+        let diagnostics: Vec<Diagnostic> = serde_json::from_value(json!([{
+            "range": {"start": {"line": 1, "character": 11}, "end": {"line": 1, "character": 12}},
+            "severity": 1,
+            "source": "pyright",
+            "message": "\"y\" is not defined"
+        }]))?;
+        let prompt = ContextAndCodePrompt {
+            context: String::new(),
+            code: file_text.to_string(),
+            selected_text: None,
+            attached_files: None,
+            diagnostics: Some(format_diagnostics(&diagnostics, file_text)),
+            position: None,
+        };
+        assert_eq!(
+            format_prompt_in_str("Fix:\n{DIAGNOSTICS}", &prompt),
+            "Fix:\nerror [pyright] at line 2, column 12: \"y\" is not defined\ny"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_action_workspace_edit() -> anyhow::Result<()> {
+        let data = CodeActionResolveData {
+            text_document: TextDocumentIdentifier {
+                uri: Url::parse("file:///action_mode.py")?,
+            },
+            range: Range::new(Position::new(1, 0), Position::new(2, 4)),
+            diagnostics: vec![],
+            model: None,
+        };
+
+        let edit =
+            build_action_workspace_edit(config::ActionMode::Replace, &data, "a".to_string())?;
+        let changes = edit.changes.unwrap();
+        assert_eq!(changes[&data.text_document.uri][0].range, data.range);
+
+        let edit = build_action_workspace_edit(config::ActionMode::Append, &data, "a".to_string())?;
+        let changes = edit.changes.unwrap();
+        assert_eq!(
+            changes[&data.text_document.uri][0].range,
+            Range::new(data.range.end, data.range.end)
+        );
+
+        let edit =
+            build_action_workspace_edit(config::ActionMode::NewDocument, &data, "a".to_string())?;
+        assert!(edit.changes.is_none());
+        match edit.document_changes {
+            Some(DocumentChanges::Operations(operations)) => {
+                assert_eq!(operations.len(), 2);
+                match (&operations[0], &operations[1]) {
+                    (
+                        DocumentChangeOperation::Op(ResourceOp::Create(create)),
+                        DocumentChangeOperation::Edit(edit),
+                    ) => {
+                        assert_eq!(create.uri, edit.text_document.uri);
+                        assert!(create.uri.path().ends_with(".py"));
+                        assert_eq!(
+                            edit.edits,
+                            vec![OneOf::Left(TextEdit::new(
+                                Range::new(Position::new(0, 0), Position::new(0, 0)),
+                                "a".to_string()
+                            ))]
+                        );
+                    }
+                    _ => anyhow::bail!("expected a create file and an edit"),
+                }
+            }
+            _ => anyhow::bail!("expected document changes"),
+        }
+        assert!(
+            build_action_workspace_edit(config::ActionMode::Explain, &data, "a".to_string())
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_document_extension() -> anyhow::Result<()> {
+        assert_eq!(
+            new_document_extension(&Url::parse("file:///src/main.rs")?),
+            ".rs"
+        );
+        // Dots in directories are not extensions
+        assert_eq!(
+            new_document_extension(&Url::parse("file:///my.project/Makefile")?),
+            ""
+        );
+        assert_eq!(
+            new_document_extension(&Url::parse("file:///my%20project/notes.md")?),
+            ".md"
+        );
+        assert_eq!(
+            new_document_extension(&Url::parse("untitled:Untitled-1")?),
+            ""
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_explanation_code_action() -> anyhow::Result<()> {
+        let action: config::Action = serde_json::from_value(json!({
+            "action_display_name": "Explain",
+            "model": "model1",
+            "mode": "explain"
+        }))?;
+        let data = CodeActionResolveData {
+            text_document: TextDocumentIdentifier {
+                uri: Url::parse("file:///explain.py")?,
+            },
+            range: Range::new(Position::new(1, 0), Position::new(2, 4)),
+            diagnostics: vec![],
+            model: None,
+        };
+        let (server, client) = Connection::memory();
+        let code_action =
+            build_explanation_code_action(&action, &server, data, "Adds `x` and `y`".to_string())?;
+        assert!(code_action.edit.is_none());
+        assert_eq!(
+            code_action.data,
+            Some(json!({
+                "textDocument": {"uri": "file:///explain.py"},
+                "range": {
+                    "start": {"line": 1, "character": 0},
+                    "end": {"line": 2, "character": 4}
+                },
+                "contents": {"kind": "markdown", "value": "Adds `x` and `y`"}
+            }))
+        );
+        match client.receiver.try_recv()? {
+            Message::Notification(notification) => {
+                assert_eq!(notification.method, "window/showMessage");
+                let params: ShowMessageParams = serde_json::from_value(notification.params)?;
+                assert_eq!(params.message, "Adds `x` and `y`");
+            }
+            _ => anyhow::bail!("expected a show message notification"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_completion_text_edit() {
+        let position = Position::new(0, 6);
+
+        let edit = build_completion_text_edit(position, "var".to_string(), None);
+        assert_eq!(
+            edit,
+            CompletionTextEdit::Edit(TextEdit::new(
+                Range::new(position, position),
+                "var".to_string()
+            ))
+        );
+
+        // Accepting in the middle of `my_var` replaces the trailing `var` instead of duplicating it
+        let replace = Range::new(position, Position::new(0, 10));
+        let edit = build_completion_text_edit(position, "var = 1".to_string(), Some(replace));
+        assert_eq!(
+            edit,
+            CompletionTextEdit::InsertAndReplace(InsertReplaceEdit {
+                new_text: "var = 1".to_string(),
+                insert: Range::new(position, position),
+                replace,
+            })
+        );
+    }
+
+    #[test]
+    fn test_applied_edit_range() {
+        let position = Position::new(2, 4);
+        let edit = TextEdit::new(Range::new(position, position), "abc".to_string());
+        assert_eq!(
+            applied_edit_range(&edit),
+            Range::new(position, Position::new(2, 7))
+        );
+
+        let edit = TextEdit::new(
+            Range::new(position, Position::new(3, 0)),
+            "abc\n\ndefg".to_string(),
+        );
+        assert_eq!(
+            applied_edit_range(&edit),
+            Range::new(position, Position::new(4, 4))
+        );
+
+        // Characters outside the BMP take two UTF-16 code units
+        let edit = TextEdit::new(Range::new(position, position), "a😀b".to_string());
+        assert_eq!(
+            applied_edit_range(&edit),
+            Range::new(position, Position::new(2, 8))
+        );
+        let edit = TextEdit::new(Range::new(position, position), "a\n😀b".to_string());
+        assert_eq!(
+            applied_edit_range(&edit),
+            Range::new(position, Position::new(3, 3))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_do_undo_generation() -> anyhow::Result<()> {
+        let (memory_tx, memory_rx) = mpsc::channel();
+        let file_store = FileStore::default_with_filler_file()?;
+        file_store.opened_text_document(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: Url::parse("file:///emoji.py")?,
+                language_id: "python".to_string(),
+                version: 0,
+                text: "x = '😀'; y = 1\n".to_string(),
+            },
+        })?;
+        let memory_backend: Box<dyn MemoryBackend + Send + Sync> = Box::new(file_store);
+        thread::spawn(move || memory_worker::run(memory_backend, memory_rx));
+
+        let uri = Url::parse("file:///filler.py")?;
+        let request = UndoGenerationRequest::new(
+            serde_json::from_value(json!(0))?,
+            serde_json::from_value(json!({
+                "textDocument": {
+                    "uri": "file:///filler.py"
+                }
+            }))?,
+        );
+
+        // The filler file contains `    return` at line 2
+        let position = Position::new(2, 4);
+        record_generation(
+            &uri,
+            &TextEdit::new(Range::new(position, position), "return".to_string()),
+        );
+        let result = do_undo_generation(memory_tx.clone(), &request).await?;
+        assert_eq!(
+            result.result.unwrap()["changes"]["file:///filler.py"][0]["range"]["end"]["character"],
+            10
+        );
+
+        // There is nothing left to undo
+        assert!(do_undo_generation(memory_tx.clone(), &request)
+            .await
+            .is_err());
+
+        // The text no longer matches what we generated
+        record_generation(
+            &uri,
+            &TextEdit::new(Range::new(position, position), "x * y".to_string()),
+        );
+        assert!(do_undo_generation(memory_tx.clone(), &request)
+            .await
+            .is_err());
+
+        // Positions after a character outside the BMP are in UTF-16 code units
+        let uri = Url::parse("file:///emoji.py")?;
+        let request = UndoGenerationRequest::new(
+            serde_json::from_value(json!(0))?,
+            serde_json::from_value(json!({
+                "textDocument": {
+                    "uri": "file:///emoji.py"
+                }
+            }))?,
+        );
+        let position = Position::new(0, 10);
+        record_generation(
+            &uri,
+            &TextEdit::new(Range::new(position, position), "y = 1".to_string()),
+        );
+        let result = do_undo_generation(memory_tx, &request).await?;
+        assert_eq!(
+            result.result.unwrap()["changes"]["file:///emoji.py"][0]["range"],
+            json!({
+                "start": {"line": 0, "character": 10},
+                "end": {"line": 0, "character": 15}
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_post_process_fim() {
+        let config = config::PostProcess::default();
+
+        let prompt = Prompt::FIM(FIMPrompt {
+            prompt: "test 1234 ".to_string(),
+            suffix: "ttabc".to_string(),
+        });
+        let response = "4 zz tta".to_string();
+        let new_response = post_process_response(response.clone(), &prompt, &config);
+        assert_eq!(new_response, "zz ");
+
+        let prompt = Prompt::FIM(FIMPrompt {
+            prompt: "test".to_string(),
+            suffix: "test".to_string(),
+        });
+        let response = "zzzz".to_string();
+        let new_response = post_process_response(response.clone(), &prompt, &config);
+        assert_eq!(new_response, "zzzz");
+
+        let prompt = Prompt::FIM(FIMPrompt {
+            prompt: "a".to_string(),
+            suffix: "b".to_string(),
+        });
+        let response = "<fim_middle>zz<｜fim▁end｜>".to_string();
+        let new_response = post_process_response(response.clone(), &prompt, &config);
+        assert_eq!(new_response, "zz");
+    }
+
+    #[test]
+    fn test_strip_special_tokens() {
+        let config = config::PostProcess::default();
+        let prompt = Prompt::FIM(FIMPrompt {
+            prompt: "a".to_string(),
+            suffix: "b".to_string(),
+        });
+
+        let response = "x * y</s>\nprint(x)".to_string();
+        let new_response = post_process_response(response, &prompt, &config);
+        assert_eq!(new_response, "x * y");
+
+        let response = "<|begin_of_text|>x * y<|eot_id|><|start_header_id|>".to_string();
+        let new_response = post_process_response(response, &prompt, &config);
+        assert_eq!(new_response, "x * y");
+
+        // Overriding the tokens only strips the tokens provided
+        let config = config::PostProcess {
+            special_tokens_to_strip: vec!["<|begin_of_text|>".to_string()],
+            ..Default::default()
+        };
+        let response = "<|begin_of_text|>x * y</s>".to_string();
+        let new_response = post_process_response(response, &prompt, &config);
+        assert_eq!(new_response, "x * y</s>");
     }
 
     #[test]
@@ -1005,6 +3668,9 @@ mod tests {
             context: "".to_string(),
             code: "tt ".to_string(),
             selected_text: None,
+            attached_files: None,
+            diagnostics: None,
+            position: None,
         });
         let response = "tt abc".to_string();
         let new_response = post_process_response(response.clone(), &prompt, &config);
@@ -1014,6 +3680,9 @@ mod tests {
             context: "".to_string(),
             code: "ff".to_string(),
             selected_text: None,
+            attached_files: None,
+            diagnostics: None,
+            position: None,
         });
         let response = "zz".to_string();
         let new_response = post_process_response(response.clone(), &prompt, &config);
@@ -1023,6 +3692,9 @@ mod tests {
             context: "".to_string(),
             code: "tt <CURSOR> tt".to_string(),
             selected_text: None,
+            attached_files: None,
+            diagnostics: None,
+            position: None,
         });
         let response = "tt abc tt".to_string();
         let new_response = post_process_response(response.clone(), &prompt, &config);
@@ -1032,6 +3704,9 @@ mod tests {
             context: "".to_string(),
             code: "d<CURSOR>d".to_string(),
             selected_text: None,
+            attached_files: None,
+            diagnostics: None,
+            position: None,
         });
         let response = "zz".to_string();
         let new_response = post_process_response(response.clone(), &prompt, &config);
@@ -1,13 +1,27 @@
-use std::path::PathBuf;
+use std::{
+    borrow::Cow,
+    io::Read,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::{anyhow, Context};
+use ignore::{overrides::OverrideBuilder, WalkBuilder};
 use lsp_server::ResponseError;
-use once_cell::sync::Lazy;
+use lsp_types::Url;
+use once_cell::sync::{Lazy, OnceCell};
+use regex::{Captures, Regex};
 use serde_json::Value;
 use tokio::runtime;
+use tracing::warn;
 use tree_sitter::Tree;
 
-use crate::{config::ChatMessage, memory_backends::ContextAndCodePrompt, splitters::Chunk};
+use crate::{
+    config::{ChatMessage, ConnectionPool, HeaderLanguage, Tls},
+    memory_backends::ContextAndCodePrompt,
+    splitters::Chunk,
+    transformer_backends::BackendError,
+};
 
 pub(crate) static TOKIO_RUNTIME: Lazy<runtime::Runtime> = Lazy::new(|| {
     runtime::Builder::new_multi_thread()
@@ -17,6 +31,94 @@ pub(crate) static TOKIO_RUNTIME: Lazy<runtime::Runtime> = Lazy::new(|| {
         .expect("Error building tokio runtime")
 });
 
+// Shared by the HTTP backends so connections are reused. Set up from the `tls` and
+// `connection_pool` config when the server starts
+static HTTP_CLIENT: OnceCell<reqwest::Client> = OnceCell::new();
+
+// Negative and NaN values are config errors rather than panics
+pub(crate) fn secs_to_duration(secs: f32, name: &str) -> anyhow::Result<Duration> {
+    Duration::try_from_secs_f32(secs)
+        .with_context(|| format!("`{name}` must be a non-negative number of seconds"))
+}
+
+// Compressed responses from gateways and proxies are decompressed
+pub(crate) fn build_http_client(
+    tls: Option<&Tls>,
+    connection_pool: Option<&ConnectionPool>,
+) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .gzip(true)
+        .deflate(true)
+        .brotli(true);
+    if let Some(connection_pool) = connection_pool {
+        if let Some(max_idle) = connection_pool.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        if let Some(idle_timeout) = connection_pool.pool_idle_timeout {
+            builder =
+                builder.pool_idle_timeout(secs_to_duration(idle_timeout, "pool_idle_timeout")?);
+        }
+        if let Some(keepalive) = connection_pool.tcp_keepalive {
+            builder = builder.tcp_keepalive(secs_to_duration(keepalive, "tcp_keepalive")?);
+        }
+    }
+    if let Some(tls) = tls {
+        if let Some(ca_cert_path) = &tls.ca_cert_path {
+            let pem = std::fs::read(ca_cert_path)
+                .with_context(|| format!("reading `ca_cert_path`: {ca_cert_path}"))?;
+            builder = builder.add_root_certificate(
+                reqwest::Certificate::from_pem(&pem).context("parsing `ca_cert_path`")?,
+            );
+        }
+        match (&tls.client_cert_path, &tls.client_key_path) {
+            (Some(client_cert_path), Some(client_key_path)) => {
+                let cert = std::fs::read(client_cert_path)
+                    .with_context(|| format!("reading `client_cert_path`: {client_cert_path}"))?;
+                let key = std::fs::read(client_key_path)
+                    .with_context(|| format!("reading `client_key_path`: {client_key_path}"))?;
+                builder = builder.identity(
+                    reqwest::Identity::from_pkcs8_pem(&cert, &key)
+                        .context("parsing the client certificate and key")?,
+                );
+            }
+            (None, None) => (),
+            _ => anyhow::bail!("`client_cert_path` and `client_key_path` must be set together"),
+        }
+        if tls.danger_accept_invalid_certs {
+            warn!("TLS certificate verification is disabled by `danger_accept_invalid_certs`");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+    }
+    Ok(builder.build()?)
+}
+
+// Later configuration changes don't rebuild the client
+pub(crate) fn init_http_client(
+    tls: Option<&Tls>,
+    connection_pool: Option<&ConnectionPool>,
+) -> anyhow::Result<()> {
+    let client = build_http_client(tls, connection_pool)?;
+    if HTTP_CLIENT.set(client).is_err() {
+        warn!("the HTTP client was already initialized");
+    }
+    Ok(())
+}
+
+// Set up from the `header_language` config when the server starts
+static HEADER_LANGUAGE: OnceCell<HeaderLanguage> = OnceCell::new();
+
+pub(crate) fn init_header_language(header_language: HeaderLanguage) {
+    if HEADER_LANGUAGE.set(header_language).is_err() {
+        warn!("the header language was already initialized");
+    }
+}
+
+pub(crate) fn http_client() -> reqwest::Client {
+    HTTP_CLIENT
+        .get_or_init(|| build_http_client(None, None).expect("Error building HTTP client"))
+        .clone()
+}
+
 pub(crate) trait ToResponseError {
     fn to_response_error(&self, code: i32) -> ResponseError;
 }
@@ -26,7 +128,9 @@ impl ToResponseError for anyhow::Error {
         ResponseError {
             code,
             message: self.to_string(),
-            data: None,
+            data: self
+                .downcast_ref::<BackendError>()
+                .map(BackendError::to_response_data),
         }
     }
 }
@@ -45,17 +149,142 @@ pub(crate) fn format_chat_messages(
         .collect()
 }
 
+// Maps a file extension to the language name used by the `{LANGUAGE}` placeholder and the LSP
+// language identifier, which is also what markdown code fences expect
+fn get_language_for_extension(extension: &str) -> Option<(&'static str, &'static str)> {
+    Some(match extension {
+        "rs" => ("Rust", "rust"),
+        "py" | "pyi" => ("Python", "python"),
+        "js" | "mjs" | "cjs" => ("JavaScript", "javascript"),
+        "jsx" => ("JavaScript React", "javascriptreact"),
+        "ts" | "mts" | "cts" => ("TypeScript", "typescript"),
+        "tsx" => ("TypeScript React", "typescriptreact"),
+        "go" => ("Go", "go"),
+        "java" => ("Java", "java"),
+        "kt" | "kts" => ("Kotlin", "kotlin"),
+        "c" | "h" => ("C", "c"),
+        "cc" | "cpp" | "cxx" | "hpp" | "hh" => ("C++", "cpp"),
+        "cs" => ("C#", "csharp"),
+        "rb" => ("Ruby", "ruby"),
+        "php" => ("PHP", "php"),
+        "swift" => ("Swift", "swift"),
+        "scala" => ("Scala", "scala"),
+        "lua" => ("Lua", "lua"),
+        "sh" | "bash" | "zsh" => ("Shell", "shellscript"),
+        "html" | "htm" => ("HTML", "html"),
+        "css" => ("CSS", "css"),
+        "json" => ("JSON", "json"),
+        "toml" => ("TOML", "toml"),
+        "yaml" | "yml" => ("YAML", "yaml"),
+        "md" => ("Markdown", "markdown"),
+        "sql" => ("SQL", "sql"),
+        "ex" | "exs" => ("Elixir", "elixir"),
+        "hs" => ("Haskell", "haskell"),
+        "zig" => ("Zig", "zig"),
+        _ => return None,
+    })
+}
+
+// The markers used by the built-in FIM templates
+static FIM_MARKER_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"<fim_\w+>|<\|fim_\w+\|>|<｜fim▁\w+｜>|<PRE> ?| ?<SUF>| ?<MID>")
+        .expect("Error building FIM marker regex")
+});
+
+// Models sometimes echo FIM markers back in their output which should never be inserted
+pub(crate) fn strip_fim_markers(s: &str) -> Cow<'_, str> {
+    FIM_MARKER_RE.replace_all(s, "")
+}
+
+// FIM only works well with base models. Instruct and chat models tend to produce garbage
+pub(crate) fn warn_if_chat_model_with_fim(model: &str) {
+    let model = model.to_lowercase();
+    if model.contains("instruct") || model.contains("chat") {
+        warn!("using FIM with `{model}` which looks like a chat model. FIM usually requires a base model. Set `fim_mode` to `instruct` to describe the code to fill in instead");
+    }
+}
+
+static ENV_VAR_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\$\{(\w+)\}").expect("Error building env var regex"));
+
+// Replaces every `${NAME}` with the value of the `NAME` environment variable
+pub(crate) fn expand_env_vars(s: &str) -> anyhow::Result<String> {
+    let mut missing = None;
+    let expanded = ENV_VAR_RE.replace_all(s, |captures: &Captures| {
+        std::env::var(&captures[1]).unwrap_or_else(|_| {
+            missing.get_or_insert_with(|| captures[1].to_string());
+            String::new()
+        })
+    });
+    match missing {
+        Some(name) => Err(anyhow!("environment variable `{name}` is not set")),
+        None => Ok(expanded.into_owned()),
+    }
+}
+
+// Matches escaped placeholders like `{{CODE}}` first so they are emitted literally as `{CODE}`
+static PLACEHOLDER_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\{\{(CONTEXT|CODE|SELECTED_TEXT|ATTACHED_FILES|DIAGNOSTICS|FILE_PATH|LANGUAGE|LINE|COLUMN)\}\}|\{(CONTEXT|CODE|SELECTED_TEXT|ATTACHED_FILES|DIAGNOSTICS|FILE_PATH|LANGUAGE|LINE|COLUMN)\}")
+        .expect("Error building placeholder regex")
+});
+
+// Supported placeholders:
+// - `{CONTEXT}` the context retrieved by the memory backend
+// - `{CODE}` the code around the cursor
+// - `{SELECTED_TEXT}` the text selected when running an action
+// - `{ATTACHED_FILES}` the `attached_files` of the action or chat being run
+// - `{DIAGNOSTICS}` the diagnostics the editor sent with the action and the code they span
+// - `{FILE_PATH}` the path of the document
+// - `{LANGUAGE}` the language of the document derived from its extension
+// - `{LINE}` and `{COLUMN}` the 1-based cursor position
+// Values that are not available are replaced with an empty string. Unknown placeholders are left as is
+// Substitution is done in a single pass so placeholders inside substituted values (e.g. code
+// containing the literal text `{CONTEXT}`) are never substituted again
+// Wrap a placeholder in double braces to escape it: `{{CODE}}` becomes `{CODE}`
 pub(crate) fn format_prompt_in_str(s: &str, prompt: &ContextAndCodePrompt) -> String {
-    s.replace("{CONTEXT}", &prompt.context)
-        .replace("{CODE}", &prompt.code)
-        .replace(
-            "{SELECTED_TEXT}",
-            prompt
-                .selected_text
-                .as_ref()
-                .map(|x| x.as_str())
-                .unwrap_or_default(),
-        )
+    let file_path = prompt
+        .position
+        .as_ref()
+        .map(|position| {
+            let uri = &position.text_document.uri;
+            uri.to_file_path()
+                .map(|path| path.to_string_lossy().to_string())
+                .unwrap_or_else(|_| uri.to_string())
+        })
+        .unwrap_or_default();
+    let (language, _) = std::path::Path::new(&file_path)
+        .extension()
+        .and_then(|extension| get_language_for_extension(&extension.to_string_lossy()))
+        .unwrap_or_default();
+    let (line, column) = prompt
+        .position
+        .as_ref()
+        .map(|position| {
+            (
+                (position.position.line + 1).to_string(),
+                (position.position.character + 1).to_string(),
+            )
+        })
+        .unwrap_or_default();
+    PLACEHOLDER_RE
+        .replace_all(s, |captures: &Captures| {
+            if let Some(escaped) = captures.get(1) {
+                return format!("{{{}}}", escaped.as_str());
+            }
+            match &captures[2] {
+                "CONTEXT" => prompt.context.clone(),
+                "CODE" => prompt.code.clone(),
+                "SELECTED_TEXT" => prompt.selected_text.clone().unwrap_or_default(),
+                "ATTACHED_FILES" => prompt.attached_files.clone().unwrap_or_default(),
+                "DIAGNOSTICS" => prompt.diagnostics.clone().unwrap_or_default(),
+                "FILE_PATH" => file_path.clone(),
+                "LANGUAGE" => language.to_string(),
+                "LINE" => line.clone(),
+                "COLUMN" => column.clone(),
+                _ => unreachable!(),
+            }
+        })
+        .into_owned()
 }
 
 pub(crate) fn format_prompt(prompt: &ContextAndCodePrompt) -> String {
@@ -66,35 +295,90 @@ pub(crate) fn chunk_to_id(uri: &str, chunk: &Chunk) -> String {
     format!("{uri}#{}-{}", chunk.range.start_byte, chunk.range.end_byte)
 }
 
+// Maps an LSP language id to an extension with a tree-sitter grammar
+fn get_extension_for_language_id(language_id: &str) -> Option<&'static str> {
+    Some(match language_id {
+        "python" => "py",
+        "rust" => "rs",
+        "shellscript" | "bash" | "sh" => "sh",
+        "c" => "c",
+        "cpp" => "cpp",
+        "csharp" => "cs",
+        "css" => "css",
+        "elixir" => "ex",
+        "erlang" => "erl",
+        "go" => "go",
+        "html" => "html",
+        "java" => "java",
+        "javascript" => "js",
+        "json" => "json",
+        "haskell" => "hs",
+        "lua" => "lua",
+        "ocaml" => "ml",
+        "ocaml.interface" => "mli",
+        _ => return None,
+    })
+}
+
+// The editor's `language_id` picks the grammar when it is known, otherwise the extension of `uri`
 pub(crate) fn parse_tree(
     uri: &str,
     contents: &str,
     old_tree: Option<&Tree>,
+    language_id: Option<&str>,
 ) -> anyhow::Result<Tree> {
     let path = std::path::Path::new(uri);
     let extension = path.extension().map(|x| x.to_string_lossy());
-    let extension = extension.as_deref().unwrap_or("");
-    let mut parser = utils_tree_sitter::get_parser_for_extension(extension)?;
+    let extension = language_id
+        .and_then(get_extension_for_language_id)
+        .or(extension.as_deref())
+        .unwrap_or("");
+    let header_language = match HEADER_LANGUAGE.get().copied().unwrap_or_default() {
+        HeaderLanguage::C => utils_tree_sitter::HeaderLanguage::C,
+        HeaderLanguage::Cpp => utils_tree_sitter::HeaderLanguage::Cpp,
+    };
+    let mut parser = utils_tree_sitter::get_parser_for_extension_with_header_language(
+        extension,
+        header_language,
+    )?;
     parser
         .parse(contents, old_tree)
         .with_context(|| format!("parsing tree failed for {uri}"))
 }
 
-pub(crate) fn format_file_chunk(uri: &str, excerpt: &str, root_uri: Option<&str>) -> String {
-    let path = match root_uri {
-        Some(root_uri) => {
-            if uri.starts_with(root_uri) {
-                &uri[root_uri.chars().count()..]
-            } else {
-                uri
-            }
+// The path of `uri` relative to the workspace root. Files outside of the root keep their full uri
+pub(crate) fn relative_path<'a>(uri: &'a str, root_uri: Option<&str>) -> &'a str {
+    root_uri
+        .and_then(|root_uri| uri.strip_prefix(root_uri.trim_end_matches('/')))
+        .and_then(|path| path.strip_prefix('/'))
+        .unwrap_or(uri)
+}
+
+// `header_template` supports the `{path}`, `{language}` and `{language_id}` placeholders and
+// defaults to `--{path}--`. `{language}` is a display name like `C++`, `{language_id}` the LSP
+// language identifier like `cpp` that works as a markdown code fence language
+pub(crate) fn format_file_chunk(
+    uri: &str,
+    excerpt: &str,
+    root_uri: Option<&str>,
+    header_template: Option<&str>,
+) -> String {
+    let path = relative_path(uri, root_uri);
+    let header = match header_template {
+        Some(header_template) => {
+            let (language, language_id) = std::path::Path::new(path)
+                .extension()
+                .and_then(|extension| get_language_for_extension(&extension.to_string_lossy()))
+                .unwrap_or_default();
+            // Substitute the path last so a path containing `{language}` is left as is
+            header_template
+                .replace("{language}", language)
+                .replace("{language_id}", language_id)
+                .replace("{path}", path)
         }
-        None => uri,
+        None => format!("--{path}--"),
     };
-    format!(
-        r#"--{path}--
-{excerpt}"#,
-    )
+    format!("{header}\n{excerpt}")
 }
 
 pub(crate) fn validate_file_exists(path: &str) -> anyhow::Result<PathBuf> {
@@ -106,6 +390,15 @@ pub(crate) fn validate_file_exists(path: &str) -> anyhow::Result<PathBuf> {
     }
 }
 
+pub(crate) fn validate_dir_exists(path: &str) -> anyhow::Result<PathBuf> {
+    let path = PathBuf::from(path);
+    if path.is_dir() {
+        Ok(path)
+    } else {
+        Err(anyhow!("Directory doesn't exist: {}", path.display()))
+    }
+}
+
 pub(crate) fn merge_json(a: &mut Value, b: &Value) {
     match (a, b) {
         (&mut Value::Object(ref mut a), &Value::Object(ref b)) => {
@@ -121,3 +414,362 @@ pub(crate) fn merge_json(a: &mut Value, b: &Value) {
         }
     }
 }
+
+// Like `merge_json` but arrays in `b` replace arrays in `a` and null values in `b` are ignored
+pub(crate) fn override_json(a: &mut Value, b: &Value) {
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (k, v) in b {
+                override_json(a.entry(k.clone()).or_insert(Value::Null), v);
+            }
+        }
+        (_, Value::Null) => (),
+        (a, b) => {
+            *a = b.clone();
+        }
+    }
+}
+
+// Prefixes each line with `N: ` where `first_line_number` is the 1-based number of the first line.
+// Lines that would be numbered below 1 belong to other files and are left as is
+pub(crate) fn add_line_numbers(text: &str, first_line_number: i64) -> String {
+    text.split('\n')
+        .enumerate()
+        .map(|(i, line)| {
+            let line_number = first_line_number + i as i64;
+            if line_number >= 1 {
+                format!("{line_number}: {line}")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// The byte index where `text` starts repeating the same block of at most `max_block_lines` lines
+// `repetitions` times in a row. Cutting there keeps the first copy of the block.
+// Blocks of blank lines are ignored
+pub(crate) fn find_repetition(
+    text: &str,
+    max_block_lines: usize,
+    repetitions: usize,
+) -> Option<usize> {
+    if repetitions < 2 {
+        return None;
+    }
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let line_starts: Vec<usize> = lines
+        .iter()
+        .scan(0, |offset, line| {
+            let start = *offset;
+            *offset += line.len();
+            Some(start)
+        })
+        .collect();
+    for start in 0..lines.len() {
+        for block_lines in 1..=max_block_lines {
+            if start + block_lines * repetitions > lines.len() {
+                break;
+            }
+            let block = &lines[start..start + block_lines];
+            if block.iter().all(|line| line.trim().is_empty()) {
+                continue;
+            }
+            let repeats = (1..repetitions).all(|i| {
+                lines[start + i * block_lines..start + (i + 1) * block_lines]
+                    .iter()
+                    .zip(block)
+                    .all(|(a, b)| a.trim_end() == b.trim_end())
+            });
+            if repeats {
+                return Some(line_starts[start + block_lines]);
+            }
+        }
+    }
+    None
+}
+
+// Like git, treats contents with a NUL byte near the start as binary
+pub(crate) fn is_binary(contents: &[u8]) -> bool {
+    contents.iter().take(8000).any(|byte| *byte == 0)
+}
+
+// Reads a text file. None is returned when it is larger than `max_file_size`, binary or not UTF-8
+pub(crate) fn read_file_with_size_cap(
+    path: &Path,
+    max_file_size: u64,
+) -> anyhow::Result<Option<String>> {
+    let mut f = std::fs::File::open(path)?;
+    if f.metadata()?.len() > max_file_size {
+        return Ok(None);
+    }
+    let mut contents = vec![];
+    f.read_to_end(&mut contents)?;
+    if is_binary(&contents) {
+        return Ok(None);
+    }
+    Ok(String::from_utf8(contents).ok())
+}
+
+// The uris of the files under `root_uri` matching any of the globs, sorted by path
+pub(crate) fn find_files_matching_globs(
+    root_uri: &str,
+    globs: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let root = Url::parse(root_uri)
+        .ok()
+        .and_then(|url| url.to_file_path().ok())
+        .with_context(|| format!("root_uri is not a file uri: {root_uri}"))?;
+    let mut overrides = OverrideBuilder::new(&root);
+    for glob in globs {
+        overrides
+            .add(glob)
+            .with_context(|| format!("invalid glob: {glob}"))?;
+    }
+    let walker = WalkBuilder::new(&root)
+        .overrides(overrides.build()?)
+        .build();
+    let mut uris = vec![];
+    for entry in walker {
+        let entry = entry?;
+        if entry.file_type().is_some_and(|t| t.is_file()) {
+            if let Ok(uri) = Url::from_file_path(entry.path()) {
+                uris.push(uri.to_string());
+            }
+        }
+    }
+    uris.sort();
+    Ok(uris)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_types::{Position, TextDocumentIdentifier, TextDocumentPositionParams, Url};
+
+    fn generate_prompt(uri: &str) -> ContextAndCodePrompt {
+        ContextAndCodePrompt {
+            context: "context".to_string(),
+            code: "code".to_string(),
+            selected_text: Some("selected".to_string()),
+            attached_files: Some("attached".to_string()),
+            diagnostics: Some("diagnostics".to_string()),
+            position: Some(TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier {
+                    uri: Url::parse(uri).unwrap(),
+                },
+                position: Position::new(2, 4),
+            }),
+        }
+    }
+
+    #[test]
+    fn format_prompt_in_str_placeholders() {
+        let prompt = generate_prompt("file:///project/main.rs");
+        assert_eq!(format_prompt_in_str("{CONTEXT}", &prompt), "context");
+        assert_eq!(format_prompt_in_str("{CODE}", &prompt), "code");
+        assert_eq!(format_prompt_in_str("{SELECTED_TEXT}", &prompt), "selected");
+        assert_eq!(
+            format_prompt_in_str("{ATTACHED_FILES}", &prompt),
+            "attached"
+        );
+        assert_eq!(
+            format_prompt_in_str("{DIAGNOSTICS}", &prompt),
+            "diagnostics"
+        );
+        assert_eq!(
+            format_prompt_in_str("{FILE_PATH}", &prompt),
+            "/project/main.rs"
+        );
+        assert_eq!(format_prompt_in_str("{LANGUAGE}", &prompt), "Rust");
+        assert_eq!(format_prompt_in_str("{LINE}", &prompt), "3");
+        assert_eq!(format_prompt_in_str("{COLUMN}", &prompt), "5");
+        assert_eq!(format_prompt_in_str("{UNKNOWN}", &prompt), "{UNKNOWN}");
+    }
+
+    #[test]
+    fn format_prompt_in_str_missing_values() {
+        let mut prompt = generate_prompt("file:///project/Makefile");
+        assert_eq!(format_prompt_in_str("{LANGUAGE}", &prompt), "");
+        prompt.position = None;
+        prompt.selected_text = None;
+        prompt.attached_files = None;
+        assert_eq!(
+            format_prompt_in_str(
+                "{SELECTED_TEXT}{ATTACHED_FILES}{FILE_PATH}{LINE}{COLUMN}",
+                &prompt
+            ),
+            ""
+        );
+    }
+
+    #[test]
+    fn format_prompt_in_str_does_not_resubstitute() {
+        let mut prompt = generate_prompt("file:///project/main.rs");
+        prompt.code = "let s = \"{CONTEXT} {SELECTED_TEXT}\";".to_string();
+        prompt.context = "fn f() { {CODE} }".to_string();
+        assert_eq!(
+            format_prompt_in_str("{CONTEXT}\n{CODE}", &prompt),
+            "fn f() { {CODE} }\nlet s = \"{CONTEXT} {SELECTED_TEXT}\";"
+        );
+    }
+
+    #[test]
+    fn format_prompt_in_str_escapes() {
+        let prompt = generate_prompt("file:///project/main.rs");
+        assert_eq!(
+            format_prompt_in_str("Replace {{CODE}} with {CODE}", &prompt),
+            "Replace {CODE} with code"
+        );
+        // Only known placeholders can be escaped
+        assert_eq!(
+            format_prompt_in_str("{{ value }} {{OTHER}}", &prompt),
+            "{{ value }} {{OTHER}}"
+        );
+    }
+
+    #[test]
+    fn format_chat_messages_placeholders() {
+        let prompt = generate_prompt("file:///project/main.py");
+        let messages = vec![ChatMessage::new(
+            "user".to_string(),
+            "{LANGUAGE} {FILE_PATH}:{LINE}:{COLUMN}\n{CODE}".to_string(),
+        )];
+        let messages = format_chat_messages(&messages, &prompt);
+        assert_eq!(messages[0].content, "Python /project/main.py:3:5\ncode");
+    }
+
+    #[test]
+    fn strip_fim_markers_from_response() {
+        assert_eq!(strip_fim_markers("<fim_middle>x<fim_suffix>"), "x");
+        assert_eq!(strip_fim_markers("<|fim_prefix|>x<|fim_middle|>"), "x");
+        assert_eq!(strip_fim_markers("<｜fim▁hole｜>x<｜fim▁end｜>"), "x");
+        assert_eq!(strip_fim_markers("<PRE> x <SUF> <MID>"), "x");
+        assert!(matches!(strip_fim_markers("a < b"), Cow::Borrowed("a < b")));
+    }
+
+    #[test]
+    fn add_line_numbers_to_text() {
+        assert_eq!(add_line_numbers("a\nb\n", 3), "3: a\n4: b\n5: ");
+        assert_eq!(add_line_numbers("other\na\nb", 0), "other\n1: a\n2: b");
+    }
+
+    #[test]
+    fn format_file_chunk_with_header_template() {
+        let uri = "file:///project/src/main.rs";
+        assert_eq!(
+            format_file_chunk(uri, "fn main() {}", Some("file:///project"), None),
+            "--src/main.rs--\nfn main() {}"
+        );
+        assert_eq!(
+            format_file_chunk(
+                uri,
+                "fn main() {}",
+                Some("file:///project"),
+                Some("### File: {path} ({language})")
+            ),
+            "### File: src/main.rs (Rust)\nfn main() {}"
+        );
+        assert_eq!(
+            format_file_chunk(
+                "file:///project/src/main.cpp",
+                "int main() {}",
+                Some("file:///project"),
+                Some("{path}\n```{language_id}")
+            ),
+            "src/main.cpp\n```cpp\nint main() {}"
+        );
+    }
+
+    #[test]
+    fn find_repetition_in_looped_text() {
+        let text = "x = 1\nprint(x)\nprint(x)\nprint(x)\nprint(x)";
+        assert_eq!(find_repetition(text, 4, 4), Some("x = 1\nprint(x)\n".len()));
+        let text = "a\nfoo()\nbar()\nfoo()\nbar()\nfoo()\nbar()\n";
+        assert_eq!(find_repetition(text, 4, 3), Some("a\nfoo()\nbar()\n".len()));
+    }
+
+    #[test]
+    fn find_repetition_in_text_without_loops() {
+        let text = "def f(x):\n    return x\n\n\n\ndef g(y):\n    return y\n";
+        assert_eq!(find_repetition(text, 4, 3), None);
+        // Fewer repeats than the threshold
+        assert_eq!(find_repetition("a\na\na\nb", 4, 4), None);
+        assert_eq!(find_repetition("a\na\na\na", 4, 0), None);
+    }
+
+    #[test]
+    fn read_file_with_size_cap_skips_large_files() -> anyhow::Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("lsp-ai-read-file-{}.txt", std::process::id()));
+        std::fs::write(&path, "0123456789")?;
+        assert_eq!(
+            read_file_with_size_cap(&path, 10)?,
+            Some("0123456789".to_string())
+        );
+        assert_eq!(read_file_with_size_cap(&path, 9)?, None);
+        std::fs::write(&path, b"\x89PNG\r\n\x1a\n\x00\x00")?;
+        assert_eq!(read_file_with_size_cap(&path, 10)?, None);
+        std::fs::write(&path, b"caf\xe9")?;
+        assert_eq!(read_file_with_size_cap(&path, 10)?, None);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn build_http_client_with_config() {
+        assert!(build_http_client(Some(&Tls::default()), None).is_ok());
+        let tls = Tls {
+            danger_accept_invalid_certs: true,
+            ..Default::default()
+        };
+        assert!(build_http_client(Some(&tls), None).is_ok());
+        let tls = Tls {
+            client_cert_path: Some("client.pem".to_string()),
+            ..Default::default()
+        };
+        assert!(build_http_client(Some(&tls), None).is_err());
+        let tls = Tls {
+            ca_cert_path: Some("/does/not/exist.pem".to_string()),
+            ..Default::default()
+        };
+        assert!(build_http_client(Some(&tls), None).is_err());
+
+        let connection_pool = ConnectionPool {
+            pool_max_idle_per_host: Some(2),
+            pool_idle_timeout: Some(30.),
+            tcp_keepalive: Some(15.),
+        };
+        assert!(build_http_client(None, Some(&connection_pool)).is_ok());
+        let connection_pool = ConnectionPool {
+            pool_idle_timeout: Some(-1.),
+            ..Default::default()
+        };
+        assert!(build_http_client(None, Some(&connection_pool)).is_err());
+        let connection_pool = ConnectionPool {
+            tcp_keepalive: Some(f32::NAN),
+            ..Default::default()
+        };
+        assert!(build_http_client(None, Some(&connection_pool)).is_err());
+    }
+
+    #[test]
+    fn expand_env_vars_in_str() -> anyhow::Result<()> {
+        std::env::set_var("LSP_AI_TEST_EXPAND", "value");
+        assert_eq!(expand_env_vars("a-${LSP_AI_TEST_EXPAND}-b")?, "a-value-b");
+        assert_eq!(expand_env_vars("no vars $HOME")?, "no vars $HOME");
+        assert!(expand_env_vars("${LSP_AI_TEST_NOT_SET}").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn relative_path_strips_root_uri() {
+        let uri = "file:///project/src/main.rs";
+        assert_eq!(relative_path(uri, Some("file:///project")), "src/main.rs");
+        assert_eq!(relative_path(uri, Some("file:///project/")), "src/main.rs");
+        assert_eq!(relative_path(uri, Some("file:///proj")), uri);
+        assert_eq!(relative_path(uri, Some("file:///other")), uri);
+        assert_eq!(relative_path(uri, None), uri);
+    }
+}
@@ -42,7 +42,7 @@ fn send_message(stdin: &mut ChildStdin, message: &str) -> Result<()> {
 }
 
 // This chat completion sequence was created using helix with lsp-ai and reading the logs
-// It utilizes Ollama with llama3.1:8b and a temperature of 0
+// It utilizes the mock backend with a canned response so it does not need a running model
 // It starts with a Python file:
 // ```
 // # Multiplies two numbers
@@ -68,6 +68,7 @@ fn send_message(stdin: &mut ChildStdin, message: &str) -> Result<()> {
 fn test_chat_completion_sequence() -> Result<()> {
     let mut child = Command::new("cargo")
         .arg("run")
+        .args(["--features", "mock"])
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -76,7 +77,7 @@ fn test_chat_completion_sequence() -> Result<()> {
     let mut stdin = child.stdin.take().unwrap();
     let mut stdout = child.stdout.take().unwrap();
 
-    let initialization_message = r##"{"jsonrpc":"2.0","method":"initialize","params":{"capabilities":{"general":{"positionEncodings":["utf-8","utf-32","utf-16"]},"textDocument":{"codeAction":{"codeActionLiteralSupport":{"codeActionKind":{"valueSet":["","quickfix","refactor","refactor.extract","refactor.inline","refactor.rewrite","source","source.organizeImports"]}},"dataSupport":true,"disabledSupport":true,"isPreferredSupport":true,"resolveSupport":{"properties":["edit","command"]}},"completion":{"completionItem":{"deprecatedSupport":true,"insertReplaceSupport":true,"resolveSupport":{"properties":["documentation","detail","additionalTextEdits"]},"snippetSupport":true,"tagSupport":{"valueSet":[1]}},"completionItemKind":{}},"hover":{"contentFormat":["markdown"]},"inlayHint":{"dynamicRegistration":false},"publishDiagnostics":{"tagSupport":{"valueSet":[1,2]},"versionSupport":true},"rename":{"dynamicRegistration":false,"honorsChangeAnnotations":false,"prepareSupport":true},"signatureHelp":{"signatureInformation":{"activeParameterSupport":true,"documentationFormat":["markdown"],"parameterInformation":{"labelOffsetSupport":true}}}},"window":{"workDoneProgress":true},"workspace":{"applyEdit":true,"configuration":true,"didChangeConfiguration":{"dynamicRegistration":false},"didChangeWatchedFiles":{"dynamicRegistration":true,"relativePatternSupport":false},"executeCommand":{"dynamicRegistration":false},"fileOperations":{"didRename":true,"willRename":true},"inlayHint":{"refreshSupport":false},"symbol":{"dynamicRegistration":false},"workspaceEdit":{"documentChanges":true,"failureHandling":"abort","normalizesLineEndings":false,"resourceOperations":["create","rename","delete"]},"workspaceFolders":true}},"clientInfo":{"name":"helix","version":"24.3 (beb5afcb)"},"initializationOptions":{"completion":{"model":"model1","parameters":{"max_context":1024,"messages":[{"content":"Instructions:\n- You are an AI programming assistant.\n- Given a piece of code with the cursor location marked by \"<CURSOR>\", replace \"<CURSOR>\" with the correct code or comment.\n- First, think step-by-step.\n- Describe your plan for what to build in pseudocode, written out in great detail.\n- Then output the code replacing the \"<CURSOR>\"\n- Ensure that your completion fits within the language context of the provided code snippet (e.g., Python, JavaScript, Rust).\n\nRules:\n- Only respond with code or comments.\n- Only replace \"<CURSOR>\"; do not include any previously written code.\n- Never include \"<CURSOR>\" in your response\n- If the cursor is within a comment, complete the comment meaningfully.\n- Handle ambiguous cases by providing the most contextually appropriate completion.\n- Be consistent with your responses.","role":"system"},{"content":"def greet(name):\n    print(f\"Hello, {<CURSOR>}\")","role":"user"},{"content":"name","role":"assistant"},{"content":"function sum(a, b) {\n    return a + <CURSOR>;\n}","role":"user"},{"content":"b","role":"assistant"},{"content":"fn multiply(a: i32, b: i32) -> i32 {\n    a * <CURSOR>\n}","role":"user"},{"content":"b","role":"assistant"},{"content":"# <CURSOR>\ndef add(a, b):\n    return a + b","role":"user"},{"content":"Adds two numbers","role":"assistant"},{"content":"# This function checks if a number is even\n<CURSOR>","role":"user"},{"content":"def is_even(n):\n    return n % 2 == 0","role":"assistant"},{"content":"{CODE}","role":"user"}],"options":{"num_predict":32,"temperature":0}}},"memory":{"file_store":{}},"models":{"model1":{"model":"llama3:8b-instruct-q4_0","type":"ollama"}}},"processId":66009,"rootPath":"/home/silas/Projects/test","rootUri":null,"workspaceFolders":[]},"id":0}"##;
+    let initialization_message = r##"{"jsonrpc":"2.0","method":"initialize","params":{"capabilities":{"general":{"positionEncodings":["utf-8","utf-32","utf-16"]},"textDocument":{"codeAction":{"codeActionLiteralSupport":{"codeActionKind":{"valueSet":["","quickfix","refactor","refactor.extract","refactor.inline","refactor.rewrite","source","source.organizeImports"]}},"dataSupport":true,"disabledSupport":true,"isPreferredSupport":true,"resolveSupport":{"properties":["edit","command"]}},"completion":{"completionItem":{"deprecatedSupport":true,"insertReplaceSupport":true,"resolveSupport":{"properties":["documentation","detail","additionalTextEdits"]},"snippetSupport":true,"tagSupport":{"valueSet":[1]}},"completionItemKind":{}},"hover":{"contentFormat":["markdown"]},"inlayHint":{"dynamicRegistration":false},"publishDiagnostics":{"tagSupport":{"valueSet":[1,2]},"versionSupport":true},"rename":{"dynamicRegistration":false,"honorsChangeAnnotations":false,"prepareSupport":true},"signatureHelp":{"signatureInformation":{"activeParameterSupport":true,"documentationFormat":["markdown"],"parameterInformation":{"labelOffsetSupport":true}}}},"window":{"workDoneProgress":true},"workspace":{"applyEdit":true,"configuration":true,"didChangeConfiguration":{"dynamicRegistration":false},"didChangeWatchedFiles":{"dynamicRegistration":true,"relativePatternSupport":false},"executeCommand":{"dynamicRegistration":false},"fileOperations":{"didRename":true,"willRename":true},"inlayHint":{"refreshSupport":false},"symbol":{"dynamicRegistration":false},"workspaceEdit":{"documentChanges":true,"failureHandling":"abort","normalizesLineEndings":false,"resourceOperations":["create","rename","delete"]},"workspaceFolders":true}},"clientInfo":{"name":"helix","version":"24.3 (beb5afcb)"},"initializationOptions":{"completion":{"model":"model1","parameters":{"max_context":1024,"messages":[{"content":"Instructions:\n- You are an AI programming assistant.\n- Given a piece of code with the cursor location marked by \"<CURSOR>\", replace \"<CURSOR>\" with the correct code or comment.\n- First, think step-by-step.\n- Describe your plan for what to build in pseudocode, written out in great detail.\n- Then output the code replacing the \"<CURSOR>\"\n- Ensure that your completion fits within the language context of the provided code snippet (e.g., Python, JavaScript, Rust).\n\nRules:\n- Only respond with code or comments.\n- Only replace \"<CURSOR>\"; do not include any previously written code.\n- Never include \"<CURSOR>\" in your response\n- If the cursor is within a comment, complete the comment meaningfully.\n- Handle ambiguous cases by providing the most contextually appropriate completion.\n- Be consistent with your responses.","role":"system"},{"content":"def greet(name):\n    print(f\"Hello, {<CURSOR>}\")","role":"user"},{"content":"name","role":"assistant"},{"content":"function sum(a, b) {\n    return a + <CURSOR>;\n}","role":"user"},{"content":"b","role":"assistant"},{"content":"fn multiply(a: i32, b: i32) -> i32 {\n    a * <CURSOR>\n}","role":"user"},{"content":"b","role":"assistant"},{"content":"# <CURSOR>\ndef add(a, b):\n    return a + b","role":"user"},{"content":"Adds two numbers","role":"assistant"},{"content":"# This function checks if a number is even\n<CURSOR>","role":"user"},{"content":"def is_even(n):\n    return n % 2 == 0","role":"assistant"},{"content":"{CODE}","role":"user"}],"options":{"num_predict":32,"temperature":0}}},"memory":{"file_store":{}},"models":{"model1":{"response":"x * y","type":"mock"}}},"processId":66009,"rootPath":"/home/silas/Projects/test","rootUri":null,"workspaceFolders":[]},"id":0}"##;
     send_message(&mut stdin, initialization_message)?;
     let _ = read_response(&mut stdout)?;
 
@@ -124,7 +125,7 @@ fn test_chat_completion_sequence() -> Result<()> {
     let output = read_response(&mut stdout)?;
     assert_eq!(
         output,
-        r##"{"jsonrpc":"2.0","id":1,"result":{"isIncomplete":false,"items":[{"filterText":"    return","kind":1,"label":"ai - x * y","textEdit":{"newText":"x * y","range":{"end":{"character":10,"line":2},"start":{"character":10,"line":2}}}}]}}"##
+        r##"{"jsonrpc":"2.0","id":1,"result":{"isIncomplete":false,"items":[{"filterText":"    return","kind":1,"label":"ai - x * y","textEdit":{"insert":{"end":{"character":10,"line":2},"start":{"character":10,"line":2}},"newText":"x * y","replace":{"end":{"character":10,"line":2},"start":{"character":10,"line":2}}}}]}}"##
     );
 
     child.kill()?;
@@ -194,7 +195,7 @@ fn test_fim_completion_sequence() -> Result<()> {
     let output = read_response(&mut stdout)?;
     assert_eq!(
         output,
-        r##"{"jsonrpc":"2.0","id":1,"result":{"isIncomplete":false,"items":[{"filterText":"    re","kind":1,"label":"ai - turn x * y","textEdit":{"newText":"turn x * y","range":{"end":{"character":6,"line":2},"start":{"character":6,"line":2}}}}]}}"##
+        r##"{"jsonrpc":"2.0","id":1,"result":{"isIncomplete":false,"items":[{"filterText":"    re","kind":1,"label":"ai - turn x * y","textEdit":{"insert":{"end":{"character":6,"line":2},"start":{"character":6,"line":2}},"newText":"turn x * y","replace":{"end":{"character":6,"line":2},"start":{"character":6,"line":2}}}}]}}"##
     );
 
     child.kill()?;
@@ -281,7 +282,7 @@ fn test_completion_sequence() -> Result<()> {
     let output = read_response(&mut stdout)?;
     assert_eq!(
         output,
-        r##"{"jsonrpc":"2.0","id":1,"result":{"isIncomplete":false,"items":[{"filterText":"    return","kind":1,"label":"ai -  x * y","textEdit":{"newText":" x * y","range":{"end":{"character":10,"line":2},"start":{"character":10,"line":2}}}}]}}"##
+        r##"{"jsonrpc":"2.0","id":1,"result":{"isIncomplete":false,"items":[{"filterText":"    return","kind":1,"label":"ai - x * y","textEdit":{"insert":{"end":{"character":10,"line":2},"start":{"character":10,"line":2}},"newText":" x * y","replace":{"end":{"character":10,"line":2},"start":{"character":10,"line":2}}}}]}}"##
     );
 
     child.kill()?;
@@ -537,3 +538,107 @@ fn test_refactor_action_sequence() -> Result<()> {
     child.kill()?;
     Ok(())
 }
+
+// The file_store keeps nothing once the process exits so there is nothing to index
+#[test]
+fn test_index_requires_persistent_memory_backend() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("lsp-ai-index-{}", std::process::id()));
+    std::fs::create_dir_all(&root)?;
+    std::fs::write(
+        root.join(".lsp-ai.json"),
+        r#"{"memory":{"file_store":{}},"models":{}}"#,
+    )?;
+    std::fs::write(root.join("main.py"), "print('hello')\n")?;
+
+    let output = Command::new("cargo")
+        .args(["run", "--", "index", "--workspace-config", "--path"])
+        .arg(&root)
+        .output()?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("only the `postgresml` memory backend keeps an index"));
+
+    // The workspace config file is only read with `--workspace-config`
+    let output = Command::new("cargo")
+        .args(["run", "--", "index", "--path"])
+        .arg(&root)
+        .output()?;
+    std::fs::remove_dir_all(&root)?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("lsp-ai does not currently provide a default configuration"));
+    Ok(())
+}
+
+// An invalid configuration is reported to the user and the server still shuts down when asked
+#[test]
+fn test_invalid_config_reports_error_and_shuts_down() -> Result<()> {
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    send_message(
+        &mut stdin,
+        r#"{"jsonrpc":"2.0","method":"initialize","params":{"capabilities":{},"initializationOptions":{"memory":{"not_a_backend":{}},"models":{}},"rootUri":null},"id":0}"#,
+    )?;
+    let _ = read_response(&mut stdout)?;
+    send_message(
+        &mut stdin,
+        r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+    )?;
+    let output = read_response(&mut stdout)?;
+    assert!(output.contains("window/showMessage"));
+    assert!(output.contains("lsp-ai could not load its configuration"));
+
+    send_message(
+        &mut stdin,
+        r##"{"jsonrpc":"2.0","method":"textDocument/completion","params":{"position":{"character":0,"line":0},"textDocument":{"uri":"file:///fake.py"}},"id":1}"##,
+    )?;
+    let output = read_response(&mut stdout)?;
+    assert!(output.contains(r#""id":1"#));
+    assert!(output.contains("lsp-ai could not load its configuration"));
+
+    send_message(
+        &mut stdin,
+        r#"{"jsonrpc":"2.0","method":"shutdown","id":2}"#,
+    )?;
+    let _ = read_response(&mut stdout)?;
+    send_message(&mut stdin, r#"{"jsonrpc":"2.0","method":"exit"}"#)?;
+    assert!(child.wait()?.success());
+    Ok(())
+}
+
+// Clients only send `workspace/didDeleteFiles` when the server registers for it
+#[test]
+fn test_registers_did_delete_files() -> Result<()> {
+    let mut child = Command::new("cargo")
+        .arg("run")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    send_message(
+        &mut stdin,
+        r#"{"jsonrpc":"2.0","method":"initialize","params":{"capabilities":{},"initializationOptions":{"memory":{"file_store":{}},"models":{}},"rootUri":null},"id":0}"#,
+    )?;
+    let output: serde_json::Value = serde_json::from_str(&read_response(&mut stdout)?)?;
+    assert_eq!(
+        output["result"]["capabilities"]["workspace"]["fileOperations"]["didDelete"],
+        serde_json::json!({
+            "filters": [{"scheme": "file", "pattern": {"glob": "**/*", "matches": "file"}}]
+        })
+    );
+
+    child.kill()?;
+    Ok(())
+}
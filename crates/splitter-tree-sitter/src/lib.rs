@@ -1,5 +1,5 @@
 use thiserror::Error;
-use tree_sitter::{Tree, TreeCursor};
+use tree_sitter::{Node, Tree, TreeCursor};
 
 #[derive(Error, Debug)]
 pub enum NewError {
@@ -90,6 +90,59 @@ impl TreeSitterCodeSplitter {
             .collect())
     }
 
+    // Only ends chunks between whole declarations, packing neighbouring declarations that fit
+    // together. A declaration larger than chunk_size is split the same way at its own children
+    pub fn split_by_declaration<'c>(
+        &self,
+        tree: &Tree,
+        utf8: &'c [u8],
+    ) -> Result<Vec<Chunk<'c>>, SplitError> {
+        self.split_declarations(tree.root_node(), utf8)
+    }
+
+    fn split_declarations<'c>(
+        &self,
+        node: Node<'_>,
+        utf8: &'c [u8],
+    ) -> Result<Vec<Chunk<'c>>, SplitError> {
+        let mut chunks: Vec<Chunk<'c>> = vec![];
+        // Pieces of a split declaration are never packed with their neighbours
+        let mut can_extend_last = false;
+        let mut cursor = node.walk();
+        for child in node.children(&mut cursor) {
+            let text = child.utf8_text(utf8)?;
+            if text.chars().count() > self.chunk_size {
+                if child.child_count() > 0 {
+                    chunks.append(&mut self.split_declarations(child, utf8)?);
+                } else {
+                    chunks.append(
+                        &mut self
+                            .split_text(text, ByteRange::new(child.start_byte(), child.end_byte())),
+                    );
+                }
+                can_extend_last = false;
+                continue;
+            }
+            if can_extend_last {
+                let last = chunks.last_mut().unwrap();
+                let text = std::str::from_utf8(&utf8[last.range.start_byte..child.end_byte()])?;
+                if text.chars().count() <= self.chunk_size {
+                    *last = Chunk::new(
+                        text,
+                        ByteRange::new(last.range.start_byte, child.end_byte()),
+                    );
+                    continue;
+                }
+            }
+            chunks.push(Chunk::new(
+                text,
+                ByteRange::new(child.start_byte(), child.end_byte()),
+            ));
+            can_extend_last = true;
+        }
+        Ok(chunks)
+    }
+
     fn split_recursive<'c>(
         &self,
         mut cursor: TreeCursor<'_>,
@@ -112,33 +165,10 @@ impl TreeSitterCodeSplitter {
             if cursor_copy.goto_first_child() {
                 self.split_recursive(cursor_copy, utf8)?
             } else {
-                let mut current_range =
-                    ByteRange::new(node.range().start_byte, node.range().end_byte);
-                let mut chunks = vec![];
-                let mut current_chunk = text;
-                loop {
-                    if current_chunk.len() < self.chunk_size {
-                        chunks.push(Chunk::new(current_chunk, current_range));
-                        break;
-                    } else {
-                        let new_chunk = &current_chunk[0..self.chunk_size.min(current_chunk.len())];
-                        let new_range = ByteRange::new(
-                            current_range.start_byte,
-                            current_range.start_byte + new_chunk.as_bytes().len(),
-                        );
-                        chunks.push(Chunk::new(new_chunk, new_range));
-                        let new_current_chunk =
-                            &current_chunk[self.chunk_size - self.chunk_overlap..];
-                        let byte_diff =
-                            current_chunk.as_bytes().len() - new_current_chunk.as_bytes().len();
-                        current_range = ByteRange::new(
-                            current_range.start_byte + byte_diff,
-                            current_range.end_byte,
-                        );
-                        current_chunk = new_current_chunk
-                    }
-                }
-                chunks
+                self.split_text(
+                    text,
+                    ByteRange::new(node.range().start_byte, node.range().end_byte),
+                )
             }
         };
         if cursor.goto_next_sibling() {
@@ -146,6 +176,32 @@ impl TreeSitterCodeSplitter {
         }
         Ok(out)
     }
+
+    // Splits the text of a node without children
+    fn split_text<'c>(&self, text: &'c str, range: ByteRange) -> Vec<Chunk<'c>> {
+        let mut current_range = range;
+        let mut chunks = vec![];
+        let mut current_chunk = text;
+        loop {
+            if current_chunk.len() < self.chunk_size {
+                chunks.push(Chunk::new(current_chunk, current_range));
+                break;
+            } else {
+                let new_chunk = &current_chunk[0..self.chunk_size.min(current_chunk.len())];
+                let new_range = ByteRange::new(
+                    current_range.start_byte,
+                    current_range.start_byte + new_chunk.len(),
+                );
+                chunks.push(Chunk::new(new_chunk, new_range));
+                let new_current_chunk = &current_chunk[self.chunk_size - self.chunk_overlap..];
+                let byte_diff = current_chunk.len() - new_current_chunk.len();
+                current_range =
+                    ByteRange::new(current_range.start_byte + byte_diff, current_range.end_byte);
+                current_chunk = new_current_chunk
+            }
+        }
+        chunks
+    }
 }
 
 #[cfg(test)]
@@ -223,6 +279,78 @@ struct Rectangle {
         );
     }
 
+    #[test]
+    fn test_split_rust_by_declaration() {
+        let splitter = TreeSitterCodeSplitter::new(128, 0).unwrap();
+
+        let mut parser = Parser::new();
+        parser
+            .set_language(&tree_sitter_rust::language())
+            .expect("Error loading Rust grammar");
+
+        let source_code = r#"
+#[derive(Debug)]
+struct Rectangle {
+    width: u32,
+    height: u32,
+}
+
+impl Rectangle {
+    fn area(&self) -> u32 {
+        self.width * self.height
+    }
+}
+
+fn main() {
+    let rect = Rectangle { width: 30, height: 50 };
+    println!("{}", rect.area());
+}
+
+fn describe(rect: &Rectangle) -> String {
+    let area = rect.area();
+    let kind = if rect.width == rect.height { "square" } else { "rectangle" };
+    format!("A {kind} of width {}, height {} and area {area}", rect.width, rect.height)
+}
+"#;
+        let tree = parser.parse(source_code, None).unwrap();
+        let chunks = splitter
+            .split_by_declaration(&tree, source_code.as_bytes())
+            .unwrap();
+        assert_eq!(
+            chunks[0].text,
+            r#"#[derive(Debug)]
+struct Rectangle {
+    width: u32,
+    height: u32,
+}"#
+        );
+        assert_eq!(
+            chunks[1].text,
+            r#"impl Rectangle {
+    fn area(&self) -> u32 {
+        self.width * self.height
+    }
+}"#
+        );
+        assert_eq!(
+            chunks[2].text,
+            r#"fn main() {
+    let rect = Rectangle { width: 30, height: 50 };
+    println!("{}", rect.area());
+}"#
+        );
+        // Only the function larger than chunk_size is split, and its pieces stay within it
+        let describe_start = source_code.find("fn describe").unwrap();
+        assert!(chunks.len() > 4);
+        assert!(chunks[3..]
+            .iter()
+            .all(|chunk| chunk.range.start_byte >= describe_start));
+        assert_eq!(
+            chunks.last().unwrap().range.end_byte,
+            source_code.trim_end().len()
+        );
+    }
+
     #[test]
     fn test_split_zig() {
         let splitter = TreeSitterCodeSplitter::new(128, 10).unwrap();
@@ -11,14 +11,29 @@ pub enum GetParserError {
     LoadingGrammer(#[from] LanguageError),
 }
 
-fn get_extension_for_language(extension: &str) -> Result<String, GetParserError> {
+// `.h` is used by both C and C++
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HeaderLanguage {
+    #[default]
+    C,
+    Cpp,
+}
+
+fn get_extension_for_language(
+    extension: &str,
+    header_language: HeaderLanguage,
+) -> Result<String, GetParserError> {
     Ok(match extension {
         "py" => "Python",
         "rs" => "Rust",
         // "zig" => "Zig",
         "sh" => "Bash",
         "c" => "C",
-        "cpp" => "C++",
+        "cpp" | "cc" | "cxx" | "hpp" | "hh" => "C++",
+        "h" => match header_language {
+            HeaderLanguage::C => "C",
+            HeaderLanguage::Cpp => "C++",
+        },
         "cs" => "C#",
         "css" => "CSS",
         "ex" => "Elixir",
@@ -31,6 +46,7 @@ fn get_extension_for_language(extension: &str) -> Result<String, GetParserError>
         "hs" => "Haskell",
         "lua" => "Lua",
         "ml" => "OCaml",
+        "mli" => "OCaml Interface",
         _ => {
             return Err(GetParserError::NoLanguageFoundForExtension(
                 extension.to_string(),
@@ -41,7 +57,14 @@ fn get_extension_for_language(extension: &str) -> Result<String, GetParserError>
 }
 
 pub fn get_parser_for_extension(extension: &str) -> Result<Parser, GetParserError> {
-    let language = get_extension_for_language(extension)?;
+    get_parser_for_extension_with_header_language(extension, HeaderLanguage::default())
+}
+
+pub fn get_parser_for_extension_with_header_language(
+    extension: &str,
+    header_language: HeaderLanguage,
+) -> Result<Parser, GetParserError> {
+    let language = get_extension_for_language(extension, header_language)?;
     let mut parser = Parser::new();
     match language.as_str() {
         #[cfg(any(feature = "all", feature = "python"))]
@@ -80,6 +103,8 @@ pub fn get_parser_for_extension(extension: &str) -> Result<Parser, GetParserErro
         "Lua" => parser.set_language(&tree_sitter_lua::language())?,
         #[cfg(any(feature = "all", feature = "ocaml"))]
         "OCaml" => parser.set_language(&tree_sitter_ocaml::language_ocaml())?,
+        #[cfg(any(feature = "all", feature = "ocaml"))]
+        "OCaml Interface" => parser.set_language(&tree_sitter_ocaml::language_ocaml_interface())?,
         _ => {
             return Err(GetParserError::NoParserFoundForExtension(
                 language.to_string(),
@@ -88,3 +113,36 @@ pub fn get_parser_for_extension(extension: &str) -> Result<Parser, GetParserErro
     }
     Ok(parser)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_extension_aliases_to_languages() {
+        for (extension, language) in [
+            ("ml", "OCaml"),
+            ("mli", "OCaml Interface"),
+            ("cpp", "C++"),
+            ("cc", "C++"),
+            ("cxx", "C++"),
+            ("hpp", "C++"),
+            ("hh", "C++"),
+            ("c", "C"),
+            ("h", "C"),
+        ] {
+            assert_eq!(
+                get_extension_for_language(extension, HeaderLanguage::default()).unwrap(),
+                language
+            );
+        }
+        assert_eq!(
+            get_extension_for_language("h", HeaderLanguage::Cpp).unwrap(),
+            "C++"
+        );
+        assert!(matches!(
+            get_extension_for_language("mll", HeaderLanguage::default()),
+            Err(GetParserError::NoLanguageFoundForExtension(_))
+        ));
+    }
+}